@@ -1,151 +1,453 @@
-pub mod utils {
-
-    pub enum Exchange {
-        NSE = 0,
-        NFO = 1,
-        CDS = 2,
-        MCX = 3,
-        BSE = 4,
-        BFO = 5,
-    }
-
-    pub fn get_exchange_str(exchange: &Exchange) -> &str {
-        match exchange {
-            Exchange::NSE => "NSE",
-            Exchange::NFO => "NFO",
-            Exchange::CDS => "CDS",
-            Exchange::MCX => "MCX",
-            Exchange::BSE => "BSE",
-            Exchange::BFO => "BFO",
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exchange {
+    NSE = 0,
+    NFO = 1,
+    CDS = 2,
+    MCX = 3,
+    BSE = 4,
+    BFO = 5,
+}
+
+pub fn get_exchange_str(exchange: &Exchange) -> &str {
+    match exchange {
+        Exchange::NSE => "NSE",
+        Exchange::NFO => "NFO",
+        Exchange::CDS => "CDS",
+        Exchange::MCX => "MCX",
+        Exchange::BSE => "BSE",
+        Exchange::BFO => "BFO",
+    }
+}
+
+pub fn get_exchange(exchange: &str) -> Option<Exchange> {
+    match exchange {
+        "NSE" => Some(Exchange::NSE),
+        "NFO" => Some(Exchange::NFO),
+        "CDS" => Some(Exchange::CDS),
+        "MCX" => Some(Exchange::MCX),
+        "BSE" => Some(Exchange::BSE),
+        "BFO" => Some(Exchange::BFO),
+        _ => None,
+    }
+}
+
+pub fn get_index(trading_symbol: &str) -> String {
+    let mut result = String::new();
+    for (i, c) in trading_symbol.chars().enumerate() {
+        if c.is_digit(10) {
+            result = trading_symbol[..i].to_string();
+            break;
         }
     }
+    result
+}
 
-    pub fn get_index(trading_symbol: &str) -> String {
-        let mut result = String::new();
-        for (i, c) in trading_symbol.chars().enumerate() {
-            if c.is_digit(10) {
-                result = trading_symbol[..i].to_string();
-                break;
-            }
+pub fn load_config(file_name: &str) -> serde_json::Value {
+    // file is is common/config.json
+    let contents = std::fs::read_to_string(file_name).unwrap();
+    let config: serde_json::Value = serde_json::from_str(&contents).unwrap();
+    config
+}
+
+/// A contract of the scrip master
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scrip {
+    pub exchange: String,
+    pub token: String,
+    pub lot_size: u32,
+    pub symbol: String,
+    pub trading_symbol: String,
+    /// None for a contract that does not expire, e.g. an equity
+    pub expiry: Option<chrono::NaiveDate>,
+    pub instrument: String,
+    /// "CE" or "PE", "XX" for a future
+    pub option_type: String,
+    pub strike: f64,
+    pub tick_size: f64,
+}
+
+/// Expiry as the scrip file and the API write it, e.g. "04-JAN-2024"
+pub fn format_expiry(expiry: chrono::NaiveDate) -> String {
+    expiry.format("%d-%b-%Y").to_string().to_uppercase()
+}
+
+//Read a txt file as a csv file
+// Header is the first line of the file
+// Exchange,Token,LotSize,Symbol,TradingSymbol,Expiry,Instrument,OptionType,StrikePrice,TickSize
+pub fn read_txt_file_as_csv(
+    file_name: &str,
+    config_file_name: &str,
+    index: &str,
+) -> (Vec<Scrip>, Option<chrono::NaiveDate>) {
+    let config = load_config(config_file_name);
+
+    let symbol_name = config["SCRIP_SYMBOL_NAME"][index].as_str().unwrap();
+
+    let contents = std::fs::read_to_string(file_name).unwrap();
+    let result = parse_scrip_csv(&contents);
+    let expiry_date = get_expiry_date(&result, symbol_name);
+    (result, expiry_date)
+}
+
+// Contracts of a scrip file, the columns are found by the header so a file
+// missing some of them still parses. Quoted fields may hold commas and rows may
+// be short or carry a trailing comma. A row without an expiry, e.g. an equity,
+// keeps None, an empty file gives no rows.
+pub fn parse_scrip_csv(contents: &str) -> Vec<Scrip> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(contents.as_bytes());
+    let header_fields = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(_) => return Vec::new(),
+    };
+    let column = |name: &str| header_fields.iter().position(|header| header == name);
+    let exchange = column("Exchange");
+    let token = column("Token");
+    let lot_size = column("LotSize");
+    let symbol = column("Symbol");
+    let trading_symbol = column("TradingSymbol");
+    let expiry = column("Expiry");
+    let instrument = column("Instrument");
+    let option_type = column("OptionType");
+    let strike = column("StrikePrice");
+    let tick_size = column("TickSize");
+
+    let mut result = Vec::new();
+    for record in reader.records() {
+        // a row the reader cannot make sense of, e.g. bad UTF-8, is dropped
+        let record = match record {
+            Ok(record) => record,
+            Err(_) => continue,
+        };
+        let field = |column: Option<usize>| column.and_then(|i| record.get(i)).unwrap_or("");
+        result.push(Scrip {
+            exchange: field(exchange).to_string(),
+            token: field(token).to_string(),
+            lot_size: field(lot_size).parse().unwrap_or(0),
+            symbol: field(symbol).to_string(),
+            trading_symbol: field(trading_symbol).to_string(),
+            expiry: chrono::NaiveDate::parse_from_str(field(expiry), "%d-%b-%Y").ok(),
+            instrument: field(instrument).to_string(),
+            option_type: field(option_type).to_string(),
+            strike: field(strike).parse().unwrap_or(0.0),
+            tick_size: field(tick_size).parse().unwrap_or(0.05),
+        });
+    }
+    result
+}
+
+/// Which of the listed expiries to trade
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExpiryKind {
+    Nearest,
+    /// Nearest expiry that is not the last of its month
+    Weekly,
+    /// Nearest expiry that is the last of its calendar month
+    Monthly,
+    /// The nth expiry from today, `Nth(0)` being the nearest
+    Nth(usize),
+}
+
+// Nearest expiry of `symbol` from today, None when all have expired
+pub fn get_expiry_date(data: &[Scrip], symbol: &str) -> Option<chrono::NaiveDate> {
+    get_expiry_date_filtered(data, symbol, ExpiryKind::Nearest)
+}
+
+// Expiry of `symbol` of the given kind from today, None when none is listed
+pub fn get_expiry_date_filtered(
+    data: &[Scrip],
+    symbol: &str,
+    kind: ExpiryKind,
+) -> Option<chrono::NaiveDate> {
+    select_expiry(data, symbol, kind, chrono::Local::now().date_naive())
+}
+
+fn select_expiry(
+    data: &[Scrip],
+    symbol: &str,
+    kind: ExpiryKind,
+    today: chrono::NaiveDate,
+) -> Option<chrono::NaiveDate> {
+    use chrono::Datelike;
+    let expiries: std::collections::BTreeSet<chrono::NaiveDate> = data
+        .iter()
+        .filter(|scrip| scrip.symbol == symbol)
+        .filter_map(|scrip| scrip.expiry)
+        .collect();
+    // no later expiry in the same month of the same year
+    let monthly = |expiry: &chrono::NaiveDate| {
+        expiries
+            .range(*expiry..)
+            .nth(1)
+            .filter(|next| (next.year(), next.month()) == (expiry.year(), expiry.month()))
+            .is_none()
+    };
+    let mut upcoming = expiries.range(today..);
+    match kind {
+        ExpiryKind::Nearest => upcoming.next(),
+        ExpiryKind::Weekly => upcoming.find(|expiry| !monthly(expiry)),
+        ExpiryKind::Monthly => upcoming.find(|expiry| monthly(expiry)),
+        ExpiryKind::Nth(n) => upcoming.nth(n),
+    }
+    .copied()
+}
+
+/// The chain has no contract at the strike asked for
+#[derive(Debug, Clone, PartialEq)]
+pub struct StrikeNotFound {
+    pub symbol: String,
+    pub expiry: chrono::NaiveDate,
+    pub strike: f64,
+    pub option_type: String,
+}
+
+impl std::fmt::Display for StrikeNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "No {} {} {} in the {} chain",
+            self.symbol,
+            self.strike,
+            self.option_type,
+            format_expiry(self.expiry)
+        )
+    }
+}
+
+impl std::error::Error for StrikeNotFound {}
+
+pub fn get_strike_info(
+    data: &[Scrip],
+    symbol: &str,
+    expiry: chrono::NaiveDate,
+    strike_price: f64,
+    opt: &str,
+) -> Result<(String, String), StrikeNotFound> {
+    // strikes are compared within half a tick, the scrip file has "22500.00"
+    data.iter()
+        .find(|scrip| {
+            scrip.symbol == symbol
+                && scrip.expiry == Some(expiry)
+                && (scrip.strike - strike_price).abs() < scrip.tick_size / 2.0
+                && scrip.option_type == opt
+        })
+        .map(|scrip| (scrip.token.clone(), scrip.trading_symbol.clone()))
+        .ok_or_else(|| StrikeNotFound {
+            symbol: symbol.to_string(),
+            expiry,
+            strike: strike_price,
+            option_type: opt.to_string(),
+        })
+}
+
+// Strike nearest to spot among the listed strikes, ties pick the lower strike
+pub fn atm_strike(
+    data: &[Scrip],
+    symbol: &str,
+    expiry: chrono::NaiveDate,
+    spot: f64,
+) -> Option<f64> {
+    let mut atm: Option<f64> = None;
+    for scrip in data.iter() {
+        if scrip.symbol != symbol || scrip.expiry != Some(expiry) {
+            continue;
         }
-        result
-    }
-
-    pub fn load_config(file_name: &str) -> serde_json::Value {
-        // file is is common/config.json
-        let contents = std::fs::read_to_string(file_name).unwrap();
-        let config: serde_json::Value = serde_json::from_str(&contents).unwrap();
-        config
-    }
-
-    //Read a txt file as a csv file
-    // Header is the first line of the file
-    // Exchange,Token,LotSize,Symbol,TradingSymbol,Expiry,Instrument,OptionType,StrikePrice,TickSize
-    pub fn read_txt_file_as_csv(
-        file_name: &str,
-        config_file_name: &str,
-        index: &str,
-    ) -> (Vec<serde_json::Value>, String) {
-        let config = load_config(config_file_name);
-
-        let symbol_name = config["SCRIP_SYMBOL_NAME"][index].as_str().unwrap();
-
-        let mut result: Vec<serde_json::Value> = Vec::new();
-        let contents = std::fs::read_to_string(file_name).unwrap();
-        let mut lines = contents.lines();
-        let header = lines.next().unwrap();
-        let header_fields: Vec<&str> = header.split(",").collect();
-        for line in lines {
-            let fields: Vec<&str> = line.split(",").collect();
-            let mut obj = serde_json::json!({});
-            for (i, field) in fields.iter().enumerate() {
-                obj[header_fields[i]] = serde_json::Value::String(field.to_string());
+        let strike = scrip.strike;
+        atm = match atm {
+            Some(best)
+                if (best - spot).abs() < (strike - spot).abs()
+                    || ((best - spot).abs() == (strike - spot).abs() && best < strike) =>
+            {
+                Some(best)
             }
-            result.push(obj);
-        }
-        let expiry_date = get_expiry_date(&result, &symbol_name);
-        (result, expiry_date)
+            _ => Some(strike),
+        };
     }
+    atm
+}
 
-    pub fn get_expiry_date(data: &Vec<serde_json::Value>, symbol: &str) -> String {
-        //let data = read_txt_file_as_csv(file_name);
+// Lot size of a symbol and a warning when it cannot be trusted. The scrip file is
+// refreshed daily, so its LotSize wins over the config's LOT_SIZE which can go stale.
+pub fn get_lot_size(
+    data: &[Scrip],
+    symbol: &str,
+    config: &serde_json::Value,
+    index: &str,
+) -> (u32, Option<String>) {
+    let config_lot_size = config["LOT_SIZE"][index].as_u64().map(|l| l as u32);
+    let scrip_lot_size = data
+        .iter()
+        .find(|scrip| scrip.symbol == symbol && scrip.lot_size > 0)
+        .map(|scrip| scrip.lot_size);
+    match (scrip_lot_size, config_lot_size) {
+        (Some(scrip), Some(config)) if scrip != config => (
+            scrip,
+            Some(format!(
+                "Lot size of {} is {} in the scrip file but {} in the config, using {}",
+                index, scrip, config, scrip
+            )),
+        ),
+        (Some(scrip), _) => (scrip, None),
+        (None, Some(config)) => (
+            config,
+            Some(format!(
+                "No lot size for {} in the scrip file, using {} from the config",
+                index, config
+            )),
+        ),
+        (None, None) => (0, Some(format!("No lot size for {}", index))),
+    }
+}
 
-        // find the closest expiry date to today
-        let mut min_diff = 100000;
-        let mut expiry_date = String::new();
-        for row in data.iter() {
-            let sym = row["Symbol"].as_str().unwrap();
-            if sym != symbol {
-                continue;
-            }
-            let expiry = row["Expiry"].as_str().unwrap();
-            let diff = chrono::NaiveDate::parse_from_str(expiry, "%d-%b-%Y")
-                .unwrap()
-                .signed_duration_since(chrono::Local::now().naive_local().date())
-                .num_days();
-            if diff < min_diff && diff >= 0 {
-                min_diff = diff;
-                expiry_date = expiry.to_string();
-            }
-        }
-        expiry_date
-    }
-
-    pub fn get_strike_info(
-        data: &Vec<serde_json::Value>,
-        expiry: &str,
-        strike_price: f64,
-        opt: &str,
-    ) -> (String, String) {
-        let mut trading_symbol = String::new();
-        let mut token = String::new();
-        for row in data.iter() {
-            let expiry_date = row["Expiry"].as_str().unwrap();
-            let sym = row["StrikePrice"].as_str().unwrap();
-            let sym = sym.parse::<f64>().unwrap();
-            let option_type = row["OptionType"].as_str().unwrap();
-
-            if expiry_date == expiry && sym == strike_price && option_type == opt {
-                token = row["Token"].as_str().unwrap().to_string();
-                trading_symbol = row["TradingSymbol"].as_str().unwrap().to_string();
-                break;
+/// An option leg of a strategy, `qty` is negative for a short leg
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptionLeg {
+    pub strike: f64,
+    pub option_type: String,
+    pub qty: i64,
+}
+
+// Breakeven underlying levels of a short strangle, straddle or iron fly: the short
+// PE strike less the net credit and the short CE strike plus the net credit
+pub fn breakevens(legs: &[OptionLeg], net_credit: f64) -> (f64, f64) {
+    let short_strikes = |opt: &str| -> Vec<f64> {
+        legs.iter()
+            .filter(|leg| leg.qty < 0 && leg.option_type == opt)
+            .map(|leg| leg.strike)
+            .collect()
+    };
+    let pe_strike = short_strikes("PE").into_iter().fold(f64::NAN, f64::max);
+    let ce_strike = short_strikes("CE").into_iter().fold(f64::NAN, f64::min);
+    (pe_strike - net_credit, ce_strike + net_credit)
+}
+
+// Max loss of one lot of an iron fly, reached beyond a wing: the wing width less
+// the net credit, times the lot size
+pub fn iron_fly_max_loss(wing_width: f64, net_credit: f64, lot_size: u32) -> f64 {
+    (wing_width - net_credit) * lot_size as f64
+}
+
+// Lots whose max loss stays within `risk_amount`, rounded down. A position without
+// a positive defined risk cannot be sized this way.
+pub fn lots_for_risk(max_loss_per_lot: f64, risk_amount: f64) -> Result<u32, String> {
+    if max_loss_per_lot.is_nan() || max_loss_per_lot <= 0.0 {
+        return Err(format!(
+            "Max loss per lot must be positive to size by risk, got {}",
+            max_loss_per_lot
+        ));
+    }
+    if risk_amount.is_nan() || risk_amount <= 0.0 {
+        return Err(format!("Risk amount must be positive, got {}", risk_amount));
+    }
+    Ok((risk_amount / max_loss_per_lot).floor() as u32)
+}
+
+// Trading holidays from the config's HOLIDAYS list, dates as "26-Jan-2024"
+pub fn load_holidays(config: &serde_json::Value) -> Vec<chrono::NaiveDate> {
+    config["HOLIDAYS"]
+        .as_array()
+        .map(|holidays| {
+            holidays
+                .iter()
+                .filter_map(|day| day.as_str())
+                .filter_map(|day| chrono::NaiveDate::parse_from_str(day, "%d-%b-%Y").ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn is_trading_day(date: chrono::NaiveDate, holidays: &[chrono::NaiveDate]) -> bool {
+    use chrono::Datelike;
+    let weekend = matches!(date.weekday(), chrono::Weekday::Sat | chrono::Weekday::Sun);
+    !weekend && !holidays.contains(&date)
+}
+
+// Regular session of an exchange, local (IST) time
+pub fn market_hours(exchange: &Exchange) -> (chrono::NaiveTime, chrono::NaiveTime) {
+    let time = |h, m| chrono::NaiveTime::from_hms_opt(h, m, 0).unwrap();
+    match exchange {
+        Exchange::CDS => (time(9, 0), time(17, 0)),
+        Exchange::MCX => (time(9, 0), time(23, 30)),
+        _ => (time(9, 15), time(15, 30)),
+    }
+}
+
+pub fn market_is_open(
+    exchange: &Exchange,
+    now: chrono::NaiveDateTime,
+    holidays: &[chrono::NaiveDate],
+) -> bool {
+    let (open, close) = market_hours(exchange);
+    is_trading_day(now.date(), holidays) && now.time() >= open && now.time() < close
+}
+
+// Equity derivative strikes are whole numbers, currency and commodity strikes are not
+pub fn format_strike(exchange: &Exchange, strike: f64) -> String {
+    match exchange {
+        Exchange::CDS => format!("{:.4}", strike),
+        Exchange::MCX => format!("{:.2}", strike),
+        _ => format!("{:.0}", strike),
+    }
+}
+
+// Rounds a price to the instrument's price precision (`pp`), removing float noise
+pub fn round_price(price: f64, precision: u32) -> f64 {
+    let factor = 10f64.powi(precision as i32);
+    (price * factor).round() / factor
+}
+
+pub fn format_price(price: f64, precision: u32) -> String {
+    format!("{:.*}", precision as usize, round_price(price, precision))
+}
+
+/// How JSON is rendered for the logs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JsonStyle {
+    /// The compact `pretty_print_json` form, starting at the given indent
+    Custom(usize),
+    /// Valid, parseable JSON from `serde_json::to_string_pretty`
+    Serde,
+}
+
+pub fn format_json(json: &serde_json::Value, style: JsonStyle) -> String {
+    match style {
+        JsonStyle::Custom(indent) => pretty_print_json(json, indent),
+        JsonStyle::Serde => serde_json::to_string_pretty(json).unwrap_or_else(|_| json.to_string()),
+    }
+}
+
+pub fn pretty_print_json(json: &serde_json::Value, indent: usize) -> String {
+    let mut result = String::new();
+    match json {
+        serde_json::Value::Null => result.push_str("null"),
+        serde_json::Value::Bool(b) => result.push_str(&b.to_string()),
+        serde_json::Value::Number(n) => result.push_str(&n.to_string()),
+        serde_json::Value::String(s) => result.push_str(&s.to_string()),
+        serde_json::Value::Array(a) => {
+            result.push_str("[\n");
+            for item in a.iter() {
+                result.push_str(&pretty_print_json(item, indent + 1));
+                result.push_str(",\n");
             }
+            result.push_str("]");
         }
-        (token, trading_symbol)
-    }
-
-    pub fn pretty_print_json(json: &serde_json::Value, indent: usize) -> String {
-        let mut result = String::new();
-        match json {
-            serde_json::Value::Null => result.push_str("null"),
-            serde_json::Value::Bool(b) => result.push_str(&b.to_string()),
-            serde_json::Value::Number(n) => result.push_str(&n.to_string()),
-            serde_json::Value::String(s) => result.push_str(&s.to_string()),
-            serde_json::Value::Array(a) => {
-                result.push_str("[\n");
-                for item in a.iter() {
-                    result.push_str(&pretty_print_json(item, indent + 1));
-                    result.push_str(",\n");
-                }
-                result.push_str("]");
-            }
-            serde_json::Value::Object(o) => {
-                result.push_str("{\n");
-                for (key, value) in o.iter() {
-                    result.push_str(&format!("{:indent$}{}: ", "", key, indent = indent + 1));
-                    result.push_str(&pretty_print_json(value, indent + 1));
-                    result.push_str(",\n");
-                }
-                result.push_str("}");
+        serde_json::Value::Object(o) => {
+            result.push_str("{\n");
+            for (key, value) in o.iter() {
+                result.push_str(&format!("{:indent$}{}: ", "", key, indent = indent + 1));
+                result.push_str(&pretty_print_json(value, indent + 1));
+                result.push_str(",\n");
             }
+            result.push_str("}");
         }
-        result
     }
+    result
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::utils::utils::*;
+    use crate::utils::*;
 
     #[test]
     fn test_get_exchange_str() {
@@ -172,9 +474,9 @@ mod tests {
             "./config.json",
             "NIFTY",
         );
-        assert_eq!(result[0]["Exchange"], "NFO");
-        assert_eq!(result[0]["TickSize"], "0.05");
-        assert_eq!(exipry_date, "04-JAN-2024");
+        assert_eq!(result[0].exchange, "NFO");
+        assert_eq!(result[0].tick_size, 0.05);
+        assert_eq!(exipry_date, Some(expiry()));
     }
 
     #[test]
@@ -184,8 +486,8 @@ mod tests {
             "./config.json",
             "NIFTY",
         );
-        assert_eq!(result[0]["Exchange"], "NFO");
-        assert_eq!(expiry_date, "04-JAN-2024");
+        assert_eq!(result[0].exchange, "NFO");
+        assert_eq!(expiry_date, Some(expiry()));
     }
 
     #[test]
@@ -195,10 +497,252 @@ mod tests {
             "./config.json",
             "NIFTY",
         );
-        assert_eq!(result[0]["Exchange"], "NFO");
-        let (token, trading_symbol) = get_strike_info(&result, &expiry_date, 21800.0, "CE");
+        assert_eq!(result[0].exchange, "NFO");
+        let (token, trading_symbol) =
+            get_strike_info(&result, "NIFTY", expiry_date.unwrap(), 21800.0, "CE").unwrap();
         assert_eq!(token, "42216");
         //
         assert_eq!(trading_symbol, "NIFTY04JAN24C21800");
     }
+
+    fn expiry() -> chrono::NaiveDate {
+        chrono::NaiveDate::from_ymd_opt(2024, 1, 4).unwrap()
+    }
+
+    fn option(token: &str, strike: f64, option_type: &str) -> Scrip {
+        Scrip {
+            exchange: "NFO".to_string(),
+            token: token.to_string(),
+            lot_size: 50,
+            symbol: "NIFTY".to_string(),
+            trading_symbol: format!("NIFTY04JAN24{}{}", &option_type[..1], strike),
+            expiry: Some(expiry()),
+            instrument: "OPTIDX".to_string(),
+            option_type: option_type.to_string(),
+            strike,
+            tick_size: 0.05,
+        }
+    }
+
+    #[test]
+    fn test_parse_scrip_rows() {
+        let contents = "Exchange,Token,LotSize,Symbol,TradingSymbol,Expiry,Instrument,OptionType,StrikePrice,TickSize\n\
+            NFO,43510,50,NIFTY,NIFTY04JAN24C22500,04-JAN-2024,OPTIDX,CE,22500.00,0.05,\n\
+            NFO,35001,50,NIFTY,NIFTY25JAN24F,25-JAN-2024,FUTIDX,XX,-1,0.05,\n\
+            NSE,2885,1,RELIANCE,RELIANCE-EQ,,EQ,,,0.05,\n";
+        let scrips = parse_scrip_csv(contents);
+        assert_eq!(scrips.len(), 3);
+        assert_eq!(scrips[0].token, "43510");
+        assert_eq!(scrips[0].lot_size, 50);
+        assert_eq!(scrips[0].expiry, Some(expiry()));
+        assert_eq!(scrips[0].strike, 22500.0);
+        assert_eq!(scrips[1].option_type, "XX");
+        assert_eq!(scrips[1].expiry.map(format_expiry).unwrap(), "25-JAN-2024");
+        // an equity has no expiry but is still a contract
+        assert_eq!(scrips[2].exchange, "NSE");
+        assert_eq!(scrips[2].trading_symbol, "RELIANCE-EQ");
+        assert_eq!(scrips[2].expiry, None);
+        assert!(parse_scrip_csv("").is_empty());
+    }
+
+    #[test]
+    fn test_quoted_field_with_comma() {
+        let contents = "Exchange,Token,LotSize,Symbol,TradingSymbol,Expiry,Instrument,OptionType,StrikePrice,TickSize\n\
+            NFO,\"43510\",50,\"BAJAJ-AUTO, LTD\",BAJAJ-AUTO25JAN24C7000,25-JAN-2024,OPTSTK,CE,7000.00,0.05,\n\
+            NFO,43511,50,BAJAJ-AUTO,BAJAJ-AUTO25JAN24P7000,25-JAN-2024,OPTSTK,PE\n";
+        let scrips = parse_scrip_csv(contents);
+        assert_eq!(scrips.len(), 2);
+        assert_eq!(scrips[0].token, "43510");
+        assert_eq!(scrips[0].symbol, "BAJAJ-AUTO, LTD");
+        assert_eq!(scrips[0].trading_symbol, "BAJAJ-AUTO25JAN24C7000");
+        assert_eq!(scrips[0].strike, 7000.0);
+        // a short row keeps the columns it has
+        assert_eq!(scrips[1].option_type, "PE");
+        assert_eq!(scrips[1].strike, 0.0);
+        assert_eq!(scrips[1].tick_size, 0.05);
+    }
+
+    #[test]
+    fn test_weekly_and_monthly_expiry() {
+        let date =
+            |day: u32, month: u32| chrono::NaiveDate::from_ymd_opt(2024, month, day).unwrap();
+        let data: Vec<Scrip> = [
+            date(4, 1),
+            date(11, 1),
+            date(25, 1),
+            date(1, 2),
+            date(29, 2),
+        ]
+        .iter()
+        .map(|expiry| Scrip {
+            expiry: Some(*expiry),
+            ..option("1", 21800.0, "CE")
+        })
+        .collect();
+        let on = |today, kind| select_expiry(&data, "NIFTY", kind, today);
+
+        assert_eq!(on(date(2, 1), ExpiryKind::Nearest), Some(date(4, 1)));
+        assert_eq!(on(date(2, 1), ExpiryKind::Weekly), Some(date(4, 1)));
+        assert_eq!(on(date(2, 1), ExpiryKind::Monthly), Some(date(25, 1)));
+        assert_eq!(on(date(2, 1), ExpiryKind::Nth(0)), Some(date(4, 1)));
+        assert_eq!(on(date(2, 1), ExpiryKind::Nth(2)), Some(date(25, 1)));
+        // an expiry day still trades that expiry
+        assert_eq!(on(date(25, 1), ExpiryKind::Nearest), Some(date(25, 1)));
+        assert_eq!(on(date(25, 1), ExpiryKind::Weekly), Some(date(1, 2)));
+        assert_eq!(on(date(26, 1), ExpiryKind::Monthly), Some(date(29, 2)));
+        assert_eq!(on(date(2, 1), ExpiryKind::Nth(5)), None);
+        assert_eq!(on(date(1, 3), ExpiryKind::Nearest), None);
+        let other = select_expiry(&data, "BANKNIFTY", ExpiryKind::Nearest, date(2, 1));
+        assert_eq!(other, None);
+    }
+
+    #[test]
+    fn test_monthly_expiry_across_year_end() {
+        let date = |year: i32, month: u32, day: u32| {
+            chrono::NaiveDate::from_ymd_opt(year, month, day).unwrap()
+        };
+        // a long dated December contract a year out
+        let data: Vec<Scrip> = [date(2024, 12, 19), date(2024, 12, 26), date(2025, 12, 30)]
+            .iter()
+            .map(|expiry| Scrip {
+                expiry: Some(*expiry),
+                ..option("1", 21800.0, "CE")
+            })
+            .collect();
+        let on = |kind| select_expiry(&data, "NIFTY", kind, date(2024, 12, 2));
+
+        assert_eq!(on(ExpiryKind::Monthly), Some(date(2024, 12, 26)));
+        assert_eq!(on(ExpiryKind::Weekly), Some(date(2024, 12, 19)));
+    }
+
+    #[test]
+    fn test_get_strike_info_tolerance() {
+        let data = vec![option("43510", 22500.0, "CE")];
+        let strike = (22512.3_f64 / 50.0).round() * 50.0;
+        let (token, trading_symbol) =
+            get_strike_info(&data, "NIFTY", expiry(), strike, "CE").unwrap();
+        assert_eq!(token, "43510");
+        assert_eq!(trading_symbol, "NIFTY04JAN24C22500");
+
+        let missing = get_strike_info(&data, "NIFTY", expiry(), 22550.0, "CE").unwrap_err();
+        assert_eq!(missing.strike, 22550.0);
+        assert_eq!(
+            missing.to_string(),
+            "No NIFTY 22550 CE in the 04-JAN-2024 chain"
+        );
+        assert!(get_strike_info(&data, "NIFTY", expiry(), strike, "PE").is_err());
+        assert!(get_strike_info(&data, "BANKNIFTY", expiry(), strike, "CE").is_err());
+    }
+
+    #[test]
+    fn test_format_strike() {
+        assert_eq!(format_strike(&Exchange::NFO, 22500.0), "22500");
+        assert_eq!(format_strike(&Exchange::CDS, 83.25), "83.2500");
+        assert_eq!(format_strike(&Exchange::MCX, 6150.5), "6150.50");
+    }
+
+    #[test]
+    fn test_price_precision() {
+        let noisy = 100.1 + 0.2;
+        assert_eq!(format_price(noisy, 2), "100.30");
+        assert_eq!(round_price(100.30000000001, 2), 100.3);
+        assert_eq!(format_price(83.12345, 4), "83.1235");
+    }
+
+    #[test]
+    fn test_atm_strike() {
+        let data: Vec<Scrip> = [21700.0, 21750.0, 21800.0, 21850.0]
+            .iter()
+            .flat_map(|strike| ["CE", "PE"].map(|opt| option("1", *strike, opt)))
+            .collect();
+        assert_eq!(atm_strike(&data, "NIFTY", expiry(), 21781.4), Some(21800.0));
+        assert_eq!(atm_strike(&data, "NIFTY", expiry(), 21712.0), Some(21700.0));
+        // ties pick the lower strike
+        assert_eq!(atm_strike(&data, "NIFTY", expiry(), 21775.0), Some(21750.0));
+        let next_week = expiry() + chrono::Duration::days(7);
+        assert_eq!(atm_strike(&data, "NIFTY", next_week, 21775.0), None);
+    }
+
+    #[test]
+    fn test_lot_size_prefers_scrip_file() {
+        let config = load_config("./config.json");
+        let mut scrip = option("42216", 21800.0, "CE");
+        scrip.lot_size = 25;
+        let (lot_size, warning) = get_lot_size(&[scrip.clone()], "NIFTY", &config, "NIFTY");
+        assert_eq!(lot_size, 25);
+        assert!(warning.unwrap().contains("50 in the config"));
+
+        scrip.symbol = "FINNIFTY".to_string();
+        scrip.lot_size = 40;
+        let lot_size = get_lot_size(&[scrip], "FINNIFTY", &config, "FINNIFTY");
+        assert_eq!(lot_size, (40, None));
+    }
+
+    #[test]
+    fn test_serde_pretty_round_trip() {
+        let value = serde_json::json!({
+            "ce_symbol": "NIFTY04JAN24C21800",
+            "ce_ltp": 101.5,
+            "legs": [{"qty": 50}, {"qty": -50}],
+            "hedged": false
+        });
+        let pretty = format_json(&value, JsonStyle::Serde);
+        let parsed: serde_json::Value = serde_json::from_str(&pretty).unwrap();
+        assert_eq!(parsed, value);
+        assert_eq!(
+            format_json(&value, JsonStyle::Custom(3)),
+            pretty_print_json(&value, 3)
+        );
+    }
+
+    #[test]
+    fn test_breakevens() {
+        let leg = |strike: f64, option_type: &str, qty: i64| OptionLeg {
+            strike,
+            option_type: option_type.to_string(),
+            qty,
+        };
+        let strangle = [leg(21600.0, "PE", -50), leg(22000.0, "CE", -50)];
+        assert_eq!(breakevens(&strangle, 85.5), (21514.5, 22085.5));
+
+        // the long wings of an iron fly do not move the breakevens
+        let iron_fly = [
+            leg(21800.0, "PE", -50),
+            leg(21800.0, "CE", -50),
+            leg(21500.0, "PE", 50),
+            leg(22100.0, "CE", 50),
+        ];
+        assert_eq!(breakevens(&iron_fly, 180.0), (21620.0, 21980.0));
+    }
+
+    #[test]
+    fn test_lots_for_risk() {
+        // 200 point wings for 130 of credit, 50 per lot
+        let max_loss = iron_fly_max_loss(200.0, 130.0, 50);
+        assert_eq!(max_loss, 3500.0);
+        assert_eq!(lots_for_risk(max_loss, 10000.0), Ok(2));
+        assert_eq!(lots_for_risk(max_loss, 3499.0), Ok(0));
+        // a credit above the wing width has no defined risk
+        assert!(lots_for_risk(iron_fly_max_loss(200.0, 210.0, 50), 10000.0).is_err());
+        assert!(lots_for_risk(0.0, 10000.0).is_err());
+        assert!(lots_for_risk(max_loss, 0.0).is_err());
+    }
+
+    #[test]
+    fn test_holiday_is_closed() {
+        let config = load_config("./config.json");
+        let holidays = load_holidays(&config);
+        let at = |date: &str, time: &str| {
+            chrono::NaiveDateTime::parse_from_str(&format!("{} {}", date, time), "%d-%b-%Y %H:%M")
+                .unwrap()
+        };
+        let open = |exchange, date, time| market_is_open(&exchange, at(date, time), &holidays);
+        // Republic Day, a Friday
+        assert!(!open(Exchange::NFO, "26-Jan-2024", "10:00"));
+        assert!(open(Exchange::NFO, "25-Jan-2024", "10:00"));
+        assert!(!open(Exchange::NFO, "25-Jan-2024", "15:45"));
+        assert!(open(Exchange::MCX, "25-Jan-2024", "21:00"));
+        // a Saturday
+        assert!(!open(Exchange::MCX, "27-Jan-2024", "10:00"));
+    }
 }
@@ -1,17 +1,25 @@
 #![allow(dead_code)]
 pub mod scrips {
 
+    use common::utils::{get_exchange_str, Exchange};
+    use std::error::Error;
     use std::fs;
     use std::fs::File;
     use std::io;
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
     use zip::read::ZipArchive;
-    use common::utils::utils::{Exchange, get_exchange_str};
-    
 
     pub const DOWNLOAD_PATH: &str = "./downloads";
 
-    pub fn download_scrip(exchange: &Exchange) {
+    /// Where today's scrip file of the exchange is saved
+    pub fn scrip_file_path(exchange: &Exchange) -> PathBuf {
+        let today = chrono::Local::now().format("%Y-%m-%d");
+        let exchange = get_exchange_str(exchange);
+        Path::new(DOWNLOAD_PATH).join(format!("{}_symbols_{}.txt", exchange, today))
+    }
+
+    /// Downloads today's scrip file of the exchange unless it is already there, returns its path
+    pub fn download_scrip(exchange: &Exchange) -> Result<PathBuf, Box<dyn Error>> {
         let url = match exchange {
             Exchange::NSE => "https://api.shoonya.com/NSE_symbols.txt.zip",
             Exchange::NFO => "https://api.shoonya.com/NFO_symbols.txt.zip",
@@ -24,53 +32,156 @@ pub mod scrips {
         // get today's date in YYYY-MM-DD format
         let today = chrono::Local::now().format("%Y-%m-%d").to_string();
 
-        // convert exchange to string
-        let exchange = get_exchange_str (&exchange);
+        let download_file = scrip_file_path(exchange);
 
-        let download_file: String = format!("{}/{}_symbols_{}.txt", DOWNLOAD_PATH, exchange, today);
+        // convert exchange to string
+        let exchange = get_exchange_str(exchange);
 
         log::info!(
             "Downloading file {} for today ({}) for exchange {}",
-            download_file,
+            download_file.display(),
             today,
             exchange
         );
 
-        if Path::new(&download_file).exists() {
+        if download_file.exists() {
             // file already exists
             log::info!(
                 "File already exists for today ({}) for exchange {}",
                 today,
                 exchange
             );
-            return;
+            return Ok(download_file);
         }
 
+        download_scrip_from(url, &download_file)?;
+        Ok(download_file)
+    }
+
+    // Unzips the symbols file at `url` into `download_file`
+    fn download_scrip_from(url: &str, download_file: &Path) -> Result<(), Box<dyn Error>> {
         let client = reqwest::blocking::Client::new();
-        let response = client.get(url).send().unwrap();
+        let response = client.get(url).send()?.error_for_status()?;
 
-        let bytes = response.bytes().unwrap();
+        let bytes = response.bytes()?;
         let cursor = io::Cursor::new(bytes);
 
-        let mut archive = ZipArchive::new(cursor).unwrap();
+        let mut archive = ZipArchive::new(cursor)?;
         for i in 0..archive.len() {
-            let mut file = archive.by_index(i).unwrap();
+            let mut file = archive.by_index(i)?;
             if file.name().ends_with(".txt") {
-                // create DOWNLOAD_PATH directory if it doesn't exist
-                fs::create_dir_all(DOWNLOAD_PATH).unwrap();
+                if let Some(dir) = download_file.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                // a copy cut short must not pass for today's file on the next run
+                let partial = download_file.with_extension("txt.part");
+                let mut outfile = File::create(&partial)?;
+                io::copy(&mut file, &mut outfile)?;
+                fs::rename(&partial, download_file)?;
+                return Ok(());
+            }
+        }
+        Err(format!("No symbols file in {}", url).into())
+    }
 
-                let _ = Path::new(DOWNLOAD_PATH).join(file.name());
+    // Date of a downloaded file, from its "{EXCHANGE}_symbols_{date}.txt" name
+    fn scrip_file_date(name: &str) -> Option<chrono::NaiveDate> {
+        let (_, date) = name.strip_suffix(".txt")?.rsplit_once("_symbols_")?;
+        chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+    }
 
-                let mut outfile = File::create(&download_file).unwrap();
-                std::io::copy(&mut file, &mut outfile).unwrap();
+    /// Deletes the scrip files downloaded more than `keep_days` days ago, returns the files deleted
+    pub fn cleanup_old_scrips(keep_days: u32) -> io::Result<Vec<PathBuf>> {
+        let today = chrono::Local::now().date_naive();
+        cleanup_scrips_in(Path::new(DOWNLOAD_PATH), keep_days, today)
+    }
+
+    fn cleanup_scrips_in(
+        dir: &Path,
+        keep_days: u32,
+        today: chrono::NaiveDate,
+    ) -> io::Result<Vec<PathBuf>> {
+        let mut removed = Vec::new();
+        if !dir.exists() {
+            return Ok(removed);
+        }
+        let oldest = today - chrono::Duration::days(keep_days as i64);
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            // files not named by the download are left alone
+            let stale = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(scrip_file_date)
+                .filter(|date| *date < oldest)
+                .is_some();
+            if stale {
+                fs::remove_file(&path)?;
+                log::info!("Removed stale scrip file {}", path.display());
+                removed.push(path);
             }
         }
+        Ok(removed)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_download_error_is_returned() {
+            let dir = std::env::temp_dir().join(format!("download_{}", std::process::id()));
+            let file = dir.join("NFO_symbols_2024-01-10.txt");
+            // nothing listens on the discard port
+            let result = download_scrip_from("http://127.0.0.1:9/NFO_symbols.txt.zip", &file);
+            assert!(result.is_err());
+            assert!(!file.exists());
+        }
+
+        #[test]
+        fn test_cleanup_old_scrips() {
+            let dir = std::env::temp_dir().join(format!("scrips_{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let names = [
+                "NFO_symbols_2024-01-01.txt",
+                "NFO_symbols_2024-01-08.txt",
+                "BFO_symbols_2024-01-09.txt",
+                "NFO_symbols_2024-01-10.txt",
+                "notes.txt",
+            ];
+            for name in names {
+                File::create(dir.join(name)).unwrap();
+            }
+
+            let today = chrono::NaiveDate::from_ymd_opt(2024, 1, 10).unwrap();
+            let removed = cleanup_scrips_in(&dir, 2, today).unwrap();
+            assert_eq!(removed, vec![dir.join("NFO_symbols_2024-01-01.txt")]);
+            let mut left: Vec<String> = fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            left.sort();
+            assert_eq!(
+                left,
+                [
+                    "BFO_symbols_2024-01-09.txt",
+                    "NFO_symbols_2024-01-08.txt",
+                    "NFO_symbols_2024-01-10.txt",
+                    "notes.txt",
+                ]
+            );
+
+            assert!(cleanup_scrips_in(&dir.join("missing"), 2, today)
+                .unwrap()
+                .is_empty());
+            fs::remove_dir_all(&dir).unwrap();
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use common::utils::utils::Exchange;
+    use common::utils::Exchange;
 
     use super::*;
     use crate::scrips::download_scrip;
@@ -78,10 +189,11 @@ mod tests {
 
     #[test]
     fn it_works() {
-        download_scrip(&Exchange::BFO);
+        let path = download_scrip(&Exchange::BFO).unwrap();
         // assert file exists
         let today = chrono::Local::now().format("%Y-%m-%d").to_string();
         let file = format!("{}/{}_symbols_{}.txt", scrips::DOWNLOAD_PATH, "BFO", today);
         assert!(Path::new(&file).exists());
+        assert_eq!(path, Path::new(&file));
     }
 }
@@ -1,142 +1,462 @@
-pub mod auth {
-
-    use crate::urls::urls::{AUTHORIZE, HOST};
-    use log::*;
-    use redis::Commands;
-    use sha2::{Digest, Sha256};
-    use totp_rs::{Rfc6238, Secret, TOTP};
-
-    pub struct Auth {
-        pub username: String,
-        pub accountid: String,
-        pub password: String,
-        pub susertoken: String,
-    }
-
-    impl Auth {
-        pub fn login(&mut self, file_name: &str, force_login: bool) {
-            const REDIS_URL: &str = "redis://127.0.0.1/";
-            const TOKEN: &str = "access_token_shoonya";
-
-            let redis_client = redis::Client::open(REDIS_URL).unwrap();
-            let mut con = redis_client.get_connection().unwrap();
-
-            let super_token: Result<String, redis::RedisError> = con.get(TOKEN);
-            let file = std::fs::File::open(file_name).unwrap();
-            let creds: serde_json::Value = serde_yaml::from_reader(file).unwrap();
-            match super_token {
-                Ok(token) if force_login == false => {
-                    debug!("Token found in cache");
-                    let userid = creds["user"].as_str().unwrap();
-                    let password = creds["pwd"].as_str().unwrap();
-                    self.set_session(userid, password, token.as_str());
-                }
-                _ => {
-                    debug!("Token not found in cache");
-                    // login and get the token
-                    let creds = self.get_creds(creds).unwrap();
-                    let token = creds["susertoken"].as_str().unwrap().to_string();
-                    // set the token in redis with expiry of 2 hours
-                    let _: () = con.set_ex(TOKEN, token, 7200).unwrap();
+use crate::urls::{AUTHORIZE, HOST, LOGOUT};
+use common::utils::{get_exchange, get_exchange_str, Exchange};
+use log::*;
+use redis::Commands;
+use sha2::{Digest, Sha256};
+use totp_rs::{Rfc6238, Secret, TOTP};
+
+/// Redis used to cache the token when neither the environment nor the config sets one
+pub const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1/";
+/// Environment variable overriding the Redis URL
+pub const REDIS_URL_ENV: &str = "SHOONYA_REDIS_URL";
+/// Lifetime of a susertoken, in seconds
+pub const TOKEN_TTL: i64 = 7200;
+
+pub struct Auth {
+    pub username: String,
+    pub accountid: String,
+    pub password: String,
+    pub susertoken: String,
+    exchanges: Vec<Exchange>,
+    cred_file: String,
+    profile: Option<String>,
+    // epoch seconds the susertoken was issued at
+    token_issued_at: Option<i64>,
+    redis_url: String,
+}
+
+/// Redis URL from SHOONYA_REDIS_URL, else the config's value, else the local default
+pub fn resolve_redis_url(config_url: Option<&str>) -> String {
+    resolve_redis_url_from(std::env::var(REDIS_URL_ENV).ok().as_deref(), config_url)
+}
+
+// `resolve_redis_url` with the environment variable passed in
+fn resolve_redis_url_from(env_url: Option<&str>, config_url: Option<&str>) -> String {
+    env_url
+        .filter(|url| !url.is_empty())
+        .or(config_url)
+        .unwrap_or(DEFAULT_REDIS_URL)
+        .to_string()
+}
+
+/// Reads the credentials file, picking `profile` out of its `profiles` map.
+/// A file without `profiles` is a single unnamed profile.
+pub fn load_creds(
+    file_name: &str,
+    profile: Option<&str>,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let file = std::fs::File::open(file_name)?;
+    let creds: serde_json::Value = serde_yaml::from_reader(file)?;
+    select_profile(creds, profile)
+}
+
+fn select_profile(
+    creds: serde_json::Value,
+    profile: Option<&str>,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let profiles = match (creds["profiles"].as_object(), profile) {
+        (Some(profiles), _) => profiles,
+        (None, None) => return Ok(creds),
+        (None, Some(name)) => return Err(format!("No profile {} in credentials", name).into()),
+    };
+    match profile {
+        Some(name) => profiles
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("Profile {} not found in credentials", name).into()),
+        None if profiles.len() == 1 => Ok(profiles.values().next().unwrap().clone()),
+        None => Err("Several profiles in credentials, pick one with --profile".into()),
+    }
+}
+
+// Credentials of the account `uid`, from its profile or a single-account file
+fn load_account_creds(
+    file_name: &str,
+    uid: &str,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let file = std::fs::File::open(file_name)?;
+    let creds: serde_json::Value = serde_yaml::from_reader(file)?;
+    select_account(creds, uid)
+}
+
+fn select_account(
+    creds: serde_json::Value,
+    uid: &str,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let found = match creds["profiles"].as_object() {
+        Some(profiles) => profiles
+            .values()
+            .find(|profile| profile["user"].as_str() == Some(uid))
+            .cloned(),
+        None if creds["user"].as_str() == Some(uid) => Some(creds),
+        None => None,
+    };
+    found.ok_or_else(|| format!("No credentials for account {}", uid).into())
+}
+
+// Redis keys of the cached token, exchanges and issue time, per account
+fn cache_keys(uid: &str) -> (String, String, String) {
+    (
+        format!("access_token_shoonya_{}", uid),
+        format!("exchanges_shoonya_{}", uid),
+        format!("token_issued_shoonya_{}", uid),
+    )
+}
+
+// Exchanges listed in the `exarr` of the login response
+fn parse_exarr(res_dict: &serde_json::Value) -> Vec<Exchange> {
+    res_dict["exarr"]
+        .as_array()
+        .map(|exarr| {
+            exarr
+                .iter()
+                .filter_map(|exch| exch.as_str().and_then(get_exchange))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+impl Auth {
+    pub fn login(&mut self, file_name: &str, force_login: bool) {
+        self.cred_file = file_name.to_string();
+        let creds = match &self.profile {
+            None if !self.accountid.is_empty() => load_account_creds(file_name, &self.accountid),
+            profile => load_creds(file_name, profile.as_deref()),
+        }
+        .unwrap();
+        let (token_key, exchanges_key, issued_key) = cache_keys(creds["user"].as_str().unwrap());
+
+        let redis_client = redis::Client::open(self.redis_url.as_str()).unwrap();
+        let mut con = redis_client.get_connection().unwrap();
+
+        let super_token: Result<String, redis::RedisError> = con.get(&token_key);
+        if let Ok(token) = super_token {
+            if force_login == false {
+                debug!("Token found in cache");
+                let userid = creds["user"].as_str().unwrap();
+                let password = creds["pwd"].as_str().unwrap();
+                self.set_session(userid, password, token.as_str());
+                // a token cached without its issue time is treated as expired
+                self.token_issued_at = con.get(&issued_key).ok();
+                if self.is_token_valid() {
+                    let exarr: Result<String, redis::RedisError> = con.get(&exchanges_key);
+                    if let Ok(exarr) = exarr {
+                        self.exchanges =
+                            parse_exarr(&serde_json::from_str(&exarr).unwrap_or_default());
+                    }
+                    return;
                 }
+                debug!("Cached token expired");
             }
         }
+        debug!("Logging in for a fresh token");
+        let creds = self.get_creds(creds).unwrap();
+        let token = creds["susertoken"].as_str().unwrap().to_string();
+        let issued_at = self.token_issued_at.unwrap_or_default();
+        // cache the token for its lifetime, along with when it was issued
+        let ttl = TOKEN_TTL as u64;
+        let _: () = con.set_ex(&token_key, token, ttl).unwrap();
+        let _: () = con.set_ex(&issued_key, issued_at, ttl).unwrap();
+        let exarr = serde_json::json!({ "exarr": creds["exarr"] }).to_string();
+        let _: () = con.set_ex(&exchanges_key, exarr, ttl).unwrap();
+    }
 
-        pub fn new() -> Auth {
-            Auth {
-                username: "".to_string(),
-                accountid: "".to_string(),
-                password: "".to_string(),
-                susertoken: "".to_string(),
+    /// True while the susertoken is within its lifetime
+    pub fn is_token_valid(&self) -> bool {
+        match self.token_issued_at {
+            Some(issued_at) if !self.susertoken.is_empty() => {
+                chrono::Local::now().timestamp() - issued_at < TOKEN_TTL
             }
+            _ => false,
         }
+    }
 
-        // read from a yml file provided by the user
-        fn get_creds(
-            &mut self,
-            creds: serde_json::Value,
-        ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
-            // convert to string creds["totp_pin"]
-            let totp_pin = creds["totp_pin"].as_str().unwrap();
-
-            let rfc =
-                Rfc6238::with_defaults(Secret::Encoded(totp_pin.to_string()).to_bytes().unwrap())
-                    .unwrap();
-
-            // create a TOTP from rfc
-            let totp = TOTP::from_rfc6238(rfc).unwrap();
-            let two_fa = totp.generate_current().unwrap();
-
-            let result = self._login(
-                creds["user"].as_str().unwrap(),
-                creds["pwd"].as_str().unwrap(),
-                &two_fa,
-                creds["vc"].as_str().unwrap(),
-                creds["apikey"].as_str().unwrap(),
-                creds["imei"].as_str().unwrap(),
-            );
-
-            result
+    pub fn new() -> Auth {
+        Auth {
+            username: "".to_string(),
+            accountid: "".to_string(),
+            password: "".to_string(),
+            susertoken: "".to_string(),
+            exchanges: Vec::new(),
+            cred_file: "".to_string(),
+            profile: None,
+            token_issued_at: None,
+            redis_url: resolve_redis_url(None),
         }
+    }
 
-        fn _login(
-            &mut self,
-            userid: &str,
-            password: &str,
-            two_fa: &str,
-            vendor_code: &str,
-            api_secret: &str,
-            imei: &str,
-        ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
-            let url = format!("{}{}", HOST, AUTHORIZE);
-
-            let mut hasher = Sha256::new();
-            hasher.update(password);
-            let pwd = format!("{:x}", hasher.finalize());
-
-            hasher = Sha256::new();
-            hasher.update(format!("{}|{}", userid, api_secret));
-            let app_key = format!("{:x}", hasher.finalize());
-
-            let values = serde_json::json!({
-                "source": "API",
-                "apkversion": "1.0.0",
-                "uid": userid,
-                "pwd": pwd,
-                "factor2": two_fa,
-                "vc": vendor_code,
-                "appkey": app_key,
-                "imei": imei,
-            });
-
-            let client = reqwest::blocking::Client::new();
-            let res: String = client
-                .post(&url)
-                .body(format!("jData={}", values.to_string()))
-                .send()?
-                .text()?;
-
-            let res_dict: serde_json::Value = serde_json::from_str(&res)?;
-
-            if res_dict["stat"] != "Ok" {
-                return Err(res_dict.to_string().into());
-            }
+    /// An Auth for one account, logging in with the credentials profile of that
+    /// user. Several accounts can be logged in side by side.
+    pub fn with_account(uid: &str) -> Auth {
+        let mut auth = Auth::new();
+        auth.username = uid.to_string();
+        auth.accountid = uid.to_string();
+        auth
+    }
+
+    /// Ends the session on the server and drops the cached token, so the next
+    /// login does not pick up a stale one
+    pub fn logout(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.susertoken.is_empty() {
+            return Err("Cannot logout before a login".into());
+        }
+        let values = serde_json::json!({
+            "ordersource": "API",
+            "uid": self.username,
+        });
+
+        let url = format!("{}{}", HOST, LOGOUT);
+        let client = reqwest::blocking::Client::new();
+        let res: String = client
+            .post(&url)
+            .body(format!("jData={}&jKey={}", values, self.susertoken))
+            .send()?
+            .text()?;
+
+        let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+        info!("Logout response: {}", res_dict);
+        if res_dict["stat"] != "Ok" {
+            return Err(res_dict.to_string().into());
+        }
+        self.susertoken.clear();
+
+        self.token_issued_at = None;
+
+        let (token_key, exchanges_key, issued_key) = cache_keys(&self.username);
+        let redis_client = redis::Client::open(self.redis_url.as_str())?;
+        let mut con = redis_client.get_connection()?;
+        let _: () = con.del(&[token_key, exchanges_key, issued_key])?;
+        Ok(())
+    }
+
+    /// Redis caching the token, e.g. "redis://:password@cache.local:6380/"
+    pub fn set_redis_url(&mut self, redis_url: &str) {
+        self.redis_url = redis_url.to_string();
+    }
 
-            self.username = userid.to_string();
-            self.accountid = userid.to_string();
-            self.password = password.to_string();
-            self.susertoken = res_dict["susertoken"].as_str().unwrap().to_string();
+    /// Selects the credentials profile used by the next login
+    pub fn set_profile(&mut self, profile: Option<&str>) {
+        self.profile = profile.map(|profile| profile.to_string());
+    }
+
+    /// Logs in again with the credentials of the last login, bypassing the cached token
+    pub fn relogin(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.cred_file.is_empty() {
+            return Err("Cannot re-login before a login".into());
+        }
+        let cred_file = self.cred_file.clone();
+        self.login(&cred_file, true);
+        Ok(())
+    }
+
+    /// Exchanges enabled for the account, empty until a login reports them
+    pub fn enabled_exchanges(&self) -> &[Exchange] {
+        &self.exchanges
+    }
 
-            Ok(res_dict)
+    /// Refuses an exchange the account is not enabled for
+    pub fn check_exchange(&self, exchange: &Exchange) -> Result<(), Box<dyn std::error::Error>> {
+        if self.exchanges.is_empty() || self.exchanges.contains(exchange) {
+            return Ok(());
         }
+        Err(format!(
+            "Exchange {} is not enabled for account {}",
+            get_exchange_str(exchange),
+            self.accountid
+        )
+        .into())
+    }
+
+    // read from a yml file provided by the user
+    fn get_creds(
+        &mut self,
+        creds: serde_json::Value,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        // convert to string creds["totp_pin"]
+        let totp_pin = creds["totp_pin"].as_str().unwrap();
+
+        let rfc =
+            Rfc6238::with_defaults(Secret::Encoded(totp_pin.to_string()).to_bytes().unwrap())
+                .unwrap();
+
+        // create a TOTP from rfc
+        let totp = TOTP::from_rfc6238(rfc).unwrap();
+        let two_fa = totp.generate_current().unwrap();
 
-        pub fn set_session(&mut self, userid: &str, password: &str, usertoken: &str) -> bool {
-            self.username = userid.to_string();
-            self.accountid = userid.to_string();
-            self.password = password.to_string();
-            self.susertoken = usertoken.to_string();
+        let result = self._login(
+            creds["user"].as_str().unwrap(),
+            creds["pwd"].as_str().unwrap(),
+            &two_fa,
+            creds["vc"].as_str().unwrap(),
+            creds["apikey"].as_str().unwrap(),
+            creds["imei"].as_str().unwrap(),
+        );
 
-            true
+        result
+    }
+
+    fn _login(
+        &mut self,
+        userid: &str,
+        password: &str,
+        two_fa: &str,
+        vendor_code: &str,
+        api_secret: &str,
+        imei: &str,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        let url = format!("{}{}", HOST, AUTHORIZE);
+
+        let mut hasher = Sha256::new();
+        hasher.update(password);
+        let pwd = format!("{:x}", hasher.finalize());
+
+        hasher = Sha256::new();
+        hasher.update(format!("{}|{}", userid, api_secret));
+        let app_key = format!("{:x}", hasher.finalize());
+
+        let values = serde_json::json!({
+            "source": "API",
+            "apkversion": "1.0.0",
+            "uid": userid,
+            "pwd": pwd,
+            "factor2": two_fa,
+            "vc": vendor_code,
+            "appkey": app_key,
+            "imei": imei,
+        });
+
+        let client = reqwest::blocking::Client::new();
+        let res: String = client
+            .post(&url)
+            .body(format!("jData={}", values.to_string()))
+            .send()?
+            .text()?;
+
+        let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+
+        if res_dict["stat"] != "Ok" {
+            return Err(res_dict.to_string().into());
         }
+
+        self.username = userid.to_string();
+        self.accountid = userid.to_string();
+        self.password = password.to_string();
+        self.susertoken = res_dict["susertoken"].as_str().unwrap().to_string();
+        self.token_issued_at = Some(chrono::Local::now().timestamp());
+        self.exchanges = parse_exarr(&res_dict);
+
+        Ok(res_dict)
+    }
+
+    pub fn set_session(&mut self, userid: &str, password: &str, usertoken: &str) -> bool {
+        self.username = userid.to_string();
+        self.accountid = userid.to_string();
+        self.password = password.to_string();
+        self.susertoken = usertoken.to_string();
+        self.token_issued_at = Some(chrono::Local::now().timestamp());
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mcx_refused_when_not_enabled() {
+        let mut auth = Auth::new();
+        auth.set_session("FA12345", "pwd", "token");
+        auth.exchanges = parse_exarr(&serde_json::json!({
+            "stat": "Ok",
+            "exarr": ["NSE", "NFO", "BSE", "BFO"]
+        }));
+        assert_eq!(auth.enabled_exchanges().len(), 4);
+        assert!(auth.check_exchange(&Exchange::NFO).is_ok());
+        assert!(auth.check_exchange(&Exchange::MCX).is_err());
+    }
+
+    #[test]
+    fn test_load_named_profile() {
+        let file_name =
+            std::env::temp_dir().join(format!("cred_profiles_{}.yml", std::process::id()));
+        std::fs::write(
+            &file_name,
+            "profiles:\n  main:\n    user: FA11111\n    pwd: one\n  family:\n    user: FA22222\n    pwd: two\n",
+        )
+        .unwrap();
+        let file_name = file_name.to_str().unwrap();
+
+        let creds = load_creds(file_name, Some("family")).unwrap();
+        assert_eq!(creds["user"], "FA22222");
+        assert_eq!(creds["pwd"], "two");
+        assert!(load_creds(file_name, Some("other")).is_err());
+        assert!(load_creds(file_name, None).is_err());
+        std::fs::remove_file(file_name).unwrap();
+
+        // a file without profiles is the single unnamed profile
+        let single: serde_json::Value = serde_yaml::from_str("user: FA11111\npwd: one\n").unwrap();
+        assert_eq!(
+            select_profile(single.clone(), None).unwrap()["user"],
+            "FA11111"
+        );
+        assert!(select_profile(single, Some("main")).is_err());
+    }
+
+    #[test]
+    fn test_logout_requires_login() {
+        let mut auth = Auth::new();
+        assert!(auth.logout().is_err());
+    }
+
+    #[test]
+    fn test_accounts_do_not_share_the_cache() {
+        let creds: serde_json::Value = serde_yaml::from_str(
+            "profiles:\n  main:\n    user: FA11111\n  family:\n    user: FA22222\n",
+        )
+        .unwrap();
+        let accounts = [Auth::with_account("FA11111"), Auth::with_account("FA22222")];
+        let keys: Vec<(String, String)> = accounts
+            .iter()
+            .map(|auth| {
+                let (token_key, exchanges_key, _) = cache_keys(&auth.accountid);
+                (token_key, exchanges_key)
+            })
+            .collect();
+        assert_ne!(keys[0].0, keys[1].0);
+        assert_eq!(keys[1].0, "access_token_shoonya_FA22222");
+
+        let family = select_account(creds.clone(), &accounts[1].accountid).unwrap();
+        assert_eq!(family["user"], "FA22222");
+        assert!(select_account(creds, "FA33333").is_err());
+    }
+
+    #[test]
+    fn test_expired_token_is_invalid() {
+        let mut auth = Auth::new();
+        assert!(!auth.is_token_valid());
+        auth.set_session("FA12345", "pwd", "token");
+        assert!(auth.is_token_valid());
+        auth.token_issued_at = Some(chrono::Local::now().timestamp() - TOKEN_TTL - 1);
+        assert!(!auth.is_token_valid());
+    }
+
+    #[test]
+    fn test_redis_url_resolution() {
+        let config_url = Some("redis://:secret@cache.local:6380/");
+        assert_eq!(resolve_redis_url_from(None, None), DEFAULT_REDIS_URL);
+        assert_eq!(
+            resolve_redis_url_from(None, config_url),
+            "redis://:secret@cache.local:6380/"
+        );
+        assert_eq!(
+            resolve_redis_url_from(Some("redis://env.local:6381/"), config_url),
+            "redis://env.local:6381/"
+        );
+        // an empty variable counts as unset
+        assert_eq!(
+            resolve_redis_url_from(Some(""), config_url),
+            "redis://:secret@cache.local:6380/"
+        );
     }
 }
@@ -0,0 +1,302 @@
+use crate::orders::{get_trade_book, sorted_fills, Trade};
+use crate::transaction::parse_f64;
+use serde_json::json;
+use std::collections::HashMap;
+
+/// File format of the trade journal
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JournalFormat {
+    Csv,
+    Json,
+}
+
+/// How the broker charges brokerage, which differs between the Noren white labels
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BrokerageModel {
+    #[default]
+    Zero,
+    /// A fixed fee per fill
+    Flat(f64),
+    /// A percent of the fill's turnover, capped at `cap` when it is above 0
+    Percent { pct: f64, cap: f64 },
+}
+
+impl BrokerageModel {
+    pub fn for_fill(&self, qty: i64, price: f64) -> f64 {
+        match *self {
+            BrokerageModel::Zero => 0.0,
+            BrokerageModel::Flat(fee) => fee,
+            BrokerageModel::Percent { pct, cap } => {
+                let brokerage = qty as f64 * price * pct / 100.0;
+                if cap > 0.0 {
+                    brokerage.min(cap)
+                } else {
+                    brokerage
+                }
+            }
+        }
+    }
+
+    /// Reads the config's BROKERAGE, e.g. {"model": "flat", "fee": 20} or
+    /// {"model": "percent", "pct": 0.03, "cap": 20}. Missing means zero brokerage.
+    pub fn from_config(config: &serde_json::Value) -> Result<Self, Box<dyn std::error::Error>> {
+        let brokerage = &config["BROKERAGE"];
+        let number = |name: &str| {
+            parse_f64(&brokerage[name])
+                .ok_or_else(|| format!("BROKERAGE needs a {}: {}", name, brokerage))
+        };
+        match brokerage["model"].as_str() {
+            None | Some("zero") => Ok(BrokerageModel::Zero),
+            Some("flat") => Ok(BrokerageModel::Flat(number("fee")?)),
+            Some("percent") => Ok(BrokerageModel::Percent {
+                pct: number("pct")?,
+                cap: parse_f64(&brokerage["cap"]).unwrap_or(0.0),
+            }),
+            Some(model) => Err(format!("Unknown brokerage model {}", model).into()),
+        }
+    }
+}
+
+/// Charges applied to every fill: the brokerage, plus a flat fee per fill and a
+/// percent of turnover for the other levies
+#[derive(Debug, Clone, Default)]
+pub struct Charges {
+    pub brokerage: BrokerageModel,
+    pub per_fill: f64,
+    pub turnover_pct: f64,
+}
+
+impl Charges {
+    pub fn for_fill(&self, qty: i64, price: f64) -> f64 {
+        self.brokerage.for_fill(qty, price)
+            + self.per_fill
+            + qty as f64 * price * self.turnover_pct / 100.0
+    }
+}
+
+/// A fill of the session with its charges and the PnL it realised
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    pub timestamp: String,
+    pub symbol: String,
+    pub side: String,
+    pub qty: i64,
+    pub price: f64,
+    pub charges: f64,
+    /// Realised PnL net of the charges, against the average cost of the open position
+    pub pnl: f64,
+}
+
+pub const JOURNAL_COLUMNS: [&str; 7] = [
+    "timestamp",
+    "symbol",
+    "side",
+    "qty",
+    "price",
+    "charges",
+    "pnl",
+];
+
+/// Turns the trade book into journal entries, oldest fill first. The timestamp is
+/// the fill time as ISO 8601, the raw `fltm` when it has no readable time.
+pub fn build_journal(trade_book: &[Trade], charges: &Charges) -> Vec<JournalEntry> {
+    let mut fills: Vec<JournalEntry> = sorted_fills(trade_book.to_vec())
+        .iter()
+        .map(|trade| JournalEntry {
+            timestamp: trade
+                .fill_time()
+                .map(|time| time.format("%Y-%m-%dT%H:%M:%S").to_string())
+                .unwrap_or_else(|| trade.fltm.clone()),
+            symbol: trade.tsym.clone(),
+            side: match trade.trantype.as_str() {
+                "S" => "SELL".to_string(),
+                _ => "BUY".to_string(),
+            },
+            qty: trade.qty,
+            price: trade.flprc,
+            charges: 0.0,
+            pnl: 0.0,
+        })
+        .collect();
+
+    // symbol -> (signed open qty, average cost)
+    let mut positions: HashMap<String, (i64, f64)> = HashMap::new();
+    for fill in fills.iter_mut() {
+        let signed_qty = if fill.side == "SELL" {
+            -fill.qty
+        } else {
+            fill.qty
+        };
+        let (open_qty, avg_cost) = positions.entry(fill.symbol.clone()).or_insert((0, 0.0));
+        let mut realised = 0.0;
+        if *open_qty != 0 && open_qty.signum() != signed_qty.signum() {
+            let closed = open_qty.abs().min(fill.qty);
+            realised = (fill.price - *avg_cost) * (closed * open_qty.signum()) as f64;
+            *open_qty += closed * signed_qty.signum();
+            let opened = fill.qty - closed;
+            if opened > 0 {
+                *open_qty = opened * signed_qty.signum();
+                *avg_cost = fill.price;
+            }
+        } else {
+            let total = open_qty.abs() + fill.qty;
+            *avg_cost =
+                (*avg_cost * open_qty.abs() as f64 + fill.price * fill.qty as f64) / total as f64;
+            *open_qty += signed_qty;
+        }
+        fill.charges = charges.for_fill(fill.qty, fill.price);
+        fill.pnl = realised - fill.charges;
+    }
+    fills
+}
+
+/// Writes the journal entries as CSV with a header row, or as a JSON array
+pub fn write_journal(
+    entries: &[JournalEntry],
+    path: &str,
+    format: JournalFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let content = match format {
+        JournalFormat::Csv => {
+            let mut lines = vec![JOURNAL_COLUMNS.join(",")];
+            for entry in entries {
+                lines.push(format!(
+                    "{},{},{},{},{:.2},{:.2},{:.2}",
+                    entry.timestamp,
+                    entry.symbol,
+                    entry.side,
+                    entry.qty,
+                    entry.price,
+                    entry.charges,
+                    entry.pnl
+                ));
+            }
+            lines.join("\n") + "\n"
+        }
+        JournalFormat::Json => {
+            let rows: Vec<serde_json::Value> = entries
+                .iter()
+                .map(|entry| {
+                    json!({
+                        "timestamp": entry.timestamp,
+                        "symbol": entry.symbol,
+                        "side": entry.side,
+                        "qty": entry.qty,
+                        "price": entry.price,
+                        "charges": entry.charges,
+                        "pnl": entry.pnl,
+                    })
+                })
+                .collect();
+            serde_json::to_string_pretty(&rows)?
+        }
+    };
+    std::fs::write(path, content)?;
+    Ok(())
+}
+
+/// Exports the session's fills from the trade book to a journal file
+pub fn export_journal(
+    auth: &crate::auth::Auth,
+    path: &str,
+    format: JournalFormat,
+    charges: &Charges,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let trade_book = get_trade_book(auth)?;
+    write_journal(&build_journal(&trade_book, charges), path, format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orders::parse_trade_book;
+
+    #[test]
+    fn test_export_trade_book_csv() {
+        // the trade book lists the newest fill first
+        let trade_book = parse_trade_book(&json!([
+            {"norenordno": "3", "tsym": "NIFTY04JAN24C21800", "trantype": "B",
+             "flqty": "50", "flprc": "80.00", "fltm": "04-01-2024 14:10:00"},
+            {"norenordno": "2", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+             "flqty": "25", "flprc": "110.00", "fltm": "04-01-2024 09:20:05"},
+            {"norenordno": "1", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+             "flqty": "25", "flprc": "100.00", "fltm": "04-01-2024 09:20:01"}
+        ]));
+        let charges = Charges {
+            per_fill: 20.0,
+            turnover_pct: 0.0,
+            ..Default::default()
+        };
+        let entries = build_journal(&trade_book, &charges);
+        assert_eq!(entries[0].timestamp, "2024-01-04T09:20:01");
+        // short 50 at an average of 105, bought back at 80
+        assert_eq!(entries[2].pnl, 25.0 * 50.0 - 20.0);
+        assert_eq!(entries[0].pnl, -20.0);
+
+        let path = std::env::temp_dir().join(format!("journal_{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_journal(&entries, path, JournalFormat::Csv).unwrap();
+        let csv = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "timestamp,symbol,side,qty,price,charges,pnl");
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[1],
+            "2024-01-04T09:20:01,NIFTY04JAN24C21800,SELL,25,100.00,20.00,-20.00"
+        );
+        assert_eq!(
+            lines[3],
+            "2024-01-04T14:10:00,NIFTY04JAN24C21800,BUY,50,80.00,20.00,1230.00"
+        );
+    }
+
+    #[test]
+    fn test_journal_falls_back_to_order_time() {
+        // the second fill has no fill time, its order's broker time places it first
+        let trade_book = parse_trade_book(&json!([
+            {"norenordno": "2", "tsym": "NIFTY04JAN24C21800", "trantype": "B",
+             "flqty": "50", "flprc": "80.00", "fltm": "04-01-2024 14:10:00"},
+            {"norenordno": "1", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+             "flqty": "50", "flprc": "100.00", "norentm": "09:20:01 04-01-2024"}
+        ]));
+        let entries = build_journal(&trade_book, &Charges::default());
+        assert_eq!(entries[0].timestamp, "2024-01-04T09:20:01");
+        assert_eq!(entries[0].side, "SELL");
+        assert_eq!(entries[1].timestamp, "2024-01-04T14:10:00");
+        assert_eq!(entries[1].pnl, 1000.0);
+    }
+
+    #[test]
+    fn test_brokerage_models() {
+        let trade_book = parse_trade_book(&json!([
+            {"norenordno": "2", "tsym": "NIFTY04JAN24C21800", "trantype": "B",
+             "flqty": "50", "flprc": "80.00", "fltm": "04-01-2024 14:10:00"},
+            {"norenordno": "1", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+             "flqty": "50", "flprc": "100.00", "fltm": "04-01-2024 09:20:01"}
+        ]));
+        let net_pnl = |config: serde_json::Value| {
+            let charges = Charges {
+                brokerage: BrokerageModel::from_config(&config).unwrap(),
+                ..Default::default()
+            };
+            build_journal(&trade_book, &charges)
+                .iter()
+                .map(|entry| entry.pnl)
+                .sum::<f64>()
+        };
+        assert_eq!(net_pnl(json!({})), 1000.0);
+        let flat = net_pnl(json!({"BROKERAGE": {"model": "flat", "fee": 20}}));
+        assert_eq!(flat, 1000.0 - 40.0);
+        // 0.5% of 5000 and 4000 turnover, the first capped at 20
+        let percent = net_pnl(json!({"BROKERAGE": {"model": "percent", "pct": 0.5, "cap": 20}}));
+        assert_eq!(percent, 1000.0 - 20.0 - 20.0);
+        let uncapped = net_pnl(json!({"BROKERAGE": {"model": "percent", "pct": 0.1}}));
+        assert_eq!(uncapped, 1000.0 - 5.0 - 4.0);
+        assert_ne!(flat, uncapped);
+
+        let unknown = json!({"BROKERAGE": {"model": "tiered"}});
+        assert!(BrokerageModel::from_config(&unknown).is_err());
+    }
+}
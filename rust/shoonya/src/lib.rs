@@ -1,6 +1,40 @@
+//! Client for the Shoonya (Noren) trading API.
+//!
+//! The commonly used types are re-exported from [`prelude`]:
+//!
+//! ```no_run
+//! use shoonya::prelude::*;
+//!
+//! let mut auth = Auth::new();
+//! auth.login("../cred.yml", false);
+//! let ltp = get_quote(&auth, &Exchange::NSE, "26000");
+//! println!("NIFTY: {}", ltp);
+//! ```
+
 #[allow(dead_code)]
 pub mod auth;
+pub mod journal;
 pub mod urls;
 pub mod orders;
+pub mod margin;
 pub mod markets;
+pub mod order_manager;
+pub mod runner;
+pub mod transaction;
+pub mod watchlist;
+pub mod websocket;
 
+pub mod prelude {
+    pub use crate::auth::Auth;
+    pub use crate::markets::{get_indices, get_ltp, get_quote, get_quote_full, Quote, QuotePolicy};
+    pub use crate::order_manager::OrderManager;
+    pub use crate::orders::{get_order_book, ExposureCap, OrderBuilder};
+    pub use crate::runner::{
+        EntryLeg, EntryMode, ExitRules, ExitSignal, PositionAudit, StrategyRunner, WarmUp,
+    };
+    pub use crate::transaction::{MarkPrice, PnlSource, TransactionManager};
+    pub use crate::websocket::{
+        ConnState, Depth, Tick, TickLog, WebSocketApp, WebSocketCallback, WebSocketConfig,
+    };
+    pub use common::utils::Exchange;
+}
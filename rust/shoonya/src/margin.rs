@@ -0,0 +1,264 @@
+use crate::urls::{HOST, LIMITS, SPAN_CALCULATOR};
+use common::utils::{get_exchange_str, Exchange};
+use serde_json::json;
+
+/// An F&O position leg as SpanCalc expects it
+#[derive(Debug, Clone)]
+pub struct SpanLeg {
+    pub exchange: Exchange,
+    pub product: String,
+    /// Instrument name, e.g. "OPTIDX"
+    pub instname: String,
+    /// Underlying symbol, e.g. "NIFTY"
+    pub symname: String,
+    /// Expiry as in the scrip master, e.g. "04-JAN-2024"
+    pub expiry: String,
+    /// "CE", "PE" or "" for futures
+    pub option_type: String,
+    pub strike: f64,
+    /// Net quantity, negative when short
+    pub net_qty: i64,
+}
+
+impl SpanLeg {
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "prd": self.product,
+            "exch": get_exchange_str(&self.exchange),
+            "instname": self.instname,
+            "symname": self.symname,
+            "exd": self.expiry,
+            "optt": self.option_type,
+            "strprc": self.strike.to_string(),
+            "buyqty": self.net_qty.max(0).to_string(),
+            "sellqty": (-self.net_qty).max(0).to_string(),
+            "netqty": self.net_qty.to_string(),
+        })
+    }
+}
+
+/// Raw SpanCalc response for a set of legs
+pub fn span_calc(
+    auth: &crate::auth::Auth,
+    legs: &[SpanLeg],
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let values = json!({
+        "actid": auth.accountid,
+        "pos": legs.iter().map(|leg| leg.to_json()).collect::<Vec<_>>(),
+    });
+
+    let url = format!("{}{}", HOST, SPAN_CALCULATOR);
+    let payload = format!("jData={}&jKey={}", values, auth.susertoken);
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
+
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+    if res_dict["stat"] != "Ok" {
+        return Err(res_dict.to_string().into());
+    }
+
+    Ok(res_dict)
+}
+
+/// Margin of a set of legs from SpanCalc
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanMargin {
+    /// SPAN margin of the legs netted together
+    pub span: f64,
+    /// Exposure margin of the legs netted together
+    pub expo: f64,
+    /// SPAN margin of the legs as separate trades
+    pub span_trade: f64,
+    /// Exposure margin of the legs as separate trades
+    pub expo_trade: f64,
+}
+
+impl SpanMargin {
+    /// Span plus exposure, what placing the legs blocks
+    pub fn total(&self) -> f64 {
+        self.span + self.expo
+    }
+}
+
+fn parse_span_margin(span: &serde_json::Value) -> Result<SpanMargin, Box<dyn std::error::Error>> {
+    let field = |name: &str| {
+        crate::transaction::parse_f64(&span[name])
+            .ok_or_else(|| format!("No {} in SpanCalc response: {}", name, span))
+    };
+    Ok(SpanMargin {
+        span: field("span")?,
+        expo: field("expo")?,
+        // only sent for some segments
+        span_trade: crate::transaction::parse_f64(&span["span_trade"]).unwrap_or(0.0),
+        expo_trade: crate::transaction::parse_f64(&span["expo_trade"]).unwrap_or(0.0),
+    })
+}
+
+/// SPAN and exposure margin of the legs taken together, e.g. the four legs of
+/// an iron fly before any of them is sent
+pub fn calculate_span(
+    auth: &crate::auth::Auth,
+    legs: &[SpanLeg],
+) -> Result<SpanMargin, Box<dyn std::error::Error>> {
+    parse_span_margin(&span_calc(auth, legs)?)
+}
+
+/// Required margin from a SpanCalc response: span plus exposure
+pub fn required_margin(span: &serde_json::Value) -> Result<f64, Box<dyn std::error::Error>> {
+    Ok(parse_span_margin(span)?.total())
+}
+
+// Margin change of adding `new_leg`, `fetch` returns the SpanCalc response of a set of legs
+fn margin_delta_with<F>(
+    fetch: F,
+    current_legs: &[SpanLeg],
+    new_leg: &SpanLeg,
+) -> Result<f64, Box<dyn std::error::Error>>
+where
+    F: Fn(&[SpanLeg]) -> Result<serde_json::Value, Box<dyn std::error::Error>>,
+{
+    let current = required_margin(&fetch(current_legs)?)?;
+    let mut legs = current_legs.to_vec();
+    legs.push(new_leg.clone());
+    let after = required_margin(&fetch(&legs)?)?;
+    Ok(after - current)
+}
+
+/// Margin change of adding `new_leg` to the current legs. Negative when the leg,
+/// e.g. a protective wing, frees margin.
+pub fn margin_delta(
+    auth: &crate::auth::Auth,
+    current_legs: &[SpanLeg],
+    new_leg: &SpanLeg,
+) -> Result<f64, Box<dyn std::error::Error>> {
+    margin_delta_with(|legs| span_calc(auth, legs), current_legs, new_leg)
+}
+
+/// Funds of the account from Limits
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    pub cash: f64,
+    pub payin: f64,
+    pub marginused: f64,
+    /// Premium paid for option buys
+    pub premium: f64,
+    /// Collateral from pledged holdings
+    pub collateral: f64,
+}
+
+impl Limits {
+    /// Margin left for new positions
+    pub fn available(&self) -> f64 {
+        self.cash + self.payin + self.collateral - self.marginused
+    }
+
+    /// Fails unless `required` fits in the available margin
+    pub fn check(&self, required: f64) -> Result<(), Box<dyn std::error::Error>> {
+        if required > self.available() {
+            return Err(format!(
+                "Insufficient margin: {:.2} required, {:.2} available",
+                required,
+                self.available()
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+fn parse_limits(res_dict: &serde_json::Value) -> Result<Limits, Box<dyn std::error::Error>> {
+    if res_dict["stat"] != "Ok" {
+        return Err(res_dict.to_string().into());
+    }
+    // fields with nothing in them are left out of the response
+    let field = |name: &str| crate::transaction::parse_f64(&res_dict[name]).unwrap_or(0.0);
+    Ok(Limits {
+        cash: field("cash"),
+        payin: field("payin"),
+        marginused: field("marginused"),
+        premium: field("premium"),
+        collateral: field("collateral"),
+    })
+}
+
+pub fn get_limits(auth: &crate::auth::Auth) -> Result<Limits, Box<dyn std::error::Error>> {
+    let values = json!({
+        "uid": auth.username,
+        "actid": auth.accountid,
+    });
+
+    let url = format!("{}{}", HOST, LIMITS);
+    let payload = format!("jData={}&jKey={}", values, auth.susertoken);
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
+
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+    parse_limits(&res_dict)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leg(option_type: &str, strike: f64, net_qty: i64) -> SpanLeg {
+        SpanLeg {
+            exchange: Exchange::NFO,
+            product: "M".to_string(),
+            instname: "OPTIDX".to_string(),
+            symname: "NIFTY".to_string(),
+            expiry: "04-JAN-2024".to_string(),
+            option_type: option_type.to_string(),
+            strike,
+            net_qty,
+        }
+    }
+
+    #[test]
+    fn test_hedge_reduces_margin() {
+        let short_call = leg("CE", 21800.0, -50);
+        let hedge = leg("CE", 22200.0, 50);
+        let payload = hedge.to_json();
+        assert_eq!(payload["buyqty"], "50");
+        assert_eq!(payload["sellqty"], "0");
+
+        // fixture SpanCalc responses, a naked short against a call spread
+        let fetch = |legs: &[SpanLeg]| {
+            Ok(match legs.len() {
+                1 => json!({"stat": "Ok", "span": "98250.00", "expo": "30125.50"}),
+                _ => json!({"stat": "Ok", "span": "21400.00", "expo": "9800.25"}),
+            })
+        };
+        let delta = margin_delta_with(fetch, &[short_call], &hedge).unwrap();
+        assert!(delta < 0.0);
+        assert_eq!(delta, 31200.25 - 128375.50);
+    }
+
+    #[test]
+    fn test_parse_span_margin() {
+        let margin = parse_span_margin(&json!({
+            "stat": "Ok", "span": "21400.00", "expo": "9800.25",
+            "span_trade": "98250.00", "expo_trade": "30125.50"
+        }))
+        .unwrap();
+        assert_eq!(margin.total(), 31200.25);
+        // hedged together the fly needs less than its legs apart
+        assert!(margin.span < margin.span_trade);
+        assert_eq!(margin.expo_trade, 30125.50);
+
+        assert!(parse_span_margin(&json!({"stat": "Ok", "expo": "9800.25"})).is_err());
+    }
+
+    #[test]
+    fn test_limits_check() {
+        let limits = parse_limits(&json!({
+            "stat": "Ok", "prfname": "SHOONYA", "cash": "150000.00", "payin": "25000.00",
+            "marginused": "40000.00", "premium": "1200.00"
+        }))
+        .unwrap();
+        assert_eq!(limits.collateral, 0.0);
+        assert_eq!(limits.available(), 135000.0);
+        assert!(limits.check(135000.0).is_ok());
+        assert!(limits.check(135000.5).is_err());
+        assert!(parse_limits(&json!({"stat": "Not_Ok", "emsg": "Session Expired"})).is_err());
+    }
+}
@@ -1,92 +1,1068 @@
-pub mod markets {
+use crate::urls::{
+    GETQUOTES, GET_DAILY_PRICE_SERIES, GET_INDICES_LIST, HOST, OPTIONCHAIN, OPTION_GREEK,
+    SCRIPINFO, SEARCHSCRIP, TPSERIES,
+};
+use crate::transaction::parse_f64;
+use serde_json::json;
+use std::collections::HashMap;
+use common::utils::{Exchange, get_exchange, get_exchange_str, pretty_print_json};
 
-    use crate::urls::urls::{GETQUOTES, GET_INDICES_LIST, HOST};
-    use serde_json::json;
-    use common::utils::utils::{Exchange, get_exchange_str, pretty_print_json};
+fn _get_payload(susertoken: &str, values: &serde_json::Value) -> String {
+    let payload = format!("jData={}&jKey={}", values.to_string(), susertoken);
 
-    fn _get_payload(susertoken: &str, values: &serde_json::Value) -> String {
-        let payload = format!("jData={}&jKey={}", values.to_string(), susertoken);
+    payload
+}
 
-        payload
-    }
+pub fn get_indices(
+    auth: &crate::auth::Auth,
+    exchange: &Exchange,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let values = json!({
+        "ordersource": "API",
+        "exch": get_exchange_str(exchange),
+        "uid": auth.username,
+    });
 
-    pub fn get_indices(
-        auth: &crate::auth::auth::Auth,
-        exchange: &Exchange,
-    ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
-        let values = json!({
-            "ordersource": "API",
-            "exch": get_exchange_str(exchange),
-            "uid": auth.username,
-        });
+    let url = format!("{}{}", HOST, GET_INDICES_LIST);
+    let payload = _get_payload(&auth.susertoken, &values);
 
-        let url = format!("{}{}", HOST, GET_INDICES_LIST);
-        let payload = _get_payload(&auth.susertoken, &values);
-
-        let client = reqwest::blocking::Client::new();
-        let res: String = client.post(&url).body(payload).send()?.text()?;
-
-        let res_dict: serde_json::Value = serde_json::from_str(&res)?;
-        if let Some(obj) = res_dict.as_object() {
-            if obj.contains_key("stat") {
-                // "stat" is present in the response
-                if obj["stat"] == "Ok" {
-                    // "stat" is "Ok"
-                    return Ok(res_dict);
-                } else {
-                    // "stat" is not "Ok"
-                    return Err(res_dict.to_string().into());
-                }
-            } else {
-                // "stat" is not present in the response
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
+
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+    if let Some(obj) = res_dict.as_object() {
+        if obj.contains_key("stat") {
+            // "stat" is present in the response
+            if obj["stat"] == "Ok" {
+                // "stat" is "Ok"
                 return Ok(res_dict);
+            } else {
+                // "stat" is not "Ok"
+                return Err(res_dict.to_string().into());
             }
+        } else {
+            // "stat" is not present in the response
+            return Ok(res_dict);
+        }
+    }
+
+    Ok(res_dict)
+}
+
+/// A GetQuotes response with the prices parsed, fields the feed leaves out are None
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quote {
+    pub lp: f64,
+    pub bid: Option<f64>,
+    pub ask: Option<f64>,
+    pub volume: Option<u64>,
+    pub open: Option<f64>,
+    pub high: Option<f64>,
+    pub low: Option<f64>,
+    pub close: Option<f64>,
+    pub change_pct: Option<f64>,
+    /// Open interest, derivatives only
+    pub oi: Option<u64>,
+    /// The response as received
+    pub raw: serde_json::Value,
+}
+
+/// GetQuotes field names of the `Quote` fields on an exchange, None where the
+/// exchange never sends the field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuoteFields {
+    pub lp: &'static str,
+    pub bid: &'static str,
+    pub ask: &'static str,
+    pub volume: &'static str,
+    pub open: &'static str,
+    pub high: &'static str,
+    pub low: &'static str,
+    pub close: &'static str,
+    pub change_pct: &'static str,
+    pub oi: Option<&'static str>,
+}
+
+impl QuoteFields {
+    pub fn for_exchange(exchange: &Exchange) -> QuoteFields {
+        let cash = QuoteFields {
+            lp: "lp",
+            bid: "bp1",
+            ask: "sp1",
+            volume: "v",
+            open: "o",
+            high: "h",
+            low: "l",
+            close: "c",
+            change_pct: "pc",
+            oi: None,
+        };
+        match exchange {
+            Exchange::NSE | Exchange::BSE => cash,
+            Exchange::NFO | Exchange::BFO | Exchange::MCX | Exchange::CDS => QuoteFields {
+                oi: Some("oi"),
+                ..cash
+            },
+        }
+    }
+}
+
+impl Quote {
+    /// Parses a quote with the fields of the exchange it names, NSE's when it names none
+    pub fn from_response(
+        res_dict: &serde_json::Value,
+    ) -> Result<Quote, Box<dyn std::error::Error>> {
+        let exchange = res_dict["exch"]
+            .as_str()
+            .and_then(get_exchange)
+            .unwrap_or(Exchange::NSE);
+        Quote::from_response_with(res_dict, &QuoteFields::for_exchange(&exchange))
+    }
+
+    pub fn from_response_with(
+        res_dict: &serde_json::Value,
+        fields: &QuoteFields,
+    ) -> Result<Quote, Box<dyn std::error::Error>> {
+        let field = |name: &str| parse_f64(&res_dict[name]);
+        let lp = field(fields.lp)
+            .ok_or_else(|| format!("No ltp in quote: {}", pretty_print_json(res_dict, 2)))?;
+        Ok(Quote {
+            lp,
+            bid: field(fields.bid),
+            ask: field(fields.ask),
+            volume: field(fields.volume).map(|v| v as u64),
+            open: field(fields.open),
+            high: field(fields.high),
+            low: field(fields.low),
+            close: field(fields.close),
+            change_pct: field(fields.change_pct),
+            oi: fields.oi.and_then(field).map(|oi| oi as u64),
+            raw: res_dict.clone(),
+        })
+    }
+
+    /// Best ask minus best bid, None without depth on both sides
+    pub fn spread(&self) -> Option<f64> {
+        Some(self.ask? - self.bid?)
+    }
+}
+
+// Raw GetQuotes response for a token
+fn get_quote_response(
+    auth: &crate::auth::Auth,
+    exchange: &Exchange,
+    token: &str,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let values = json!({
+        "ordersource": "API",
+        "exch": get_exchange_str(exchange),
+        "uid": auth.username,
+        "token": token,
+    });
+
+    let url = format!("{}{}", HOST, GETQUOTES);
+    let payload = _get_payload(&auth.susertoken, &values);
+
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
+
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+    if res_dict["stat"] != "Ok" {
+        return Err(res_dict.to_string().into());
+    }
+
+    Ok(res_dict)
+}
+
+/// Full quote of a token
+pub fn get_quote_full(
+    auth: &crate::auth::Auth,
+    exchange: &Exchange,
+    token: &str,
+) -> Result<Quote, Box<dyn std::error::Error>> {
+    Quote::from_response_with(
+        &get_quote_response(auth, exchange, token)?,
+        &QuoteFields::for_exchange(exchange),
+    )
+}
+
+/// What a failed quote turns into
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuotePolicy {
+    /// Return the error, for callers that cannot go on without the price
+    Fatal,
+    /// Log the error and return `None`, for callers that can skip a beat
+    Soft,
+}
+
+// Extracts the ltp from a GetQuotes result according to the policy
+fn ltp_with_policy(
+    res: Result<serde_json::Value, Box<dyn std::error::Error>>,
+    policy: QuotePolicy,
+) -> Result<Option<f64>, Box<dyn std::error::Error>> {
+    let res = res.and_then(|res_dict| Quote::from_response(&res_dict).map(|quote| quote.lp));
+    match (res, policy) {
+        (Ok(ltp), _) => Ok(Some(ltp)),
+        (Err(e), QuotePolicy::Fatal) => Err(e),
+        (Err(e), QuotePolicy::Soft) => {
+            log::error!("Error: {}", e);
+            Ok(None)
         }
+    }
+}
+
+/// Last traded price of a token, failures are handled according to `policy`
+pub fn get_ltp(
+    auth: &crate::auth::Auth,
+    exchange: &Exchange,
+    token: &str,
+    policy: QuotePolicy,
+) -> Result<Option<f64>, Box<dyn std::error::Error>> {
+    ltp_with_policy(get_quote_response(auth, exchange, token), policy)
+}
 
-        Ok(res_dict)
+/// Ltp reported by `get_quote` and the price sources when there is no quote
+pub const NO_QUOTE: f64 = -9999.0;
+
+pub fn get_quote(auth: &crate::auth::Auth, exchange: &Exchange, token: &str) -> f64 {
+    match get_ltp(auth, exchange, token, QuotePolicy::Soft) {
+        Ok(Some(ltp)) => ltp,
+        _ => NO_QUOTE,
     }
+}
+
+/// A SearchScrip result
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScripMatch {
+    pub exchange: String,
+    pub token: String,
+    pub tsym: String,
+    pub instname: String,
+    /// Company name
+    pub cname: String,
+}
+
+fn parse_scrip_matches(res_dict: &serde_json::Value) -> Vec<ScripMatch> {
+    let field =
+        |value: &serde_json::Value, name: &str| value[name].as_str().unwrap_or("").to_string();
+    res_dict["values"]
+        .as_array()
+        .map(|values| {
+            values
+                .iter()
+                .map(|value| ScripMatch {
+                    exchange: field(value, "exch"),
+                    token: field(value, "token"),
+                    tsym: field(value, "tsym"),
+                    instname: field(value, "instname"),
+                    cname: field(value, "cname"),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Looks up scrips by name, e.g. "RELIANCE", without the scrip master file
+pub fn search_scrip(
+    auth: &crate::auth::Auth,
+    exchange: &Exchange,
+    query: &str,
+) -> Result<Vec<ScripMatch>, Box<dyn std::error::Error>> {
+    let values = json!({
+        "uid": auth.username,
+        "exch": get_exchange_str(exchange),
+        "stext": query,
+    });
+
+    let url = format!("{}{}", HOST, SEARCHSCRIP);
+    let payload = _get_payload(&auth.susertoken, &values);
+
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
 
-    pub fn get_quote(auth: &crate::auth::auth::Auth, exchange: &Exchange, token: &str) -> f64 {
-        let values = json!({
-            "ordersource": "API",
-            "exch": get_exchange_str(exchange),
-            "uid": auth.username,
-            "token": token,
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+    if res_dict["stat"] != "Ok" {
+        return Err(res_dict.to_string().into());
+    }
+
+    Ok(parse_scrip_matches(&res_dict))
+}
+
+/// A contract of GetOptionChain
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptionChainRow {
+    pub exchange: String,
+    pub token: String,
+    pub tsym: String,
+    /// "CE" or "PE"
+    pub optt: String,
+    pub strprc: f64,
+}
+
+// Some chains carry the underlying or its future among the options, without an
+// "optt" or with "XX", those rows are left out
+fn parse_option_chain(res_dict: &serde_json::Value) -> Vec<OptionChainRow> {
+    let field =
+        |value: &serde_json::Value, name: &str| value[name].as_str().unwrap_or("").to_string();
+    res_dict["values"]
+        .as_array()
+        .map(|values| {
+            values
+                .iter()
+                .filter(|value| matches!(value["optt"].as_str(), Some("CE") | Some("PE")))
+                .filter_map(|value| {
+                    Some(OptionChainRow {
+                        exchange: field(value, "exch"),
+                        token: field(value, "token"),
+                        tsym: field(value, "tsym"),
+                        optt: field(value, "optt"),
+                        strprc: parse_f64(&value["strprc"])?,
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Calls and puts of `tsym`'s expiry, `count` strikes each side of `strike`
+pub fn get_option_chain(
+    auth: &crate::auth::Auth,
+    exchange: &Exchange,
+    tsym: &str,
+    strike: f64,
+    count: u32,
+) -> Result<Vec<OptionChainRow>, Box<dyn std::error::Error>> {
+    let values = json!({
+        "uid": auth.username,
+        "exch": get_exchange_str(exchange),
+        "tsym": tsym,
+        "strprc": strike.to_string(),
+        "cnt": count.to_string(),
+    });
+
+    let url = format!("{}{}", HOST, OPTIONCHAIN);
+    let payload = _get_payload(&auth.susertoken, &values);
+
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
+
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+    if res_dict["stat"] != "Ok" {
+        return Err(res_dict.to_string().into());
+    }
+
+    Ok(parse_option_chain(&res_dict))
+}
+
+// "EXCH|token" of the `width` strikes each side of the strike nearest `center`,
+// the lower one on a tie, for the option type of `tsym`
+fn chain_subscriptions(
+    chain: &[OptionChainRow],
+    tsym: &str,
+    center: f64,
+    width: usize,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let optt = &chain
+        .iter()
+        .find(|row| row.tsym == tsym)
+        .ok_or_else(|| format!("{} is not in the option chain", tsym))?
+        .optt;
+    let mut rows: Vec<&OptionChainRow> = chain.iter().filter(|row| &row.optt == optt).collect();
+    rows.sort_by(|a, b| a.strprc.total_cmp(&b.strprc));
+    let at = rows
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| {
+            (a.strprc - center)
+                .abs()
+                .total_cmp(&(b.strprc - center).abs())
+        })
+        .map_or(0, |(at, _)| at);
+    let window = &rows[at.saturating_sub(width)..(at + width + 1).min(rows.len())];
+    Ok(window
+        .iter()
+        .map(|row| format!("{}|{}", row.exchange, row.token))
+        .collect())
+}
+
+/// Subscribes the `width` strikes each side of `center`, of the option type and
+/// expiry of `tsym`, in one call. Returns the "EXCH|token" subscribed.
+pub fn subscribe_chain(
+    auth: &crate::auth::Auth,
+    app: &crate::websocket::WebSocketApp,
+    exchange: &Exchange,
+    tsym: &str,
+    center: f64,
+    width: u32,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let chain = get_option_chain(auth, exchange, tsym, center, width)?;
+    let symbols = chain_subscriptions(&chain, tsym, center, width as usize)?;
+    let instruments: Vec<&str> = symbols.iter().map(|symbol| symbol.as_str()).collect();
+    app.subscribe(&instruments)?;
+    Ok(symbols)
+}
+
+/// Contract details from GetSecurityInfo
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecurityInfo {
+    pub tsym: String,
+    /// Lot size
+    pub ls: u32,
+    /// Tick size
+    pub ti: f64,
+    /// Price precision, decimal places
+    pub pp: u32,
+    /// Freeze quantity, 0 when the exchange sets none
+    pub frzqty: u32,
+}
+
+fn parse_security_info(
+    res_dict: &serde_json::Value,
+) -> Result<SecurityInfo, Box<dyn std::error::Error>> {
+    if res_dict["stat"] != "Ok" {
+        return Err(res_dict.to_string().into());
+    }
+    let number = |name: &str| parse_f64(&res_dict[name]);
+    Ok(SecurityInfo {
+        tsym: res_dict["tsym"].as_str().unwrap_or("").to_string(),
+        ls: number("ls").ok_or_else(|| format!("No lot size in {}", res_dict))? as u32,
+        ti: number("ti").ok_or_else(|| format!("No tick size in {}", res_dict))?,
+        pp: number("pp").unwrap_or(2.0) as u32,
+        frzqty: number("frzqty").unwrap_or(0.0) as u32,
+    })
+}
+
+/// Live lot size, tick size and freeze quantity of a token
+pub fn get_security_info(
+    auth: &crate::auth::Auth,
+    exchange: &Exchange,
+    token: &str,
+) -> Result<SecurityInfo, Box<dyn std::error::Error>> {
+    let values = json!({
+        "uid": auth.username,
+        "exch": get_exchange_str(exchange),
+        "token": token,
+    });
+
+    let url = format!("{}{}", HOST, SCRIPINFO);
+    let payload = _get_payload(&auth.susertoken, &values);
+
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
+
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+    parse_security_info(&res_dict)
+}
+
+/// One bar of a TPSeries response
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candle {
+    pub time: chrono::NaiveDateTime,
+    pub into: f64,
+    pub inth: f64,
+    pub intl: f64,
+    pub intc: f64,
+    pub intv: u64,
+    pub intvwap: Option<f64>,
+}
+
+// The series comes newest first, candles are returned oldest first
+fn parse_candles(res_dict: &serde_json::Value) -> Result<Vec<Candle>, Box<dyn std::error::Error>> {
+    let rows = match res_dict.as_array() {
+        Some(rows) => rows,
+        None => return Err(res_dict.to_string().into()),
+    };
+    let mut candles = Vec::with_capacity(rows.len());
+    for row in rows {
+        let time = row["time"].as_str().unwrap_or("");
+        let price = |name: &str| {
+            parse_f64(&row[name]).ok_or_else(|| format!("No {} in candle: {}", name, row))
+        };
+        candles.push(Candle {
+            time: chrono::NaiveDateTime::parse_from_str(time, "%d-%m-%Y %H:%M:%S")?,
+            into: price("into")?,
+            inth: price("inth")?,
+            intl: price("intl")?,
+            intc: price("intc")?,
+            intv: parse_f64(&row["intv"]).unwrap_or(0.0) as u64,
+            intvwap: parse_f64(&row["intvwap"]),
         });
+    }
+    candles.sort_by_key(|candle| candle.time);
+    Ok(candles)
+}
 
-        let url = format!("{}{}", HOST, GETQUOTES);
-        let payload = _get_payload(&auth.susertoken, &values);
-
-        let client = reqwest::blocking::Client::new();
-        let res: String = client
-            .post(&url)
-            .body(payload)
-            .send()
-            .unwrap()
-            .text()
-            .unwrap();
-
-        let res_dict: serde_json::Value = serde_json::from_str(&res).unwrap();
-        if let Some(obj) = res_dict.as_object() {
-            if obj.contains_key("stat") {
-                // "stat" is present in the response
-                if obj["stat"] == "Ok" {
-                    // "stat" is "Ok"
-                    let lp: f64 = obj["lp"].as_str().unwrap().parse().unwrap_or_else(|_| {
-                        log::error!("Error: {}", pretty_print_json(&res_dict, 2));
-                        -9999.0
-                    });
-                    return lp;
-
-                } else {
-                    // "stat" is not "Ok"
-                    return -9999.0;
-                }
-            } else {
-                // "stat" is not present in the response
-                return  -9999.0;
+/// Intraday candles of a token between `start` and `end`
+pub fn get_time_price_series(
+    auth: &crate::auth::Auth,
+    exchange: &Exchange,
+    token: &str,
+    start: chrono::DateTime<chrono::Local>,
+    end: chrono::DateTime<chrono::Local>,
+    interval_minutes: u32,
+) -> Result<Vec<Candle>, Box<dyn std::error::Error>> {
+    let values = json!({
+        "uid": auth.username,
+        "exch": get_exchange_str(exchange),
+        "token": token,
+        "st": start.timestamp().to_string(),
+        "et": end.timestamp().to_string(),
+        "intrv": interval_minutes.to_string(),
+    });
+
+    let url = format!("{}{}", HOST, TPSERIES);
+    let payload = _get_payload(&auth.susertoken, &values);
+
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
+
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+    parse_candles(&res_dict)
+}
+
+/// A daily candle from EODChartData
+#[derive(Debug, Clone, PartialEq)]
+pub struct DailyCandle {
+    pub date: chrono::NaiveDate,
+    pub into: f64,
+    pub inth: f64,
+    pub intl: f64,
+    pub intc: f64,
+    pub intv: u64,
+}
+
+// Each row is a JSON object sent as a string, e.g. "{\"time\":\"04-JAN-2024\", ...}".
+// Candles are returned oldest first.
+fn parse_daily_candles(
+    res_dict: &serde_json::Value,
+) -> Result<Vec<DailyCandle>, Box<dyn std::error::Error>> {
+    let rows = match res_dict.as_array() {
+        Some(rows) => rows,
+        None => return Err(res_dict.to_string().into()),
+    };
+    let mut candles = Vec::with_capacity(rows.len());
+    for row in rows {
+        let row: serde_json::Value = match row.as_str() {
+            Some(text) => serde_json::from_str(text)?,
+            None => row.clone(),
+        };
+        let price = |name: &str| {
+            parse_f64(&row[name]).ok_or_else(|| format!("No {} in daily candle: {}", name, row))
+        };
+        let time = row["time"].as_str().unwrap_or("");
+        candles.push(DailyCandle {
+            date: chrono::NaiveDate::parse_from_str(time, "%d-%b-%Y")?,
+            into: price("into")?,
+            inth: price("inth")?,
+            intl: price("intl")?,
+            intc: price("intc")?,
+            intv: parse_f64(&row["intv"]).unwrap_or(0.0) as u64,
+        });
+    }
+    candles.sort_by_key(|candle| candle.date);
+    Ok(candles)
+}
+
+// Start of `date` on the local clock, as epoch seconds
+fn local_epoch(date: chrono::NaiveDate) -> i64 {
+    date.and_hms_opt(0, 0, 0)
+        .and_then(|midnight| midnight.and_local_timezone(chrono::Local).earliest())
+        .map_or(0, |midnight| midnight.timestamp())
+}
+
+/// Daily candles from `from` to `to`, both included. EODChartData looks the scrip
+/// up by its trading symbol, e.g. "NIFTY INDEX" on NSE, not by its token.
+pub fn get_eod_series(
+    auth: &crate::auth::Auth,
+    exchange: &Exchange,
+    tradingsymbol: &str,
+    from: chrono::NaiveDate,
+    to: chrono::NaiveDate,
+) -> Result<Vec<DailyCandle>, Box<dyn std::error::Error>> {
+    let values = json!({
+        "uid": auth.username,
+        "sym": format!("{}:{}", get_exchange_str(exchange), tradingsymbol),
+        "from": local_epoch(from).to_string(),
+        "to": local_epoch(to.succ_opt().unwrap_or(to)).to_string(),
+    });
+
+    let url = format!("{}{}", HOST, GET_DAILY_PRICE_SERIES);
+    let payload = _get_payload(&auth.susertoken, &values);
+
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
+
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+    parse_daily_candles(&res_dict)
+}
+
+/// Option Greeks from GetOptionGreek, for the side that was asked
+#[derive(Debug, Clone, PartialEq)]
+pub struct Greeks {
+    pub price: f64,
+    pub delta: f64,
+    pub gamma: f64,
+    pub theta: f64,
+    pub vega: f64,
+    pub rho: f64,
+}
+
+// Expiry is sent as a date, `days_to_expiry` counts from `today`
+#[allow(clippy::too_many_arguments)]
+fn option_greeks_values(
+    auth: &crate::auth::Auth,
+    today: chrono::NaiveDate,
+    spot: f64,
+    strike: f64,
+    volatility: f64,
+    interest_rate: f64,
+    days_to_expiry: u32,
+    option_type: &str,
+) -> serde_json::Value {
+    let expiry = today + chrono::Duration::days(days_to_expiry as i64);
+    json!({
+        "uid": auth.username,
+        "exd": expiry.format("%d-%b-%Y").to_string().to_uppercase(),
+        "strprc": strike.to_string(),
+        "sptprc": spot.to_string(),
+        "int_rate": interest_rate.to_string(),
+        "volatility": volatility.to_string(),
+        "optt": option_type,
+    })
+}
+
+// The response carries both sides, "cal_*" for the call and "put_*" for the put
+fn parse_greeks(
+    res_dict: &serde_json::Value,
+    option_type: &str,
+) -> Result<Greeks, Box<dyn std::error::Error>> {
+    if !res_dict["stat"]
+        .as_str()
+        .is_some_and(|stat| stat.eq_ignore_ascii_case("ok"))
+    {
+        return Err(res_dict.to_string().into());
+    }
+    let side = if option_type == "PE" { "put" } else { "cal" };
+    let greek = |name: &str| {
+        parse_f64(&res_dict[format!("{}_{}", side, name)])
+            .ok_or_else(|| format!("No {} in {}", name, res_dict))
+    };
+    Ok(Greeks {
+        price: greek("price")?,
+        delta: greek("delta")?,
+        gamma: greek("gamma")?,
+        theta: greek("theta")?,
+        // spelt "vego" by the API
+        vega: greek("vego")?,
+        rho: greek("rho")?,
+    })
+}
+
+/// Greeks of an option, `option_type` is "CE" or "PE", `volatility` and
+/// `interest_rate` are in percent
+pub fn get_option_greeks(
+    auth: &crate::auth::Auth,
+    spot: f64,
+    strike: f64,
+    volatility: f64,
+    interest_rate: f64,
+    days_to_expiry: u32,
+    option_type: &str,
+) -> Result<Greeks, Box<dyn std::error::Error>> {
+    let today = chrono::Local::now().date_naive();
+    let values = option_greeks_values(
+        auth,
+        today,
+        spot,
+        strike,
+        volatility,
+        interest_rate,
+        days_to_expiry,
+        option_type,
+    );
+
+    let url = format!("{}{}", HOST, OPTION_GREEK);
+    let payload = _get_payload(&auth.susertoken, &values);
+
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
+
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+    parse_greeks(&res_dict, option_type)
+}
+
+/// Where strike selection reads its prices, so live trading and backtests
+/// share one code path
+pub trait PriceSource {
+    /// Last price of a token, `NO_QUOTE` when unavailable like `get_quote`
+    fn ltp(&self, exchange: &Exchange, token: &str) -> f64;
+
+    /// Last prices of several tokens, one `ltp` call each unless overridden
+    fn ltps(&self, exchange: &Exchange, tokens: &[&str]) -> HashMap<String, f64> {
+        tokens
+            .iter()
+            .map(|token| (token.to_string(), self.ltp(exchange, token)))
+            .collect()
+    }
+}
+
+/// Live quotes of the logged in session
+impl PriceSource for crate::auth::Auth {
+    fn ltp(&self, exchange: &Exchange, token: &str) -> f64 {
+        get_quote(self, exchange, token)
+    }
+
+    fn ltps(&self, exchange: &Exchange, tokens: &[&str]) -> HashMap<String, f64> {
+        get_quotes_batch(self, exchange, tokens)
+    }
+}
+
+/// Prices replayed from candles, the ltp is the close of the latest candle
+/// at or before the current time
+pub struct HistoricalPrices {
+    // "EXCH|token" -> candles, oldest first
+    series: HashMap<String, Vec<Candle>>,
+    at: chrono::NaiveDateTime,
+}
+
+impl HistoricalPrices {
+    pub fn new(at: chrono::NaiveDateTime) -> Self {
+        HistoricalPrices {
+            series: HashMap::new(),
+            at,
+        }
+    }
+
+    pub fn add_series(&mut self, exchange: &Exchange, token: &str, mut candles: Vec<Candle>) {
+        candles.sort_by_key(|candle| candle.time);
+        let key = format!("{}|{}", get_exchange_str(exchange), token);
+        self.series.insert(key, candles);
+    }
+
+    /// Moves the replay clock
+    pub fn set_time(&mut self, at: chrono::NaiveDateTime) {
+        self.at = at;
+    }
+}
+
+impl PriceSource for HistoricalPrices {
+    fn ltp(&self, exchange: &Exchange, token: &str) -> f64 {
+        let key = format!("{}|{}", get_exchange_str(exchange), token);
+        self.series
+            .get(&key)
+            .and_then(|candles| candles.iter().rev().find(|candle| candle.time <= self.at))
+            .map_or(NO_QUOTE, |candle| candle.intc)
+    }
+}
+
+// Runs `fetch` for every token on its own thread and collects the results
+fn quotes_batch_with<F>(tokens: &[&str], fetch: F) -> HashMap<String, f64>
+where
+    F: Fn(&str) -> f64 + Sync,
+{
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = tokens
+            .iter()
+            .map(|token| {
+                let fetch = &fetch;
+                scope.spawn(move || (token.to_string(), fetch(token)))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .filter_map(|handle| handle.join().ok())
+            .collect()
+    })
+}
+
+/// Ltp of several tokens fetched concurrently, failed quotes are `NO_QUOTE` like `get_quote`
+pub fn get_quotes_batch(
+    auth: &crate::auth::Auth,
+    exchange: &Exchange,
+    tokens: &[&str],
+) -> HashMap<String, f64> {
+    quotes_batch_with(tokens, |token| get_quote(auth, exchange, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failing_quote() -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        Err(json!({"stat": "Not_Ok", "emsg": "Session Expired"})
+            .to_string()
+            .into())
+    }
+
+    #[test]
+    fn test_quote_policy() {
+        assert!(ltp_with_policy(failing_quote(), QuotePolicy::Fatal).is_err());
+        assert_eq!(
+            ltp_with_policy(failing_quote(), QuotePolicy::Soft).unwrap(),
+            None
+        );
+
+        // an Ok response without lp is a failure as well
+        let no_ltp = || Ok(json!({"stat": "Ok", "tk": "26000"}));
+        assert!(ltp_with_policy(no_ltp(), QuotePolicy::Fatal).is_err());
+        assert_eq!(ltp_with_policy(no_ltp(), QuotePolicy::Soft).unwrap(), None);
+
+        let quote = || Ok(json!({"stat": "Ok", "tk": "26000", "lp": "21710.80"}));
+        assert_eq!(
+            ltp_with_policy(quote(), QuotePolicy::Fatal).unwrap(),
+            Some(21710.80)
+        );
+    }
+
+    #[test]
+    fn test_parse_quote() {
+        let quote = Quote::from_response(&json!({
+            "stat": "Ok", "exch": "NFO", "tk": "42216", "lp": "101.50",
+            "bp1": "101.40", "sp1": "101.65", "v": "1250300",
+            "o": "120.00", "h": "124.35", "l": "98.10", "c": "118.20", "pc": "-14.13"
+        }))
+        .unwrap();
+        assert_eq!(quote.lp, 101.50);
+        assert_eq!(quote.volume, Some(1250300));
+        assert_eq!(quote.change_pct, Some(-14.13));
+        assert!((quote.spread().unwrap() - 0.25).abs() < 1e-9);
+
+        // an illiquid strike without depth
+        let quote = Quote::from_response(&json!({"stat": "Ok", "lp": "0.05"})).unwrap();
+        assert_eq!(quote.bid, None);
+        assert_eq!(quote.spread(), None);
+    }
+
+    #[test]
+    fn test_quote_fields_per_exchange() {
+        // NSE cash has no open interest
+        let nse = Quote::from_response(&json!({
+            "stat": "Ok", "exch": "NSE", "tk": "2885", "lp": "2589.45",
+            "bp1": "2589.40", "sp1": "2589.55", "v": "4521300", "c": "2575.10"
+        }))
+        .unwrap();
+        assert_eq!(nse.lp, 2589.45);
+        assert_eq!(nse.close, Some(2575.10));
+        assert_eq!(nse.oi, None);
+
+        let nfo = Quote::from_response(&json!({
+            "stat": "Ok", "exch": "NFO", "tk": "42216", "lp": "101.50", "oi": "8120450",
+            "poi": "7890200", "v": "1250300"
+        }))
+        .unwrap();
+        assert_eq!(nfo.oi, Some(8120450));
+        assert_eq!(nfo.bid, None);
+
+        // an oi on a cash quote is not read as one
+        let fields = QuoteFields::for_exchange(&Exchange::NSE);
+        let quote =
+            Quote::from_response_with(&json!({"stat": "Ok", "lp": "1.0", "oi": "5"}), &fields)
+                .unwrap();
+        assert_eq!(quote.oi, None);
+        assert_eq!(QuoteFields::for_exchange(&Exchange::MCX).oi, Some("oi"));
+    }
+
+    #[test]
+    fn test_quotes_batch_concurrent() {
+        // no fetch returns before all five are in flight, run one at a time they would hang
+        let in_flight = std::sync::Barrier::new(5);
+        let quotes = quotes_batch_with(&["42216", "42217", "42218", "42219", "0"], |token| {
+            in_flight.wait();
+            match token {
+                "0" => -9999.0,
+                _ => token.parse::<f64>().unwrap() / 100.0,
+            }
+        });
+        assert_eq!(quotes.len(), 5);
+        assert_eq!(quotes["42217"], 422.17);
+        assert_eq!(quotes["0"], -9999.0);
+    }
+
+    #[test]
+    fn test_parse_search_scrip() {
+        let matches = parse_scrip_matches(&json!({
+            "stat": "Ok",
+            "values": [
+                {"exch": "NSE", "token": "2885", "tsym": "RELIANCE-EQ",
+                 "cname": "RELIANCE INDUSTRIES LTD", "instname": "EQ", "pp": "2", "ls": "1", "ti": "0.05"},
+                {"exch": "NSE", "token": "25", "tsym": "ADANIENT-EQ",
+                 "cname": "ADANI ENTERPRISES LIMITED", "instname": "EQ", "pp": "2", "ls": "1", "ti": "0.05"}
+            ]
+        }));
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].token, "2885");
+        assert_eq!(matches[0].tsym, "RELIANCE-EQ");
+        assert_eq!(matches[0].instname, "EQ");
+        assert_eq!(matches[1].cname, "ADANI ENTERPRISES LIMITED");
+        assert!(parse_scrip_matches(&json!({"stat": "Ok"})).is_empty());
+    }
+
+    #[test]
+    fn test_parse_time_price_series() {
+        let res_dict = json!([
+            {"stat": "Ok", "time": "04-01-2024 09:20:00", "into": "21650.10", "inth": "21662.00",
+             "intl": "21641.35", "intc": "21655.80", "intvwap": "21651.02", "intv": "1200"},
+            {"stat": "Ok", "time": "04-01-2024 09:15:00", "into": "21605.80", "inth": "21652.40",
+             "intl": "21600.00", "intc": "21650.10", "intv": "3400"}
+        ]);
+        let candles = parse_candles(&res_dict).unwrap();
+        assert_eq!(candles.len(), 2);
+        assert_eq!(candles[0].time.format("%H:%M").to_string(), "09:15");
+        assert_eq!(candles[0].intc, 21650.10);
+        assert_eq!(candles[0].intv, 3400);
+        assert_eq!(candles[0].intvwap, None);
+        assert_eq!(candles[1].intvwap, Some(21651.02));
+
+        let error = json!({"stat": "Not_Ok", "emsg": "no data"});
+        assert!(parse_candles(&error).is_err());
+    }
+
+    #[test]
+    fn test_chain_window_subscriptions() {
+        let mut values = Vec::new();
+        for i in 0..15 {
+            let strike = 21450 + i * 50;
+            for (optt, token) in [("CE", 40000 + i), ("PE", 50000 + i)] {
+                values.push(json!({
+                    "exch": "NFO", "token": token.to_string(),
+                    "tsym": format!("NIFTY04JAN24{}{}", &optt[..1], strike),
+                    "optt": optt, "strprc": format!("{}.00", strike), "ls": "50"
+                }));
             }
         }
-        -9999.0
+        let chain = parse_option_chain(&json!({"stat": "Ok", "values": values}));
+        assert_eq!(chain.len(), 30);
+
+        let symbols = chain_subscriptions(&chain, "NIFTY04JAN24C21800", 21800.0, 5).unwrap();
+        assert_eq!(symbols.len(), 11);
+        assert_eq!(symbols[0], "NFO|40002");
+        assert_eq!(symbols[5], "NFO|40007");
+        assert_eq!(symbols[10], "NFO|40012");
+
+        // a spot between strikes centres on the nearest, 21650 and not 21700
+        let symbols = chain_subscriptions(&chain, "NIFTY04JAN24C21800", 21660.0, 2).unwrap();
+        assert_eq!(symbols.len(), 5);
+        assert_eq!(symbols[2], "NFO|40004");
+        // halfway goes to the lower strike
+        let symbols = chain_subscriptions(&chain, "NIFTY04JAN24C21800", 21675.0, 0).unwrap();
+        assert_eq!(symbols, ["NFO|40004"]);
+
+        // the window is cut at the edge of the chain
+        let puts = chain_subscriptions(&chain, "NIFTY04JAN24P21800", 21500.0, 5).unwrap();
+        assert_eq!(puts.len(), 7);
+        assert!(chain_subscriptions(&chain, "NIFTY11JAN24C21800", 21800.0, 5).is_err());
+    }
+
+    #[test]
+    fn test_chain_skips_underlying_row() {
+        let chain = parse_option_chain(&json!({"stat": "Ok", "values": [
+            {"exch": "NFO", "token": "35001", "tsym": "NIFTY25JAN24F", "ls": "50"},
+            {"exch": "NFO", "token": "35002", "tsym": "NIFTY25JAN24F", "optt": "XX",
+             "strprc": "0.00", "ls": "50"},
+            {"exch": "NFO", "token": "40007", "tsym": "NIFTY04JAN24C21800", "optt": "CE",
+             "strprc": "21800.00", "ls": "50"},
+            {"exch": "NFO", "token": "50007", "tsym": "NIFTY04JAN24P21800", "optt": "PE",
+             "strprc": "21800.00", "ls": "50"}
+        ]}));
+        assert_eq!(chain.len(), 2);
+        assert!(chain.iter().all(|row| row.optt == "CE" || row.optt == "PE"));
+        assert_eq!(
+            chain_subscriptions(&chain, "NIFTY04JAN24C21800", 21800.0, 5).unwrap(),
+            vec!["NFO|40007"]
+        );
+    }
+
+    #[test]
+    fn test_parse_eod_series() {
+        let res_dict = json!([
+            "{\"time\":\"05-JAN-2024\",\"into\":\"21705.75\",\"inth\":\"21749.60\",\"intl\":\"21629.20\",\"intc\":\"21710.80\",\"ssboe\":\"1704412800\",\"intv\":\"294254233\"}",
+            {"time": "04-JAN-2024", "into": "21605.80", "inth": "21685.65", "intl": "21564.55",
+             "intc": "21658.60", "ssboe": "1704326400", "intv": "309082389"}
+        ]);
+        let candles = parse_daily_candles(&res_dict).unwrap();
+        assert_eq!(candles.len(), 2);
+        assert_eq!(
+            candles[0].date,
+            chrono::NaiveDate::from_ymd_opt(2024, 1, 4).unwrap()
+        );
+        assert_eq!(candles[0].intc, 21658.60);
+        assert_eq!(candles[1].inth, 21749.60);
+        assert_eq!(candles[1].intv, 294254233);
+
+        let error = json!({"stat": "Not_Ok", "emsg": "no data"});
+        assert!(parse_daily_candles(&error).is_err());
+    }
+
+    #[test]
+    fn test_parse_security_info() {
+        let res_dict = json!({
+            "stat": "Ok", "exch": "NFO", "tsym": "NIFTY04JAN24C21800", "ls": "50",
+            "ti": "0.05", "pp": "2", "frzqty": "1801"
+        });
+        let info = parse_security_info(&res_dict).unwrap();
+        assert_eq!(info.tsym, "NIFTY04JAN24C21800");
+        assert_eq!(info.ls, 50);
+        assert_eq!(info.ti, 0.05);
+        assert_eq!(info.pp, 2);
+        assert_eq!(info.frzqty, 1801);
+
+        let error = json!({"stat": "Not_Ok", "emsg": "Invalid Input : Token"});
+        assert!(parse_security_info(&error).is_err());
+    }
+
+    #[test]
+    fn test_historical_prices() {
+        let time = |hm: &str| {
+            chrono::NaiveDateTime::parse_from_str(&format!("04-01-2024 {}", hm), "%d-%m-%Y %H:%M")
+                .unwrap()
+        };
+        let candle = |hm: &str, intc: f64| Candle {
+            time: time(hm),
+            into: intc,
+            inth: intc,
+            intl: intc,
+            intc,
+            intv: 0,
+            intvwap: None,
+        };
+        let mut prices = HistoricalPrices::new(time("09:17"));
+        prices.add_series(
+            &Exchange::NSE,
+            "26000",
+            vec![candle("09:20", 21660.0), candle("09:15", 21650.0)],
+        );
+        assert_eq!(prices.ltp(&Exchange::NSE, "26000"), 21650.0);
+        prices.set_time(time("09:20"));
+        assert_eq!(prices.ltp(&Exchange::NSE, "26000"), 21660.0);
+        assert_eq!(prices.ltp(&Exchange::NFO, "26000"), -9999.0);
+        prices.set_time(time("09:00"));
+        assert_eq!(prices.ltps(&Exchange::NSE, &["26000"])["26000"], -9999.0);
+    }
+
+    #[test]
+    fn test_option_greeks() {
+        let today = chrono::NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let auth = crate::auth::Auth::new();
+        let values = option_greeks_values(&auth, today, 21650.0, 21700.0, 12.5, 7.0, 3, "PE");
+        assert_eq!(values["exd"], "04-JAN-2024");
+        assert_eq!(values["strprc"], "21700");
+        assert_eq!(values["sptprc"], "21650");
+        assert_eq!(values["optt"], "PE");
+
+        let res_dict = json!({
+            "stat": "OK", "cal_price": "72.10", "put_price": "118.45",
+            "cal_delta": "0.4121", "put_delta": "-0.5879", "cal_gamma": "0.0011",
+            "put_gamma": "0.0011", "cal_theta": "-21.3", "put_theta": "-17.2",
+            "cal_rho": "0.71", "put_rho": "-1.07", "cal_vego": "9.82", "put_vego": "9.82"
+        });
+        let put = parse_greeks(&res_dict, "PE").unwrap();
+        assert_eq!(put.price, 118.45);
+        assert_eq!(put.delta, -0.5879);
+        assert_eq!(put.vega, 9.82);
+        let call = parse_greeks(&res_dict, "CE").unwrap();
+        assert_eq!(call.delta, 0.4121);
+        assert_eq!(call.theta, -21.3);
+
+        assert!(parse_greeks(&json!({"stat": "Not_Ok"}), "CE").is_err());
     }
-}
\ No newline at end of file
+}
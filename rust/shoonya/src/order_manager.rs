@@ -0,0 +1,1514 @@
+#![allow(dead_code)]
+
+use crate::auth::Auth;
+use crate::orders::{
+    cancel_order, closing_orders, get_order_book, get_positions, get_trade_book, wait_for_fill,
+    ExposureCap, OrderBuilder, OrderStatus, Position,
+};
+use crate::runner::{
+    apply_day_end, place_entry_using, DayEndAction, EntryLeg, EntryMode, ExitSignal, StrategyRunner,
+};
+use crate::transaction::TransactionManager;
+use crate::websocket::{
+    ConnState, ConnectAck, Tick, WebSocketApp, WebSocketCallback, WebSocketConfig,
+};
+use common::utils::{get_exchange, Exchange};
+use log::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+// Sends of an order before giving up, see `OrderBuilder::place_with_retry`
+const PLACE_ATTEMPTS: u32 = 3;
+
+// The broker calls of the order manager, faked in the tests
+trait Broker: Send + Sync {
+    fn place(&self, order: &OrderBuilder) -> Result<String, Box<dyn std::error::Error>>;
+    fn cancel(&self, norenordno: &str) -> Result<(), Box<dyn std::error::Error>>;
+    // Brings the order table up to date with the order and trade books, the table
+    // is only locked once the books are in
+    fn resync(&self, tm: &Mutex<TransactionManager>) -> Result<(), Box<dyn std::error::Error>>;
+    fn positions(&self) -> Result<Vec<Position>, Box<dyn std::error::Error>>;
+    fn wait_for_fill(
+        &self,
+        norenordno: &str,
+        timeout: Duration,
+    ) -> Result<OrderStatus, Box<dyn std::error::Error>>;
+    // Keeps a leg overnight, see `apply_day_end`
+    fn carry(&self, action: &DayEndAction) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+struct LiveBroker {
+    auth: Arc<RwLock<Auth>>,
+}
+
+impl Broker for LiveBroker {
+    fn place(&self, order: &OrderBuilder) -> Result<String, Box<dyn std::error::Error>> {
+        order.place_with_retry(&self.auth.read().unwrap(), PLACE_ATTEMPTS)
+    }
+
+    fn cancel(&self, norenordno: &str) -> Result<(), Box<dyn std::error::Error>> {
+        cancel_order(&self.auth.read().unwrap(), norenordno).map(|_| ())
+    }
+
+    fn resync(&self, tm: &Mutex<TransactionManager>) -> Result<(), Box<dyn std::error::Error>> {
+        let auth = self.auth.read().unwrap();
+        let order_book = get_order_book(&auth)?;
+        let trades = get_trade_book(&auth)?;
+        let mut tm = tm.lock().unwrap();
+        tm.resync_from_order_book(&order_book);
+        tm.resync_from_trade_book(&trades);
+        Ok(())
+    }
+
+    fn positions(&self) -> Result<Vec<Position>, Box<dyn std::error::Error>> {
+        get_positions(&self.auth.read().unwrap())
+    }
+
+    fn wait_for_fill(
+        &self,
+        norenordno: &str,
+        timeout: Duration,
+    ) -> Result<OrderStatus, Box<dyn std::error::Error>> {
+        wait_for_fill(&self.auth.read().unwrap(), norenordno, timeout)
+    }
+
+    fn carry(&self, action: &DayEndAction) -> Result<(), Box<dyn std::error::Error>> {
+        apply_day_end(&self.auth.read().unwrap(), std::slice::from_ref(action))
+    }
+}
+
+// The MTM exit rules, and the exit signal the pause holds back until resume
+#[derive(Default)]
+struct MtmExit {
+    runner: Option<StrategyRunner>,
+    held: Option<ExitSignal>,
+}
+
+// Feeds the websocket frames into the transaction manager
+struct FeedHandler {
+    broker: Arc<dyn Broker>,
+    tm: Arc<Mutex<TransactionManager>>,
+    watchdog: Arc<Mutex<FeedWatchdog>>,
+    last_ticks: Arc<Mutex<HashMap<Subscription, Tick>>>,
+    mtm_exit: Arc<Mutex<MtmExit>>,
+    // the exit worker, the exit's broker calls must not hold up the reader thread
+    exit_tx: mpsc::Sender<ExitSignal>,
+    paused: Arc<AtomicBool>,
+    day_over: Arc<AtomicBool>,
+    // set by the first session, any later one follows a gap in the order updates
+    opened: Arc<AtomicBool>,
+}
+
+impl FeedHandler {
+    // Hands the exit of every leg to the exit worker once the MTM reaches the
+    // target or the stop
+    fn check_mtm_exit(&mut self) {
+        let signal = mtm_exit(
+            &mut self.mtm_exit.lock().unwrap(),
+            &self.tm.lock().unwrap(),
+            &self.day_over,
+            self.paused.load(Ordering::SeqCst),
+            Instant::now(),
+        );
+        if let Some(signal) = signal {
+            if self.exit_tx.send(signal).is_err() {
+                error!("Exit worker gone, MTM {:?} exit not sent", signal);
+            }
+        }
+    }
+}
+
+impl WebSocketCallback for FeedHandler {
+    // Called before the reader loop, so the resync lands before the first tick
+    fn on_open(&mut self, ack: &ConnectAck) {
+        info!("Order manager connected as {:?}", ack.uid);
+        if !self.opened.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // the order updates sent while disconnected are lost, the books still have them
+        warn!("Feed reconnected, resyncing the orders");
+        if let Err(e) = self.broker.resync(&self.tm) {
+            error!("Resync after the reconnect failed: {}", e);
+        }
+    }
+
+    fn on_order(&mut self, order: &serde_json::Value) {
+        self.tm.lock().unwrap().on_order(order);
+    }
+
+    fn subscribe_callback(&mut self, tick: &Tick) {
+        if let Some(symbol) = tick.symbol() {
+            self.watchdog
+                .lock()
+                .unwrap()
+                .on_tick(&symbol, Instant::now());
+            if let Some(subscription) = Subscription::parse(&symbol) {
+                let mut last_ticks = self.last_ticks.lock().unwrap();
+                last_ticks.entry(subscription).or_default().apply(tick);
+            }
+        }
+        self.tm.lock().unwrap().on_tick(tick);
+        self.check_mtm_exit();
+    }
+
+    fn on_give_up(&mut self) {
+        let positions = self.tm.lock().unwrap().open_positions();
+        if !positions.is_empty() {
+            error!("Order feed lost with open positions: {:?}", positions);
+        }
+    }
+
+    // Squares off every open position with a market order, unless paused
+    fn flatten(&mut self) {
+        let paused = self.paused.load(Ordering::SeqCst);
+        square_off(&self.tm, paused, |order| self.broker.place(order));
+    }
+}
+
+// Cancels each order in `norenordnos`, returning the ones cancelled
+fn cancel_orders<F>(norenordnos: Vec<String>, mut cancel: F) -> Vec<String>
+where
+    F: FnMut(&str) -> Result<(), Box<dyn std::error::Error>>,
+{
+    let mut cancelled = Vec::new();
+    for norenordno in norenordnos {
+        match cancel(&norenordno) {
+            Ok(()) => {
+                warn!("Cancelled open order {}", norenordno);
+                cancelled.push(norenordno);
+            }
+            Err(e) => error!("Cancel of {} failed: {}", norenordno, e),
+        }
+    }
+    cancelled
+}
+
+// Cancels the resting orders first, so none of them fills after its leg was
+// squared off, then reads the order book again to catch the fills that raced
+// the cancel and squares off, unless paused. `cancel` cancels an order, `refresh`
+// brings the order table up to date and `send` places a closing order. The
+// order table is not locked over the broker calls.
+fn exit_all<C, R, S>(
+    tm: &Mutex<TransactionManager>,
+    paused: bool,
+    cancel: C,
+    refresh: R,
+    send: S,
+) -> Vec<String>
+where
+    C: FnMut(&str) -> Result<(), Box<dyn std::error::Error>>,
+    R: FnOnce(&Mutex<TransactionManager>) -> Result<(), Box<dyn std::error::Error>>,
+    S: FnMut(&OrderBuilder) -> Result<String, Box<dyn std::error::Error>>,
+{
+    let open_orders = tm.lock().unwrap().open_orders();
+    cancel_orders(open_orders, cancel);
+    if let Err(e) = refresh(tm) {
+        error!(
+            "Order book unavailable, flattening the fills seen so far: {}",
+            e
+        );
+    }
+    square_off(tm, paused, send)
+}
+
+// `exit_all` through the broker
+fn exit_using(tm: &Mutex<TransactionManager>, paused: bool, broker: &dyn Broker) -> Vec<String> {
+    exit_all(
+        tm,
+        paused,
+        |norenordno| broker.cancel(norenordno),
+        |tm| broker.resync(tm),
+        |order| broker.place(order),
+    )
+}
+
+// Runs the exits `check_mtm_exit` signals until the order manager and its feed
+// handlers are gone
+fn spawn_exit_worker(
+    tm: Arc<Mutex<TransactionManager>>,
+    broker: Arc<dyn Broker>,
+) -> mpsc::Sender<ExitSignal> {
+    let (exit_tx, exit_rx) = mpsc::channel::<ExitSignal>();
+    std::thread::spawn(move || {
+        for signal in exit_rx {
+            warn!("MTM {:?} hit, exiting every leg", signal);
+            exit_using(&tm, false, broker.as_ref());
+        }
+    });
+    exit_tx
+}
+
+// The MTM check after a tick. The first target or stop hit ends the day and
+// returns the signal to exit every leg on, later ticks leave it alone. A hit
+// while paused is held and returned by the first check after resume, whatever
+// the MTM is by then.
+fn mtm_exit(
+    exit: &mut MtmExit,
+    tm: &TransactionManager,
+    day_over: &AtomicBool,
+    paused: bool,
+    now: Instant,
+) -> Option<ExitSignal> {
+    if day_over.load(Ordering::SeqCst) {
+        return None;
+    }
+    let signal = match exit.held.take() {
+        Some(signal) => signal,
+        None => exit.runner.as_ref()?.check_exit(tm, now)?,
+    };
+    if paused {
+        if exit.held.replace(signal).is_none() {
+            warn!(
+                "MTM {:?} hit while paused, the exit waits for resume",
+                signal
+            );
+        }
+        return None;
+    }
+    day_over.store(true, Ordering::SeqCst);
+    Some(signal)
+}
+
+// Sends a market order offsetting each open position, `send` places the order
+// with the order table unlocked. Returns the norenordno of the orders placed,
+// none while paused.
+fn square_off<F>(tm: &Mutex<TransactionManager>, paused: bool, mut send: F) -> Vec<String>
+where
+    F: FnMut(&OrderBuilder) -> Result<String, Box<dyn std::error::Error>>,
+{
+    let mut placed = Vec::new();
+    let positions = tm.lock().unwrap().open_positions();
+    if paused {
+        if !positions.is_empty() {
+            error!("Trading is paused, positions left open: {:?}", positions);
+        }
+        return placed;
+    }
+    for position in positions {
+        let exchange = match get_exchange(&position.exchange) {
+            Some(exchange) => exchange,
+            None => {
+                error!(
+                    "Cannot flatten {}: unknown exchange",
+                    position.tradingsymbol
+                );
+                continue;
+            }
+        };
+        let (order, exposure) = {
+            let mut tm = tm.lock().unwrap();
+            if tm.is_carried(&position.tradingsymbol) {
+                info!("Leaving {} open overnight", position.tradingsymbol);
+                continue;
+            }
+            let buy_or_sell = if position.net_qty > 0 { "S" } else { "B" };
+            let remarks = tm.new_client_order_id("flatten");
+            tm.record_sent(&remarks);
+            let order = OrderBuilder::new(
+                buy_or_sell,
+                exchange,
+                &position.tradingsymbol,
+                position.net_qty.unsigned_abs() as u32,
+            )
+            .product_type(&position.product)
+            .remarks(&remarks);
+            match tm.check_exposure(&order) {
+                Ok(exposure) => (order, exposure),
+                Err(e) => {
+                    error!("Cannot flatten {}: {}", position.tradingsymbol, e);
+                    continue;
+                }
+            }
+        };
+        match send(&order) {
+            Ok(norenordno) => {
+                let mut tm = tm.lock().unwrap();
+                tm.record_exposure(&exposure);
+                tm.record_placed(order.client_order_id(), &norenordno);
+                warn!("Flattened {}: {}", position.tradingsymbol, norenordno);
+                placed.push(norenordno);
+            }
+            Err(e) => error!("Flatten of {} failed: {}", position.tradingsymbol, e),
+        }
+    }
+    placed
+}
+
+/// A touchline subscription, "EXCH|token" on the wire
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Subscription {
+    pub exchange: String,
+    pub token: String,
+}
+
+impl Subscription {
+    pub fn parse(symbol: &str) -> Option<Subscription> {
+        let (exchange, token) = symbol.split_once('|')?;
+        Some(Subscription {
+            exchange: exchange.to_string(),
+            token: token.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for Subscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}|{}", self.exchange, self.token)
+    }
+}
+
+/// What to do about the subscriptions that stopped ticking
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchdogAction {
+    None,
+    /// Subscribe these symbols again
+    Resubscribe(Vec<String>),
+    /// Too many symbols stalled, the feed itself is suspect
+    Reconnect,
+}
+
+/// Spots subscriptions the server silently stopped ticking, without closing the socket
+pub struct FeedWatchdog {
+    stall_timeout: Duration,
+    reconnect_ratio: f64,
+    // symbol -> last tick, or the subscribe time before the first tick
+    last_seen: HashMap<String, Instant>,
+}
+
+impl FeedWatchdog {
+    pub fn new(stall_timeout: Duration, reconnect_ratio: f64) -> Self {
+        FeedWatchdog {
+            stall_timeout,
+            reconnect_ratio,
+            last_seen: HashMap::new(),
+        }
+    }
+
+    pub fn watch(&mut self, symbol: &str, now: Instant) {
+        self.last_seen.insert(symbol.to_string(), now);
+    }
+
+    pub fn unwatch(&mut self, symbol: &str) {
+        self.last_seen.remove(symbol);
+    }
+
+    pub fn on_tick(&mut self, symbol: &str, now: Instant) {
+        if let Some(last_seen) = self.last_seen.get_mut(symbol) {
+            *last_seen = now;
+        }
+    }
+
+    /// Stalled symbols, nothing is stalled outside market hours. The clock of the
+    /// symbols reported restarts, giving the action time to take effect.
+    pub fn check(&mut self, now: Instant, market_open: bool) -> WatchdogAction {
+        if !market_open || self.last_seen.is_empty() {
+            return WatchdogAction::None;
+        }
+        let mut stalled: Vec<String> = self
+            .last_seen
+            .iter()
+            .filter(|(_, last_seen)| now.duration_since(**last_seen) >= self.stall_timeout)
+            .map(|(symbol, _)| symbol.clone())
+            .collect();
+        if stalled.is_empty() {
+            return WatchdogAction::None;
+        }
+        stalled.sort();
+        let reconnect = stalled.len() as f64 / self.last_seen.len() as f64 >= self.reconnect_ratio;
+        for symbol in &stalled {
+            self.last_seen.insert(symbol.clone(), now);
+        }
+        if reconnect {
+            WatchdogAction::Reconnect
+        } else {
+            WatchdogAction::Resubscribe(stalled)
+        }
+    }
+}
+
+/// Places the strategy orders and tracks them through the websocket feed
+pub struct OrderManager {
+    auth: Arc<RwLock<Auth>>,
+    broker: Arc<dyn Broker>,
+    app: WebSocketApp,
+    tm: Arc<Mutex<TransactionManager>>,
+    watchdog: Arc<Mutex<FeedWatchdog>>,
+    subscribed_symbols: HashSet<String>,
+    // the ticks merged per subscription, a token alone is ambiguous across exchanges
+    last_ticks: Arc<Mutex<HashMap<Subscription, Tick>>>,
+    paused: Arc<AtomicBool>,
+    mtm_exit: Arc<Mutex<MtmExit>>,
+    exit_tx: mpsc::Sender<ExitSignal>,
+    day_over: Arc<AtomicBool>,
+    feed_opened: Arc<AtomicBool>,
+}
+
+impl OrderManager {
+    pub fn new(auth: Arc<RwLock<Auth>>, config: WebSocketConfig) -> Self {
+        let broker = Arc::new(LiveBroker { auth: auth.clone() });
+        OrderManager::with_broker(auth, config, broker)
+    }
+
+    fn with_broker(
+        auth: Arc<RwLock<Auth>>,
+        config: WebSocketConfig,
+        broker: Arc<dyn Broker>,
+    ) -> Self {
+        let watchdog = FeedWatchdog::new(config.stall_timeout, config.stall_reconnect_ratio);
+        let tm = Arc::new(Mutex::new(TransactionManager::new()));
+        let exit_tx = spawn_exit_worker(tm.clone(), broker.clone());
+        OrderManager {
+            auth,
+            broker,
+            app: WebSocketApp::new(config),
+            tm,
+            watchdog: Arc::new(Mutex::new(watchdog)),
+            subscribed_symbols: HashSet::new(),
+            last_ticks: Arc::new(Mutex::new(HashMap::new())),
+            paused: Arc::new(AtomicBool::new(false)),
+            mtm_exit: Arc::new(Mutex::new(MtmExit::default())),
+            exit_tx,
+            day_over: Arc::new(AtomicBool::new(false)),
+            feed_opened: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Halts order placement, exits included, the feed and the PnL keep running.
+    /// An MTM exit hit meanwhile is held until `resume`, the flatten on losing
+    /// the feed and the square off on shutdown leave the positions open.
+    pub fn pause(&self) {
+        warn!("Trading paused, orders are held back");
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Lifts the pause and sends the MTM exit it held back, if any
+    pub fn resume(&self) {
+        info!("Trading resumed");
+        self.paused.store(false, Ordering::SeqCst);
+        let signal = mtm_exit(
+            &mut self.mtm_exit.lock().unwrap(),
+            &self.tm.lock().unwrap(),
+            &self.day_over,
+            false,
+            Instant::now(),
+        );
+        if let Some(signal) = signal {
+            warn!("MTM {:?} hit, exiting every leg", signal);
+            exit_using(&self.tm, false, self.broker.as_ref());
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Caps the quantity and notional held open across the legs. Every order sent,
+    /// entries and exits alike, is checked against it; closing orders free it.
+    pub fn limit_exposure(&self, cap: ExposureCap) {
+        self.tm.lock().unwrap().set_exposure_cap(cap);
+    }
+
+    /// Checks the MTM against the runner's target and stop after every tick. The
+    /// first exit signal ends the day and squares off every leg from a worker
+    /// thread, off the feed's reader thread. While paused it waits for `resume`.
+    pub fn exit_on_mtm(&self, runner: StrategyRunner) {
+        self.mtm_exit.lock().unwrap().runner = Some(runner);
+    }
+
+    /// True once the MTM exit or the shutdown squared off the legs, the strategy
+    /// loop should stop then
+    pub fn day_over(&self) -> bool {
+        self.day_over.load(Ordering::SeqCst)
+    }
+
+    /// State of the websocket, see `WebSocketApp::state`
+    pub fn feed_state(&self) -> ConnState {
+        self.app.state()
+    }
+
+    pub fn transactions(&self) -> Arc<Mutex<TransactionManager>> {
+        self.tm.clone()
+    }
+
+    /// Connects the websocket and subscribes to the order updates. A session
+    /// opened after a drop first resyncs the orders from the order and trade books.
+    pub fn start(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let handler = self.feed_handler();
+        self.app.start_websocket(self.auth.clone(), handler)?;
+        self.app.subscribe_orders(&self.auth.read().unwrap())
+    }
+
+    fn feed_handler(&self) -> FeedHandler {
+        FeedHandler {
+            broker: self.broker.clone(),
+            tm: self.tm.clone(),
+            watchdog: self.watchdog.clone(),
+            last_ticks: self.last_ticks.clone(),
+            mtm_exit: self.mtm_exit.clone(),
+            exit_tx: self.exit_tx.clone(),
+            paused: self.paused.clone(),
+            day_over: self.day_over.clone(),
+            opened: self.feed_opened.clone(),
+        }
+    }
+
+    /// Subscribes to ticks, symbols are "EXCH|token"
+    pub fn subscribe(&mut self, symbols: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        self.app.subscribe(symbols)?;
+        let mut watchdog = self.watchdog.lock().unwrap();
+        for symbol in symbols {
+            self.subscribed_symbols.insert(symbol.to_string());
+            watchdog.watch(symbol, Instant::now());
+        }
+        Ok(())
+    }
+
+    pub fn unsubscribe(&mut self, symbols: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        self.app.unsubscribe(symbols)?;
+        let mut watchdog = self.watchdog.lock().unwrap();
+        let mut last_ticks = self.last_ticks.lock().unwrap();
+        for symbol in symbols {
+            self.subscribed_symbols.remove(*symbol);
+            watchdog.unwatch(symbol);
+            if let Some(subscription) = Subscription::parse(symbol) {
+                last_ticks.remove(&subscription);
+            }
+        }
+        Ok(())
+    }
+
+    /// Re-subscribes the symbols that stopped ticking, or restarts the feed when
+    /// most of them did. Meant to be called periodically from the strategy loop.
+    pub fn check_feed(&mut self, market_open: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let action = self
+            .watchdog
+            .lock()
+            .unwrap()
+            .check(Instant::now(), market_open);
+        match action {
+            WatchdogAction::None => Ok(()),
+            WatchdogAction::Resubscribe(symbols) => {
+                warn!("No ticks for {:?}, subscribing again", symbols);
+                let symbols: Vec<&str> = symbols.iter().map(|symbol| symbol.as_str()).collect();
+                self.app.subscribe(&symbols)
+            }
+            WatchdogAction::Reconnect => {
+                warn!("Most subscriptions stopped ticking, reconnecting the feed");
+                self.app.close();
+                self.start()?;
+                let symbols: Vec<&str> = self
+                    .subscribed_symbols
+                    .iter()
+                    .map(|symbol| symbol.as_str())
+                    .collect();
+                self.app.subscribe(&symbols)
+            }
+        }
+    }
+
+    /// Names the instrument behind `symbol`, "EXCH|token", so its ticks mark the
+    /// legs traded in it. `ltp` values the orders in it until the first tick.
+    pub fn track(&self, symbol: &str, tradingsymbol: &str, ltp: f64) {
+        let subscription = match Subscription::parse(symbol) {
+            Some(subscription) => subscription,
+            None => {
+                error!("Cannot track {}: not EXCH|token", symbol);
+                return;
+            }
+        };
+        let mut tm = self.tm.lock().unwrap();
+        tm.add_symbol(symbol, tradingsymbol);
+        if tm.get_quote(symbol).is_none() {
+            tm.on_tick(&Tick {
+                e: Some(subscription.exchange),
+                tk: Some(subscription.token),
+                lp: Some(ltp),
+                ..Default::default()
+            });
+        }
+    }
+
+    pub fn subscribed_symbols(&self) -> &HashSet<String> {
+        &self.subscribed_symbols
+    }
+
+    /// Each subscription with the latest tick received for it, the partial updates
+    /// merged in, None if it never ticked
+    pub fn subscription_status(&self) -> Vec<(Subscription, Option<Tick>)> {
+        let last_ticks = self.last_ticks.lock().unwrap();
+        let mut status: Vec<(Subscription, Option<Tick>)> = self
+            .subscribed_symbols
+            .iter()
+            .filter_map(|symbol| Subscription::parse(symbol))
+            .map(|subscription| {
+                let tick = last_ticks.get(&subscription).cloned();
+                (subscription, tick)
+            })
+            .collect();
+        status.sort_by(|a, b| a.0.cmp(&b.0));
+        status
+    }
+
+    /// One line per subscription, for the logs
+    pub fn subscription_report(&self) -> String {
+        self.subscription_status()
+            .iter()
+            .map(|(subscription, tick)| match tick {
+                Some(Tick { lp: Some(lp), .. }) => format!("{} : ltp {:.2}", subscription, lp),
+                Some(_) => format!("{} : no trade yet", subscription),
+                None => format!("{} : no tick received", subscription),
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Stamps the order with a client order id, records the send time and places it,
+    /// retrying a failed send without doubling the order. Returns the norenordno and
+    /// the client order id to look the order up by. Fails without sending anything
+    /// while paused or when the order would breach the exposure cap.
+    pub fn place(
+        &self,
+        order: OrderBuilder,
+        tag: &str,
+    ) -> Result<(String, String), Box<dyn std::error::Error>> {
+        self.place_using(order, tag, |order| self.broker.place(order))
+    }
+
+    // The pause gate, exposure cap and bookkeeping of place, `send` places the
+    // stamped order
+    fn place_using<F>(
+        &self,
+        order: OrderBuilder,
+        tag: &str,
+        send: F,
+    ) -> Result<(String, String), Box<dyn std::error::Error>>
+    where
+        F: FnOnce(&OrderBuilder) -> Result<String, Box<dyn std::error::Error>>,
+    {
+        if self.is_paused() {
+            return Err(format!("Trading is paused, {} not placed", tag).into());
+        }
+        let (client_order_id, exposure) = {
+            let mut tm = self.tm.lock().unwrap();
+            let exposure = tm.check_exposure(&order)?;
+            let client_order_id = tm.new_client_order_id(tag);
+            tm.record_sent(&client_order_id);
+            (client_order_id, exposure)
+        };
+        let norenordno = send(&order.remarks(&client_order_id))?;
+        let mut tm = self.tm.lock().unwrap();
+        tm.record_exposure(&exposure);
+        tm.record_placed(&client_order_id, &norenordno);
+        Ok((norenordno, client_order_id))
+    }
+
+    /// Places the entry legs, each through `place`. A sequential entry sends the
+    /// widest quoted spread first and waits for each fill before the next.
+    /// Returns the norenordno of each leg in the order sent.
+    pub fn place_entry(
+        &self,
+        orders: Vec<OrderBuilder>,
+        mode: EntryMode,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let legs = {
+            let tm = self.tm.lock().unwrap();
+            orders
+                .into_iter()
+                .map(|order| EntryLeg {
+                    spread: tm.spread(order.tradingsymbol()).unwrap_or(0.0),
+                    order,
+                })
+                .collect()
+        };
+        let timeout = match mode {
+            EntryMode::Sequential { fill_timeout } => fill_timeout,
+            EntryMode::Simultaneous => Duration::ZERO,
+        };
+        place_entry_using(
+            legs,
+            mode,
+            |leg| {
+                self.place(leg.order.clone(), "entry")
+                    .map(|(norenordno, _)| norenordno)
+            },
+            |norenordno| self.broker.wait_for_fill(norenordno, timeout),
+        )
+    }
+
+    /// Closes the position in one symbol, e.g. a single leg of a strangle, with
+    /// market orders placed like any other. Returns the norenordno of each closing
+    /// order, none if already flat.
+    pub fn close_position(
+        &self,
+        exchange: Exchange,
+        tradingsymbol: &str,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let positions = self.broker.positions()?;
+        let orders = closing_orders(&positions, exchange, tradingsymbol);
+        if orders.is_empty() {
+            info!("{} is already flat", tradingsymbol);
+        }
+        orders
+            .into_iter()
+            .map(|order| self.place(order, "close").map(|(norenordno, _)| norenordno))
+            .collect()
+    }
+
+    /// Carries the legs `runner` keeps overnight with the underlying at `spot`,
+    /// see `StrategyRunner::day_end_actions`, the square off on shutdown leaves
+    /// them open. A leg that cannot be carried is squared off with the rest.
+    /// Returns the tradingsymbols carried.
+    pub fn carry_overnight(
+        &self,
+        runner: &StrategyRunner,
+        spot: f64,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let positions = self.broker.positions()?;
+        let mut carried = Vec::new();
+        for action in runner.day_end_actions(&positions, spot) {
+            let position = match &action {
+                DayEndAction::Carry(position) => position,
+                DayEndAction::Flatten(_) => continue,
+            };
+            match self.broker.carry(&action) {
+                Ok(()) => {
+                    self.tm.lock().unwrap().carry_overnight(&position.tsym);
+                    carried.push(position.tsym.clone());
+                }
+                Err(e) => error!("Cannot carry {}, squaring it off: {}", position.tsym, e),
+            }
+        }
+        Ok(carried)
+    }
+
+    /// Cancels every order of this instance still resting at the broker.
+    /// Returns the norenordno of the orders cancelled.
+    pub fn cancel_all_open(&self) -> Vec<String> {
+        self.cancel_all_open_using(|norenordno| self.broker.cancel(norenordno))
+    }
+
+    fn cancel_all_open_using<F>(&self, cancel: F) -> Vec<String>
+    where
+        F: FnMut(&str) -> Result<(), Box<dyn std::error::Error>>,
+    {
+        let open_orders = self.tm.lock().unwrap().open_orders();
+        cancel_orders(open_orders, cancel)
+    }
+
+    /// Squares off every open position with a market order. Returns the norenordno
+    /// of the closing orders, none while paused.
+    pub fn square_off_all(&self) -> Vec<String> {
+        square_off(&self.tm, self.is_paused(), |order| self.broker.place(order))
+    }
+
+    /// Leaves the account flat on day end, Ctrl-C or exit: the resting orders are
+    /// cancelled before the open positions are squared off, see `exit_all`. While
+    /// paused the positions are left open.
+    pub fn shutdown(&mut self) {
+        self.shutdown_using(
+            |norenordno| self.broker.cancel(norenordno),
+            |tm| self.broker.resync(tm),
+            |order| self.broker.place(order),
+        );
+        self.stop();
+    }
+
+    fn shutdown_using<C, R, S>(&self, cancel: C, refresh: R, send: S) -> Vec<String>
+    where
+        C: FnMut(&str) -> Result<(), Box<dyn std::error::Error>>,
+        R: FnOnce(&Mutex<TransactionManager>) -> Result<(), Box<dyn std::error::Error>>,
+        S: FnMut(&OrderBuilder) -> Result<String, Box<dyn std::error::Error>>,
+    {
+        self.day_over.store(true, Ordering::SeqCst);
+        exit_all(&self.tm, self.is_paused(), cancel, refresh, send)
+    }
+
+    pub fn stop(&mut self) {
+        let latency_report = self.tm.lock().unwrap().latency_report();
+        if !latency_report.is_empty() {
+            info!("Order latency:\n{}", latency_report);
+        }
+        self.app.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::net::TcpListener;
+    use tungstenite::Message;
+
+    // Records the broker calls, a resync applies `order_book` to the order table,
+    // `positions` is the PositionBook reply and every order waited on fills
+    #[derive(Default)]
+    struct FakeBroker {
+        calls: Mutex<Vec<String>>,
+        order_book: Mutex<serde_json::Value>,
+        positions: Mutex<serde_json::Value>,
+        // when set, each place waits for a message on it
+        place_gate: Mutex<Option<mpsc::Receiver<()>>>,
+    }
+
+    impl FakeBroker {
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    impl Broker for FakeBroker {
+        fn place(&self, order: &OrderBuilder) -> Result<String, Box<dyn std::error::Error>> {
+            let payload = order.build_payload(&Auth::new());
+            let norenordno = {
+                let mut calls = self.calls.lock().unwrap();
+                calls.push(format!(
+                    "place {} {} {}",
+                    payload["trantype"].as_str().unwrap(),
+                    payload["tsym"].as_str().unwrap(),
+                    order.quantity()
+                ));
+                (100 + calls.len()).to_string()
+            };
+            if let Some(gate) = self.place_gate.lock().unwrap().as_ref() {
+                gate.recv()?;
+            }
+            Ok(norenordno)
+        }
+
+        fn cancel(&self, norenordno: &str) -> Result<(), Box<dyn std::error::Error>> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("cancel {}", norenordno));
+            Ok(())
+        }
+
+        fn resync(&self, tm: &Mutex<TransactionManager>) -> Result<(), Box<dyn std::error::Error>> {
+            self.calls.lock().unwrap().push("resync".to_string());
+            tm.lock()
+                .unwrap()
+                .resync_from_order_book(&self.order_book.lock().unwrap());
+            Ok(())
+        }
+
+        fn positions(&self) -> Result<Vec<Position>, Box<dyn std::error::Error>> {
+            Ok(crate::orders::parse_positions(
+                &self.positions.lock().unwrap(),
+            ))
+        }
+
+        fn wait_for_fill(
+            &self,
+            norenordno: &str,
+            _: Duration,
+        ) -> Result<OrderStatus, Box<dyn std::error::Error>> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("wait {}", norenordno));
+            Ok(OrderStatus::Complete)
+        }
+
+        fn carry(&self, action: &DayEndAction) -> Result<(), Box<dyn std::error::Error>> {
+            if let DayEndAction::Carry(position) = action {
+                self.calls
+                    .lock()
+                    .unwrap()
+                    .push(format!("carry {}", position.tsym));
+            }
+            Ok(())
+        }
+    }
+
+    // Accepts a connection and acknowledges its connect frame
+    fn accept_login(listener: &TcpListener) -> tungstenite::WebSocket<std::net::TcpStream> {
+        let (stream, _) = listener.accept().unwrap();
+        let mut socket = tungstenite::accept(stream).unwrap();
+        socket.read().unwrap();
+        socket
+            .send(Message::Text(json!({"t": "ck", "s": "OK"}).to_string()))
+            .unwrap();
+        socket
+    }
+
+    // Polls `done` for up to two seconds
+    fn wait_for<F: FnMut() -> bool>(mut done: F) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(2) {
+            if done() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn test_subscription_status() {
+        let auth = Arc::new(RwLock::new(Auth::new()));
+        let mut om = OrderManager::new(auth, WebSocketConfig::default());
+        for symbol in ["NFO|42216", "NFO|42217", "BFO|42216"] {
+            om.subscribed_symbols.insert(symbol.to_string());
+        }
+        let mut handler = om.feed_handler();
+        handler.subscribe_callback(
+            &json!({"t": "tk", "e": "NFO", "tk": "42216", "lp": "101.50", "v": "1250"}).into(),
+        );
+        // the update only carries what changed
+        handler.subscribe_callback(
+            &json!({"t": "tf", "e": "NFO", "tk": "42216", "lp": "102.00"}).into(),
+        );
+        // the same token on another exchange is another instrument
+        handler.subscribe_callback(&json!({"t": "tk", "e": "BFO", "tk": "42216", "v": "0"}).into());
+
+        let status = om.subscription_status();
+        assert_eq!(status.len(), 3);
+        assert_eq!(status[0].0.to_string(), "BFO|42216");
+        assert_eq!(status[0].1.as_ref().unwrap().lp, None);
+        assert_eq!(status[1].0, Subscription::parse("NFO|42216").unwrap());
+        let tick = status[1].1.as_ref().unwrap();
+        assert_eq!((tick.lp, tick.v), (Some(102.0), Some(1250)));
+        assert!(status[2].1.is_none());
+        assert_eq!(
+            om.subscription_report(),
+            "BFO|42216 : no trade yet\nNFO|42216 : ltp 102.00\nNFO|42217 : no tick received"
+        );
+    }
+
+    #[test]
+    fn test_stalled_subscription_resubscribed() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut watchdog = FeedWatchdog::new(Duration::from_secs(30), 0.5);
+        for symbol in ["NFO|42216", "NFO|42217", "NSE|26000"] {
+            watchdog.watch(symbol, start);
+        }
+        watchdog.on_tick("NFO|42216", at(20));
+        watchdog.on_tick("NSE|26000", at(20));
+        assert_eq!(watchdog.check(at(29), true), WatchdogAction::None);
+        // the put stopped ticking, but not outside market hours
+        assert_eq!(watchdog.check(at(31), false), WatchdogAction::None);
+        assert_eq!(
+            watchdog.check(at(31), true),
+            WatchdogAction::Resubscribe(vec!["NFO|42217".to_string()])
+        );
+        // given another timeout before being reported again
+        assert_eq!(watchdog.check(at(45), true), WatchdogAction::None);
+
+        // every symbol silent means the feed is stuck
+        assert_eq!(watchdog.check(at(61), true), WatchdogAction::Reconnect);
+    }
+
+    #[test]
+    fn test_paused_stop_loss_held_until_resume() {
+        use crate::runner::{ExitRules, StrategyRunner, WarmUp};
+        use crate::transaction::MarkPrice;
+
+        let auth = Arc::new(RwLock::new(Auth::new()));
+        let broker = Arc::new(FakeBroker::default());
+        let om = OrderManager::with_broker(auth, WebSocketConfig::default(), broker.clone());
+        {
+            let tm = om.transactions();
+            let mut tm = tm.lock().unwrap();
+            let remarks = tm.make_remarks("straddle");
+            tm.add_symbol("NFO|42216", "NIFTY04JAN24C21800");
+            let fill = json!({
+                "norenordno": "1", "exch": "NFO", "tsym": "NIFTY04JAN24C21800", "prd": "M",
+                "trantype": "S", "status": "COMPLETE", "fillshares": "50", "flprc": "100.00",
+                "remarks": remarks
+            });
+            tm.on_order(&fill);
+            *broker.order_book.lock().unwrap() = json!([fill]);
+        }
+        om.exit_on_mtm(StrategyRunner::new(ExitRules {
+            target_mtm: 1000.0,
+            stop_mtm: 1000.0,
+            mark: MarkPrice::Ltp,
+            warmup: WarmUp::UntilFilled,
+        }));
+        let mut handler = om.feed_handler();
+
+        // (100 - 130) * 50 is past the stop
+        om.pause();
+        handler.subscribe_callback(&json!({"e": "NFO", "tk": "42216", "lp": "130.00"}).into());
+        assert!(broker.calls().is_empty());
+        assert!(!om.day_over());
+        // the MTM coming back inside the stop does not drop the held exit
+        handler.subscribe_callback(&json!({"e": "NFO", "tk": "42216", "lp": "110.00"}).into());
+        handler.flatten();
+        assert!(om.square_off_all().is_empty());
+        assert!(broker.calls().is_empty());
+
+        om.resume();
+        assert_eq!(broker.calls(), ["resync", "place B NIFTY04JAN24C21800 50"]);
+        assert!(om.day_over());
+        handler.subscribe_callback(&json!({"e": "NFO", "tk": "42216", "lp": "140.00"}).into());
+        assert_eq!(broker.calls().len(), 2);
+    }
+
+    #[test]
+    fn test_exposure_cap_checked_on_every_send() {
+        use common::utils::Exchange;
+
+        let auth = Arc::new(RwLock::new(Auth::new()));
+        let broker = Arc::new(FakeBroker::default());
+        let om =
+            OrderManager::with_broker(auth.clone(), WebSocketConfig::default(), broker.clone());
+        om.limit_exposure(ExposureCap::new(Some(100), None));
+        let sell = |tsym: &str, qty| OrderBuilder::new("S", Exchange::NFO, tsym, qty);
+
+        let (norenordno, client_order_id) =
+            om.place(sell("NIFTY04JAN24C21800", 50), "entry").unwrap();
+        let e = om
+            .place(sell("NIFTY04JAN24P21800", 60), "entry")
+            .unwrap_err();
+        assert!(e.to_string().contains("max qty"));
+        om.transactions().lock().unwrap().on_order(&json!({
+            "norenordno": norenordno, "exch": "NFO", "tsym": "NIFTY04JAN24C21800", "prd": "M",
+            "trantype": "S", "status": "COMPLETE", "fillshares": "50", "flprc": "100.00",
+            "remarks": client_order_id
+        }));
+        // the exit goes through the cap too and frees what the call held
+        assert_eq!(om.square_off_all().len(), 1);
+        assert!(om.place(sell("NIFTY04JAN24P21800", 100), "entry").is_ok());
+        assert_eq!(
+            broker.calls(),
+            [
+                "place S NIFTY04JAN24C21800 50",
+                "place B NIFTY04JAN24C21800 50",
+                "place S NIFTY04JAN24P21800 100",
+            ]
+        );
+
+        // market orders are valued at their last tick
+        let om = OrderManager::with_broker(auth, WebSocketConfig::default(), broker.clone());
+        om.limit_exposure(ExposureCap::new(None, Some(10000.0)));
+        assert!(om.place(sell("NIFTY04JAN24C21800", 50), "entry").is_err());
+        {
+            let tm = om.transactions();
+            let mut tm = tm.lock().unwrap();
+            tm.add_symbol("NFO|42216", "NIFTY04JAN24C21800");
+            tm.on_tick(&json!({"e": "NFO", "tk": "42216", "lp": "100.00"}).into());
+        }
+        assert!(om.place(sell("NIFTY04JAN24C21800", 50), "entry").is_ok());
+        assert!(om.place(sell("NIFTY04JAN24C21800", 60), "entry").is_err());
+        assert_eq!(broker.calls().len(), 4);
+    }
+
+    #[test]
+    fn test_sequential_entry_through_place() {
+        use common::utils::Exchange;
+
+        let auth = Arc::new(RwLock::new(Auth::new()));
+        let broker = Arc::new(FakeBroker::default());
+        let om = OrderManager::with_broker(auth, WebSocketConfig::default(), broker.clone());
+        om.track("NFO|42216", "NIFTY04JAN24C21800", 100.0);
+        om.track("NFO|42217", "NIFTY04JAN24P21800", 90.0);
+        om.transactions().lock().unwrap().on_tick(
+            &json!({"e": "NFO", "tk": "42217", "lp": "90.00", "bp1": "89.00", "sp1": "91.00"})
+                .into(),
+        );
+        let orders = || {
+            vec![
+                OrderBuilder::new("S", Exchange::NFO, "NIFTY04JAN24C21800", 50),
+                OrderBuilder::new("S", Exchange::NFO, "NIFTY04JAN24P21800", 50),
+            ]
+        };
+        let mode = EntryMode::Sequential {
+            fill_timeout: Duration::from_secs(30),
+        };
+
+        // the put has the wider spread and goes first
+        let placed = om.place_entry(orders(), mode).unwrap();
+        assert_eq!(placed, ["101", "103"]);
+        assert_eq!(
+            broker.calls(),
+            [
+                "place S NIFTY04JAN24P21800 50",
+                "wait 101",
+                "place S NIFTY04JAN24C21800 50",
+            ]
+        );
+        // each leg is stamped like any other order of the instance
+        let tm = om.transactions();
+        let mut tm = tm.lock().unwrap();
+        let client_order_id = tm.make_remarks("1_entry");
+        tm.on_order(&json!({
+            "norenordno": "101", "tsym": "NIFTY04JAN24P21800", "trantype": "S",
+            "status": "OPEN", "remarks": client_order_id
+        }));
+        assert!(tm.get_order_by_client_id(&client_order_id).is_some());
+        drop(tm);
+
+        om.pause();
+        assert!(om.place_entry(orders(), mode).is_err());
+        assert!(om.place_entry(orders(), EntryMode::Simultaneous).is_err());
+        assert_eq!(broker.calls().len(), 3);
+    }
+
+    #[test]
+    fn test_close_position_placed_as_own_order() {
+        let auth = Arc::new(RwLock::new(Auth::new()));
+        let broker = Arc::new(FakeBroker::default());
+        let om = OrderManager::with_broker(auth, WebSocketConfig::default(), broker.clone());
+        *broker.positions.lock().unwrap() = json!([
+            {"exch": "NFO", "tsym": "NIFTY04JAN24C21800", "prd": "M", "netqty": "-50"},
+            {"exch": "NFO", "tsym": "NIFTY04JAN24P21800", "prd": "M", "netqty": "-50"}
+        ]);
+
+        let placed = om
+            .close_position(Exchange::NFO, "NIFTY04JAN24C21800")
+            .unwrap();
+        assert_eq!(broker.calls(), ["place B NIFTY04JAN24C21800 50"]);
+        // stamped with a client order id, so its fill is tracked like any other
+        {
+            let tm = om.transactions();
+            let mut tm = tm.lock().unwrap();
+            let client_order_id = tm.make_remarks("1_close");
+            tm.on_order(&json!({
+                "norenordno": placed[0], "exch": "NFO", "tsym": "NIFTY04JAN24C21800",
+                "prd": "M", "trantype": "B", "status": "COMPLETE", "fillshares": "50",
+                "flprc": "100.00", "remarks": client_order_id
+            }));
+            assert!(tm.get_order_by_client_id(&client_order_id).is_some());
+        }
+
+        om.pause();
+        assert!(om
+            .close_position(Exchange::NFO, "NIFTY04JAN24P21800")
+            .is_err());
+        assert!(om
+            .close_position(Exchange::NFO, "NIFTY04JAN24C22000")
+            .unwrap()
+            .is_empty());
+        assert_eq!(broker.calls().len(), 1);
+    }
+
+    #[test]
+    fn test_itm_leg_carried_past_shutdown() {
+        use crate::runner::{ExitRules, WarmUp};
+        use crate::transaction::MarkPrice;
+
+        let auth = Arc::new(RwLock::new(Auth::new()));
+        let broker = Arc::new(FakeBroker::default());
+        let mut om = OrderManager::with_broker(auth, WebSocketConfig::default(), broker.clone());
+        let mut book = Vec::new();
+        for (norenordno, tsym) in [("1", "NIFTY04JAN24C21800"), ("2", "NIFTY04JAN24P21800")] {
+            let tm = om.transactions();
+            let mut tm = tm.lock().unwrap();
+            let remarks = tm.make_remarks(norenordno);
+            let fill = json!({
+                "norenordno": norenordno, "exch": "NFO", "tsym": tsym, "prd": "I",
+                "trantype": "S", "status": "COMPLETE", "fillshares": "50", "flprc": "100.00",
+                "remarks": remarks
+            });
+            tm.on_order(&fill);
+            book.push(fill);
+        }
+        *broker.order_book.lock().unwrap() = json!(book);
+        *broker.positions.lock().unwrap() = json!([
+            {"exch": "NFO", "tsym": "NIFTY04JAN24C21800", "prd": "I", "netqty": "-50"},
+            {"exch": "NFO", "tsym": "NIFTY04JAN24P21800", "prd": "I", "netqty": "-50"}
+        ]);
+        let runner = StrategyRunner::new(ExitRules {
+            target_mtm: 1000.0,
+            stop_mtm: 1000.0,
+            mark: MarkPrice::Ltp,
+            warmup: WarmUp::UntilFilled,
+        })
+        .carry_if_itm(true);
+
+        // the spot above the call strike puts the short call in the money
+        let carried = om.carry_overnight(&runner, 21900.0).unwrap();
+        assert_eq!(carried, ["NIFTY04JAN24C21800"]);
+        om.shutdown();
+        assert_eq!(
+            broker.calls(),
+            [
+                "carry NIFTY04JAN24C21800",
+                "resync",
+                "place B NIFTY04JAN24P21800 50",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shutdown_cancels_open_orders_first() {
+        let auth = Arc::new(RwLock::new(Auth::new()));
+        let om = OrderManager::new(auth, WebSocketConfig::default());
+        let order = |norenordno: &str, tsym: &str, trantype: &str, status: &str, fill: &str| {
+            let tm = om.transactions();
+            let mut tm = tm.lock().unwrap();
+            let remarks = tm.make_remarks(norenordno);
+            tm.on_order(&json!({
+                "norenordno": norenordno, "exch": "NFO", "tsym": tsym, "prd": "M",
+                "trantype": trantype, "status": status, "fillshares": fill,
+                "flprc": "100.00", "remarks": remarks
+            }));
+        };
+        order("1", "NIFTY04JAN24C21800", "S", "COMPLETE", "50");
+        // the stop-loss of the call and an unfilled limit entry on the put
+        order("2", "NIFTY04JAN24C21800", "B", "TRIGGER_PENDING", "0");
+        order("3", "NIFTY04JAN24P21800", "S", "OPEN", "0");
+        assert_eq!(
+            om.transactions().lock().unwrap().open_orders(),
+            vec!["2", "3"]
+        );
+
+        let calls = std::cell::RefCell::new(Vec::new());
+        let placed = om.shutdown_using(
+            |norenordno| {
+                calls.borrow_mut().push(format!("cancel {}", norenordno));
+                Ok(())
+            },
+            |tm| {
+                let mut tm = tm.lock().unwrap();
+                // half the put entry filled before the cancel reached the exchange
+                for (norenordno, fill) in [("2", "0"), ("3", "25")] {
+                    let remarks = tm.make_remarks(norenordno);
+                    let tsym = if norenordno == "2" {
+                        "C21800"
+                    } else {
+                        "P21800"
+                    };
+                    tm.on_order(&json!({
+                        "norenordno": norenordno, "exch": "NFO",
+                        "tsym": format!("NIFTY04JAN24{}", tsym), "prd": "M",
+                        "trantype": if norenordno == "2" { "B" } else { "S" },
+                        "status": "CANCELED", "fillshares": fill, "flprc": "100.00",
+                        "remarks": remarks
+                    }));
+                }
+                Ok(())
+            },
+            |order| {
+                let payload = order.build_payload(&Auth::new());
+                calls.borrow_mut().push(format!(
+                    "place {} {} {}",
+                    payload["trantype"].as_str().unwrap(),
+                    payload["tsym"].as_str().unwrap(),
+                    order.quantity()
+                ));
+                Ok((10 + calls.borrow().len()).to_string())
+            },
+        );
+
+        assert_eq!(
+            calls.into_inner(),
+            vec![
+                "cancel 2",
+                "cancel 3",
+                "place B NIFTY04JAN24C21800 50",
+                "place B NIFTY04JAN24P21800 25",
+            ]
+        );
+        assert_eq!(placed.len(), 2);
+        assert!(om.transactions().lock().unwrap().open_orders().is_empty());
+    }
+
+    #[test]
+    fn test_target_hit_squares_off_once() {
+        use crate::runner::{ExitRules, WarmUp};
+        use crate::transaction::MarkPrice;
+
+        let mut tm = TransactionManager::new();
+        let remarks = tm.make_remarks("straddle");
+        tm.add_symbol("NFO|42216", "NIFTY04JAN24C21800");
+        tm.on_order(&json!({
+            "norenordno": "1", "exch": "NFO", "tsym": "NIFTY04JAN24C21800", "prd": "M",
+            "trantype": "S", "status": "COMPLETE", "fillshares": "50", "flprc": "100.00",
+            "remarks": remarks
+        }));
+        let runner = StrategyRunner::new(ExitRules {
+            target_mtm: 1000.0,
+            stop_mtm: 1500.0,
+            mark: MarkPrice::Ltp,
+            warmup: WarmUp::UntilFilled,
+        });
+        let mut exit = MtmExit {
+            runner: Some(runner),
+            held: None,
+        };
+        let day_over = AtomicBool::new(false);
+        let mut check =
+            |tm: &TransactionManager| mtm_exit(&mut exit, tm, &day_over, false, Instant::now());
+
+        tm.on_tick(&json!({"e": "NFO", "tk": "42216", "lp": "90.00"}).into());
+        assert_eq!(check(&tm), None);
+
+        // (100 - 80) * 50 reaches the target
+        tm.on_tick(&json!({"e": "NFO", "tk": "42216", "lp": "80.00"}).into());
+        assert_eq!(check(&tm), Some(ExitSignal::Target));
+        assert!(day_over.load(Ordering::SeqCst));
+
+        // the closing order is not filled yet, the next tick must not send it again
+        tm.on_tick(&json!({"e": "NFO", "tk": "42216", "lp": "79.00"}).into());
+        assert_eq!(check(&tm), None);
+    }
+
+    #[test]
+    fn test_mtm_exit_runs_off_the_reader_thread() {
+        use crate::runner::{ExitRules, WarmUp};
+        use crate::transaction::MarkPrice;
+
+        let auth = Arc::new(RwLock::new(Auth::new()));
+        let broker = Arc::new(FakeBroker::default());
+        let (release_tx, release_rx) = mpsc::channel();
+        *broker.place_gate.lock().unwrap() = Some(release_rx);
+        let om = OrderManager::with_broker(auth, WebSocketConfig::default(), broker.clone());
+        let tm = om.transactions();
+        let remarks = tm.lock().unwrap().make_remarks("straddle");
+        tm.lock()
+            .unwrap()
+            .add_symbol("NFO|42216", "NIFTY04JAN24C21800");
+        tm.lock().unwrap().on_order(&json!({
+            "norenordno": "1", "exch": "NFO", "tsym": "NIFTY04JAN24C21800", "prd": "M",
+            "trantype": "S", "status": "COMPLETE", "fillshares": "50", "flprc": "100.00",
+            "remarks": remarks
+        }));
+        om.exit_on_mtm(StrategyRunner::new(ExitRules {
+            target_mtm: 1000.0,
+            stop_mtm: 1500.0,
+            mark: MarkPrice::Ltp,
+            warmup: WarmUp::UntilFilled,
+        }));
+        let mut handler = om.feed_handler();
+
+        // the target is hit while the exit's place is held at the broker
+        handler.subscribe_callback(&json!({"e": "NFO", "tk": "42216", "lp": "80.00"}).into());
+        assert!(om.day_over());
+        assert!(wait_for(
+            || broker.calls() == ["resync", "place B NIFTY04JAN24C21800 50"]
+        ));
+        // the reader thread keeps taking frames meanwhile
+        handler.subscribe_callback(&json!({"e": "NFO", "tk": "42216", "lp": "79.00"}).into());
+        handler.on_order(&json!({
+            "norenordno": "1", "exch": "NFO", "tsym": "NIFTY04JAN24C21800", "prd": "M",
+            "trantype": "S", "status": "COMPLETE", "fillshares": "50", "flprc": "100.00",
+            "remarks": remarks
+        }));
+        assert_eq!(tm.lock().unwrap().get_pnl(MarkPrice::Ltp).0, 1050.0);
+
+        release_tx.send(()).unwrap();
+        handler.subscribe_callback(&json!({"e": "NFO", "tk": "42216", "lp": "78.00"}).into());
+        assert_eq!(broker.calls().len(), 2);
+    }
+
+    #[test]
+    fn test_unsubscribe_drops_only_requested() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let mut frames = Vec::new();
+            while let Ok(message) = socket.read() {
+                if let Message::Text(text) = message {
+                    let frame: serde_json::Value = serde_json::from_str(&text).unwrap();
+                    if frame["t"] == "c" {
+                        socket
+                            .send(Message::Text(json!({"t": "ck", "s": "OK"}).to_string()))
+                            .unwrap();
+                    }
+                    frames.push(frame);
+                }
+            }
+            frames
+        });
+
+        let mut auth = Auth::new();
+        auth.set_session("FA12345", "pwd", "token");
+        let config = WebSocketConfig {
+            endpoint,
+            ..Default::default()
+        };
+        let mut om = OrderManager::new(Arc::new(RwLock::new(auth)), config);
+        om.start().unwrap();
+        om.subscribe(&["NFO|42216", "NFO|42217", "NSE|26000"])
+            .unwrap();
+        om.unsubscribe(&["NFO|42217"]).unwrap();
+
+        let mut remaining: Vec<&String> = om.subscribed_symbols().iter().collect();
+        remaining.sort();
+        assert_eq!(remaining, vec!["NFO|42216", "NSE|26000"]);
+        om.stop();
+
+        let frames = server.join().unwrap();
+        let unsubscribe = frames.iter().find(|frame| frame["t"] == "u").unwrap();
+        assert_eq!(unsubscribe["k"], "NFO|42217");
+    }
+
+    #[test]
+    fn test_reconnect_resyncs_missed_fill() {
+        use crate::transaction::MarkPrice;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("ws://{}/", listener.local_addr().unwrap());
+        let mut auth = Auth::new();
+        auth.set_session("FA12345", "pwd", "token");
+        let broker = Arc::new(FakeBroker::default());
+        let config = WebSocketConfig {
+            endpoint,
+            ..Default::default()
+        };
+        let mut om = OrderManager::with_broker(Arc::new(RwLock::new(auth)), config, broker.clone());
+        let tm = om.transactions();
+        let remarks = tm.lock().unwrap().make_remarks("entry");
+        tm.lock()
+            .unwrap()
+            .add_symbol("NFO|42216", "NIFTY04JAN24C21800");
+        let update = |status: &str, fill: &str| {
+            json!({
+                "t": "om", "norenordno": "1", "exch": "NFO", "tsym": "NIFTY04JAN24C21800",
+                "prd": "M", "trantype": "S", "status": status, "fillshares": fill,
+                "flprc": "100.00", "avgprc": "100.00", "remarks": remarks
+            })
+        };
+        let open = update("OPEN", "0");
+
+        let (drop_tx, drop_rx) = std::sync::mpsc::channel();
+        let server = std::thread::spawn(move || {
+            let mut socket = accept_login(&listener);
+            socket.send(Message::Text(open.to_string())).unwrap();
+            drop_rx.recv().unwrap();
+            drop(socket);
+
+            // the fill went out while disconnected, the new session only ticks
+            let mut socket = accept_login(&listener);
+            let tick = json!({"t": "tk", "e": "NFO", "tk": "42216", "lp": "90.00"});
+            socket.send(Message::Text(tick.to_string())).unwrap();
+            while socket.read().is_ok() {}
+        });
+
+        om.start().unwrap();
+        assert!(wait_for(|| tm
+            .lock()
+            .unwrap()
+            .get_order("1")
+            .is_some_and(|row| row.status == "OPEN")));
+        assert!(broker.calls().is_empty());
+
+        *broker.order_book.lock().unwrap() = json!([
+            update("COMPLETE", "50"),
+            {
+                "stat": "Ok", "norenordno": "2", "tsym": "NIFTY04JAN24P21800",
+                "trantype": "S", "status": "COMPLETE", "fillshares": "50",
+                "avgprc": "90.00", "remarks": "manual"
+            }
+        ]);
+        drop_tx.send(()).unwrap();
+        assert!(wait_for(
+            || tm.lock().unwrap().get_pnl(MarkPrice::Ltp).0 == 500.0
+        ));
+        let row = tm.lock().unwrap().get_order("1").cloned().unwrap();
+        assert_eq!(row.status, "COMPLETE");
+        assert_eq!(row.qty, 50);
+        assert!(tm.lock().unwrap().get_order("2").is_none());
+        assert_eq!(broker.calls(), ["resync"]);
+
+        om.stop();
+        server.join().unwrap();
+    }
+}
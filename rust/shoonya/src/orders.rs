@@ -1,36 +1,1636 @@
-#[allow(dead_code)]
+#![allow(dead_code)]
 
-pub mod orders {
+use crate::urls::{
+    CANCELORDER, HOLDINGS, HOST, MODIFYORDER, ORDERBOOK, PLACEORDER, POSITIONS, PRODUCT_CONVERSION,
+    SINGLEORDERHISTORY, TRADEBOOK,
+};
+use common::utils::{get_exchange_str, round_price, Exchange};
+use serde_json::json;
+use std::time::{Duration, Instant};
 
-    use crate::urls::urls::{HOST, ORDERBOOK};
-    use serde_json::json;
-    
+/// Interval between two order status polls
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
 
-    pub fn get_order_book(
-        auth: &crate::auth::auth::Auth,
-    ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
-        let values = json!({
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderStatus {
+    Pending,
+    Open,
+    TriggerPending,
+    Complete,
+    Rejected,
+    Cancelled,
+    Unknown(String),
+}
+
+impl From<&str> for OrderStatus {
+    fn from(status: &str) -> OrderStatus {
+        match status {
+            "PENDING" => OrderStatus::Pending,
+            "OPEN" => OrderStatus::Open,
+            "TRIGGER_PENDING" => OrderStatus::TriggerPending,
+            "COMPLETE" => OrderStatus::Complete,
+            "REJECTED" => OrderStatus::Rejected,
+            "CANCELED" | "CANCELLED" => OrderStatus::Cancelled,
+            _ => OrderStatus::Unknown(status.to_string()),
+        }
+    }
+}
+
+impl OrderStatus {
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            OrderStatus::Complete | OrderStatus::Rejected | OrderStatus::Cancelled
+        )
+    }
+}
+
+/// Why a PlaceOrder call failed, deciding whether it is safe to send again
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlaceError {
+    /// The request never reached the broker, e.g. connection refused
+    NotPlaced(String),
+    /// The request was sent but no answer came back, the order may exist
+    Unknown(String),
+    /// The broker answered and refused the order
+    Rejected(String),
+}
+
+impl std::fmt::Display for PlaceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PlaceError::NotPlaced(e) => write!(f, "Order not placed: {}", e),
+            PlaceError::Unknown(e) => write!(f, "Order state unknown: {}", e),
+            PlaceError::Rejected(e) => write!(f, "Order rejected: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PlaceError {}
+
+impl From<reqwest::Error> for PlaceError {
+    fn from(e: reqwest::Error) -> PlaceError {
+        // a connect error fails before anything is sent
+        if e.is_connect() || e.is_builder() {
+            PlaceError::NotPlaced(e.to_string())
+        } else {
+            PlaceError::Unknown(e.to_string())
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct OrderBuilder {
+    buy_or_sell: String,
+    product_type: String,
+    exchange: Exchange,
+    tradingsymbol: String,
+    quantity: u32,
+    discloseqty: u32,
+    price_type: String,
+    price: f64,
+    trigger_price: f64,
+    retention: String,
+    amo: String,
+    remarks: String,
+    bookloss_price: f64,
+    bookprofit_price: f64,
+    trail_price: f64,
+    price_precision: u32,
+}
+
+impl OrderBuilder {
+    /// A DAY market order, `buy_or_sell` is "B" or "S"
+    pub fn new(buy_or_sell: &str, exchange: Exchange, tradingsymbol: &str, quantity: u32) -> Self {
+        OrderBuilder {
+            buy_or_sell: buy_or_sell.to_string(),
+            product_type: "M".to_string(),
+            exchange,
+            tradingsymbol: tradingsymbol.to_string(),
+            quantity,
+            discloseqty: 0,
+            price_type: "MKT".to_string(),
+            price: 0.0,
+            trigger_price: 0.0,
+            retention: "DAY".to_string(),
+            amo: "NO".to_string(),
+            remarks: String::new(),
+            bookloss_price: 0.0,
+            bookprofit_price: 0.0,
+            trail_price: 0.0,
+            price_precision: 2,
+        }
+    }
+
+    pub fn product_type(mut self, product_type: &str) -> Self {
+        self.product_type = product_type.to_string();
+        self
+    }
+
+    pub fn discloseqty(mut self, discloseqty: u32) -> Self {
+        self.discloseqty = discloseqty;
+        self
+    }
+
+    pub fn price_type(mut self, price_type: &str) -> Self {
+        self.price_type = price_type.to_string();
+        self
+    }
+
+    pub fn price(mut self, price: f64) -> Self {
+        self.price = price;
+        self
+    }
+
+    pub fn trigger_price(mut self, trigger_price: f64) -> Self {
+        self.trigger_price = trigger_price;
+        self
+    }
+
+    pub fn retention(mut self, retention: &str) -> Self {
+        self.retention = retention.to_string();
+        self
+    }
+
+    pub fn amo(mut self, amo: &str) -> Self {
+        self.amo = amo.to_string();
+        self
+    }
+
+    /// Sends the order as an after market order when `amo` is set, to queue for
+    /// the open. The exchange only takes AMOs outside the session.
+    pub fn after_market(
+        self,
+        amo: bool,
+        market_open: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if !amo {
+            return Ok(self);
+        }
+        if market_open {
+            return Err(format!(
+                "{}: AMO is only accepted outside market hours",
+                self.tradingsymbol
+            )
+            .into());
+        }
+        Ok(self.amo("YES"))
+    }
+
+    pub fn remarks(mut self, remarks: &str) -> Self {
+        self.remarks = remarks.to_string();
+        self
+    }
+
+    pub fn bookloss_price(mut self, bookloss_price: f64) -> Self {
+        self.bookloss_price = bookloss_price;
+        self
+    }
+
+    pub fn bookprofit_price(mut self, bookprofit_price: f64) -> Self {
+        self.bookprofit_price = bookprofit_price;
+        self
+    }
+
+    pub fn trail_price(mut self, trail_price: f64) -> Self {
+        self.trail_price = trail_price;
+        self
+    }
+
+    /// Decimals the exchange takes for this contract, `pp` from GetSecurityInfo.
+    /// Every price sent is rounded to it.
+    pub fn price_precision(mut self, price_precision: u32) -> Self {
+        self.price_precision = price_precision;
+        self
+    }
+
+    // A price as the exchange takes it, without float noise like 101.45000000000002
+    fn price_str(&self, price: f64) -> String {
+        round_price(price, self.price_precision).to_string()
+    }
+
+    pub fn quantity(&self) -> u32 {
+        self.quantity
+    }
+
+    /// "B" or "S"
+    pub fn buy_or_sell(&self) -> &str {
+        &self.buy_or_sell
+    }
+
+    pub fn tradingsymbol(&self) -> &str {
+        &self.tradingsymbol
+    }
+
+    /// The price set on the order, None for a market order
+    pub fn limit_price(&self) -> Option<f64> {
+        Some(self.price).filter(|price| *price > 0.0)
+    }
+
+    /// The remarks, which carry the client order id and come back in the order
+    /// updates, the order book and the trade book
+    pub fn client_order_id(&self) -> &str {
+        &self.remarks
+    }
+
+    /// The jData PlaceOrder is sent, without the session token. Needs no network,
+    /// to inspect an order in tests or log it when the broker rejects it.
+    pub fn build_payload(&self, auth: &crate::auth::Auth) -> serde_json::Value {
+        let mut values = json!({
             "ordersource": "API",
             "uid": auth.username,
+            "actid": auth.accountid,
+            "trantype": self.buy_or_sell,
+            "prd": self.product_type,
+            "exch": get_exchange_str(&self.exchange),
+            "tsym": self.tradingsymbol,
+            "qty": self.quantity.to_string(),
+            "dscqty": self.discloseqty.to_string(),
+            "prctyp": self.price_type,
+            "prc": self.price_str(self.price),
+            "trgprc": self.price_str(self.trigger_price),
+            "ret": self.retention,
+            "remarks": self.remarks,
+            "amo": self.amo,
         });
+        // cover order
+        if self.product_type == "H" {
+            values["blprc"] = json!(self.price_str(self.bookloss_price));
+            if self.trail_price != 0.0 {
+                values["trailprc"] = json!(self.price_str(self.trail_price));
+            }
+        }
+        // bracket order
+        if self.product_type == "B" {
+            values["blprc"] = json!(self.price_str(self.bookloss_price));
+            values["bpprc"] = json!(self.price_str(self.bookprofit_price));
+            if self.trail_price != 0.0 {
+                values["trailprc"] = json!(self.price_str(self.trail_price));
+            }
+        }
+        values
+    }
 
-        let url = format!("{}{}", HOST, ORDERBOOK);
-        let payload = format!("jData={}&jKey={}", values.to_string(), auth.susertoken);
+    fn modify_payload(&self, auth: &crate::auth::Auth, norenordno: &str) -> serde_json::Value {
+        json!({
+            "ordersource": "API",
+            "uid": auth.username,
+            "norenordno": norenordno,
+            "exch": get_exchange_str(&self.exchange),
+            "tsym": self.tradingsymbol,
+            "qty": self.quantity.to_string(),
+            "prctyp": self.price_type,
+            "prc": self.price_str(self.price),
+            "trgprc": self.price_str(self.trigger_price),
+        })
+    }
+
+    /// Changes the open order `norenordno` to this order's quantity, price type,
+    /// price and trigger price, e.g. to trail a stop-loss without cancelling it
+    pub fn modify(
+        &self,
+        auth: &crate::auth::Auth,
+        norenordno: &str,
+    ) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        let url = format!("{}{}", HOST, MODIFYORDER);
+        let payload = format!(
+            "jData={}&jKey={}",
+            self.modify_payload(auth, norenordno),
+            auth.susertoken
+        );
         let client = reqwest::blocking::Client::new();
-        let res: String = client
-            .post(&url)
-            .body(payload)
-            .send()
-            .unwrap()
-            .text()
-            .unwrap();
+        let res: String = client.post(&url).body(payload).send()?.text()?;
 
         let res_dict: serde_json::Value = serde_json::from_str(&res)?;
-
+        log::debug!("Modify order response: {}", res_dict);
         if res_dict["stat"] != "Ok" {
             return Err(res_dict.to_string().into());
         }
 
         Ok(res_dict)
     }
+
+    /// A limit order needs its price and a stop-loss its trigger, the exchange
+    /// rejects them at zero
+    pub fn check_prices(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let needs_price = matches!(self.price_type.as_str(), "LMT" | "SL-LMT");
+        let needs_trigger = matches!(self.price_type.as_str(), "SL-LMT" | "SL-MKT");
+        if needs_price && self.price <= 0.0 {
+            return Err(format!(
+                "{}: {} order without a price",
+                self.tradingsymbol, self.price_type
+            )
+            .into());
+        }
+        if needs_trigger && self.trigger_price <= 0.0 {
+            return Err(format!(
+                "{}: {} order without a trigger price",
+                self.tradingsymbol, self.price_type
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// A bracket order needs both exits, the stop-loss below a buy and the target
+    /// above it, the other way round for a sell. Without a limit price there is no
+    /// entry to compare them with, only their presence is checked then.
+    pub fn check_bracket(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.product_type != "B" {
+            return Ok(());
+        }
+        let tsym = &self.tradingsymbol;
+        if self.bookloss_price <= 0.0 {
+            return Err(format!("{}: bracket order without a book loss price", tsym).into());
+        }
+        if self.bookprofit_price <= 0.0 {
+            return Err(format!("{}: bracket order without a book profit price", tsym).into());
+        }
+        if self.trail_price < 0.0 {
+            return Err(format!("{}: negative trail price {}", tsym, self.trail_price).into());
+        }
+        if self.price <= 0.0 {
+            return Ok(());
+        }
+        let (loss, profit, entry) = (self.bookloss_price, self.bookprofit_price, self.price);
+        let sensible = match self.buy_or_sell.as_str() {
+            "B" => loss < entry && entry < profit,
+            "S" => profit < entry && entry < loss,
+            side => return Err(format!("{}: unknown side {}", tsym, side).into()),
+        };
+        if !sensible {
+            return Err(format!(
+                "{}: {} bracket at {} needs the loss at {} and the profit at {} on either side",
+                tsym, self.buy_or_sell, entry, loss, profit
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Places the order and returns its norenordno
+    pub fn place(&self, auth: &crate::auth::Auth) -> Result<String, Box<dyn std::error::Error>> {
+        auth.check_exchange(&self.exchange)?;
+        self.check_prices()?;
+        self.check_bracket()?;
+        Ok(self.send(auth)?)
+    }
+
+    // One PlaceOrder call, with the failure classified
+    fn send(&self, auth: &crate::auth::Auth) -> Result<String, PlaceError> {
+        let url = format!("{}{}", HOST, PLACEORDER);
+        let values = self.build_payload(auth);
+        let payload = format!("jData={}&jKey={}", values, auth.susertoken);
+        let client = reqwest::blocking::Client::new();
+        let res: String = client.post(&url).body(payload).send()?.text()?;
+
+        let res_dict: serde_json::Value = serde_json::from_str(&res)
+            .map_err(|e| PlaceError::Unknown(format!("{}: {}", e, res)))?;
+        log::debug!("Place order response: {}", res_dict);
+        if res_dict["stat"] != "Ok" {
+            log::warn!("Rejected order: {}", values);
+            return Err(PlaceError::Rejected(res_dict.to_string()));
+        }
+
+        Ok(res_dict["norenordno"].as_str().unwrap_or("").to_string())
+    }
+
+    /// Places the order, retrying up to `max_attempts` without ever doubling it.
+    /// A failure before sending is retried. When the outcome is unknown, the order
+    /// book is searched for the order's remarks first, so remarks must be unique.
+    pub fn place_with_retry(
+        &self,
+        auth: &crate::auth::Auth,
+        max_attempts: u32,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        auth.check_exchange(&self.exchange)?;
+        self.check_prices()?;
+        self.check_bracket()?;
+        if self.remarks.is_empty() {
+            return Err("Retrying an order needs remarks to find it in the order book".into());
+        }
+        place_with_retry_using(
+            || self.send(auth),
+            || Ok(find_order_by_remarks(&get_order_book(auth)?, &self.remarks)),
+            max_attempts,
+        )
+    }
+}
+
+// Retry decision of place_with_retry, `find` looks the order up by its remarks
+fn place_with_retry_using<P, F>(
+    mut place: P,
+    mut find: F,
+    max_attempts: u32,
+) -> Result<String, Box<dyn std::error::Error>>
+where
+    P: FnMut() -> Result<String, PlaceError>,
+    F: FnMut() -> Result<Option<String>, Box<dyn std::error::Error>>,
+{
+    let mut attempt = 1;
+    loop {
+        let e = match place() {
+            Ok(norenordno) => return Ok(norenordno),
+            Err(PlaceError::Rejected(e)) => return Err(PlaceError::Rejected(e).into()),
+            Err(PlaceError::Unknown(e)) => {
+                // the order may have gone through, it must not be sent twice
+                if let Some(norenordno) = find()? {
+                    log::warn!("Order placed despite {}: {}", e, norenordno);
+                    return Ok(norenordno);
+                }
+                PlaceError::Unknown(e)
+            }
+            Err(e) => e,
+        };
+        if attempt >= max_attempts {
+            return Err(e.into());
+        }
+        log::warn!("{}, retrying ({}/{})", e, attempt, max_attempts);
+        attempt += 1;
+    }
+}
+
+/// Places every order of the basket at once, one thread each. Returns the
+/// norenordno or the error of each order, in the order given: a rejected leg
+/// does not stop the others.
+pub fn place_basket(
+    auth: &crate::auth::Auth,
+    orders: Vec<OrderBuilder>,
+) -> Vec<Result<String, Box<dyn std::error::Error>>> {
+    place_basket_using(orders, |order| order.place(auth))
+}
+
+// Concurrency of place_basket, `send` places one order
+fn place_basket_using<F>(
+    orders: Vec<OrderBuilder>,
+    send: F,
+) -> Vec<Result<String, Box<dyn std::error::Error>>>
+where
+    F: Fn(&OrderBuilder) -> Result<String, Box<dyn std::error::Error>> + Sync,
+{
+    let send = &send;
+    std::thread::scope(|scope| {
+        let legs: Vec<_> = orders
+            .iter()
+            .map(|order| scope.spawn(move || send(order).map_err(|e| e.to_string())))
+            .collect();
+        legs.into_iter()
+            .map(|leg| match leg.join() {
+                Ok(result) => result.map_err(|e| e.into()),
+                Err(_) => Err("Order thread panicked".into()),
+            })
+            .collect()
+    })
+}
+
+/// Cancels the open order `norenordno`
+pub fn cancel_order(
+    auth: &crate::auth::Auth,
+    norenordno: &str,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let values = json!({
+        "ordersource": "API",
+        "uid": auth.username,
+        "norenordno": norenordno,
+    });
+
+    let url = format!("{}{}", HOST, CANCELORDER);
+    let payload = format!("jData={}&jKey={}", values, auth.susertoken);
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
+
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+    log::debug!("Cancel order response: {}", res_dict);
+    if res_dict["stat"] != "Ok" {
+        return Err(res_dict.to_string().into());
+    }
+
+    Ok(res_dict)
+}
+
+/// norenordno of the order carrying `remarks`, if the order book has it
+pub fn find_order_by_remarks(order_book: &serde_json::Value, remarks: &str) -> Option<String> {
+    order_book
+        .as_array()?
+        .iter()
+        .find(|order| order["remarks"].as_str() == Some(remarks))
+        .and_then(|order| order["norenordno"].as_str())
+        .map(|norenordno| norenordno.to_string())
+}
+
+/// Hard limit on the total quantity and notional the strategy holds open. Orders
+/// are `record`ed as they open exposure and `release`d as they close it. Set it
+/// with `OrderManager::limit_exposure`, which holds every order sent to it.
+#[derive(Debug, Clone, Default)]
+pub struct ExposureCap {
+    max_qty: Option<u32>,
+    max_notional: Option<f64>,
+    open_qty: u32,
+    open_notional: f64,
+}
+
+impl ExposureCap {
+    pub fn new(max_qty: Option<u32>, max_notional: Option<f64>) -> Self {
+        ExposureCap {
+            max_qty,
+            max_notional,
+            open_qty: 0,
+            open_notional: 0.0,
+        }
+    }
+
+    pub fn check(&self, qty: u32, price: f64) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(max_qty) = self.max_qty {
+            if self.open_qty + qty > max_qty {
+                return Err(format!(
+                    "Order of {} would breach max qty {} (open {})",
+                    qty, max_qty, self.open_qty
+                )
+                .into());
+            }
+        }
+        if let Some(max_notional) = self.max_notional {
+            let notional = qty as f64 * price;
+            if self.open_notional + notional > max_notional {
+                return Err(format!(
+                    "Order notional {:.2} would breach max notional {:.2} (open {:.2})",
+                    notional, max_notional, self.open_notional
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn record(&mut self, qty: u32, price: f64) {
+        self.open_qty += qty;
+        self.open_notional += qty as f64 * price;
+    }
+
+    /// Takes `qty` closed off the open exposure, at its average price
+    pub fn release(&mut self, qty: u32) {
+        let qty = qty.min(self.open_qty);
+        if qty > 0 {
+            self.open_notional -= self.open_notional * qty as f64 / self.open_qty as f64;
+            self.open_qty -= qty;
+        }
+    }
+
+    /// Whether orders need a price to be checked, see `check`
+    pub fn limits_notional(&self) -> bool {
+        self.max_notional.is_some()
+    }
+}
+
+pub fn get_order_book(
+    auth: &crate::auth::Auth,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let values = json!({
+        "ordersource": "API",
+        "uid": auth.username,
+    });
+
+    let url = format!("{}{}", HOST, ORDERBOOK);
+    let payload = format!("jData={}&jKey={}", values, auth.susertoken);
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
+
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+
+    // the order book is an array of orders, an error is an object
+    if !res_dict.is_array() && res_dict["stat"] != "Ok" {
+        return Err(res_dict.to_string().into());
+    }
+
+    Ok(res_dict)
+}
+
+/// A TradeBook row, one fill of an order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trade {
+    pub norenordno: String,
+    pub exch: String,
+    pub tsym: String,
+    /// "B" or "S"
+    pub trantype: String,
+    /// Filled quantity of this fill, not of the whole order
+    pub qty: i64,
+    /// Fill price
+    pub flprc: f64,
+    /// Fill time as "dd-mm-yyyy HH:MM:SS"
+    pub fltm: String,
+    /// Broker time of the row as "HH:MM:SS dd-mm-yyyy"
+    pub norentm: String,
+    /// The remarks of the order, its client order id when placed by this instance
+    pub remarks: String,
+}
+
+/// A Noren timestamp, exchange time (IST). The trade book's `fltm` is date first
+/// and the `norentm` of the books time first, both are accepted.
+pub fn parse_noren_time(time: &str) -> Option<chrono::NaiveDateTime> {
+    chrono::NaiveDateTime::parse_from_str(time, "%d-%m-%Y %H:%M:%S")
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(time, "%H:%M:%S %d-%m-%Y"))
+        .ok()
+}
+
+impl Trade {
+    /// When the fill happened, from `fltm` or else `norentm`
+    pub fn fill_time(&self) -> Option<chrono::NaiveDateTime> {
+        parse_noren_time(&self.fltm).or_else(|| parse_noren_time(&self.norentm))
+    }
+}
+
+/// The fills oldest first, to replay them in the order they were executed. A fill
+/// without a readable time goes last.
+pub fn sorted_fills(mut trades: Vec<Trade>) -> Vec<Trade> {
+    trades.sort_by_key(|trade| {
+        let fill_time = trade.fill_time();
+        (fill_time.is_none(), fill_time)
+    });
+    trades
+}
+
+/// Parses the TradeBook rows, an object (no fills or an error) gives none
+pub fn parse_trade_book(trade_book: &serde_json::Value) -> Vec<Trade> {
+    let trades = match trade_book.as_array() {
+        Some(trades) => trades,
+        None => return Vec::new(),
+    };
+    let text =
+        |trade: &serde_json::Value, name: &str| trade[name].as_str().unwrap_or("").to_string();
+    let number = |trade: &serde_json::Value, name: &str| {
+        crate::transaction::parse_f64(&trade[name]).unwrap_or(0.0)
+    };
+    trades
+        .iter()
+        .map(|trade| Trade {
+            norenordno: text(trade, "norenordno"),
+            exch: text(trade, "exch"),
+            tsym: text(trade, "tsym"),
+            trantype: text(trade, "trantype"),
+            qty: number(trade, "flqty") as i64,
+            flprc: number(trade, "flprc"),
+            fltm: text(trade, "fltm"),
+            norentm: text(trade, "norentm"),
+            remarks: text(trade, "remarks"),
+        })
+        .collect()
+}
+
+/// The fills of the day, the broker's record of what was executed
+pub fn get_trade_book(auth: &crate::auth::Auth) -> Result<Vec<Trade>, Box<dyn std::error::Error>> {
+    let values = json!({
+        "ordersource": "API",
+        "uid": auth.username,
+        "actid": auth.accountid,
+    });
+
+    let url = format!("{}{}", HOST, TRADEBOOK);
+    let payload = format!("jData={}&jKey={}", values, auth.susertoken);
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
+
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+
+    // like the order book, the trade book is an array of fills, an object when
+    // there are none or on error
+    if !res_dict.is_array() && res_dict["stat"] != "Ok" {
+        if res_dict["emsg"]
+            .as_str()
+            .is_some_and(|emsg| emsg.contains("no data"))
+        {
+            return Ok(Vec::new());
+        }
+        return Err(res_dict.to_string().into());
+    }
+
+    Ok(parse_trade_book(&res_dict))
+}
+
+/// A PositionBook row, quantities are signed and negative when short
+#[derive(Debug, Clone, PartialEq)]
+pub struct Position {
+    pub exch: String,
+    pub tsym: String,
+    pub prd: String,
+    pub netqty: i64,
+    pub daybuyqty: i64,
+    pub daysellqty: i64,
+    /// Realized PnL
+    pub rpnl: f64,
+    /// Unrealized mark-to-market
+    pub urmtom: f64,
+    /// Net average price
+    pub avgprc: f64,
+    pub lp: f64,
+}
+
+impl Position {
+    /// Realized plus unrealized PnL
+    pub fn pnl(&self) -> f64 {
+        self.rpnl + self.urmtom
+    }
+}
+
+/// Parses the PositionBook rows, an object (no positions or an error) gives none
+pub fn parse_positions(positions: &serde_json::Value) -> Vec<Position> {
+    let positions = match positions.as_array() {
+        Some(positions) => positions,
+        None => return Vec::new(),
+    };
+    let number = |position: &serde_json::Value, name: &str| {
+        crate::transaction::parse_f64(&position[name]).unwrap_or(0.0)
+    };
+    let text = |position: &serde_json::Value, name: &str| {
+        position[name].as_str().unwrap_or("").to_string()
+    };
+    positions
+        .iter()
+        .map(|position| Position {
+            exch: text(position, "exch"),
+            tsym: text(position, "tsym"),
+            prd: text(position, "prd"),
+            netqty: number(position, "netqty") as i64,
+            daybuyqty: number(position, "daybuyqty") as i64,
+            daysellqty: number(position, "daysellqty") as i64,
+            rpnl: number(position, "rpnl"),
+            urmtom: number(position, "urmtom"),
+            avgprc: number(position, "netavgprc"),
+            lp: number(position, "lp"),
+        })
+        .collect()
+}
+
+/// The open and closed positions of the day, the broker's own view of the exposure
+pub fn get_positions(
+    auth: &crate::auth::Auth,
+) -> Result<Vec<Position>, Box<dyn std::error::Error>> {
+    let values = json!({
+        "ordersource": "API",
+        "uid": auth.username,
+        "actid": auth.accountid,
+    });
+
+    let url = format!("{}{}", HOST, POSITIONS);
+    let payload = format!("jData={}&jKey={}", values, auth.susertoken);
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
+
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+
+    // an array of positions, an object when there are none or on error
+    if !res_dict.is_array() && res_dict["stat"] != "Ok" {
+        if res_dict["emsg"]
+            .as_str()
+            .is_some_and(|emsg| emsg.contains("no data"))
+        {
+            return Ok(Vec::new());
+        }
+        return Err(res_dict.to_string().into());
+    }
+
+    Ok(parse_positions(&res_dict))
+}
+
+/// A scrip a holding is listed as, holdings carry one per exchange
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExchTsym {
+    pub exch: String,
+    pub tsym: String,
+    pub token: String,
+}
+
+/// A Holdings row, positions carried overnight
+#[derive(Debug, Clone, PartialEq)]
+pub struct Holding {
+    pub exch_tsym: Vec<ExchTsym>,
+    pub holdqty: i64,
+    /// Quantity not yet pledged to the depository (non-POA)
+    pub npoadqty: i64,
+    /// Average upload price
+    pub upldprc: f64,
+}
+
+/// Parses the Holdings rows, an object (no holdings or an error) gives none
+pub fn parse_holdings(holdings: &serde_json::Value) -> Vec<Holding> {
+    let holdings = match holdings.as_array() {
+        Some(holdings) => holdings,
+        None => return Vec::new(),
+    };
+    let text =
+        |value: &serde_json::Value, name: &str| value[name].as_str().unwrap_or("").to_string();
+    let number =
+        |value: &serde_json::Value, name: &str| crate::transaction::parse_f64(&value[name]);
+    holdings
+        .iter()
+        .map(|holding| Holding {
+            exch_tsym: holding["exch_tsym"]
+                .as_array()
+                .map(|scrips| {
+                    scrips
+                        .iter()
+                        .map(|scrip| ExchTsym {
+                            exch: text(scrip, "exch"),
+                            tsym: text(scrip, "tsym"),
+                            token: text(scrip, "token"),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            holdqty: number(holding, "holdqty").unwrap_or(0.0) as i64,
+            npoadqty: number(holding, "npoadqty").unwrap_or(0.0) as i64,
+            upldprc: number(holding, "upldprc").unwrap_or(0.0),
+        })
+        .collect()
+}
+
+/// Holdings of the account for `product_type`, e.g. "C" for delivery
+pub fn get_holdings(
+    auth: &crate::auth::Auth,
+    product_type: &str,
+) -> Result<Vec<Holding>, Box<dyn std::error::Error>> {
+    let values = json!({
+        "uid": auth.username,
+        "actid": auth.accountid,
+        "prd": product_type,
+    });
+
+    let url = format!("{}{}", HOST, HOLDINGS);
+    let payload = format!("jData={}&jKey={}", values, auth.susertoken);
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
+
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+
+    // like the positions, an object means no holdings or an error
+    if !res_dict.is_array() && res_dict["stat"] != "Ok" {
+        if res_dict["emsg"]
+            .as_str()
+            .is_some_and(|emsg| emsg.contains("no data"))
+        {
+            return Ok(Vec::new());
+        }
+        return Err(res_dict.to_string().into());
+    }
+
+    Ok(parse_holdings(&res_dict))
+}
+
+/// Whether a position was opened today or carried in from an earlier session, the
+/// `postype` of a product conversion
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PositionType {
+    Day,
+    CarryForward,
+}
+
+impl PositionType {
+    /// The value ProductConversion takes
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PositionType::Day => "Day",
+            PositionType::CarryForward => "CF",
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn convert_product_values(
+    auth: &crate::auth::Auth,
+    tsym: &str,
+    exchange: &Exchange,
+    qty: u32,
+    prev_prd: &str,
+    new_prd: &str,
+    trantype: &str,
+    pos_type: PositionType,
+) -> serde_json::Value {
+    json!({
+        "ordersource": "API",
+        "uid": auth.username,
+        "actid": auth.accountid,
+        "exch": get_exchange_str(exchange),
+        "tsym": tsym,
+        "qty": qty.to_string(),
+        "prd": new_prd,
+        "prevprd": prev_prd,
+        "trantype": trantype,
+        "postype": pos_type.as_str(),
+    })
+}
+
+/// Converts an open position between products, e.g. an intraday "I" leg to "M"
+/// (NRML) or "C" (delivery) to carry it overnight without closing and reopening it.
+/// `trantype` is the side of the position, "B" or "S".
+#[allow(clippy::too_many_arguments)]
+pub fn convert_product(
+    auth: &crate::auth::Auth,
+    tsym: &str,
+    exchange: &Exchange,
+    qty: u32,
+    prev_prd: &str,
+    new_prd: &str,
+    trantype: &str,
+    pos_type: PositionType,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let values = convert_product_values(
+        auth, tsym, exchange, qty, prev_prd, new_prd, trantype, pos_type,
+    );
+
+    let url = format!("{}{}", HOST, PRODUCT_CONVERSION);
+    let payload = format!("jData={}&jKey={}", values, auth.susertoken);
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
+
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+    if res_dict["stat"] != "Ok" {
+        return Err(res_dict.to_string().into());
+    }
+
+    Ok(res_dict)
+}
+
+/// Market orders offsetting the net quantity of `tradingsymbol`, one per product.
+/// Empty when the symbol is already flat; change the price type for a limit exit.
+/// `OrderManager::close_position` places them.
+pub fn closing_orders(
+    positions: &[Position],
+    exchange: Exchange,
+    tradingsymbol: &str,
+) -> Vec<OrderBuilder> {
+    positions
+        .iter()
+        .filter(|position| {
+            position.exch == get_exchange_str(&exchange) && position.tsym == tradingsymbol
+        })
+        .filter_map(|position| {
+            let netqty = position.netqty;
+            if netqty == 0 {
+                return None;
+            }
+            let buy_or_sell = if netqty > 0 { "S" } else { "B" };
+            let product_type = if position.prd.is_empty() {
+                "M"
+            } else {
+                position.prd.as_str()
+            };
+            Some(
+                OrderBuilder::new(
+                    buy_or_sell,
+                    exchange,
+                    tradingsymbol,
+                    netqty.unsigned_abs() as u32,
+                )
+                .product_type(product_type),
+            )
+        })
+        .collect()
+}
+
+/// An event of an order's SingleOrdHist
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderEvent {
+    pub status: OrderStatus,
+    /// Report type, e.g. "NewAck", "Fill" or "Rejected"
+    pub rpt: String,
+    /// Broker time of the event
+    pub norentm: Option<chrono::NaiveDateTime>,
+    pub qty: i64,
+    pub fillshares: i64,
+    pub prc: f64,
+    pub avgprc: f64,
+    /// Why the broker refused the order, e.g. margin, price band or freeze quantity
+    pub rejreason: Option<String>,
+}
+
+/// Parses a SingleOrdHist reply into the order's events, oldest first. An order
+/// the broker has no history for yet has none.
+pub fn parse_order_history(
+    history: &serde_json::Value,
+) -> Result<Vec<OrderEvent>, Box<dyn std::error::Error>> {
+    let no_data = history["emsg"]
+        .as_str()
+        .is_some_and(|emsg| emsg.contains("no data"));
+    let events = match history.as_array() {
+        Some(events) if events.iter().all(|event| event["stat"] == "Ok") => events,
+        None if no_data => return Ok(Vec::new()),
+        _ => return Err(history.to_string().into()),
+    };
+    let text =
+        |event: &serde_json::Value, name: &str| event[name].as_str().unwrap_or("").to_string();
+    let number = |event: &serde_json::Value, name: &str| {
+        crate::transaction::parse_f64(&event[name]).unwrap_or(0.0)
+    };
+    // the newest event comes first
+    Ok(events
+        .iter()
+        .rev()
+        .map(|event| OrderEvent {
+            status: OrderStatus::from(event["status"].as_str().unwrap_or("")),
+            rpt: text(event, "rpt"),
+            norentm: event["norentm"].as_str().and_then(parse_noren_time),
+            qty: number(event, "qty") as i64,
+            fillshares: number(event, "fillshares") as i64,
+            prc: number(event, "prc"),
+            avgprc: number(event, "avgprc"),
+            rejreason: event["rejreason"]
+                .as_str()
+                .filter(|rejreason| !rejreason.is_empty())
+                .map(|rejreason| rejreason.to_string()),
+        })
+        .collect())
+}
+
+/// The lifecycle of an order, e.g. PENDING, OPEN then COMPLETE or REJECTED
+pub fn get_order_history(
+    auth: &crate::auth::Auth,
+    norenordno: &str,
+) -> Result<Vec<OrderEvent>, Box<dyn std::error::Error>> {
+    let values = json!({
+        "ordersource": "API",
+        "uid": auth.username,
+        "norenordno": norenordno,
+    });
+
+    let url = format!("{}{}", HOST, SINGLEORDERHISTORY);
+    let payload = format!("jData={}&jKey={}", values, auth.susertoken);
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
+
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+    parse_order_history(&res_dict)
+}
+
+/// Status of `norenordno` in the order book, if the book has it
+pub fn order_book_status(order_book: &serde_json::Value, norenordno: &str) -> Option<OrderStatus> {
+    order_book
+        .as_array()?
+        .iter()
+        .find(|order| order["norenordno"].as_str() == Some(norenordno))
+        .and_then(|order| order["status"].as_str())
+        .map(OrderStatus::from)
+}
+
+// Latest status of an order from its history, else from the order book. An order
+// placed a moment ago may be in neither yet, it counts as pending then.
+fn get_order_status(
+    auth: &crate::auth::Auth,
+    norenordno: &str,
+) -> Result<OrderStatus, Box<dyn std::error::Error>> {
+    order_status_using(
+        norenordno,
+        || get_order_history(auth, norenordno),
+        || get_order_book(auth),
+    )
+}
+
+// The lookups of get_order_status, `history` fetches the order's events and
+// `order_book` the order book
+fn order_status_using<H, B>(
+    norenordno: &str,
+    history: H,
+    order_book: B,
+) -> Result<OrderStatus, Box<dyn std::error::Error>>
+where
+    H: FnOnce() -> Result<Vec<OrderEvent>, Box<dyn std::error::Error>>,
+    B: FnOnce() -> Result<serde_json::Value, Box<dyn std::error::Error>>,
+{
+    if let Some(event) = history()?.pop() {
+        return Ok(event.status);
+    }
+    // an empty order book comes back as an error
+    let status = order_book()
+        .ok()
+        .and_then(|order_book| order_book_status(&order_book, norenordno));
+    Ok(status.unwrap_or(OrderStatus::Pending))
+}
+
+// Polls `fetch` until it reports a terminal status or `timeout` elapses
+fn poll_until_terminal<F>(
+    mut fetch: F,
+    timeout: Duration,
+    interval: Duration,
+) -> Result<OrderStatus, Box<dyn std::error::Error>>
+where
+    F: FnMut() -> Result<OrderStatus, Box<dyn std::error::Error>>,
+{
+    let start = Instant::now();
+    loop {
+        let status = fetch()?;
+        if status.is_terminal() {
+            return Ok(status);
+        }
+        if start.elapsed() + interval > timeout {
+            return Err(format!("Timed out in {:?} with status {:?}", timeout, status).into());
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+/// Blocks until the order is COMPLETE, REJECTED or CANCELLED, or `timeout` elapses
+pub fn wait_for_fill(
+    auth: &crate::auth::Auth,
+    norenordno: &str,
+    timeout: Duration,
+) -> Result<OrderStatus, Box<dyn std::error::Error>> {
+    poll_until_terminal(
+        || get_order_status(auth, norenordno),
+        timeout,
+        POLL_INTERVAL,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exposure_cap() {
+        let mut cap = ExposureCap::new(Some(100), Some(10000.0));
+        assert!(cap.check(50, 100.0).is_ok());
+        cap.record(50, 100.0);
+        // within qty but breaching notional
+        assert!(cap.check(50, 150.0).is_err());
+        // breaching qty
+        assert!(cap.check(60, 10.0).is_err());
+        assert!(cap.check(50, 100.0).is_ok());
+
+        // closing half the open legs frees their share of the cap
+        cap.release(25);
+        assert!(cap.check(75, 10.0).is_ok());
+        assert!(cap.check(50, 149.0).is_ok());
+        cap.release(100);
+        assert!(cap.check(100, 100.0).is_ok());
+
+        let unlimited = ExposureCap::new(None, None);
+        assert!(unlimited.check(100000, 1000.0).is_ok());
+    }
+
+    #[test]
+    fn test_fill_after_two_polls() {
+        let mut polls = 0;
+        let status = poll_until_terminal(
+            || {
+                polls += 1;
+                match polls {
+                    1 => Ok(OrderStatus::Pending),
+                    2 => Ok(OrderStatus::Open),
+                    _ => Ok(OrderStatus::Complete),
+                }
+            },
+            Duration::from_secs(1),
+            Duration::from_millis(1),
+        )
+        .unwrap();
+        assert_eq!(status, OrderStatus::Complete);
+        assert_eq!(polls, 3);
+    }
+
+    #[test]
+    fn test_wait_times_out() {
+        let status = poll_until_terminal(
+            || Ok(OrderStatus::Open),
+            Duration::from_millis(5),
+            Duration::from_millis(1),
+        );
+        assert!(status.is_err());
+    }
+
+    #[test]
+    fn test_status_from_order_book() {
+        let order_book = json!([
+            {"norenordno": "24010400001", "status": "OPEN"},
+            {"norenordno": "24010400002", "status": "COMPLETE"}
+        ]);
+        assert_eq!(
+            order_book_status(&order_book, "24010400002"),
+            Some(OrderStatus::Complete)
+        );
+        assert_eq!(order_book_status(&order_book, "24010400003"), None);
+    }
+
+    #[test]
+    fn test_basket_reports_each_leg() {
+        let orders = vec![
+            OrderBuilder::new("S", Exchange::NFO, "NIFTY04JAN24C21650", 50),
+            OrderBuilder::new("S", Exchange::NFO, "NIFTY04JAN24P21650", 50),
+            OrderBuilder::new("B", Exchange::NFO, "NIFTY04JAN24C21850", 50),
+            OrderBuilder::new("B", Exchange::NFO, "NIFTY04JAN24P21450", 50),
+        ];
+        // sent together, not one after the other: no leg returns before all four are out
+        let sent = std::sync::Barrier::new(4);
+        let results = place_basket_using(orders, |order| {
+            sent.wait();
+            match order.tradingsymbol.as_str() {
+                "NIFTY04JAN24C21850" => Err("RED:Margin Shortfall".into()),
+                tsym => Ok(format!("id-{}", tsym)),
+            }
+        });
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().unwrap(), "id-NIFTY04JAN24C21650");
+        assert_eq!(results[1].as_ref().unwrap(), "id-NIFTY04JAN24P21650");
+        let rejected = results[2].as_ref().unwrap_err();
+        assert_eq!(rejected.to_string(), "RED:Margin Shortfall");
+        assert_eq!(results[3].as_ref().unwrap(), "id-NIFTY04JAN24P21450");
+    }
+
+    #[test]
+    fn test_close_short_leg() {
+        let positions = json!([
+            {"exch": "NFO", "tsym": "NIFTY04JAN24C21800", "prd": "M", "netqty": "-50"},
+            {"exch": "NFO", "tsym": "NIFTY04JAN24P21800", "prd": "M", "netqty": "-50"},
+            {"exch": "NFO", "tsym": "NIFTY04JAN24C22000", "prd": "M", "netqty": "0"}
+        ]);
+        let positions = parse_positions(&positions);
+        let orders = closing_orders(&positions, Exchange::NFO, "NIFTY04JAN24C21800");
+        assert_eq!(orders.len(), 1);
+        let payload = orders[0].build_payload(&crate::auth::Auth::new());
+        assert_eq!(payload["trantype"], "B");
+        assert_eq!(payload["qty"], "50");
+        assert_eq!(payload["prd"], "M");
+        assert_eq!(payload["prctyp"], "MKT");
+
+        // already flat
+        assert!(closing_orders(&positions, Exchange::NFO, "NIFTY04JAN24C22000").is_empty());
+        assert!(parse_positions(&json!({"stat": "Not_Ok"})).is_empty());
+    }
+
+    #[test]
+    fn test_not_placed_is_retried() {
+        let mut sent = 0;
+        let norenordno = place_with_retry_using(
+            || {
+                sent += 1;
+                match sent {
+                    1 | 2 => Err(PlaceError::NotPlaced("connection refused".to_string())),
+                    _ => Ok("24010400000001".to_string()),
+                }
+            },
+            || panic!("a refused connection needs no lookup"),
+            3,
+        )
+        .unwrap();
+        assert_eq!(norenordno, "24010400000001");
+        assert_eq!(sent, 3);
+
+        let rejected = place_with_retry_using(
+            || Err(PlaceError::Rejected("RMS: margin exceeds".to_string())),
+            || Ok(None),
+            3,
+        );
+        assert!(rejected.is_err());
+    }
+
+    #[test]
+    fn test_unknown_looks_up_before_retry() {
+        let order_book = json!([
+            {"norenordno": "24010400000007", "remarks": "shoonya_1_ce_entry"}
+        ]);
+        let mut sent = 0;
+        let norenordno = place_with_retry_using(
+            || {
+                sent += 1;
+                Err(PlaceError::Unknown("operation timed out".to_string()))
+            },
+            || Ok(find_order_by_remarks(&order_book, "shoonya_1_ce_entry")),
+            3,
+        )
+        .unwrap();
+        // found in the order book, never sent a second time
+        assert_eq!(norenordno, "24010400000007");
+        assert_eq!(sent, 1);
+
+        // not in the order book, so it did not go through and is sent again
+        let mut sent = 0;
+        let mut lookups = 0;
+        let norenordno = place_with_retry_using(
+            || {
+                sent += 1;
+                match sent {
+                    1 => Err(PlaceError::Unknown("operation timed out".to_string())),
+                    _ => Ok("24010400000008".to_string()),
+                }
+            },
+            || {
+                lookups += 1;
+                Ok(None)
+            },
+            3,
+        )
+        .unwrap();
+        assert_eq!(norenordno, "24010400000008");
+        assert_eq!((sent, lookups), (2, 1));
+    }
+
+    #[test]
+    fn test_parse_positions() {
+        let positions = parse_positions(&json!([{
+            "stat": "Ok", "exch": "NFO", "tsym": "NIFTY04JAN24P21800", "prd": "M",
+            "netqty": "-50", "daybuyqty": "50", "daysellqty": "100", "rpnl": "750.00",
+            "urmtom": "-250.50", "netavgprc": "95.40", "lp": "100.41"
+        }]));
+        assert_eq!(positions.len(), 1);
+        let position = &positions[0];
+        assert_eq!(position.tsym, "NIFTY04JAN24P21800");
+        assert_eq!(position.netqty, -50);
+        assert_eq!(position.daysellqty, 100);
+        assert_eq!(position.avgprc, 95.40);
+        assert_eq!(position.lp, 100.41);
+        assert_eq!(position.pnl(), 499.5);
+    }
+
+    #[test]
+    fn test_parse_trade_book() {
+        let trade_book = json!([
+            {"stat": "Ok", "norenordno": "24010400000002", "exch": "NFO",
+             "tsym": "NIFTY04JAN24C21800", "trantype": "B", "qty": "100", "flqty": "50",
+             "flprc": "80.05", "fltm": "04-01-2024 14:10:00"},
+            {"stat": "Ok", "norenordno": "24010400000001", "exch": "NFO",
+             "tsym": "NIFTY04JAN24C21800", "trantype": "S", "qty": "50", "flqty": "50",
+             "flprc": "100.00", "fltm": "04-01-2024 09:20:01"}
+        ]);
+        let trades = parse_trade_book(&trade_book);
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].norenordno, "24010400000002");
+        // a partial fill of the order
+        assert_eq!(trades[0].qty, 50);
+        assert_eq!(trades[0].flprc, 80.05);
+        assert_eq!(trades[1].trantype, "S");
+        assert!(trades[1].fill_time() < trades[0].fill_time());
+        assert!(parse_trade_book(&json!({"stat": "Not_Ok", "emsg": "no data"})).is_empty());
+    }
+
+    #[test]
+    fn test_fills_in_time_order() {
+        let trade_book = json!([
+            {"norenordno": "24010400000001", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+             "flqty": "25", "flprc": "99.50", "fltm": "04-01-2024 09:20:02"},
+            {"norenordno": "24010400000001", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+             "flqty": "25", "flprc": "100.00", "fltm": "04-01-2024 09:20:01"},
+            {"norenordno": "24010400000002", "tsym": "NIFTY04JAN24P21800", "trantype": "S",
+             "flqty": "50", "flprc": "90.00", "norentm": "09:20:03 04-01-2024"}
+        ]);
+        let fills = sorted_fills(parse_trade_book(&trade_book));
+        let prices: Vec<f64> = fills.iter().map(|fill| fill.flprc).collect();
+        assert_eq!(prices, vec![100.0, 99.5, 90.0]);
+        assert_eq!(
+            fills[0].fill_time().unwrap(),
+            chrono::NaiveDate::from_ymd_opt(2024, 1, 4)
+                .unwrap()
+                .and_hms_opt(9, 20, 1)
+                .unwrap()
+        );
+        // no fltm, the row's norentm is used
+        let put_fill = fills[2].fill_time().unwrap();
+        assert_eq!(put_fill.format("%H:%M:%S").to_string(), "09:20:03");
+        assert_eq!(parse_noren_time("4 Jan 09:20"), None);
+    }
+
+    #[test]
+    fn test_parse_holdings() {
+        let holdings = parse_holdings(&json!([{
+            "stat": "Ok",
+            "exch_tsym": [
+                {"exch": "NSE", "tsym": "NIFTYBEES-EQ", "token": "10576", "pp": "2"},
+                {"exch": "BSE", "tsym": "NIFTYBEES", "token": "590103", "pp": "2"}
+            ],
+            "holdqty": "150", "npoadqty": "150", "upldprc": "221.35", "colqty": "0"
+        }]));
+        assert_eq!(holdings.len(), 1);
+        assert_eq!(holdings[0].exch_tsym.len(), 2);
+        assert_eq!(holdings[0].exch_tsym[1].exch, "BSE");
+        assert_eq!(holdings[0].exch_tsym[0].token, "10576");
+        assert_eq!(holdings[0].holdqty, 150);
+        assert_eq!(holdings[0].npoadqty, 150);
+        assert_eq!(holdings[0].upldprc, 221.35);
+        assert!(parse_holdings(&json!({"stat": "Not_Ok", "emsg": "no data"})).is_empty());
+    }
+
+    #[test]
+    fn test_after_market_order() {
+        let auth = crate::auth::Auth::new();
+        let order = OrderBuilder::new("S", Exchange::NFO, "NIFTY04JAN24C21800", 50);
+        let payload = order
+            .after_market(true, false)
+            .unwrap()
+            .build_payload(&auth);
+        assert_eq!(payload["amo"], "YES");
+
+        let order = OrderBuilder::new("S", Exchange::NFO, "NIFTY04JAN24C21800", 50);
+        assert!(order.after_market(true, true).is_err());
+        let order = OrderBuilder::new("S", Exchange::NFO, "NIFTY04JAN24C21800", 50);
+        let payload = order
+            .after_market(false, true)
+            .unwrap()
+            .build_payload(&auth);
+        assert_eq!(payload["amo"], "NO");
+    }
+
+    #[test]
+    fn test_limit_order_payload() {
+        let mut auth = crate::auth::Auth::new();
+        auth.set_session("FA12345", "pwd", "token");
+        let order = OrderBuilder::new("S", Exchange::NFO, "NIFTY04JAN24C21800", 50)
+            .price_type("LMT")
+            .price(101.5)
+            .remarks("shoonya_1_straddle");
+        let payload = order.build_payload(&auth);
+        assert_eq!(
+            payload,
+            json!({
+                "ordersource": "API",
+                "uid": "FA12345",
+                "actid": "FA12345",
+                "trantype": "S",
+                "prd": "M",
+                "exch": "NFO",
+                "tsym": "NIFTY04JAN24C21800",
+                "qty": "50",
+                "dscqty": "0",
+                "prctyp": "LMT",
+                "prc": "101.5",
+                "trgprc": "0",
+                "ret": "DAY",
+                "remarks": "shoonya_1_straddle",
+                "amo": "NO"
+            })
+        );
+        assert!(!payload.to_string().contains("token"));
+    }
+
+    #[test]
+    fn test_payload_prices_rounded_to_precision() {
+        let mut auth = crate::auth::Auth::new();
+        auth.set_session("FA12345", "pwd", "token");
+        let order = OrderBuilder::new("B", Exchange::NFO, "NIFTY04JAN24C21800", 50)
+            .product_type("B")
+            .price_type("SL-LMT")
+            .price(101.45000000000002)
+            .trigger_price(101.39999999999999)
+            .bookloss_price(90.10000000000001)
+            .bookprofit_price(120.30000000000001)
+            .trail_price(0.49999999999999994);
+        let payload = order.build_payload(&auth);
+        assert_eq!(payload["prc"], "101.45");
+        assert_eq!(payload["trgprc"], "101.4");
+        assert_eq!(payload["blprc"], "90.1");
+        assert_eq!(payload["bpprc"], "120.3");
+        assert_eq!(payload["trailprc"], "0.5");
+        let payload = order.modify_payload(&auth, "24010400000001");
+        assert_eq!(payload["prc"], "101.45");
+        assert_eq!(payload["trgprc"], "101.4");
+
+        // a currency pair quoted to four decimals
+        let order = OrderBuilder::new("B", Exchange::CDS, "USDINR24JANFUT", 1)
+            .price_type("LMT")
+            .price(83.12345 + 1e-9)
+            .price_precision(4);
+        assert_eq!(order.build_payload(&auth)["prc"], "83.1235");
+    }
+
+    #[test]
+    fn test_price_type_needs_prices() {
+        let order = |price_type: &str, price: f64, trigger_price: f64| {
+            OrderBuilder::new("S", Exchange::NFO, "NIFTY04JAN24C21800", 50)
+                .price_type(price_type)
+                .price(price)
+                .trigger_price(trigger_price)
+        };
+        assert!(order("MKT", 0.0, 0.0).check_prices().is_ok());
+        assert!(order("LMT", 101.5, 0.0).check_prices().is_ok());
+        assert!(order("SL-LMT", 120.0, 119.5).check_prices().is_ok());
+        assert!(order("SL-MKT", 0.0, 119.5).check_prices().is_ok());
+
+        assert!(order("LMT", 0.0, 0.0).check_prices().is_err());
+        assert!(order("SL-LMT", 0.0, 119.5).check_prices().is_err());
+        assert!(order("SL-LMT", 120.0, 0.0).check_prices().is_err());
+        assert!(order("SL-MKT", 0.0, 0.0).check_prices().is_err());
+    }
+
+    #[test]
+    fn test_bracket_prices_checked() {
+        let bracket = |side: &str, price: f64, loss: f64, profit: f64| {
+            OrderBuilder::new(side, Exchange::NFO, "NIFTY04JAN24C21800", 50)
+                .product_type("B")
+                .price_type("LMT")
+                .price(price)
+                .bookloss_price(loss)
+                .bookprofit_price(profit)
+        };
+        assert!(bracket("B", 100.0, 90.0, 120.0).check_bracket().is_ok());
+        assert!(bracket("S", 100.0, 110.0, 80.0).check_bracket().is_ok());
+        // a market bracket has no entry price to compare with
+        assert!(bracket("B", 0.0, 90.0, 120.0).check_bracket().is_ok());
+
+        assert!(bracket("B", 100.0, 0.0, 120.0).check_bracket().is_err());
+        assert!(bracket("B", 100.0, 90.0, 0.0).check_bracket().is_err());
+        assert!(bracket("B", 0.0, 0.0, 0.0).check_bracket().is_err());
+        assert!(bracket("B", 100.0, 110.0, 120.0).check_bracket().is_err());
+        assert!(bracket("B", 100.0, 90.0, 95.0).check_bracket().is_err());
+        assert!(bracket("B", 100.0, 100.0, 120.0).check_bracket().is_err());
+        assert!(bracket("S", 100.0, 90.0, 80.0).check_bracket().is_err());
+        assert!(bracket("S", 100.0, 110.0, 105.0).check_bracket().is_err());
+        assert!(bracket("X", 100.0, 90.0, 120.0).check_bracket().is_err());
+        let trailing = bracket("B", 100.0, 90.0, 120.0).trail_price(-1.0);
+        assert!(trailing.check_bracket().is_err());
+
+        // only bracket orders carry the exits
+        let order = OrderBuilder::new("B", Exchange::NFO, "NIFTY04JAN24C21800", 50);
+        assert!(order.check_bracket().is_ok());
+    }
+
+    #[test]
+    fn test_modify_stop_loss() {
+        let mut auth = crate::auth::Auth::new();
+        auth.set_session("FA12345", "pwd", "token");
+        let order = OrderBuilder::new("B", Exchange::NFO, "NIFTY04JAN24C21800", 50)
+            .price_type("SL-LMT")
+            .price(131.0)
+            .trigger_price(130.5);
+        let payload = order.modify_payload(&auth, "24010400000001");
+        assert_eq!(payload["norenordno"], "24010400000001");
+        assert_eq!(payload["uid"], "FA12345");
+        assert_eq!(payload["exch"], "NFO");
+        assert_eq!(payload["tsym"], "NIFTY04JAN24C21800");
+        assert_eq!(payload["qty"], "50");
+        assert_eq!(payload["prctyp"], "SL-LMT");
+        assert_eq!(payload["prc"], "131");
+        assert_eq!(payload["trgprc"], "130.5");
+    }
+
+    #[test]
+    fn test_convert_product_payload() {
+        let mut auth = crate::auth::Auth::new();
+        auth.set_session("FA12345", "pwd", "token");
+        let values = convert_product_values(
+            &auth,
+            "NIFTY04JAN24P21800",
+            &Exchange::NFO,
+            50,
+            "I",
+            "M",
+            "S",
+            PositionType::Day,
+        );
+        assert_eq!(values["tsym"], "NIFTY04JAN24P21800");
+        assert_eq!(values["exch"], "NFO");
+        assert_eq!(values["qty"], "50");
+        assert_eq!(values["prevprd"], "I");
+        assert_eq!(values["prd"], "M");
+        assert_eq!(values["trantype"], "S");
+        assert_eq!(values["postype"], "Day");
+        assert_eq!(PositionType::CarryForward.as_str(), "CF");
+    }
+
+    #[test]
+    fn test_rejected_order_history() {
+        let history = json!([
+            {"stat": "Ok", "norenordno": "24010400000001", "status": "REJECTED", "rpt": "Rejected",
+             "qty": "1800", "prc": "0.00", "norentm": "09:20:02 04-01-2024",
+             "rejreason": "RED:Margin Shortfall:INR 12,000.00 Available:INR 3,000.00"},
+            {"stat": "Ok", "norenordno": "24010400000001", "status": "PENDING", "rpt": "PendingNew",
+             "qty": "1800", "prc": "0.00", "norentm": "09:20:01 04-01-2024"}
+        ]);
+        let events = parse_order_history(&history).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].status, OrderStatus::Pending);
+        assert_eq!(events[0].rejreason, None);
+        assert_eq!(events[1].status, OrderStatus::Rejected);
+        assert_eq!(events[1].qty, 1800);
+        assert!(events[1]
+            .rejreason
+            .as_deref()
+            .is_some_and(|rejreason| rejreason.contains("Margin Shortfall")));
+        assert!(events[0].norentm < events[1].norentm);
+
+        assert!(parse_order_history(&json!({"stat": "Not_Ok", "emsg": "Invalid order"})).is_err());
+    }
+
+    #[test]
+    fn test_order_without_history_read_from_order_book() {
+        let no_data = json!({"stat": "Not_Ok", "emsg": "Error Occurred : 5 \"no data\""});
+        assert!(parse_order_history(&no_data).unwrap().is_empty());
+
+        // the order book catches up before the history does
+        let books = std::cell::RefCell::new(vec![
+            json!([{"norenordno": "1", "status": "COMPLETE"}]),
+            json!([{"norenordno": "1", "status": "OPEN"}]),
+            json!({"stat": "Not_Ok", "emsg": "Error Occurred : 5 \"no data\""}),
+        ]);
+        let fetches = std::cell::Cell::new(0);
+        let status = poll_until_terminal(
+            || {
+                fetches.set(fetches.get() + 1);
+                order_status_using(
+                    "1",
+                    || parse_order_history(&no_data),
+                    || Ok(books.borrow_mut().pop().unwrap()),
+                )
+            },
+            Duration::from_secs(1),
+            Duration::from_millis(1),
+        );
+        assert_eq!(status.unwrap(), OrderStatus::Complete);
+        assert_eq!(fetches.get(), 3);
+    }
 }
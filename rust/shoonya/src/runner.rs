@@ -0,0 +1,570 @@
+use crate::auth::Auth;
+use crate::orders::{
+    closing_orders, convert_product, get_positions, OrderBuilder, OrderStatus, Position,
+    PositionType,
+};
+use crate::transaction::{MarkPrice, OpenPosition, TransactionManager};
+use common::utils::get_exchange;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// How often the position audit thread checks whether it was stopped
+const AUDIT_POLL: Duration = Duration::from_millis(200);
+
+/// When the runner starts enforcing the exits after entry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WarmUp {
+    /// Skip the exit checks for a fixed time after the start
+    For(Duration),
+    /// Skip the exit checks until every order is done and at least one filled
+    UntilFilled,
+}
+
+/// Why the runner wants to exit the strategy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitSignal {
+    Target,
+    StopLoss,
+}
+
+/// MTM exits of a strategy, `stop_mtm` is the loss as a positive amount
+#[derive(Debug, Clone)]
+pub struct ExitRules {
+    pub target_mtm: f64,
+    pub stop_mtm: f64,
+    pub mark: MarkPrice,
+    pub warmup: WarmUp,
+}
+
+/// How the entry legs of a strategy are sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryMode {
+    /// Send every leg at once
+    Simultaneous,
+    /// Send the least liquid leg first and wait for each fill before the next
+    Sequential { fill_timeout: Duration },
+}
+
+/// An entry order with the bid-ask spread of its instrument, a wider spread
+/// being the less liquid leg
+pub struct EntryLeg {
+    pub order: OrderBuilder,
+    pub spread: f64,
+}
+
+/// What the runner does with an open leg at the end of the day
+pub enum DayEndAction {
+    /// Square the leg off
+    Flatten(OrderBuilder),
+    /// Keep the leg overnight, an intraday leg is converted to NRML first
+    Carry(Position),
+}
+
+pub struct StrategyRunner {
+    rules: ExitRules,
+    started: Instant,
+    carry_if_itm: bool,
+}
+
+// Option type and strike of an NFO option symbol, e.g. "NIFTY04JAN24C21800"
+fn option_of(tradingsymbol: &str) -> Option<(char, f64)> {
+    let at = tradingsymbol.rfind(|c: char| !c.is_ascii_digit() && c != '.')?;
+    let option_type = tradingsymbol[at..].chars().next()?;
+    if option_type != 'C' && option_type != 'P' {
+        return None;
+    }
+    let strike = tradingsymbol[at + 1..].parse::<f64>().ok()?;
+    Some((option_type, strike))
+}
+
+/// Whether the option symbol is in the money with the underlying at `spot`
+pub fn is_itm(tradingsymbol: &str, spot: f64) -> bool {
+    match option_of(tradingsymbol) {
+        Some(('C', strike)) => spot > strike,
+        Some(('P', strike)) => spot < strike,
+        _ => false,
+    }
+}
+
+impl StrategyRunner {
+    pub fn new(rules: ExitRules) -> Self {
+        StrategyRunner::with_start(rules, Instant::now())
+    }
+
+    pub fn with_start(rules: ExitRules, started: Instant) -> Self {
+        StrategyRunner {
+            rules,
+            started,
+            carry_if_itm: false,
+        }
+    }
+
+    /// Carry the legs that are in the money at the day's exit instead of squaring them off
+    pub fn carry_if_itm(mut self, carry_if_itm: bool) -> Self {
+        self.carry_if_itm = carry_if_itm;
+        self
+    }
+
+    /// The day's exit of each open leg, with the underlying at `spot`
+    pub fn day_end_actions(&self, positions: &[Position], spot: f64) -> Vec<DayEndAction> {
+        let mut actions = Vec::new();
+        for position in positions.iter().filter(|position| position.netqty != 0) {
+            if self.carry_if_itm && is_itm(&position.tsym, spot) {
+                actions.push(DayEndAction::Carry(position.clone()));
+                continue;
+            }
+            let exchange = match get_exchange(&position.exch) {
+                Some(exchange) => exchange,
+                None => {
+                    log::error!("Cannot flatten {}: unknown exchange", position.tsym);
+                    continue;
+                }
+            };
+            actions.extend(
+                closing_orders(std::slice::from_ref(position), exchange, &position.tsym)
+                    .into_iter()
+                    .map(DayEndAction::Flatten),
+            );
+        }
+        actions
+    }
+
+    /// True while stale ticks or pending legs could misfire the exits
+    pub fn in_warmup(&self, tm: &TransactionManager, now: Instant) -> bool {
+        match self.rules.warmup {
+            WarmUp::For(duration) => now.duration_since(self.started) < duration,
+            WarmUp::UntilFilled => !tm.all_orders_settled(),
+        }
+    }
+
+    /// Checks the MTM against the target and stop, nothing is reported during the warm-up
+    pub fn check_exit(&self, tm: &TransactionManager, now: Instant) -> Option<ExitSignal> {
+        if self.in_warmup(tm, now) {
+            return None;
+        }
+        let (pnl, _) = tm.get_pnl(self.rules.mark);
+        if pnl >= self.rules.target_mtm {
+            Some(ExitSignal::Target)
+        } else if pnl <= -self.rules.stop_mtm {
+            Some(ExitSignal::StopLoss)
+        } else {
+            None
+        }
+    }
+}
+
+/// Squares off or carries the open legs as `day_end_actions` decided
+pub fn apply_day_end(
+    auth: &Auth,
+    actions: &[DayEndAction],
+) -> Result<(), Box<dyn std::error::Error>> {
+    for action in actions {
+        match action {
+            DayEndAction::Flatten(order) => {
+                order.place(auth)?;
+            }
+            // an intraday leg would be squared off by the broker
+            DayEndAction::Carry(position) if position.prd == "I" => {
+                let exchange = get_exchange(&position.exch)
+                    .ok_or(format!("Unknown exchange {}", position.exch))?;
+                let trantype = if position.netqty > 0 { "B" } else { "S" };
+                convert_product(
+                    auth,
+                    &position.tsym,
+                    &exchange,
+                    position.netqty.unsigned_abs() as u32,
+                    "I",
+                    "M",
+                    trantype,
+                    PositionType::Day,
+                )?;
+                log::info!("Carrying {} overnight as NRML", position.tsym);
+            }
+            DayEndAction::Carry(position) => {
+                log::info!("Carrying {} overnight", position.tsym)
+            }
+        }
+    }
+    Ok(())
+}
+
+// Entry sequencing of `OrderManager::place_entry`, `place` sends a leg and `wait`
+// blocks on the fill of an order
+pub(crate) fn place_entry_using<P, W>(
+    mut legs: Vec<EntryLeg>,
+    mode: EntryMode,
+    mut place: P,
+    mut wait: W,
+) -> Result<Vec<String>, Box<dyn std::error::Error>>
+where
+    P: FnMut(&EntryLeg) -> Result<String, Box<dyn std::error::Error>>,
+    W: FnMut(&str) -> Result<OrderStatus, Box<dyn std::error::Error>>,
+{
+    if mode == EntryMode::Simultaneous {
+        return legs.iter().map(&mut place).collect();
+    }
+    legs.sort_by(|a, b| b.spread.total_cmp(&a.spread));
+    let mut placed = Vec::new();
+    let last = legs.len().saturating_sub(1);
+    for (i, leg) in legs.iter().enumerate() {
+        let norenordno = place(leg)?;
+        placed.push(norenordno.clone());
+        if i == last {
+            break;
+        }
+        // a leg left unfilled would leave the next one naked
+        let status = wait(&norenordno)?;
+        if status != OrderStatus::Complete {
+            return Err(format!(
+                "Entry leg {} ended {:?}, not sending the rest",
+                norenordno, status
+            )
+            .into());
+        }
+    }
+    Ok(placed)
+}
+
+/// A symbol whose net quantity differs between the broker and the order table
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionMismatch {
+    pub exchange: String,
+    pub tradingsymbol: String,
+    pub product: String,
+    pub broker_qty: i64,
+    pub cache_qty: i64,
+}
+
+// Matches the broker positions with the order table by symbol and product, a
+// position flat on both sides is consistent
+fn compare_positions(broker: &[Position], cache: &[OpenPosition]) -> Vec<PositionMismatch> {
+    let mut mismatches: Vec<PositionMismatch> = Vec::new();
+    for position in broker {
+        let cache_qty = cache
+            .iter()
+            .find(|open| open.tradingsymbol == position.tsym && open.product == position.prd)
+            .map_or(0, |open| open.net_qty);
+        if position.netqty != cache_qty {
+            mismatches.push(PositionMismatch {
+                exchange: position.exch.clone(),
+                tradingsymbol: position.tsym.clone(),
+                product: position.prd.clone(),
+                broker_qty: position.netqty,
+                cache_qty,
+            });
+        }
+    }
+    for open in cache {
+        let at_broker = broker
+            .iter()
+            .any(|position| position.tsym == open.tradingsymbol && position.prd == open.product);
+        if !at_broker {
+            mismatches.push(PositionMismatch {
+                exchange: open.exchange.clone(),
+                tradingsymbol: open.tradingsymbol.clone(),
+                product: open.product.clone(),
+                broker_qty: 0,
+                cache_qty: open.net_qty,
+            });
+        }
+    }
+    mismatches
+}
+
+/// Compares the broker positions with the order table and logs each difference,
+/// e.g. a fill missed while disconnected or a leg closed by hand
+pub fn audit_positions(
+    auth: &Auth,
+    tm: &TransactionManager,
+) -> Result<Vec<PositionMismatch>, Box<dyn std::error::Error>> {
+    let mismatches = compare_positions(&get_positions(auth)?, &tm.open_positions());
+    log_drift(&mismatches);
+    Ok(mismatches)
+}
+
+fn log_drift(mismatches: &[PositionMismatch]) {
+    for mismatch in mismatches {
+        log::warn!(
+            "Position drift on {} {}: broker {} vs cache {}",
+            mismatch.tradingsymbol,
+            mismatch.product,
+            mismatch.broker_qty,
+            mismatch.cache_qty
+        );
+    }
+}
+
+/// Runs `audit_positions` every `interval` on a background thread until stopped
+pub struct PositionAudit {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl PositionAudit {
+    pub fn start(
+        auth: Arc<RwLock<Auth>>,
+        tm: Arc<Mutex<TransactionManager>>,
+        interval: Duration,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle = std::thread::spawn(move || {
+            let mut next = Instant::now() + interval;
+            while !stopped.load(Ordering::SeqCst) {
+                if Instant::now() < next {
+                    std::thread::sleep(AUDIT_POLL.min(interval));
+                    continue;
+                }
+                next = Instant::now() + interval;
+                // the positions are fetched before locking, the feed keeps updating meanwhile
+                let positions = match get_positions(&auth.read().unwrap()) {
+                    Ok(positions) => positions,
+                    Err(e) => {
+                        log::error!("Position audit failed: {}", e);
+                        continue;
+                    }
+                };
+                let mut tm = tm.lock().unwrap();
+                log_drift(&compare_positions(&positions, &tm.open_positions()));
+                tm.on_positions(positions);
+            }
+        });
+        PositionAudit {
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for PositionAudit {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn short_straddle(tm: &mut TransactionManager, pe_status: &str) {
+        let remarks = tm.make_remarks("straddle");
+        tm.add_symbol("NFO|42216", "NIFTY04JAN24C21800");
+        tm.add_symbol("NFO|42217", "NIFTY04JAN24P21800");
+        tm.on_order(&json!({
+            "norenordno": "1", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "100.00", "remarks": remarks
+        }));
+        tm.on_order(&json!({
+            "norenordno": "2", "tsym": "NIFTY04JAN24P21800", "trantype": "S",
+            "status": pe_status, "fillshares": "50", "flprc": "100.00", "remarks": remarks
+        }));
+        // a stale first tick shows a large loss on the call
+        tm.on_tick(&json!({"e": "NFO", "tk": "42216", "lp": "130.00"}).into());
+        tm.on_tick(&json!({"e": "NFO", "tk": "42217", "lp": "100.00"}).into());
+    }
+
+    #[test]
+    fn test_exits_suppressed_during_warmup() {
+        let rules = ExitRules {
+            target_mtm: 1000.0,
+            stop_mtm: 1000.0,
+            mark: MarkPrice::Ltp,
+            warmup: WarmUp::For(Duration::from_secs(10)),
+        };
+        let mut tm = TransactionManager::new();
+        short_straddle(&mut tm, "COMPLETE");
+        let started = Instant::now();
+        let runner = StrategyRunner::with_start(rules.clone(), started);
+        assert_eq!(
+            runner.check_exit(&tm, started + Duration::from_secs(5)),
+            None
+        );
+        assert_eq!(
+            runner.check_exit(&tm, started + Duration::from_secs(10)),
+            Some(ExitSignal::StopLoss)
+        );
+
+        let rules = ExitRules {
+            warmup: WarmUp::UntilFilled,
+            ..rules
+        };
+        let mut tm = TransactionManager::new();
+        short_straddle(&mut tm, "OPEN");
+        let runner = StrategyRunner::new(rules);
+        assert_eq!(runner.check_exit(&tm, Instant::now()), None);
+        tm.on_order(&json!({
+            "norenordno": "2", "tsym": "NIFTY04JAN24P21800", "trantype": "S",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "100.00",
+            "remarks": tm.make_remarks("straddle")
+        }));
+        assert_eq!(
+            runner.check_exit(&tm, Instant::now()),
+            Some(ExitSignal::StopLoss)
+        );
+    }
+
+    #[test]
+    fn test_cancelled_order_ends_warmup() {
+        let rules = ExitRules {
+            target_mtm: 1000.0,
+            stop_mtm: 1000.0,
+            mark: MarkPrice::Ltp,
+            warmup: WarmUp::UntilFilled,
+        };
+        let mut tm = TransactionManager::new();
+        short_straddle(&mut tm, "COMPLETE");
+        // the stop-loss of the call, cancelled before its trigger
+        tm.on_order(&json!({
+            "norenordno": "3", "tsym": "NIFTY04JAN24C21800", "trantype": "B",
+            "status": "CANCELED", "remarks": tm.make_remarks("straddle_sl")
+        }));
+        let runner = StrategyRunner::new(rules);
+        assert_eq!(
+            runner.check_exit(&tm, Instant::now()),
+            Some(ExitSignal::StopLoss)
+        );
+
+        // nothing filled yet, e.g. every leg rejected
+        let mut tm = TransactionManager::new();
+        tm.on_order(&json!({
+            "norenordno": "1", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+            "status": "REJECTED", "remarks": tm.make_remarks("straddle")
+        }));
+        assert!(runner.in_warmup(&tm, Instant::now()));
+    }
+
+    fn leg(tradingsymbol: &str, spread: f64) -> EntryLeg {
+        EntryLeg {
+            order: OrderBuilder::new("S", common::utils::Exchange::NFO, tradingsymbol, 50),
+            spread,
+        }
+    }
+
+    #[test]
+    fn test_sequential_entry_waits_for_fill() {
+        let events = std::cell::RefCell::new(Vec::new());
+        let mode = EntryMode::Sequential {
+            fill_timeout: Duration::from_secs(30),
+        };
+        let placed = place_entry_using(
+            vec![
+                leg("NIFTY04JAN24C22000", 0.5),
+                leg("NIFTY04JAN24P21600", 2.0),
+            ],
+            mode,
+            |leg| {
+                let norenordno = format!("{}", events.borrow().len() + 1);
+                events
+                    .borrow_mut()
+                    .push(format!("place {}", leg.order.quantity()));
+                Ok(norenordno)
+            },
+            |norenordno| {
+                events.borrow_mut().push(format!("wait {}", norenordno));
+                Ok(OrderStatus::Complete)
+            },
+        )
+        .unwrap();
+        assert_eq!(placed, vec!["1", "3"]);
+        assert_eq!(*events.borrow(), vec!["place 50", "wait 1", "place 50"]);
+
+        // the less liquid put goes first, and a rejection stops the call
+        let mut sent = Vec::new();
+        let result = place_entry_using(
+            vec![
+                leg("NIFTY04JAN24C22000", 0.5),
+                leg("NIFTY04JAN24P21600", 2.0),
+            ],
+            mode,
+            |leg| {
+                sent.push(leg.spread);
+                Ok("1".to_string())
+            },
+            |_| Ok(OrderStatus::Rejected),
+        );
+        assert!(result.is_err());
+        assert_eq!(sent, vec![2.0]);
+    }
+
+    #[test]
+    fn test_broker_only_position_reported() {
+        let mut tm = TransactionManager::new();
+        let remarks = tm.make_remarks("straddle");
+        tm.on_order(&json!({
+            "norenordno": "1", "exch": "NFO", "tsym": "NIFTY04JAN24C21800", "prd": "M",
+            "trantype": "S", "status": "COMPLETE", "fillshares": "50", "flprc": "100.00",
+            "remarks": remarks
+        }));
+        let broker = crate::orders::parse_positions(&json!([
+            {"exch": "NFO", "tsym": "NIFTY04JAN24C21800", "prd": "M", "netqty": "-50"},
+            // sold by hand from the web terminal
+            {"exch": "NFO", "tsym": "NIFTY04JAN24P21800", "prd": "M", "netqty": "-50"},
+            {"exch": "NFO", "tsym": "NIFTY04JAN24C22000", "prd": "M", "netqty": "0"}
+        ]));
+        let mismatches = compare_positions(&broker, &tm.open_positions());
+        assert_eq!(
+            mismatches,
+            vec![PositionMismatch {
+                exchange: "NFO".to_string(),
+                tradingsymbol: "NIFTY04JAN24P21800".to_string(),
+                product: "M".to_string(),
+                broker_qty: -50,
+                cache_qty: 0,
+            }]
+        );
+
+        // and a leg the cache holds but the broker closed
+        let mismatches = compare_positions(&broker[1..], &tm.open_positions());
+        assert_eq!(mismatches.len(), 2);
+        assert_eq!(mismatches[1].tradingsymbol, "NIFTY04JAN24C21800");
+        assert_eq!(mismatches[1].broker_qty, 0);
+        assert_eq!(mismatches[1].cache_qty, -50);
+    }
+
+    #[test]
+    fn test_itm_leg_carried_otm_leg_flattened() {
+        let rules = ExitRules {
+            target_mtm: 1000.0,
+            stop_mtm: 1000.0,
+            mark: MarkPrice::Ltp,
+            warmup: WarmUp::UntilFilled,
+        };
+        let positions = crate::orders::parse_positions(&json!([
+            {"exch": "NFO", "tsym": "NIFTY04JAN24C21800", "prd": "I", "netqty": "-50"},
+            {"exch": "NFO", "tsym": "NIFTY04JAN24P21800", "prd": "I", "netqty": "-50"},
+            {"exch": "NFO", "tsym": "NIFTY04JAN24C22000", "prd": "I", "netqty": "0"}
+        ]));
+        // the call is deep in the money near the close
+        let runner = StrategyRunner::new(rules.clone()).carry_if_itm(true);
+        let actions = runner.day_end_actions(&positions, 21950.0);
+        assert_eq!(actions.len(), 2);
+        assert!(
+            matches!(&actions[0], DayEndAction::Carry(position) if position.tsym == "NIFTY04JAN24C21800")
+        );
+        match &actions[1] {
+            DayEndAction::Flatten(order) => assert_eq!(order.quantity(), 50),
+            DayEndAction::Carry(_) => panic!("the out of the money put is squared off"),
+        }
+
+        // without the flag every leg is squared off
+        let actions = StrategyRunner::new(rules).day_end_actions(&positions, 21950.0);
+        assert_eq!(actions.len(), 2);
+        assert!(actions
+            .iter()
+            .all(|action| matches!(action, DayEndAction::Flatten(_))));
+
+        assert!(is_itm("NIFTY04JAN24P21800", 21750.0));
+        assert!(!is_itm("NIFTY04JAN24P21800", 21800.0));
+        assert!(!is_itm("NIFTY-EQ", 21800.0));
+    }
+}
@@ -0,0 +1,1065 @@
+use crate::websocket::Tick;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Maximum length of the `remarks` field accepted by the broker
+pub const MAX_REMARKS_LEN: usize = 32;
+
+/// Price used to value an open leg in `get_pnl`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MarkPrice {
+    /// Last traded price
+    Ltp,
+    /// Estimated cost to close: ask for shorts, bid for longs
+    ExitCost,
+}
+
+/// Where `get_pnl` takes the PnL from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PnlSource {
+    /// Recomputed from the fills and the latest ticks, live but can drift
+    Ticks,
+    /// The broker's `rpnl + urmtom` of every position of the account, as of the
+    /// last position book recorded with `on_positions`
+    Broker,
+}
+
+/// A row of the order table, as reported by the order updates
+#[derive(Debug, Clone)]
+pub struct OrderRow {
+    pub norenordno: String,
+    /// The remarks the order was placed with, see `new_client_order_id`
+    pub client_order_id: String,
+    pub exchange: String,
+    pub tradingsymbol: String,
+    pub product: String,
+    pub buysell: String,
+    pub qty: i64,
+    pub avgprice: f64,
+    pub status: String,
+}
+
+/// Net filled quantity of a symbol, negative when short
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenPosition {
+    pub exchange: String,
+    pub tradingsymbol: String,
+    pub product: String,
+    pub net_qty: i64,
+}
+
+/// Latest market data seen for a symbol code
+#[derive(Debug, Clone, Default)]
+pub struct LiveQuote {
+    pub ltp: f64,
+    pub bid: Option<f64>,
+    pub ask: Option<f64>,
+    /// Feed time (`ft`, epoch seconds) of the latest tick applied
+    pub feed_time: Option<i64>,
+}
+
+/// What a sent order does to the exposure cap, see `TransactionManager::check_exposure`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exposure {
+    /// Quantity adding to the exposure
+    pub opening: u32,
+    /// Quantity offsetting an open position
+    pub closing: u32,
+    /// Price the opening quantity is valued at
+    pub price: f64,
+}
+
+/// Order, quote and symbol tables of one strategy instance. They live in memory
+/// only, nothing is written to Redis, so a stop or restart leaves no keys behind.
+pub struct TransactionManager {
+    instance_id: String,
+    order_tbl: HashMap<String, OrderRow>,
+    // "EXCH|token" -> latest quote, for marking the legs. A token alone is
+    // ambiguous across exchanges, e.g. NFO and BFO.
+    ltp_tbl: HashMap<String, LiveQuote>,
+    // tradingsymbol -> "EXCH|token"
+    symbol_tbl: HashMap<String, String>,
+    // remarks -> time the order was sent
+    sent_tbl: HashMap<String, Instant>,
+    // remarks -> time from send to the COMPLETE order update
+    latency_tbl: HashMap<String, Duration>,
+    // client order id -> norenordno
+    client_tbl: HashMap<String, String>,
+    client_seq: u32,
+    pnl_source: PnlSource,
+    // latest position book, for PnlSource::Broker
+    broker_positions: Vec<crate::orders::Position>,
+    exposure_cap: crate::orders::ExposureCap,
+    // tradingsymbols kept overnight, the day's square off leaves them alone
+    carried: HashSet<String>,
+}
+
+// Shoonya sends numbers as strings, accept both
+pub(crate) fn parse_f64(value: &serde_json::Value) -> Option<f64> {
+    match value {
+        serde_json::Value::String(s) => s.parse::<f64>().ok(),
+        serde_json::Value::Number(n) => n.as_f64(),
+        _ => None,
+    }
+}
+
+impl Default for TransactionManager {
+    fn default() -> Self {
+        TransactionManager::new()
+    }
+}
+
+impl TransactionManager {
+    pub fn new() -> TransactionManager {
+        let instance_id = format!(
+            "shoonya_{}_{}",
+            std::process::id(),
+            chrono::Local::now().timestamp()
+        );
+        TransactionManager::with_instance(&instance_id)
+    }
+
+    pub fn with_instance(instance_id: &str) -> TransactionManager {
+        TransactionManager {
+            instance_id: instance_id.to_string(),
+            order_tbl: HashMap::new(),
+            ltp_tbl: HashMap::new(),
+            symbol_tbl: HashMap::new(),
+            sent_tbl: HashMap::new(),
+            latency_tbl: HashMap::new(),
+            client_tbl: HashMap::new(),
+            client_seq: 0,
+            pnl_source: PnlSource::Ticks,
+            broker_positions: Vec::new(),
+            exposure_cap: crate::orders::ExposureCap::default(),
+            carried: HashSet::new(),
+        }
+    }
+
+    pub fn set_pnl_source(&mut self, pnl_source: PnlSource) {
+        self.pnl_source = pnl_source;
+    }
+
+    /// Limits what the orders of this instance may hold open, unlimited by default
+    pub fn set_exposure_cap(&mut self, exposure_cap: crate::orders::ExposureCap) {
+        self.exposure_cap = exposure_cap;
+    }
+
+    /// Keeps the position in `tradingsymbol` open past the day's square off
+    pub fn carry_overnight(&mut self, tradingsymbol: &str) {
+        self.carried.insert(tradingsymbol.to_string());
+    }
+
+    pub fn is_carried(&self, tradingsymbol: &str) -> bool {
+        self.carried.contains(tradingsymbol)
+    }
+
+    /// Checks `order` against the exposure cap before it is sent. The quantity
+    /// offsetting an open position never counts against the cap. A market order
+    /// is valued at the last tick of its symbol.
+    pub fn check_exposure(
+        &self,
+        order: &crate::orders::OrderBuilder,
+    ) -> Result<Exposure, Box<dyn std::error::Error>> {
+        let side = if order.buy_or_sell() == "B" { 1 } else { -1 };
+        let offsetting: i64 = self
+            .open_positions()
+            .iter()
+            .filter(|position| position.tradingsymbol == order.tradingsymbol())
+            .map(|position| position.net_qty)
+            .filter(|net_qty| net_qty * side < 0)
+            .sum();
+        let closing = (offsetting.unsigned_abs() as u32).min(order.quantity());
+        let opening = order.quantity() - closing;
+        let price = match order.limit_price().or_else(|| {
+            self.symbol_mark(order.tradingsymbol(), false, MarkPrice::Ltp)
+        }) {
+            Some(price) => price,
+            None if opening > 0 && self.exposure_cap.limits_notional() => {
+                return Err(format!(
+                    "No price for {} to check against the notional cap",
+                    order.tradingsymbol()
+                )
+                .into())
+            }
+            None => 0.0,
+        };
+        if opening > 0 {
+            self.exposure_cap.check(opening, price)?;
+        }
+        Ok(Exposure {
+            opening,
+            closing,
+            price,
+        })
+    }
+
+    /// Books a sent order into the exposure cap, see `check_exposure`
+    pub fn record_exposure(&mut self, exposure: &Exposure) {
+        self.exposure_cap.release(exposure.closing);
+        self.exposure_cap.record(exposure.opening, exposure.price);
+    }
+
+    /// Records the broker position book, e.g. from the periodic position audit
+    pub fn on_positions(&mut self, positions: Vec<crate::orders::Position>) {
+        self.broker_positions = positions;
+    }
+
+    // The instance id, or a short hash of it when it would not leave room for a tag
+    fn remarks_prefix(&self) -> String {
+        if self.instance_id.len() + 1 < MAX_REMARKS_LEN / 2 {
+            return self.instance_id.clone();
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(&self.instance_id);
+        let hash = format!("{:x}", hasher.finalize());
+        hash[..8].to_string()
+    }
+
+    /// Remarks to stamp on an order placed by this instance, trimmed to the allowed length
+    pub fn make_remarks(&self, tag: &str) -> String {
+        let mut remarks = format!("{}_{}", self.remarks_prefix(), tag);
+        remarks.truncate(MAX_REMARKS_LEN);
+        remarks
+    }
+
+    /// Remarks unique to one order, to follow it from the place response through the
+    /// order updates to the trade book even when the same symbol is traded again
+    pub fn new_client_order_id(&mut self, tag: &str) -> String {
+        self.client_seq += 1;
+        self.make_remarks(&format!("{}_{}", self.client_seq, tag))
+    }
+
+    /// Records the norenordno the broker gave the order `client_order_id`
+    pub fn record_placed(&mut self, client_order_id: &str, norenordno: &str) {
+        self.client_tbl
+            .insert(client_order_id.to_string(), norenordno.to_string());
+    }
+
+    /// The order placed as `client_order_id`, once an update or the order book has it
+    pub fn get_order_by_client_id(&self, client_order_id: &str) -> Option<&OrderRow> {
+        self.order_tbl.get(self.client_tbl.get(client_order_id)?)
+    }
+
+    /// The trade book fills of the order placed as `client_order_id`
+    #[cfg(test)]
+    pub fn fills_for<'a>(
+        &self,
+        client_order_id: &str,
+        trades: &'a [crate::orders::Trade],
+    ) -> Vec<&'a crate::orders::Trade> {
+        let norenordno = self.client_tbl.get(client_order_id);
+        trades
+            .iter()
+            .filter(|trade| {
+                trade.remarks == client_order_id || Some(&trade.norenordno) == norenordno
+            })
+            .collect()
+    }
+
+    /// Whether the remarks were stamped by this instance
+    pub fn validate_self(&self, remarks: &str) -> bool {
+        remarks.starts_with(&format!("{}_", self.remarks_prefix()))
+    }
+
+    /// Records that the order stamped with `remarks` is being sent now
+    pub fn record_sent(&mut self, remarks: &str) {
+        self.record_sent_at(remarks, Instant::now());
+    }
+
+    fn record_sent_at(&mut self, remarks: &str, at: Instant) {
+        self.sent_tbl.insert(remarks.to_string(), at);
+    }
+
+    /// Round trip from send to fill of the order stamped with `remarks`
+    #[cfg(test)]
+    pub fn get_latency(&self, remarks: &str) -> Option<Duration> {
+        self.latency_tbl.get(remarks).copied()
+    }
+
+    /// One line per filled order with its send to fill latency
+    pub fn latency_report(&self) -> String {
+        let mut lines: Vec<String> = self
+            .latency_tbl
+            .iter()
+            .map(|(remarks, latency)| format!("{} : {} ms", remarks, latency.as_millis()))
+            .collect();
+        lines.sort();
+        lines.join("\n")
+    }
+
+    /// Marks the legs in `tradingsymbol` with the ticks of `symbol`, "EXCH|token"
+    /// as subscribed
+    pub fn add_symbol(&mut self, symbol: &str, tradingsymbol: &str) {
+        self.symbol_tbl
+            .insert(tradingsymbol.to_string(), symbol.to_string());
+    }
+
+    /// Upsert an order update ("t": "om") into the order table
+    pub fn on_order(&mut self, order_data: &serde_json::Value) {
+        let norenordno = match order_data["norenordno"].as_str() {
+            Some(norenordno) => norenordno.to_string(),
+            None => {
+                log::error!("Order update without norenordno: {}", order_data);
+                return;
+            }
+        };
+        let remarks = order_data["remarks"].as_str().unwrap_or("");
+        if !self.validate_self(remarks) {
+            log::debug!("Ignoring other instance order update {}", remarks);
+            return;
+        }
+        let buysell = match order_data["trantype"].as_str() {
+            Some("S") => "SELL",
+            _ => "BUY",
+        };
+        let fillshares = parse_f64(&order_data["fillshares"]).map(|q| q as i64);
+        let flprc = parse_f64(&order_data["flprc"]);
+        let row = self
+            .order_tbl
+            .entry(norenordno.clone())
+            .or_insert_with(|| OrderRow {
+                norenordno: norenordno.clone(),
+                client_order_id: remarks.to_string(),
+                exchange: String::new(),
+                tradingsymbol: String::new(),
+                product: String::new(),
+                buysell: String::new(),
+                qty: -1,
+                avgprice: -1.0,
+                status: String::new(),
+            });
+        row.exchange = order_data["exch"].as_str().unwrap_or("").to_string();
+        row.tradingsymbol = order_data["tsym"].as_str().unwrap_or("").to_string();
+        row.product = order_data["prd"].as_str().unwrap_or("").to_string();
+        row.buysell = buysell.to_string();
+        row.status = order_data["status"].as_str().unwrap_or("").to_string();
+        // fillshares is cumulative while flprc is the price of the latest fill
+        if let (Some(fillshares), Some(flprc)) = (fillshares, flprc) {
+            let filled = row.qty.max(0);
+            if fillshares > filled {
+                let notional = row.avgprice.max(0.0) * filled as f64
+                    + flprc * (fillshares - filled) as f64;
+                row.qty = fillshares;
+                row.avgprice = notional / fillshares as f64;
+            }
+        }
+        log::debug!("Order update: {:?}", row);
+        self.client_tbl
+            .insert(remarks.to_string(), norenordno.clone());
+
+        if row.status == "COMPLETE" && !self.latency_tbl.contains_key(remarks) {
+            if let Some(sent) = self.sent_tbl.get(remarks) {
+                let latency = sent.elapsed();
+                log::info!("Order {} filled in {} ms", norenordno, latency.as_millis());
+                self.latency_tbl.insert(remarks.to_string(), latency);
+            }
+        }
+    }
+
+    /// Reconciles the order table against the broker order book, e.g. after
+    /// a reconnect during which order updates were missed
+    pub fn resync_from_order_book(&mut self, order_book: &serde_json::Value) {
+        let orders = match order_book.as_array() {
+            Some(orders) => orders,
+            None => return,
+        };
+        for order in orders {
+            let remarks = order["remarks"].as_str().unwrap_or("");
+            let norenordno = match order["norenordno"].as_str() {
+                Some(norenordno) if self.validate_self(remarks) => norenordno,
+                _ => continue,
+            };
+            let buysell = match order["trantype"].as_str() {
+                Some("S") => "SELL",
+                _ => "BUY",
+            };
+            // the order book carries the overall average, so it replaces the row
+            let row = OrderRow {
+                norenordno: norenordno.to_string(),
+                client_order_id: remarks.to_string(),
+                exchange: order["exch"].as_str().unwrap_or("").to_string(),
+                tradingsymbol: order["tsym"].as_str().unwrap_or("").to_string(),
+                product: order["prd"].as_str().unwrap_or("").to_string(),
+                buysell: buysell.to_string(),
+                qty: parse_f64(&order["fillshares"]).map_or(-1, |q| q as i64),
+                avgprice: parse_f64(&order["avgprc"]).unwrap_or(-1.0),
+                status: order["status"].as_str().unwrap_or("").to_string(),
+            };
+            log::debug!("Resynced order: {:?}", row);
+            self.client_tbl
+                .insert(remarks.to_string(), norenordno.to_string());
+            self.order_tbl.insert(norenordno.to_string(), row);
+        }
+    }
+
+    /// Tops up the fills of the known orders from the trade book, which can be
+    /// ahead of the order book's average right after a fill
+    pub fn resync_from_trade_book(&mut self, trades: &[crate::orders::Trade]) {
+        for row in self.order_tbl.values_mut() {
+            let fills: Vec<&crate::orders::Trade> = trades
+                .iter()
+                .filter(|trade| trade.norenordno == row.norenordno)
+                .collect();
+            let qty: i64 = fills.iter().map(|trade| trade.qty).sum();
+            if qty <= row.qty.max(0) {
+                continue;
+            }
+            let notional: f64 = fills
+                .iter()
+                .map(|trade| trade.flprc * trade.qty as f64)
+                .sum();
+            row.qty = qty;
+            row.avgprice = notional / qty as f64;
+            log::debug!("Fills resynced from the trade book: {:?}", row);
+        }
+    }
+
+    /// Fetches the order and trade books and reconciles the order table with them
+    pub fn resync(&mut self, auth: &crate::auth::Auth) -> Result<(), Box<dyn std::error::Error>> {
+        let order_book = crate::orders::get_order_book(auth)?;
+        self.resync_from_order_book(&order_book);
+        let trades = crate::orders::get_trade_book(auth)?;
+        self.resync_from_trade_book(&trades);
+        Ok(())
+    }
+
+    pub fn get_order(&self, norenordno: &str) -> Option<&OrderRow> {
+        self.order_tbl.get(norenordno)
+    }
+
+    /// Net filled quantity per symbol and product, flat symbols are left out
+    pub fn open_positions(&self) -> Vec<OpenPosition> {
+        let mut positions: Vec<OpenPosition> = Vec::new();
+        for row in self.order_tbl.values() {
+            if row.qty <= 0 {
+                continue;
+            }
+            let qty = if row.buysell == "SELL" {
+                -row.qty
+            } else {
+                row.qty
+            };
+            match positions.iter_mut().find(|position| {
+                position.tradingsymbol == row.tradingsymbol && position.product == row.product
+            }) {
+                Some(position) => position.net_qty += qty,
+                None => positions.push(OpenPosition {
+                    exchange: row.exchange.clone(),
+                    tradingsymbol: row.tradingsymbol.clone(),
+                    product: row.product.clone(),
+                    net_qty: qty,
+                }),
+            }
+        }
+        positions.retain(|position| position.net_qty != 0);
+        positions.sort_by(|a, b| a.tradingsymbol.cmp(&b.tradingsymbol));
+        positions
+    }
+
+    /// True once every order reached a terminal status and at least one of them
+    /// filled. A cancelled stop-loss or a rejected leg does not hold this back.
+    pub fn all_orders_settled(&self) -> bool {
+        self.order_tbl.values().any(|row| row.qty > 0)
+            && self
+                .order_tbl
+                .values()
+                .all(|row| crate::orders::OrderStatus::from(row.status.as_str()).is_terminal())
+    }
+
+    /// norenordno of the orders still resting at the broker, e.g. an unfilled limit
+    /// entry or a stop-loss waiting for its trigger
+    pub fn open_orders(&self) -> Vec<String> {
+        let mut open: Vec<String> = self
+            .order_tbl
+            .values()
+            .filter(|row| !crate::orders::OrderStatus::from(row.status.as_str()).is_terminal())
+            .map(|row| row.norenordno.clone())
+            .collect();
+        open.sort();
+        open
+    }
+
+    /// Record the latest ltp and best bid/ask from a feed tick
+    pub fn on_tick(&mut self, tick: &Tick) {
+        let symbol = match tick.symbol() {
+            Some(symbol) => symbol,
+            None => return,
+        };
+        let quote = self.ltp_tbl.entry(symbol.clone()).or_default();
+        // a late frame from before a reconnect must not move the price back
+        if let (Some(ft), Some(last)) = (tick.ft, quote.feed_time) {
+            if ft < last {
+                log::debug!("Ignoring stale tick for {}: ft {} < {}", symbol, ft, last);
+                return;
+            }
+        }
+        if tick.ft.is_some() {
+            quote.feed_time = tick.ft;
+        }
+        if let Some(lp) = tick.lp {
+            quote.ltp = lp;
+        }
+        if let Some(bp1) = tick.bp1 {
+            quote.bid = Some(bp1);
+        }
+        if let Some(sp1) = tick.sp1 {
+            quote.ask = Some(sp1);
+        }
+    }
+
+    /// Latest quote merged from the ticks of `symbol`, "EXCH|token", None before
+    /// the first tick
+    pub fn get_quote(&self, symbol: &str) -> Option<&LiveQuote> {
+        self.ltp_tbl.get(symbol)
+    }
+
+    /// Bid-ask spread of `tradingsymbol`, once a tick quoted both sides
+    pub fn spread(&self, tradingsymbol: &str) -> Option<f64> {
+        let quote = self.ltp_tbl.get(self.symbol_tbl.get(tradingsymbol)?)?;
+        Some(quote.ask? - quote.bid?)
+    }
+
+    fn symbol_mark(&self, tradingsymbol: &str, short: bool, mark: MarkPrice) -> Option<f64> {
+        let symbol = self.symbol_tbl.get(tradingsymbol)?;
+        let quote = self.ltp_tbl.get(symbol)?;
+        match mark {
+            MarkPrice::Ltp => Some(quote.ltp),
+            // closing a short means buying at the ask, closing a long means selling at the bid
+            MarkPrice::ExitCost if short => Some(quote.ask.unwrap_or(quote.ltp)),
+            MarkPrice::ExitCost => Some(quote.bid.unwrap_or(quote.ltp)),
+        }
+    }
+
+    /// Returns the total PnL and a per-leg summary, from the ticks or the broker as
+    /// set with `set_pnl_source`. `mark` only applies to the ticks.
+    pub fn get_pnl(&self, mark: MarkPrice) -> (f64, String) {
+        match self.pnl_source {
+            PnlSource::Ticks => self.tick_pnl(mark),
+            PnlSource::Broker => self.broker_pnl(),
+        }
+    }
+
+    fn broker_pnl(&self) -> (f64, String) {
+        let pnl = self
+            .broker_positions
+            .iter()
+            .map(|position| position.pnl())
+            .sum();
+        let pnl_str: Vec<String> = self
+            .broker_positions
+            .iter()
+            .map(|position| {
+                format!(
+                    "{} {} x {} : {:.2}",
+                    position.prd,
+                    position.tsym,
+                    position.netqty,
+                    position.pnl()
+                )
+            })
+            .collect();
+        (pnl, pnl_str.join("\n"))
+    }
+
+    // The PnL of the filled quantity of every order, a partly filled order that
+    // was cancelled included, marked at the latest ticks. All legs of a symbol are
+    // marked at one price picked by the net position, so the offsetting quantity
+    // books at the fill prices and only the open quantity pays the exit side.
+    fn tick_pnl(&self, mark: MarkPrice) -> (f64, String) {
+        let mut prices: HashMap<&str, f64> = HashMap::new();
+        for (tradingsymbol, (bought, bought_value, sold, _)) in self.net_fills() {
+            let net_qty = bought - sold;
+            let price = if net_qty == 0 {
+                // a flat symbol's legs offset each other at any one price
+                self.symbol_mark(tradingsymbol, false, MarkPrice::Ltp)
+                    .unwrap_or(bought_value / bought as f64)
+            } else {
+                match self.symbol_mark(tradingsymbol, net_qty < 0, mark) {
+                    Some(price) => price,
+                    None => continue,
+                }
+            };
+            prices.insert(tradingsymbol, price);
+        }
+
+        let mut pnl = 0.0;
+        let mut pnl_str: Vec<String> = Vec::new();
+        let mut rows: Vec<&OrderRow> = self.order_tbl.values().collect();
+        rows.sort_by(|a, b| a.norenordno.cmp(&b.norenordno));
+        for row in rows {
+            if row.qty <= 0 || row.avgprice < 0.0 {
+                continue;
+            }
+            let price = match prices.get(row.tradingsymbol.as_str()) {
+                Some(price) => *price,
+                None => continue,
+            };
+            let sign = if row.buysell == "SELL" { -1.0 } else { 1.0 };
+            let leg_pnl = sign * (price - row.avgprice) * row.qty as f64;
+            pnl += leg_pnl;
+            pnl_str.push(format!(
+                "{} {} x {} : {:.2}",
+                row.buysell, row.tradingsymbol, row.qty, leg_pnl
+            ));
+        }
+        (pnl, pnl_str.join("\n"))
+    }
+
+    // tradingsymbol -> (bought qty, bought value, sold qty, sold value) over the
+    // filled quantity of the orders
+    fn net_fills(&self) -> HashMap<&str, (i64, f64, i64, f64)> {
+        let mut fills: HashMap<&str, (i64, f64, i64, f64)> = HashMap::new();
+        for row in self.order_tbl.values() {
+            if row.qty <= 0 || row.avgprice < 0.0 {
+                continue;
+            }
+            let symbol = fills.entry(&row.tradingsymbol).or_default();
+            let value = row.avgprice * row.qty as f64;
+            if row.buysell == "SELL" {
+                symbol.2 += row.qty;
+                symbol.3 += value;
+            } else {
+                symbol.0 += row.qty;
+                symbol.1 += value;
+            }
+        }
+        fills
+    }
+
+    /// Net PnL per tradingsymbol over the filled quantity of the orders, e.g. a leg
+    /// built up across several partial fills. Each side's fills are averaged by volume, the
+    /// offsetting quantity is booked at those averages and the net quantity is
+    /// marked to market. A symbol still open but not ticked yet is left out.
+    pub fn get_pnl_by_symbol(&self, mark: MarkPrice) -> HashMap<String, f64> {
+        let mut pnl = HashMap::new();
+        for (tradingsymbol, (bought, bought_value, sold, sold_value)) in self.net_fills() {
+            let net_qty = bought - sold;
+            let open_value = if net_qty == 0 {
+                0.0
+            } else {
+                match self.symbol_mark(tradingsymbol, net_qty < 0, mark) {
+                    Some(price) => net_qty as f64 * price,
+                    None => continue,
+                }
+            };
+            pnl.insert(
+                tradingsymbol.to_string(),
+                sold_value - bought_value + open_value,
+            );
+        }
+        pnl
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transaction::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_get_pnl_exit_cost() {
+        let mut tm = TransactionManager::new();
+        let remarks = tm.make_remarks("straddle");
+        tm.add_symbol("NFO|42216", "NIFTY04JAN24C21800");
+        tm.add_symbol("NFO|42217", "NIFTY04JAN24P21800");
+        tm.on_order(&json!({
+            "norenordno": "1", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "100.00", "remarks": remarks
+        }));
+        tm.on_order(&json!({
+            "norenordno": "2", "tsym": "NIFTY04JAN24P21800", "trantype": "B",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "20.00", "remarks": remarks
+        }));
+        tm.on_tick(
+            &json!({"e": "NFO", "tk": "42216", "lp": "90.00", "bp1": "89.50", "sp1": "91.00"})
+                .into(),
+        );
+        tm.on_tick(
+            &json!({"e": "NFO", "tk": "42217", "lp": "22.00", "bp1": "21.50", "sp1": "22.50"})
+                .into(),
+        );
+
+        let (ltp_pnl, _) = tm.get_pnl(MarkPrice::Ltp);
+        // short: (100 - 90) * 50 = 500, long: (22 - 20) * 50 = 100
+        assert_eq!(ltp_pnl, 600.0);
+
+        let (exit_pnl, _) = tm.get_pnl(MarkPrice::ExitCost);
+        // short buys back at 91: 450, long sells at 21.5: 75
+        assert_eq!(exit_pnl, 525.0);
+        assert!(exit_pnl < ltp_pnl);
+    }
+
+    #[test]
+    fn test_exit_cost_books_closed_leg_at_fills() {
+        let mut tm = TransactionManager::new();
+        let remarks = tm.make_remarks("straddle");
+        tm.add_symbol("NFO|42216", "NIFTY04JAN24C21800");
+        tm.on_order(&json!({
+            "norenordno": "1", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "100.00", "remarks": remarks
+        }));
+        tm.on_order(&json!({
+            "norenordno": "2", "tsym": "NIFTY04JAN24C21800", "trantype": "B",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "80.00", "remarks": remarks
+        }));
+        tm.on_tick(
+            &json!({"e": "NFO", "tk": "42216", "lp": "71.00", "bp1": "70.00", "sp1": "72.00"})
+                .into(),
+        );
+
+        // flat: (100 - 80) * 50, whatever the spread
+        assert_eq!(tm.get_pnl(MarkPrice::ExitCost).0, 1000.0);
+        assert_eq!(tm.get_pnl(MarkPrice::Ltp).0, 1000.0);
+    }
+
+    #[test]
+    fn test_short_leg_profits_when_price_drops() {
+        let mut tm = TransactionManager::new();
+        let remarks = tm.make_remarks("straddle");
+        tm.add_symbol("NFO|42216", "NIFTY04JAN24C21800");
+        tm.on_order(&json!({
+            "norenordno": "1", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "100.00", "remarks": remarks
+        }));
+        tm.on_tick(&json!({"e": "NFO", "tk": "42216", "lp": "80.00"}).into());
+        assert_eq!(tm.get_pnl(MarkPrice::Ltp).0, 1000.0);
+
+        tm.on_tick(&json!({"e": "NFO", "tk": "42216", "lp": "115.00"}).into());
+        assert_eq!(tm.get_pnl(MarkPrice::Ltp).0, -750.0);
+    }
+
+    #[test]
+    fn test_pnl_str_shows_each_leg() {
+        let mut tm = TransactionManager::new();
+        let remarks = tm.make_remarks("strangle");
+        tm.add_symbol("NFO|42216", "NIFTY04JAN24C22000");
+        tm.add_symbol("NFO|42217", "NIFTY04JAN24P21600");
+        tm.on_order(&json!({
+            "norenordno": "1", "tsym": "NIFTY04JAN24C22000", "trantype": "S",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "60.00", "remarks": remarks
+        }));
+        tm.on_order(&json!({
+            "norenordno": "2", "tsym": "NIFTY04JAN24P21600", "trantype": "B",
+            "status": "COMPLETE", "fillshares": "25", "flprc": "40.00", "remarks": remarks
+        }));
+        tm.on_tick(&json!({"e": "NFO", "tk": "42216", "lp": "52.50"}).into());
+        tm.on_tick(&json!({"e": "NFO", "tk": "42217", "lp": "36.00"}).into());
+
+        let (pnl, pnl_str) = tm.get_pnl(MarkPrice::Ltp);
+        assert_eq!(pnl, 275.0);
+        let lines: Vec<&str> = pnl_str.lines().collect();
+        assert_eq!(lines[0], "SELL NIFTY04JAN24C22000 x 50 : 375.00");
+        assert_eq!(lines[1], "BUY NIFTY04JAN24P21600 x 25 : -100.00");
+    }
+
+    #[test]
+    fn test_get_pnl_skips_unfilled_legs() {
+        let mut tm = TransactionManager::new();
+        let remarks = tm.make_remarks("iron_fly");
+        for (symbol, tsym) in [
+            ("NFO|42216", "NIFTY04JAN24C21800"),
+            ("NFO|42217", "NIFTY04JAN24P21800"),
+            ("NFO|42218", "NIFTY04JAN24C22000"),
+            ("NFO|42219", "NIFTY04JAN24P21600"),
+        ] {
+            tm.add_symbol(symbol, tsym);
+        }
+        tm.on_order(&json!({
+            "norenordno": "1", "tsym": "NIFTY04JAN24C21800", "trantype": "B",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "100.00", "remarks": remarks
+        }));
+        tm.on_order(&json!({
+            "norenordno": "2", "tsym": "NIFTY04JAN24P21800", "trantype": "B",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "90.00", "remarks": remarks
+        }));
+        // still open, its qty and avgprice are the -1 placeholders
+        tm.on_order(&json!({
+            "norenordno": "3", "tsym": "NIFTY04JAN24C22000", "trantype": "B",
+            "status": "OPEN", "remarks": remarks
+        }));
+        // filled but never ticked
+        tm.on_order(&json!({
+            "norenordno": "4", "tsym": "NIFTY04JAN24P21600", "trantype": "B",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "30.00", "remarks": remarks
+        }));
+        // complete, but its fill details have not arrived yet
+        tm.on_order(&json!({
+            "norenordno": "5", "tsym": "NIFTY04JAN24C22000", "trantype": "B",
+            "status": "COMPLETE", "remarks": remarks
+        }));
+        tm.on_tick(&json!({"e": "NFO", "tk": "42216", "lp": "120.00"}).into());
+        tm.on_tick(&json!({"e": "NFO", "tk": "42217", "lp": "110.00"}).into());
+        tm.on_tick(&json!({"e": "NFO", "tk": "42218", "lp": "25.00"}).into());
+
+        let (pnl, _) = tm.get_pnl(MarkPrice::Ltp);
+        // (120 - 100) * 50 + (110 - 90) * 50
+        assert_eq!(pnl, 2000.0);
+    }
+
+    #[test]
+    fn test_cancelled_partial_fill_counted() {
+        let mut tm = TransactionManager::new();
+        let remarks = tm.make_remarks("strangle");
+        tm.add_symbol("NFO|42217", "NIFTY04JAN24P21600");
+        // half the limit entry filled before it was cancelled
+        tm.on_order(&json!({
+            "norenordno": "1", "exch": "NFO", "tsym": "NIFTY04JAN24P21600", "prd": "M",
+            "trantype": "S", "status": "OPEN", "fillshares": "25", "flprc": "40.00",
+            "remarks": remarks
+        }));
+        tm.on_order(&json!({
+            "norenordno": "1", "exch": "NFO", "tsym": "NIFTY04JAN24P21600", "prd": "M",
+            "trantype": "S", "status": "CANCELED", "remarks": remarks
+        }));
+        tm.on_tick(&json!({"e": "NFO", "tk": "42217", "lp": "36.00"}).into());
+        assert_eq!(tm.get_pnl(MarkPrice::Ltp).0, 100.0);
+
+        // the square off of the 25 filled
+        tm.on_order(&json!({
+            "norenordno": "2", "exch": "NFO", "tsym": "NIFTY04JAN24P21600", "prd": "M",
+            "trantype": "B", "status": "COMPLETE", "fillshares": "25", "flprc": "36.00",
+            "remarks": remarks
+        }));
+        tm.on_tick(&json!({"e": "NFO", "tk": "42217", "lp": "30.00"}).into());
+        assert!(tm.open_positions().is_empty());
+        // (40 - 36) * 25, flat so the later tick moves nothing
+        assert_eq!(tm.get_pnl(MarkPrice::Ltp).0, 100.0);
+        assert_eq!(
+            tm.get_pnl_by_symbol(MarkPrice::Ltp)["NIFTY04JAN24P21600"],
+            100.0
+        );
+    }
+
+    #[test]
+    fn test_long_remarks_still_validate() {
+        let tm = TransactionManager::with_instance("shoonya_123456_1703999999");
+        let remarks = tm.make_remarks("banknifty_iron_fly_stop_loss_adjustment");
+        assert!(remarks.len() <= MAX_REMARKS_LEN);
+        assert!(tm.validate_self(&remarks));
+
+        let other = TransactionManager::with_instance("shoonya_654321_1703999999");
+        assert!(!other.validate_self(&remarks));
+    }
+
+    #[test]
+    fn test_partial_fills_accumulate() {
+        let mut tm = TransactionManager::new();
+        let remarks = tm.make_remarks("straddle");
+        tm.add_symbol("NFO|42216", "NIFTY04JAN24C21800");
+        tm.on_order(&json!({
+            "norenordno": "1", "tsym": "NIFTY04JAN24C21800", "trantype": "B",
+            "status": "OPEN", "fillshares": "30", "flprc": "100.00", "remarks": remarks
+        }));
+        tm.on_order(&json!({
+            "norenordno": "1", "tsym": "NIFTY04JAN24C21800", "trantype": "B",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "110.00", "remarks": remarks
+        }));
+        tm.on_tick(&json!({"e": "NFO", "tk": "42216", "lp": "104.00"}).into());
+
+        let row = tm.get_order("1").unwrap();
+        assert_eq!(row.qty, 50);
+        // vwap = (30 * 100 + 20 * 110) / 50 = 104
+        assert_eq!(row.avgprice, 104.0);
+
+        let (pnl, pnl_str) = tm.get_pnl(MarkPrice::Ltp);
+        assert_eq!(pnl, 0.0);
+        assert_eq!(pnl_str, "BUY NIFTY04JAN24C21800 x 50 : 0.00");
+    }
+
+    #[test]
+    fn test_trade_book_tops_up_fills() {
+        let mut tm = TransactionManager::new();
+        let remarks = tm.make_remarks("straddle");
+        tm.on_order(&json!({
+            "norenordno": "1", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+            "status": "OPEN", "fillshares": "25", "flprc": "100.00", "remarks": remarks
+        }));
+        // the rest of the order filled, so far only the trade book shows it
+        let trades = crate::orders::parse_trade_book(&json!([
+            {"stat": "Ok", "norenordno": "1", "tsym": "NIFTY04JAN24C21800",
+             "trantype": "S", "flqty": "25", "flprc": "100.00"},
+            {"stat": "Ok", "norenordno": "1", "tsym": "NIFTY04JAN24C21800",
+             "trantype": "S", "flqty": "25", "flprc": "110.00"},
+            {"stat": "Ok", "norenordno": "2", "tsym": "NIFTY04JAN24P21800",
+             "trantype": "S", "flqty": "50", "flprc": "90.00"}
+        ]));
+        tm.resync_from_trade_book(&trades);
+        let row = tm.get_order("1").unwrap();
+        assert_eq!(row.qty, 50);
+        assert_eq!(row.avgprice, 105.0);
+        assert!(tm.get_order("2").is_none());
+    }
+
+    #[test]
+    fn test_send_to_fill_latency() {
+        let mut tm = TransactionManager::new();
+        let remarks = tm.make_remarks("straddle");
+        tm.record_sent_at(&remarks, Instant::now() - Duration::from_millis(150));
+        tm.on_order(&json!({
+            "norenordno": "1", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+            "status": "OPEN", "remarks": remarks
+        }));
+        assert!(tm.get_latency(&remarks).is_none());
+        tm.on_order(&json!({
+            "norenordno": "1", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "100.00", "remarks": remarks
+        }));
+        let latency = tm.get_latency(&remarks).unwrap();
+        assert!(latency >= Duration::from_millis(150));
+        assert!(latency < Duration::from_secs(5));
+        assert!(tm.latency_report().starts_with(&remarks));
+    }
+
+    #[test]
+    fn test_out_of_order_tick_ignored() {
+        let mut tm = TransactionManager::new();
+        let remarks = tm.make_remarks("straddle");
+        tm.add_symbol("NFO|42216", "NIFTY04JAN24C21800");
+        tm.on_order(&json!({
+            "norenordno": "1", "tsym": "NIFTY04JAN24C21800", "trantype": "B",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "100.00", "remarks": remarks
+        }));
+        tm.on_tick(&json!({"e": "NFO", "tk": "42216", "lp": "90.00", "ft": "1704351605"}).into());
+        // an older frame delivered late
+        tm.on_tick(&json!({"e": "NFO", "tk": "42216", "lp": "120.00", "ft": "1704351600"}).into());
+        assert_eq!(tm.get_pnl(MarkPrice::Ltp).0, -500.0);
+
+        // ticks within the same second still apply
+        tm.on_tick(&json!({"e": "NFO", "tk": "42216", "lp": "95.00", "ft": "1704351605"}).into());
+        assert_eq!(tm.get_pnl(MarkPrice::Ltp).0, -250.0);
+    }
+
+    #[test]
+    fn test_same_token_on_another_exchange() {
+        let mut tm = TransactionManager::new();
+        let remarks = tm.make_remarks("straddle");
+        tm.add_symbol("NFO|42216", "NIFTY04JAN24C21800");
+        tm.on_order(&json!({
+            "norenordno": "1", "tsym": "NIFTY04JAN24C21800", "trantype": "B",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "100.00", "remarks": remarks
+        }));
+        tm.on_tick(&json!({"e": "NFO", "tk": "42216", "lp": "90.00", "ft": "1704351600"}).into());
+        // a BFO contract with the same token, and a later feed time
+        tm.on_tick(&json!({"e": "BFO", "tk": "42216", "lp": "550.00", "ft": "1704351610"}).into());
+        assert_eq!(tm.get_pnl(MarkPrice::Ltp).0, -500.0);
+
+        // nor does its feed time make the next NFO tick look stale
+        tm.on_tick(&json!({"e": "NFO", "tk": "42216", "lp": "95.00", "ft": "1704351605"}).into());
+        assert_eq!(tm.get_pnl(MarkPrice::Ltp).0, -250.0);
+        assert_eq!(tm.get_quote("BFO|42216").unwrap().ltp, 550.0);
+    }
+
+    #[test]
+    fn test_order_update_matched_to_client_order_id() {
+        let mut tm = TransactionManager::new();
+        // the same strike sold twice, e.g. a re-entry after a stop-loss
+        let first = tm.new_client_order_id("ce");
+        let second = tm.new_client_order_id("ce");
+        assert_ne!(first, second);
+        assert!(tm.validate_self(&second));
+
+        tm.record_placed(&first, "24010400000001");
+        tm.on_order(&json!({
+            "norenordno": "24010400000002", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "90.00", "remarks": second
+        }));
+        tm.on_order(&json!({
+            "norenordno": "24010400000001", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "100.00", "remarks": first
+        }));
+        let row = tm.get_order_by_client_id(&second).unwrap();
+        assert_eq!(row.norenordno, "24010400000002");
+        assert_eq!(row.avgprice, 90.0);
+        assert_eq!(
+            tm.get_order_by_client_id(&first).unwrap().client_order_id,
+            first
+        );
+
+        let trades = crate::orders::parse_trade_book(&json!([
+            {"stat": "Ok", "norenordno": "24010400000002", "tsym": "NIFTY04JAN24C21800",
+             "trantype": "S", "flqty": "50", "flprc": "90.00", "remarks": second},
+            {"stat": "Ok", "norenordno": "24010400000001", "tsym": "NIFTY04JAN24C21800",
+             "trantype": "S", "flqty": "50", "flprc": "100.00"}
+        ]));
+        let fills = tm.fills_for(&first, &trades);
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].flprc, 100.0);
+        assert_eq!(
+            tm.fills_for(&second, &trades)[0].norenordno,
+            "24010400000002"
+        );
+    }
+
+    #[test]
+    fn test_broker_pnl_source() {
+        let mut tm = TransactionManager::new();
+        let remarks = tm.make_remarks("straddle");
+        tm.add_symbol("NFO|42216", "NIFTY04JAN24C21800");
+        tm.on_order(&json!({
+            "norenordno": "1", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+            "status": "COMPLETE", "fillshares": "50", "flprc": "100.00", "remarks": remarks
+        }));
+        tm.on_tick(&json!({"e": "NFO", "tk": "42216", "lp": "90.00"}).into());
+        tm.on_positions(crate::orders::parse_positions(&json!([
+            {"exch": "NFO", "tsym": "NIFTY04JAN24C21800", "prd": "M", "netqty": "-50",
+             "rpnl": "0.00", "urmtom": "512.50"},
+            {"exch": "NFO", "tsym": "NIFTY04JAN24P21800", "prd": "M", "netqty": "0",
+             "rpnl": "-120.00", "urmtom": "0.00"}
+        ])));
+        assert_eq!(tm.get_pnl(MarkPrice::Ltp).0, 500.0);
+
+        tm.set_pnl_source(PnlSource::Broker);
+        let (pnl, pnl_str) = tm.get_pnl(MarkPrice::Ltp);
+        assert_eq!(pnl, 392.5);
+        assert_eq!(
+            pnl_str,
+            "M NIFTY04JAN24C21800 x -50 : 512.50\nM NIFTY04JAN24P21800 x 0 : -120.00"
+        );
+    }
+
+    #[test]
+    fn test_pnl_by_symbol_across_fills() {
+        let mut tm = TransactionManager::new();
+        let remarks = tm.make_remarks("straddle");
+        tm.add_symbol("NFO|42216", "NIFTY04JAN24C21800");
+        tm.add_symbol("NFO|42217", "NIFTY04JAN24P21800");
+        // the call sold in two orders, the put bought and sold back
+        for (norenordno, tsym, trantype, flprc) in [
+            ("1", "NIFTY04JAN24C21800", "S", "100.00"),
+            ("2", "NIFTY04JAN24C21800", "S", "110.00"),
+            ("3", "NIFTY04JAN24P21800", "B", "20.00"),
+            ("4", "NIFTY04JAN24P21800", "S", "25.00"),
+        ] {
+            tm.on_order(&json!({
+                "norenordno": norenordno, "tsym": tsym, "trantype": trantype,
+                "status": "COMPLETE", "fillshares": "25", "flprc": flprc, "remarks": remarks
+            }));
+        }
+        tm.on_order(&json!({
+            "norenordno": "5", "tsym": "NIFTY04JAN24C21800", "trantype": "S",
+            "status": "OPEN", "fillshares": "0", "remarks": remarks
+        }));
+        tm.on_tick(&json!({"e": "NFO", "tk": "42216", "lp": "90.00", "sp1": "91.00"}).into());
+
+        let pnl = tm.get_pnl_by_symbol(MarkPrice::Ltp);
+        assert_eq!(pnl.len(), 2);
+        // short 50 at an average of 105: (105 - 90) * 50
+        assert_eq!(pnl["NIFTY04JAN24C21800"], 750.0);
+        // the closed put needs no tick: (25 - 20) * 25
+        assert_eq!(pnl["NIFTY04JAN24P21800"], 125.0);
+        // bought back at the ask of 91
+        let exit_pnl = tm.get_pnl_by_symbol(MarkPrice::ExitCost);
+        assert_eq!(exit_pnl["NIFTY04JAN24C21800"], 700.0);
+
+        // no tick for a symbol still open
+        tm.on_order(&json!({
+            "norenordno": "6", "tsym": "NIFTY04JAN24C22000", "trantype": "B",
+            "status": "COMPLETE", "fillshares": "25", "flprc": "50.00", "remarks": remarks
+        }));
+        assert!(!tm
+            .get_pnl_by_symbol(MarkPrice::Ltp)
+            .contains_key("NIFTY04JAN24C22000"));
+    }
+}
@@ -0,0 +1,143 @@
+use crate::urls::{HOST, WATCHLIST, WATCHLIST_ADD, WATCHLIST_DELETE, WATCHLIST_NAMES};
+use serde_json::json;
+
+/// A scrip of a server side watchlist
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchlistScrip {
+    pub exch: String,
+    pub token: String,
+    pub tsym: String,
+}
+
+// Posts `values` to a watchlist endpoint, a reply without "stat": "Ok" is an error
+fn post(
+    auth: &crate::auth::Auth,
+    endpoint: &str,
+    values: &serde_json::Value,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let url = format!("{}{}", HOST, endpoint);
+    let payload = format!("jData={}&jKey={}", values, auth.susertoken);
+    let client = reqwest::blocking::Client::new();
+    let res: String = client.post(&url).body(payload).send()?.text()?;
+
+    let res_dict: serde_json::Value = serde_json::from_str(&res)?;
+    if res_dict["stat"] != "Ok" {
+        return Err(res_dict.to_string().into());
+    }
+
+    Ok(res_dict)
+}
+
+fn parse_watchlist_names(res_dict: &serde_json::Value) -> Vec<String> {
+    res_dict["values"]
+        .as_array()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| name.as_str().map(|name| name.to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn parse_watchlist(res_dict: &serde_json::Value) -> Vec<WatchlistScrip> {
+    let text =
+        |scrip: &serde_json::Value, name: &str| scrip[name].as_str().unwrap_or("").to_string();
+    res_dict["values"]
+        .as_array()
+        .map(|scrips| {
+            scrips
+                .iter()
+                .map(|scrip| WatchlistScrip {
+                    exch: text(scrip, "exch"),
+                    token: text(scrip, "token"),
+                    tsym: text(scrip, "tsym"),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Scrips are "EXCH|token", sent as one field separated by '#'
+fn scrips_values(
+    auth: &crate::auth::Auth,
+    name: &str,
+    scrips: &[&str],
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    if let Some(scrip) = scrips.iter().find(|scrip| !scrip.contains('|')) {
+        return Err(format!("Watchlist scrip {} is not EXCH|token", scrip).into());
+    }
+    Ok(json!({
+        "uid": auth.username,
+        "wlname": name,
+        "scrips": scrips.join("#"),
+    }))
+}
+
+/// Names of the account's watchlists
+pub fn get_watchlist_names(
+    auth: &crate::auth::Auth,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let values = json!({ "uid": auth.username });
+    let res_dict = post(auth, WATCHLIST_NAMES, &values)?;
+    Ok(parse_watchlist_names(&res_dict))
+}
+
+/// Scrips of the watchlist `name`
+pub fn get_watchlist(
+    auth: &crate::auth::Auth,
+    name: &str,
+) -> Result<Vec<WatchlistScrip>, Box<dyn std::error::Error>> {
+    let values = json!({
+        "uid": auth.username,
+        "wlname": name,
+    });
+    Ok(parse_watchlist(&post(auth, WATCHLIST, &values)?))
+}
+
+/// Adds the scrips, each "EXCH|token", to the watchlist `name`
+pub fn add_scrips(
+    auth: &crate::auth::Auth,
+    name: &str,
+    scrips: &[&str],
+) -> Result<(), Box<dyn std::error::Error>> {
+    post(auth, WATCHLIST_ADD, &scrips_values(auth, name, scrips)?)?;
+    Ok(())
+}
+
+/// Removes the scrips, each "EXCH|token", from the watchlist `name`
+pub fn delete_scrips(
+    auth: &crate::auth::Auth,
+    name: &str,
+    scrips: &[&str],
+) -> Result<(), Box<dyn std::error::Error>> {
+    post(auth, WATCHLIST_DELETE, &scrips_values(auth, name, scrips)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_watchlist() {
+        let names = parse_watchlist_names(&json!({"stat": "Ok", "values": ["1", "2", "rolls"]}));
+        assert_eq!(names, vec!["1", "2", "rolls"]);
+
+        let scrips = parse_watchlist(&json!({"stat": "Ok", "values": [
+            {"exch": "NFO", "token": "42216", "tsym": "NIFTY04JAN24C21800", "ls": "50"},
+            {"exch": "NSE", "token": "26000", "tsym": "Nifty 50"}
+        ]}));
+        assert_eq!(scrips.len(), 2);
+        assert_eq!(scrips[0].tsym, "NIFTY04JAN24C21800");
+        assert_eq!(scrips[1].token, "26000");
+        assert!(parse_watchlist(&json!({"stat": "Ok"})).is_empty());
+
+        let mut auth = crate::auth::Auth::new();
+        auth.set_session("FA12345", "pwd", "token");
+        let values = scrips_values(&auth, "rolls", &["NFO|42216", "NFO|42217"]).unwrap();
+        assert_eq!(values["scrips"], "NFO|42216#NFO|42217");
+        assert_eq!(values["wlname"], "rolls");
+        assert!(scrips_values(&auth, "rolls", &["42216"]).is_err());
+    }
+}
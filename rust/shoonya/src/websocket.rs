@@ -0,0 +1,1425 @@
+#![allow(dead_code)]
+
+use crate::urls::WEBSOCKET_ENDPOINT;
+use log::*;
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+
+type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+type SharedAuth = Arc<RwLock<crate::auth::Auth>>;
+type RefreshAuth = Box<dyn FnMut(&mut crate::auth::Auth) -> Result<(), Box<dyn std::error::Error>> + Send>;
+
+// How long a read blocks before the loop gets to send queued frames
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Clock difference with the server above which a warning is logged
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5);
+
+/// The `"t": "ck"` acknowledgement of a successful connect frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectAck {
+    pub uid: Option<String>,
+    /// Server time (`ft`, epoch seconds), when the frame carries one
+    pub server_time: Option<i64>,
+}
+
+impl ConnectAck {
+    /// Parses a "ck" frame, a status other than OK is a failed login
+    pub fn parse(frame: &serde_json::Value) -> Result<ConnectAck, Box<dyn std::error::Error>> {
+        if frame["t"] != "ck" {
+            return Err(format!("Not a connect acknowledgement: {}", frame).into());
+        }
+        if frame["s"] != "OK" {
+            return Err(format!("Websocket login failed: {}", frame).into());
+        }
+        let server_time = match &frame["ft"] {
+            serde_json::Value::String(ft) => ft.parse().ok(),
+            ft => ft.as_i64(),
+        };
+        Ok(ConnectAck {
+            uid: frame["uid"].as_str().map(|uid| uid.to_string()),
+            server_time,
+        })
+    }
+
+    /// Seconds the local clock `now` (epoch seconds) is ahead of the server
+    pub fn clock_skew(&self, now: i64) -> Option<i64> {
+        self.server_time.map(|server_time| now - server_time)
+    }
+}
+
+/// A touchline or depth frame ("t": "tk" / "tf" / "dk" / "df"). Every field is
+/// optional: the "tf" and "df" updates only carry what changed since the last one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Tick {
+    /// Frame type
+    pub t: Option<String>,
+    /// Exchange
+    pub e: Option<String>,
+    /// Token
+    pub tk: Option<String>,
+    /// Trading symbol, sent on the acknowledgement frames only
+    pub ts: Option<String>,
+    /// Last traded price
+    pub lp: Option<f64>,
+    /// Percent change
+    pub pc: Option<f64>,
+    pub o: Option<f64>,
+    pub h: Option<f64>,
+    pub l: Option<f64>,
+    pub c: Option<f64>,
+    /// Average traded price
+    pub ap: Option<f64>,
+    /// Volume
+    pub v: Option<u64>,
+    /// Open interest
+    pub oi: Option<u64>,
+    /// Best bid and ask with their quantities
+    pub bp1: Option<f64>,
+    pub sp1: Option<f64>,
+    pub bq1: Option<u64>,
+    pub sq1: Option<u64>,
+    /// Feed time, epoch seconds
+    pub ft: Option<i64>,
+}
+
+impl Tick {
+    /// Reads the fields present in `frame`, numbers come as strings or numbers
+    pub fn from_value(frame: &serde_json::Value) -> Tick {
+        let text = |key: &str| frame[key].as_str().map(|value| value.to_string());
+        let float = |key: &str| crate::transaction::parse_f64(&frame[key]);
+        let count = |key: &str| parse_u64(&frame[key]);
+        Tick {
+            t: text("t"),
+            e: text("e"),
+            tk: text("tk"),
+            ts: text("ts"),
+            lp: float("lp"),
+            pc: float("pc"),
+            o: float("o"),
+            h: float("h"),
+            l: float("l"),
+            c: float("c"),
+            ap: float("ap"),
+            v: count("v"),
+            oi: count("oi"),
+            bp1: float("bp1"),
+            sp1: float("sp1"),
+            bq1: count("bq1"),
+            sq1: count("sq1"),
+            ft: float("ft").map(|ft| ft as i64),
+        }
+    }
+
+    /// Applies the fields present in `update`, a "tf" frame only carries what
+    /// changed. A frame older than the last one applied is ignored.
+    pub fn apply(&mut self, update: &Tick) {
+        if let (Some(ft), Some(last)) = (update.ft, self.ft) {
+            if ft < last {
+                return;
+            }
+        }
+        fn newer<T: Clone>(field: &mut Option<T>, update: &Option<T>) {
+            if update.is_some() {
+                *field = update.clone();
+            }
+        }
+        newer(&mut self.t, &update.t);
+        newer(&mut self.e, &update.e);
+        newer(&mut self.tk, &update.tk);
+        newer(&mut self.ts, &update.ts);
+        newer(&mut self.lp, &update.lp);
+        newer(&mut self.pc, &update.pc);
+        newer(&mut self.o, &update.o);
+        newer(&mut self.h, &update.h);
+        newer(&mut self.l, &update.l);
+        newer(&mut self.c, &update.c);
+        newer(&mut self.ap, &update.ap);
+        newer(&mut self.v, &update.v);
+        newer(&mut self.oi, &update.oi);
+        newer(&mut self.bp1, &update.bp1);
+        newer(&mut self.sp1, &update.sp1);
+        newer(&mut self.bq1, &update.bq1);
+        newer(&mut self.sq1, &update.sq1);
+        newer(&mut self.ft, &update.ft);
+    }
+
+    /// "EXCH|token" of the instrument, as subscribed
+    pub fn symbol(&self) -> Option<String> {
+        match (&self.e, &self.tk) {
+            (Some(e), Some(tk)) => Some(format!("{}|{}", e, tk)),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Value> for Tick {
+    fn from(frame: serde_json::Value) -> Tick {
+        Tick::from_value(&frame)
+    }
+}
+
+fn parse_u64(value: &serde_json::Value) -> Option<u64> {
+    match value {
+        serde_json::Value::String(value) => value.parse().ok(),
+        value => value.as_u64(),
+    }
+}
+
+/// Levels of the order book sent on the depth frames
+pub const DEPTH_LEVELS: usize = 5;
+
+/// Order book of a token from the depth frames ("t": "dk" / "df"). The levels are
+/// (price, qty, orders), best first, zero where the book is that thin.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Depth {
+    pub e: Option<String>,
+    pub tk: Option<String>,
+    pub bids: [(f64, u64, u64); DEPTH_LEVELS],
+    pub asks: [(f64, u64, u64); DEPTH_LEVELS],
+}
+
+impl Depth {
+    /// Reads a "dk" snapshot
+    pub fn from_value(frame: &serde_json::Value) -> Depth {
+        let mut depth = Depth::default();
+        depth.merge(frame);
+        depth
+    }
+
+    /// Overlays the fields present in `frame`, a "df" update carries only what changed
+    pub fn merge(&mut self, frame: &serde_json::Value) {
+        if let Some(e) = frame["e"].as_str() {
+            self.e = Some(e.to_string());
+        }
+        if let Some(tk) = frame["tk"].as_str() {
+            self.tk = Some(tk.to_string());
+        }
+        let sides = [("b", &mut self.bids), ("s", &mut self.asks)];
+        for (side, levels) in sides {
+            for (i, level) in levels.iter_mut().enumerate() {
+                let field = |name: &str| &frame[format!("{}{}{}", side, name, i + 1)];
+                if let Some(price) = crate::transaction::parse_f64(field("p")) {
+                    level.0 = price;
+                }
+                if let Some(qty) = parse_u64(field("q")) {
+                    level.1 = qty;
+                }
+                if let Some(orders) = parse_u64(field("o")) {
+                    level.2 = orders;
+                }
+            }
+        }
+    }
+
+    /// Quantity bid across the levels, what a sell can hit
+    pub fn bid_qty(&self) -> u64 {
+        self.bids.iter().map(|level| level.1).sum()
+    }
+
+    /// Quantity offered across the levels, what a buy can lift
+    pub fn ask_qty(&self) -> u64 {
+        self.asks.iter().map(|level| level.1).sum()
+    }
+}
+
+/// How often the ticks are written to the debug log, independent of the log level
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TickLog {
+    Off,
+    /// Every Nth tick of a token
+    EveryNth(u64),
+    /// At most one tick of a token per interval
+    Interval(Duration),
+}
+
+// Decides per instrument whether a tick gets logged
+pub struct TickLogThrottle {
+    tick_log: TickLog,
+    // "EXCH|token" -> (ticks seen, last logged)
+    seen: HashMap<String, (u64, Option<Instant>)>,
+}
+
+impl TickLogThrottle {
+    pub fn new(tick_log: TickLog) -> Self {
+        TickLogThrottle {
+            tick_log,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// `symbol` is "EXCH|token", a token alone is ambiguous across exchanges
+    pub fn should_log(&mut self, symbol: &str, now: Instant) -> bool {
+        let (count, last) = self.seen.entry(symbol.to_string()).or_insert((0, None));
+        *count += 1;
+        let log = match self.tick_log {
+            TickLog::Off => false,
+            TickLog::EveryNth(n) => n > 0 && (*count - 1) % n == 0,
+            TickLog::Interval(interval) => match last {
+                Some(last) => now.duration_since(*last) >= interval,
+                None => true,
+            },
+        };
+        if log {
+            *last = Some(now);
+        }
+        log
+    }
+}
+
+/// Connection parameters of the websocket feed
+#[derive(Debug, Clone)]
+pub struct WebSocketConfig {
+    /// Feed URL, `WEBSOCKET_ENDPOINT` unless the vendor host or a test server differs
+    pub endpoint: String,
+    pub source: String,
+    pub heartbeat_interval: Duration,
+    /// Silence after which the connection is taken as half-open and reconnected. A
+    /// ping goes out with every heartbeat and its pong counts as a read, so a live
+    /// feed without ticks stays up; keep it a few heartbeat intervals long.
+    pub read_timeout: Duration,
+    pub connect_timeout: Duration,
+    /// Resends of the connect frame within `connect_timeout` when no "ck" comes back,
+    /// the first frame is sometimes dropped
+    pub connect_frame_retries: u32,
+    /// Open a new session after a drop or an expired token, else stay disconnected
+    pub reconnect: bool,
+    /// Reconnect attempts after a drop before giving up
+    pub max_reconnect_attempts: u32,
+    /// Wait after the first failed reconnect attempt, doubled after each further one
+    pub reconnect_delay: Duration,
+    pub max_reconnect_delay: Duration,
+    /// Flatten the open positions when giving up, a dead feed leaves them unwatched
+    pub flatten_on_give_up: bool,
+    pub tick_log: TickLog,
+    /// A subscription without a tick for this long during market hours is stalled
+    pub stall_timeout: Duration,
+    /// Share of stalled subscriptions from which the whole feed is reconnected
+    pub stall_reconnect_ratio: f64,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            endpoint: WEBSOCKET_ENDPOINT.to_string(),
+            source: "API".to_string(),
+            heartbeat_interval: Duration::from_secs(30),
+            read_timeout: Duration::from_secs(90),
+            connect_timeout: Duration::from_secs(10),
+            connect_frame_retries: 2,
+            reconnect: true,
+            max_reconnect_attempts: 5,
+            reconnect_delay: Duration::from_secs(2),
+            max_reconnect_delay: Duration::from_secs(30),
+            flatten_on_give_up: false,
+            tick_log: TickLog::Interval(Duration::from_secs(1)),
+            stall_timeout: Duration::from_secs(30),
+            stall_reconnect_ratio: 0.5,
+        }
+    }
+}
+
+/// Receives the frames of the websocket feed, called from the reader thread
+pub trait WebSocketCallback: Send {
+    fn on_open(&mut self, _ack: &ConnectAck) {}
+    fn on_close(&mut self) {}
+    /// Order updates ("t": "om")
+    fn on_order(&mut self, _order: &serde_json::Value) {}
+    /// Touchline and depth ticks ("t": "tk" / "tf" / "dk" / "df")
+    fn subscribe_callback(&mut self, _tick: &Tick) {}
+    /// The order book after each depth frame ("t": "dk" / "df")
+    fn depth_callback(&mut self, _depth: &Depth) {}
+    /// The feed is lost for good, after the reconnect attempts ran out
+    fn on_give_up(&mut self) {}
+    /// Closes the open positions, called on give-up with `flatten_on_give_up`
+    fn flatten(&mut self) {}
+}
+
+/// Where the websocket connection stands, see `WebSocketApp::state`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnState {
+    /// Not started, or lost for good after the reconnect attempts ran out
+    Disconnected,
+    /// Opening the first session
+    Connecting,
+    /// Logged in, frames flow both ways
+    Connected,
+    /// The session dropped or expired and a new one is being opened
+    Reconnecting,
+    /// Closed by `close`
+    Closed,
+}
+
+pub struct WebSocketApp {
+    config: WebSocketConfig,
+    state: Arc<Mutex<ConnState>>,
+    tx: Option<mpsc::Sender<Message>>,
+    handle: Option<JoinHandle<()>>,
+    refresh_auth: Option<RefreshAuth>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    // set by `close`, a reconnect in progress stops at the next attempt or wait
+    closing: Arc<AtomicBool>,
+}
+
+// What the server was asked to send, replayed on a new session after a reconnect
+#[derive(Default)]
+struct Subscriptions {
+    // "EXCH|token"
+    instruments: BTreeSet<String>,
+    // account of the order updates
+    orders: Option<String>,
+}
+
+impl Subscriptions {
+    fn frames(&self) -> Vec<serde_json::Value> {
+        let mut frames = Vec::new();
+        if let Some(actid) = &self.orders {
+            frames.push(json!({"t": "o", "actid": actid}));
+        }
+        if !self.instruments.is_empty() {
+            let instruments: Vec<&str> = self.instruments.iter().map(|i| i.as_str()).collect();
+            frames.push(json!({"t": "t", "k": instruments.join("#")}));
+        }
+        frames
+    }
+}
+
+// Why the reader loop stopped
+#[derive(Debug, PartialEq)]
+enum SessionEnd {
+    // closed by us
+    Closed,
+    // closed by the server because the susertoken is no longer valid
+    AuthExpired,
+    // any other close or error
+    Dropped,
+}
+
+// Shoonya closes the socket with a policy violation once the session token is
+// invalid, the reason text is free form and not to be relied on
+fn is_auth_close(frame: &Option<CloseFrame>) -> bool {
+    matches!(frame, Some(frame) if frame.code == CloseCode::Policy)
+}
+
+fn set_read_timeout(socket: &Socket, timeout: Duration) -> std::io::Result<()> {
+    match socket.get_ref() {
+        MaybeTlsStream::Plain(stream) => stream.set_read_timeout(Some(timeout)),
+        MaybeTlsStream::NativeTls(stream) => stream.get_ref().set_read_timeout(Some(timeout)),
+        _ => Ok(()),
+    }
+}
+
+fn is_timeout(e: &tungstenite::Error) -> bool {
+    matches!(e, tungstenite::Error::Io(e)
+        if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut)
+}
+
+impl WebSocketApp {
+    pub fn new(config: WebSocketConfig) -> Self {
+        WebSocketApp {
+            config,
+            state: Arc::new(Mutex::new(ConnState::Disconnected)),
+            tx: None,
+            handle: None,
+            refresh_auth: None,
+            subscriptions: Arc::new(Mutex::new(Subscriptions::default())),
+            closing: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Replaces how the token is refreshed when the server closes an expired
+    /// session, by default `Auth::relogin`
+    pub fn on_auth_expired<F>(&mut self, refresh_auth: F)
+    where
+        F: FnMut(&mut crate::auth::Auth) -> Result<(), Box<dyn std::error::Error>> + Send + 'static,
+    {
+        self.refresh_auth = Some(Box::new(refresh_auth));
+    }
+
+    pub fn config(&self) -> &WebSocketConfig {
+        &self.config
+    }
+
+    /// The `"t": "c"` frame authenticating the session
+    pub fn connect_frame(&self, auth: &crate::auth::Auth) -> serde_json::Value {
+        connect_frame(&self.config, auth)
+    }
+
+    /// Connection state, updated by the reader thread. Orders are safe to send
+    /// once it is `Connected`, the order updates only come over a live session.
+    pub fn state(&self) -> ConnState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Connects, authenticates and starts the reader thread dispatching to `callback`
+    pub fn start_websocket<C: WebSocketCallback + 'static>(
+        &mut self,
+        auth: SharedAuth,
+        mut callback: C,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let state = self.state.clone();
+        let set_state = move |next: ConnState| *state.lock().unwrap() = next;
+        set_state(ConnState::Connecting);
+        self.closing.store(false, Ordering::SeqCst);
+        let (mut socket, mut ack) = match open_session(&self.config, &auth.read().unwrap()) {
+            Ok(session) => session,
+            Err(e) => {
+                set_state(ConnState::Disconnected);
+                return Err(e);
+            }
+        };
+        info!("Websocket connected to {}", self.config.endpoint);
+        set_state(ConnState::Connected);
+
+        let (tx, rx) = mpsc::channel::<Message>();
+        let config = self.config.clone();
+        let mut refresh_auth: RefreshAuth = self
+            .refresh_auth
+            .take()
+            .unwrap_or_else(|| Box::new(|auth| auth.relogin()));
+        let mut throttle = TickLogThrottle::new(config.tick_log);
+        let mut depths = HashMap::new();
+        let subscriptions = self.subscriptions.clone();
+        let closing = self.closing.clone();
+        let handle = std::thread::spawn(move || loop {
+            callback.on_open(&ack);
+            let end = run(
+                &mut socket,
+                &rx,
+                &mut callback,
+                &mut throttle,
+                &mut depths,
+                &config,
+            );
+            // sends any pending close reply so the server releases the session
+            let _ = socket.flush();
+            callback.on_close();
+            match end {
+                SessionEnd::Closed => {
+                    set_state(ConnState::Closed);
+                    return;
+                }
+                SessionEnd::AuthExpired | SessionEnd::Dropped if !config.reconnect => {
+                    set_state(ConnState::Disconnected);
+                    return;
+                }
+                SessionEnd::AuthExpired => {
+                    set_state(ConnState::Reconnecting);
+                    warn!("Websocket session expired, logging in again");
+                    if let Err(e) = refresh_auth(&mut auth.write().unwrap()) {
+                        error!("Re-login failed: {}", e);
+                        set_state(ConnState::Disconnected);
+                        give_up(&config, &mut callback);
+                        return;
+                    }
+                }
+                SessionEnd::Dropped => {
+                    set_state(ConnState::Reconnecting);
+                    warn!("Websocket dropped, reconnecting");
+                }
+            }
+            (socket, ack) = match reconnect(&config, &auth, &closing) {
+                Some(session) => session,
+                // closed by us while reconnecting, not a lost feed
+                None if closing.load(Ordering::SeqCst) => {
+                    info!("Websocket closed while reconnecting");
+                    set_state(ConnState::Closed);
+                    return;
+                }
+                None => {
+                    set_state(ConnState::Disconnected);
+                    give_up(&config, &mut callback);
+                    return;
+                }
+            };
+            info!("Websocket reconnected");
+            set_state(ConnState::Connected);
+            resubscribe(&mut socket, &subscriptions.lock().unwrap());
+        });
+        self.tx = Some(tx);
+        self.handle = Some(handle);
+        Ok(())
+    }
+
+    fn send(&self, frame: serde_json::Value) -> Result<(), Box<dyn std::error::Error>> {
+        let tx = self.tx.as_ref().ok_or("Websocket is not started")?;
+        tx.send(Message::Text(frame.to_string()))?;
+        Ok(())
+    }
+
+    /// Subscribes to touchline ticks, instruments are "EXCH|token". The
+    /// subscription is sent again after a reconnect.
+    pub fn subscribe(&self, instruments: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        for instrument in instruments {
+            subscriptions.instruments.insert(instrument.to_string());
+        }
+        self.send(json!({"t": "t", "k": instruments.join("#")}))
+    }
+
+    pub fn unsubscribe(&self, instruments: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        for instrument in instruments {
+            subscriptions.instruments.remove(*instrument);
+        }
+        self.send(json!({"t": "u", "k": instruments.join("#")}))
+    }
+
+    /// Subscribes to the order updates of the account, again after a reconnect
+    pub fn subscribe_orders(
+        &self,
+        auth: &crate::auth::Auth,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.subscriptions.lock().unwrap().orders = Some(auth.accountid.clone());
+        self.send(json!({"t": "o", "actid": auth.accountid}))
+    }
+
+    /// Closes the socket and waits for the reader thread to finish, the next
+    /// start begins without subscriptions
+    pub fn close(&mut self) {
+        self.closing.store(true, Ordering::SeqCst);
+        *self.subscriptions.lock().unwrap() = Subscriptions::default();
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(Message::Close(None));
+        }
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        *self.state.lock().unwrap() = ConnState::Closed;
+    }
+}
+
+fn connect_frame(config: &WebSocketConfig, auth: &crate::auth::Auth) -> serde_json::Value {
+    json!({
+        "t": "c",
+        "uid": auth.username,
+        "actid": auth.accountid,
+        "susertoken": auth.susertoken,
+        "source": config.source,
+    })
+}
+
+fn connect(config: &WebSocketConfig) -> Result<Socket, Box<dyn std::error::Error>> {
+    let uri: tungstenite::http::Uri = config.endpoint.parse()?;
+    let host = uri.host().ok_or("Websocket endpoint has no host")?;
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("wss") { 443 } else { 80 });
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or("Websocket endpoint did not resolve")?;
+    let stream = TcpStream::connect_timeout(&addr, config.connect_timeout)?;
+    stream.set_read_timeout(Some(config.connect_timeout))?;
+    let (socket, _) =
+        tungstenite::client_tls(config.endpoint.as_str(), stream).map_err(|e| e.to_string())?;
+    Ok(socket)
+}
+
+// Sends the connect frame and waits for the "ck" acknowledgement, resending the
+// frame when a share of the connect timeout passes without one
+fn login(
+    config: &WebSocketConfig,
+    socket: &mut Socket,
+    auth: &crate::auth::Auth,
+) -> Result<ConnectAck, Box<dyn std::error::Error>> {
+    set_read_timeout(socket, POLL_INTERVAL)?;
+    let frames = config.connect_frame_retries + 1;
+    let resend_after = config.connect_timeout / frames;
+    for attempt in 1..=frames {
+        if attempt > 1 {
+            warn!(
+                "No connect acknowledgement within {:?}, resending the connect frame ({}/{})",
+                resend_after, attempt, frames
+            );
+        }
+        socket.send(Message::Text(connect_frame(config, auth).to_string()))?;
+        let deadline = Instant::now() + resend_after;
+        while Instant::now() < deadline {
+            let message = match socket.read() {
+                Ok(message) => message,
+                Err(e) if is_timeout(&e) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if let Message::Text(text) = message {
+                let frame: serde_json::Value = serde_json::from_str(&text)?;
+                if frame["t"] == "ck" {
+                    let ack = ConnectAck::parse(&frame)?;
+                    warn_on_clock_skew(&ack, chrono::Utc::now().timestamp());
+                    return Ok(ack);
+                }
+            }
+        }
+    }
+    Err(format!("No connect acknowledgement within {:?}", config.connect_timeout).into())
+}
+
+// TOTP and the market hours schedule both rely on the local clock
+fn warn_on_clock_skew(ack: &ConnectAck, now: i64) -> bool {
+    match ack.clock_skew(now) {
+        Some(skew) if skew.unsigned_abs() > MAX_CLOCK_SKEW.as_secs() => {
+            warn!("Local clock is {}s off the server clock", skew);
+            true
+        }
+        _ => false,
+    }
+}
+
+// A connected and authenticated socket, ready for the reader loop
+fn open_session(
+    config: &WebSocketConfig,
+    auth: &crate::auth::Auth,
+) -> Result<(Socket, ConnectAck), Box<dyn std::error::Error>> {
+    let mut socket = connect(config)?;
+    let ack = login(config, &mut socket, auth)?;
+    set_read_timeout(&socket, POLL_INTERVAL)?;
+    Ok((socket, ack))
+}
+
+// Wait after the failed reconnect `attempt`, starting at 1
+fn reconnect_backoff(config: &WebSocketConfig, attempt: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+    config
+        .reconnect_delay
+        .saturating_mul(factor)
+        .min(config.max_reconnect_delay)
+}
+
+// Opens a new session, retrying up to the configured attempts with a growing wait.
+// Gives up early once `closing` is set.
+fn reconnect(
+    config: &WebSocketConfig,
+    auth: &SharedAuth,
+    closing: &AtomicBool,
+) -> Option<(Socket, ConnectAck)> {
+    for attempt in 1..=config.max_reconnect_attempts {
+        if closing.load(Ordering::SeqCst) {
+            return None;
+        }
+        match open_session(config, &auth.read().unwrap()) {
+            Ok(session) => return Some(session),
+            Err(e) => error!(
+                "Websocket reconnect attempt {}/{} failed: {}",
+                attempt, config.max_reconnect_attempts, e
+            ),
+        }
+        if attempt < config.max_reconnect_attempts {
+            let until = Instant::now() + reconnect_backoff(config, attempt);
+            while Instant::now() < until && !closing.load(Ordering::SeqCst) {
+                std::thread::sleep(POLL_INTERVAL.min(until - Instant::now()));
+            }
+        }
+    }
+    None
+}
+
+// Sends the subscriptions of the previous session on a new one, a failure shows
+// up as a read error in the reader loop
+fn resubscribe(socket: &mut Socket, subscriptions: &Subscriptions) {
+    for frame in subscriptions.frames() {
+        debug!("Subscribing again: {}", frame);
+        if let Err(e) = socket.send(Message::Text(frame.to_string())) {
+            error!("Websocket resubscribe failed: {}", e);
+            return;
+        }
+    }
+}
+
+fn give_up<C: WebSocketCallback>(config: &WebSocketConfig, callback: &mut C) {
+    error!("Websocket feed lost, giving up");
+    callback.on_give_up();
+    if config.flatten_on_give_up {
+        callback.flatten();
+    }
+}
+
+fn dispatch<C: WebSocketCallback>(
+    callback: &mut C,
+    throttle: &mut TickLogThrottle,
+    depths: &mut HashMap<String, Depth>,
+    text: &str,
+) {
+    let frame: serde_json::Value = match serde_json::from_str(text) {
+        Ok(frame) => frame,
+        Err(e) => {
+            error!("Invalid frame {}: {}", text, e);
+            return;
+        }
+    };
+    let kind = frame["t"].as_str();
+    match kind {
+        Some("tk") | Some("tf") | Some("dk") | Some("df") => {
+            let tick = Tick::from_value(&frame);
+            let symbol = tick.symbol().unwrap_or_default();
+            if throttle.should_log(&symbol, Instant::now()) {
+                debug!("Tick Data: {}", frame);
+            }
+            callback.subscribe_callback(&tick);
+            if let (Some("dk") | Some("df"), Some(symbol)) = (kind, tick.symbol()) {
+                let depth = depths.entry(symbol).or_default();
+                if kind == Some("dk") {
+                    *depth = Depth::from_value(&frame);
+                } else {
+                    depth.merge(&frame);
+                }
+                callback.depth_callback(depth);
+            }
+        }
+        Some("om") => callback.on_order(&frame),
+        _ => debug!("Unhandled frame: {}", frame),
+    }
+}
+
+// Reader loop, interleaves reads with queued frames and heartbeats
+fn run<C: WebSocketCallback>(
+    socket: &mut Socket,
+    rx: &mpsc::Receiver<Message>,
+    callback: &mut C,
+    throttle: &mut TickLogThrottle,
+    depths: &mut HashMap<String, Depth>,
+    config: &WebSocketConfig,
+) -> SessionEnd {
+    let mut last_heartbeat = Instant::now();
+    let mut last_read = Instant::now();
+    loop {
+        while let Ok(message) = rx.try_recv() {
+            let closing = matches!(message, Message::Close(_));
+            if let Err(e) = socket.send(message) {
+                error!("Websocket send failed: {}", e);
+                return SessionEnd::Dropped;
+            }
+            if closing {
+                return SessionEnd::Closed;
+            }
+        }
+        if last_heartbeat.elapsed() >= config.heartbeat_interval {
+            // the server answers the ping even when it has no ticks to send
+            let heartbeat = [
+                Message::Text(json!({"t": "h"}).to_string()),
+                Message::Ping(Vec::new()),
+            ];
+            for message in heartbeat {
+                if let Err(e) = socket.send(message) {
+                    error!("Websocket heartbeat failed: {}", e);
+                    return SessionEnd::Dropped;
+                }
+            }
+            last_heartbeat = Instant::now();
+        }
+        let message = socket.read();
+        if message.is_ok() {
+            last_read = Instant::now();
+        } else if last_read.elapsed() >= config.read_timeout {
+            // a half-open connection never errors, it just stays silent
+            error!("Websocket silent for {:?}", config.read_timeout);
+            return SessionEnd::Dropped;
+        }
+        match message {
+            Ok(Message::Text(text)) => dispatch(callback, throttle, depths, &text),
+            // the pong is queued by the read, send it now rather than on the next read
+            Ok(Message::Ping(_)) => {
+                if let Err(e) = socket.flush() {
+                    error!("Websocket pong failed: {}", e);
+                    return SessionEnd::Dropped;
+                }
+            }
+            Ok(Message::Close(frame)) => {
+                warn!("Websocket closed: {:?}", frame);
+                if is_auth_close(&frame) {
+                    return SessionEnd::AuthExpired;
+                }
+                return SessionEnd::Dropped;
+            }
+            Ok(_) => {}
+            Err(e) if is_timeout(&e) => {}
+            Err(e) => {
+                error!("Websocket read failed: {}", e);
+                return SessionEnd::Dropped;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    // A server accepting one connection, returning the connect frame it received
+    fn mock_server(ack: bool) -> (String, JoinHandle<serde_json::Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("ws://{}/", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let frame = match socket.read().unwrap() {
+                Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                _ => serde_json::Value::Null,
+            };
+            if ack {
+                socket
+                    .send(Message::Text(json!({"t": "ck", "s": "OK", "uid": frame["uid"]}).to_string()))
+                    .unwrap();
+                // wait for the client to close
+                while socket.read().is_ok() {}
+            } else {
+                std::thread::sleep(Duration::from_millis(500));
+            }
+            frame
+        });
+        (endpoint, handle)
+    }
+
+    struct NoopCallback;
+    impl WebSocketCallback for NoopCallback {}
+
+    fn test_auth() -> SharedAuth {
+        let mut auth = crate::auth::Auth::new();
+        auth.set_session("FA12345", "pwd", "token");
+        Arc::new(RwLock::new(auth))
+    }
+
+    // Accepts a connection and acknowledges its connect frame
+    fn accept_login(listener: &TcpListener) -> (WebSocket<TcpStream>, serde_json::Value) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut socket = tungstenite::accept(stream).unwrap();
+        let frame = match socket.read().unwrap() {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            _ => serde_json::Value::Null,
+        };
+        socket
+            .send(Message::Text(json!({"t": "ck", "s": "OK"}).to_string()))
+            .unwrap();
+        (socket, frame)
+    }
+
+    #[test]
+    fn test_connect_frame_uses_config() {
+        let (endpoint, server) = mock_server(true);
+        let config = WebSocketConfig {
+            endpoint,
+            source: "WHITELABEL".to_string(),
+            ..Default::default()
+        };
+        let mut app = WebSocketApp::new(config);
+        assert_eq!(app.state(), ConnState::Disconnected);
+        app.start_websocket(test_auth(), NoopCallback).unwrap();
+        assert_eq!(app.state(), ConnState::Connected);
+        app.close();
+        assert_eq!(app.state(), ConnState::Closed);
+
+        let frame = server.join().unwrap();
+        assert_eq!(frame["t"], "c");
+        assert_eq!(frame["source"], "WHITELABEL");
+        assert_eq!(frame["uid"], "FA12345");
+        assert_eq!(frame["susertoken"], "token");
+    }
+
+    #[test]
+    fn test_connect_timeout_from_config() {
+        let (endpoint, server) = mock_server(false);
+        let config = WebSocketConfig {
+            endpoint,
+            connect_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let mut app = WebSocketApp::new(config);
+        // the server holds the socket open for 500ms, past the configured timeout
+        let e = app.start_websocket(test_auth(), NoopCallback).unwrap_err();
+        assert_eq!(e.to_string(), "No connect acknowledgement within 200ms");
+        assert_eq!(app.state(), ConnState::Disconnected);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_connect_frame_resent() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            // the first connect frame is dropped, the second is acknowledged
+            let mut frames = 0;
+            while let Ok(Message::Text(_)) = socket.read() {
+                frames += 1;
+                if frames == 2 {
+                    socket
+                        .send(Message::Text(json!({"t": "ck", "s": "OK"}).to_string()))
+                        .unwrap();
+                    break;
+                }
+            }
+            while socket.read().is_ok() {}
+            frames
+        });
+
+        let mut app = WebSocketApp::new(WebSocketConfig {
+            endpoint,
+            connect_timeout: Duration::from_millis(600),
+            connect_frame_retries: 1,
+            ..Default::default()
+        });
+        app.start_websocket(test_auth(), NoopCallback).unwrap();
+        app.close();
+        assert_eq!(server.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_auth_close_triggers_relogin() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("ws://{}/", listener.local_addr().unwrap());
+        let (frames_tx, frames_rx) = mpsc::channel();
+        let server = std::thread::spawn(move || {
+            let (mut socket, frame) = accept_login(&listener);
+            frames_tx.send(frame).unwrap();
+            socket
+                .close(Some(CloseFrame {
+                    code: CloseCode::Policy,
+                    reason: "Session Expired".into(),
+                }))
+                .unwrap();
+            while socket.read().is_ok() {}
+
+            let (mut socket, frame) = accept_login(&listener);
+            frames_tx.send(frame).unwrap();
+            while socket.read().is_ok() {}
+        });
+
+        let relogins = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = relogins.clone();
+        let mut app = WebSocketApp::new(WebSocketConfig {
+            endpoint,
+            ..Default::default()
+        });
+        app.on_auth_expired(move |auth| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            auth.susertoken = "fresh_token".to_string();
+            Ok(())
+        });
+        app.start_websocket(test_auth(), NoopCallback).unwrap();
+
+        let first = frames_rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!(first["susertoken"], "token");
+        let second = frames_rx.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert_eq!(second["susertoken"], "fresh_token");
+        assert_eq!(relogins.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        app.close();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_auth_close_without_reconnect() {
+        let close = |code, reason: &str| {
+            Some(CloseFrame {
+                code,
+                reason: reason.to_string().into(),
+            })
+        };
+        assert!(is_auth_close(&close(CloseCode::Policy, "")));
+        assert!(!is_auth_close(&close(CloseCode::Normal, "token refreshed")));
+        assert!(!is_auth_close(&close(CloseCode::Away, "session ended")));
+        assert!(!is_auth_close(&None));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = accept_login(&listener);
+            socket
+                .close(Some(CloseFrame {
+                    code: CloseCode::Policy,
+                    reason: "Session Expired".into(),
+                }))
+                .unwrap();
+            while socket.read().is_ok() {}
+        });
+
+        let relogins = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = relogins.clone();
+        let mut app = WebSocketApp::new(WebSocketConfig {
+            endpoint,
+            reconnect: false,
+            ..Default::default()
+        });
+        app.on_auth_expired(move |_| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        });
+        app.start_websocket(test_auth(), NoopCallback).unwrap();
+        server.join().unwrap();
+
+        let start = Instant::now();
+        while app.state() == ConnState::Connected && start.elapsed() < Duration::from_secs(2) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(app.state(), ConnState::Disconnected);
+        assert_eq!(relogins.load(std::sync::atomic::Ordering::SeqCst), 0);
+        app.close();
+    }
+
+    #[test]
+    fn test_silent_connection_reconnected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("ws://{}/", listener.local_addr().unwrap());
+        let (frames_tx, frames_rx) = mpsc::channel();
+        let server = std::thread::spawn(move || {
+            // the first connection stays open but sends nothing more
+            let (silent, frame) = accept_login(&listener);
+            frames_tx.send(frame).unwrap();
+
+            let (mut socket, frame) = accept_login(&listener);
+            frames_tx.send(frame).unwrap();
+            while socket.read().is_ok() {}
+            drop(silent);
+        });
+
+        let mut app = WebSocketApp::new(WebSocketConfig {
+            endpoint,
+            read_timeout: Duration::from_millis(200),
+            ..Default::default()
+        });
+        app.start_websocket(test_auth(), NoopCallback).unwrap();
+
+        let timeout = Duration::from_secs(2);
+        assert_eq!(frames_rx.recv_timeout(timeout).unwrap()["t"], "c");
+        assert_eq!(frames_rx.recv_timeout(timeout).unwrap()["t"], "c");
+        app.close();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_quiet_feed_kept_alive_by_pongs() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            // no ticks, the reads only answer the pings
+            let (mut socket, _) = accept_login(&listener);
+            let mut pings = 0;
+            while let Ok(message) = socket.read() {
+                if let Message::Ping(_) = message {
+                    pings += 1;
+                }
+            }
+            pings
+        });
+
+        let mut app = WebSocketApp::new(WebSocketConfig {
+            endpoint,
+            heartbeat_interval: Duration::from_millis(50),
+            read_timeout: Duration::from_millis(200),
+            reconnect: false,
+            ..Default::default()
+        });
+        app.start_websocket(test_auth(), NoopCallback).unwrap();
+        std::thread::sleep(Duration::from_millis(600));
+        // a drop would have left it disconnected
+        assert_eq!(app.state(), ConnState::Connected);
+        app.close();
+        assert!(server.join().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_reconnect_resubscribes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("ws://{}/", listener.local_addr().unwrap());
+        let (frames_tx, frames_rx) = mpsc::channel();
+        let server = std::thread::spawn(move || {
+            let read = |socket: &mut WebSocket<TcpStream>| match socket.read().unwrap() {
+                Message::Text(text) => serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+                _ => serde_json::Value::Null,
+            };
+            // the order and touchline subscriptions, then the connection drops
+            let (mut socket, _) = accept_login(&listener);
+            for _ in 0..2 {
+                read(&mut socket);
+            }
+            drop(socket);
+
+            let (mut socket, _) = accept_login(&listener);
+            for _ in 0..2 {
+                frames_tx.send(read(&mut socket)).unwrap();
+            }
+            while socket.read().is_ok() {}
+        });
+
+        struct OpenCallback(mpsc::Sender<()>);
+        impl WebSocketCallback for OpenCallback {
+            fn on_open(&mut self, _ack: &ConnectAck) {
+                self.0.send(()).unwrap();
+            }
+        }
+
+        let (opens_tx, opens_rx) = mpsc::channel();
+        let auth = test_auth();
+        let mut app = WebSocketApp::new(WebSocketConfig {
+            endpoint,
+            ..Default::default()
+        });
+        app.start_websocket(auth.clone(), OpenCallback(opens_tx))
+            .unwrap();
+        app.subscribe_orders(&auth.read().unwrap()).unwrap();
+        app.subscribe(&["NFO|42217", "NFO|42216"]).unwrap();
+
+        let timeout = Duration::from_secs(2);
+        let orders = frames_rx.recv_timeout(timeout).unwrap();
+        assert_eq!(orders, json!({"t": "o", "actid": "FA12345"}));
+        let touchline = frames_rx.recv_timeout(timeout).unwrap();
+        assert_eq!(touchline, json!({"t": "t", "k": "NFO|42216#NFO|42217"}));
+        // once for the first session and again after the reconnect
+        assert!(opens_rx.recv_timeout(timeout).is_ok());
+        assert!(opens_rx.recv_timeout(timeout).is_ok());
+        app.close();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let config = WebSocketConfig {
+            reconnect_delay: Duration::from_secs(2),
+            max_reconnect_delay: Duration::from_secs(30),
+            ..Default::default()
+        };
+        let waits: Vec<u64> = (1..=6)
+            .map(|attempt| reconnect_backoff(&config, attempt).as_secs())
+            .collect();
+        assert_eq!(waits, vec![2, 4, 8, 16, 30, 30]);
+        assert_eq!(reconnect_backoff(&config, 40), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_ping_answered() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut socket, _) = accept_login(&listener);
+            socket.send(Message::Ping(b"keepalive".to_vec())).unwrap();
+            let pong = loop {
+                match socket.read().unwrap() {
+                    Message::Pong(data) => break data,
+                    _ => continue,
+                }
+            };
+            while socket.read().is_ok() {}
+            pong
+        });
+
+        let mut app = WebSocketApp::new(WebSocketConfig {
+            endpoint,
+            ..Default::default()
+        });
+        app.start_websocket(test_auth(), NoopCallback).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        app.close();
+        assert_eq!(server.join().unwrap(), b"keepalive".to_vec());
+    }
+
+    #[test]
+    fn test_give_up_after_reconnect_attempts() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            // drop the connection and stop listening, every reconnect is refused
+            let (socket, _) = accept_login(&listener);
+            drop(socket);
+        });
+
+        struct GiveUpCallback(mpsc::Sender<&'static str>);
+        impl WebSocketCallback for GiveUpCallback {
+            fn on_give_up(&mut self) {
+                self.0.send("give_up").unwrap();
+            }
+            fn flatten(&mut self) {
+                self.0.send("flatten").unwrap();
+            }
+        }
+
+        let (events_tx, events_rx) = mpsc::channel();
+        let mut app = WebSocketApp::new(WebSocketConfig {
+            endpoint,
+            max_reconnect_attempts: 3,
+            reconnect_delay: Duration::from_millis(10),
+            flatten_on_give_up: true,
+            ..Default::default()
+        });
+        app.start_websocket(test_auth(), GiveUpCallback(events_tx))
+            .unwrap();
+        server.join().unwrap();
+
+        let timeout = Duration::from_secs(2);
+        assert_eq!(events_rx.recv_timeout(timeout).unwrap(), "give_up");
+        assert_eq!(app.state(), ConnState::Disconnected);
+        assert_eq!(events_rx.recv_timeout(timeout).unwrap(), "flatten");
+        app.close();
+    }
+
+    #[test]
+    fn test_close_during_backoff_is_quiet() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (socket, _) = accept_login(&listener);
+            drop(socket);
+        });
+
+        struct GiveUpCallback(mpsc::Sender<&'static str>);
+        impl WebSocketCallback for GiveUpCallback {
+            fn on_close(&mut self) {
+                self.0.send("close").unwrap();
+            }
+            fn on_give_up(&mut self) {
+                self.0.send("give_up").unwrap();
+            }
+            fn flatten(&mut self) {
+                self.0.send("flatten").unwrap();
+            }
+        }
+
+        let (events_tx, events_rx) = mpsc::channel();
+        let mut app = WebSocketApp::new(WebSocketConfig {
+            endpoint,
+            max_reconnect_attempts: 5,
+            reconnect_delay: Duration::from_secs(10),
+            flatten_on_give_up: true,
+            ..Default::default()
+        });
+        app.start_websocket(test_auth(), GiveUpCallback(events_tx))
+            .unwrap();
+        server.join().unwrap();
+        assert_eq!(
+            events_rx.recv_timeout(Duration::from_secs(2)).unwrap(),
+            "close"
+        );
+
+        // the first attempt is refused, the close lands in the 10s wait after it
+        std::thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        app.close();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(app.state(), ConnState::Closed);
+        assert!(events_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_tick_log_throttle() {
+        let mut throttle = TickLogThrottle::new(TickLog::Interval(Duration::from_secs(1)));
+        let start = Instant::now();
+        let logged = (0..50)
+            .filter(|i| throttle.should_log("NFO|42216", start + Duration::from_millis(i * 10)))
+            .count();
+        assert_eq!(logged, 1);
+        // other instruments are throttled on their own, the same token on
+        // another exchange included
+        assert!(throttle.should_log("NFO|42217", start));
+        assert!(throttle.should_log("NSE|42216", start));
+        assert!(throttle.should_log("NFO|42216", start + Duration::from_secs(1)));
+
+        let mut throttle = TickLogThrottle::new(TickLog::EveryNth(10));
+        let logged = (0..25)
+            .filter(|_| throttle.should_log("NFO|42216", start))
+            .count();
+        assert_eq!(logged, 3);
+    }
+
+    #[test]
+    fn test_connect_ack_clock_skew() {
+        let frame = json!({"t": "ck", "s": "OK", "uid": "FA12345", "ft": "1704340800"});
+        let ack = ConnectAck::parse(&frame).unwrap();
+        assert_eq!(ack.uid.as_deref(), Some("FA12345"));
+        assert_eq!(ack.server_time, Some(1704340800));
+        // a mock clock 2s and then 30s ahead of the server
+        assert_eq!(ack.clock_skew(1704340802), Some(2));
+        assert!(!warn_on_clock_skew(&ack, 1704340802));
+        assert!(warn_on_clock_skew(&ack, 1704340830));
+        assert!(warn_on_clock_skew(&ack, 1704340770));
+
+        let ack = ConnectAck::parse(&json!({"t": "ck", "s": "OK"})).unwrap();
+        assert_eq!(ack.clock_skew(1704340800), None);
+        assert!(ConnectAck::parse(&json!({"t": "ck", "s": "NOT_OK"})).is_err());
+    }
+
+    #[test]
+    fn test_partial_tick() {
+        let tick = Tick::from_value(&json!({
+            "t": "tk", "e": "NFO", "tk": "42216", "ts": "NIFTY04JAN24C21650",
+            "lp": "101.50", "v": "1250", "bp1": "101.45", "sp1": "101.55", "ft": "1704340800"
+        }));
+        assert_eq!(tick.symbol().as_deref(), Some("NFO|42216"));
+        assert_eq!(tick.lp, Some(101.5));
+        assert_eq!(tick.v, Some(1250));
+        assert_eq!(tick.ft, Some(1704340800));
+
+        // a "tf" update with only the depth moving
+        let tick: Tick = json!({"t": "tf", "e": "NFO", "tk": "42216", "bp1": "101.40"}).into();
+        assert_eq!(tick.lp, None);
+        assert_eq!(tick.bp1, Some(101.4));
+        assert_eq!(tick.sp1, None);
+    }
+
+    #[test]
+    fn test_depth_snapshot_and_update() {
+        struct DepthCallback(Vec<Depth>);
+        impl WebSocketCallback for DepthCallback {
+            fn depth_callback(&mut self, depth: &Depth) {
+                self.0.push(depth.clone());
+            }
+        }
+
+        let mut callback = DepthCallback(Vec::new());
+        let mut throttle = TickLogThrottle::new(TickLog::Off);
+        let mut depths = HashMap::new();
+        let frames = [
+            json!({
+                "t": "dk", "e": "NFO", "tk": "42216", "lp": "101.50",
+                "bp1": "101.45", "bq1": "150", "bo1": "3", "bp2": "101.40", "bq2": "50", "bo2": "1",
+                "sp1": "101.55", "sq1": "100", "so1": "2"
+            }),
+            // only the best ask moved
+            json!({"t": "df", "e": "NFO", "tk": "42216", "sp1": "101.60", "sq1": "25"}),
+            // touchline frames carry no depth
+            json!({"t": "tf", "e": "NFO", "tk": "42216", "lp": "101.55"}),
+        ];
+        for frame in frames {
+            let text = frame.to_string();
+            dispatch(&mut callback, &mut throttle, &mut depths, &text);
+        }
+
+        assert_eq!(callback.0.len(), 2);
+        let depth = &callback.0[1];
+        assert_eq!(depth.tk.as_deref(), Some("42216"));
+        assert_eq!(depth.bids[0], (101.45, 150, 3));
+        assert_eq!(depth.bids[1], (101.40, 50, 1));
+        assert_eq!(depth.bids[2], (0.0, 0, 0));
+        assert_eq!(depth.asks[0], (101.60, 25, 2));
+        assert_eq!(depth.bid_qty(), 200);
+        assert_eq!(depth.ask_qty(), 25);
+
+        // the same token on another exchange keeps a book of its own
+        let frames = [
+            json!({"t": "dk", "e": "BFO", "tk": "42216", "bp1": "55.10", "bq1": "10", "bo1": "1"}),
+            json!({"t": "df", "e": "NFO", "tk": "42216", "bp1": "101.50", "bq1": "75"}),
+            json!({"t": "df", "e": "BFO", "tk": "42216", "sp1": "55.30", "sq1": "20"}),
+        ];
+        for frame in frames {
+            let text = frame.to_string();
+            dispatch(&mut callback, &mut throttle, &mut depths, &text);
+        }
+        assert_eq!(depths.len(), 2);
+        let nfo = &callback.0[3];
+        assert_eq!(nfo.e.as_deref(), Some("NFO"));
+        assert_eq!(nfo.bids[0], (101.50, 75, 3));
+        assert_eq!(nfo.asks[0], (101.60, 25, 2));
+        let bfo = &callback.0[4];
+        assert_eq!(bfo.e.as_deref(), Some("BFO"));
+        assert_eq!(bfo.bids[0], (55.10, 10, 1));
+        assert_eq!(bfo.asks[0], (55.30, 20, 0));
+    }
+}
@@ -1,13 +1,15 @@
-use common::utils::utils::*;
-use scrip_master::scrips::download_scrip;
-use shoonya::auth::auth::Auth;
-use shoonya::markets::markets::{get_indices, get_quote};
-use shoonya::orders::orders::get_order_book;
+use common::utils::*;
+use scrip_master::scrips::{cleanup_old_scrips, download_scrip, scrip_file_path};
+use shoonya::auth::{resolve_redis_url, Auth};
+use shoonya::margin::{calculate_span, get_limits, SpanLeg};
+use shoonya::markets::{get_indices, get_quote_full, get_security_info, PriceSource, NO_QUOTE};
+use shoonya::orders::{get_order_book, OrderBuilder};
 
 use clap::Parser;
 use log::*;
 
 mod logger;
+mod run;
 
 fn build_indices_map(auth: &Auth) -> std::collections::HashMap<String, String> {
     let mut result = std::collections::HashMap::new();
@@ -19,7 +21,7 @@ fn build_indices_map(auth: &Auth) -> std::collections::HashMap<String, String> {
         Exchange::MCX,
     ];
     for exchange in exchanges.iter() {
-        let indices = get_indices(&auth, exchange);
+        let indices = get_indices(auth, exchange);
         match indices {
             Ok(indices) => {
                 let values = indices["values"].as_array().unwrap();
@@ -39,49 +41,73 @@ fn build_indices_map(auth: &Auth) -> std::collections::HashMap<String, String> {
     result
 }
 
-fn get_straddle_strikes(auth: &Auth, index: &str) -> serde_json::Value {
-    // get the config file
-    let config_file = String::from("./common/config.json");
-    let config = load_config(&config_file);
-    let index_token: &str = config["INDICES_TOKEN"][index].as_str().unwrap();
-    let today = chrono::Local::now().format("%Y-%m-%d").to_string();
-    let mut file_name = String::new();
-    let mut exchange: Exchange = Exchange::NSE;
-    let mut index_exchange = Exchange::NSE;
-    match index {
-        "NIFTY" | "BANKNIFTY" | "FINNIFTY" | "MIDCPNIFTY" => {
-            exchange = Exchange::NFO;
-            index_exchange = Exchange::NSE;
-            file_name = format!("./downloads/NFO_symbols_{}.txt", today);
-        }
-        "SENSEX" | "BANKEX" => {
-            exchange = Exchange::BFO;
-            index_exchange = Exchange::BSE;
-            file_name = format!("./downloads/BFO_symbols_{}.txt", today);
-        }
-        "CRUDEOIL" | "GOLD" | "SILVER" => {
-            exchange = Exchange::MCX;
-            index_exchange = Exchange::MCX;
-            file_name = format!("./downloads/MCX_symbols_{}.txt", today);
-        }
-        _ => {
-            info!("Error: {}", "Unknown index");
-            std::process::exit(-1);
-        }
+/// Strikes and premiums of the iron fly, chosen from a `PriceSource`
+#[derive(Debug)]
+struct StrikeSelection {
+    index_quote: f64,
+    atm_strike: f64,
+    otm_strike_ce: f64,
+    otm_strike_pe: f64,
+    ce_code: String,
+    ce_symbol: String,
+    pe_code: String,
+    pe_symbol: String,
+    ce_quote: f64,
+    pe_quote: f64,
+    ce_code_sl: String,
+    ce_symbol_sl: String,
+    pe_code_sl: String,
+    pe_symbol_sl: String,
+    ce_quote_sl: f64,
+    pe_quote_sl: f64,
+}
+
+// The NO_QUOTE sentinel must never reach the premium math, it would give absurd strikes
+fn checked_quote(token: &str, ltp: Option<f64>) -> Result<f64, String> {
+    match ltp {
+        Some(ltp) if ltp != NO_QUOTE => Ok(ltp),
+        _ => Err(format!("No quote for {}, cannot price the fly", token)),
     }
-    download_scrip(&exchange);
-    let (scrip_data, expiry_date) = read_txt_file_as_csv(&file_name, &config_file, &index);
-    info!("Expiry date: {}", expiry_date);
+}
 
-    let index_quote = get_quote(&auth, &index_exchange, index_token);
-    let rounding = config["INDICES_ROUNDING"][index].as_f64().unwrap();
-    let rounded_ltp = (index_quote / rounding).round() * rounding;
+// Quotes both legs, a missing price fails the selection
+fn leg_quotes<P: PriceSource>(
+    prices: &P,
+    exchange: &Exchange,
+    ce: &str,
+    pe: &str,
+) -> Result<(f64, f64), String> {
+    let quotes = prices.ltps(exchange, &[ce, pe]);
+    let ce_quote = checked_quote(ce, quotes.get(ce).copied())?;
+    let pe_quote = checked_quote(pe, quotes.get(pe).copied())?;
+    Ok((ce_quote, pe_quote))
+}
 
-    let (ce_code, ce_symbol) = get_strike_info(&scrip_data, &expiry_date, rounded_ltp, "CE");
-    let (pe_code, pe_symbol) = get_strike_info(&scrip_data, &expiry_date, rounded_ltp, "PE");
+// Sells the ATM straddle and buys wings one straddle premium away
+#[allow(clippy::too_many_arguments)]
+fn select_strikes<P: PriceSource>(
+    prices: &P,
+    exchange: &Exchange,
+    index_exchange: &Exchange,
+    index_token: &str,
+    scrip_data: &[Scrip],
+    expiry_date: chrono::NaiveDate,
+    symbol_name: &str,
+    rounding: f64,
+) -> Result<StrikeSelection, String> {
+    let index_quote = checked_quote(index_token, Some(prices.ltp(index_exchange, index_token)))?;
+    let rounded_ltp = atm_strike(scrip_data, symbol_name, expiry_date, index_quote)
+        .unwrap_or((index_quote / rounding).round() * rounding);
+    info!("ATM strike: {}", format_strike(exchange, rounded_ltp));
 
-    let ce_quote = get_quote(&auth, &exchange, &ce_code);
-    let pe_quote = get_quote(&auth, &exchange, &pe_code);
+    let (ce_code, ce_symbol) =
+        get_strike_info(scrip_data, symbol_name, expiry_date, rounded_ltp, "CE")
+            .map_err(|e| e.to_string())?;
+    let (pe_code, pe_symbol) =
+        get_strike_info(scrip_data, symbol_name, expiry_date, rounded_ltp, "PE")
+            .map_err(|e| e.to_string())?;
+
+    let (ce_quote, pe_quote) = leg_quotes(prices, exchange, &ce_code, &pe_code)?;
 
     let straddle_preimum = ce_quote + pe_quote;
     let otm_strike_ce = rounded_ltp + straddle_preimum;
@@ -92,40 +118,442 @@ fn get_straddle_strikes(auth: &Auth, index: &str) -> serde_json::Value {
 
     // check if the OTM strikes are same as the rounded_ltp
     if otm_strike_ce == rounded_ltp || otm_strike_pe == rounded_ltp {
-        error!("Cannot do the iron fly strategy, exiting!");
-        std::process::exit(-1);
+        return Err("Cannot do the iron fly strategy".to_string());
     }
 
     let (ce_code_sl, ce_symbol_sl) =
-        get_strike_info(&scrip_data, &expiry_date, otm_strike_ce, "CE");
+        get_strike_info(scrip_data, symbol_name, expiry_date, otm_strike_ce, "CE")
+            .map_err(|e| e.to_string())?;
     let (pe_code_sl, pe_symbol_sl) =
-        get_strike_info(&scrip_data, &expiry_date, otm_strike_pe, "PE");
-
-    let ce_quote_sl = get_quote(&auth, &exchange, &ce_code_sl);
-    let pe_quote_sl = get_quote(&auth, &exchange, &pe_code_sl);
-
-    // max diff between ce_strike and otm_strike_ce and pe_strike and otm_strike_pe
-    let max_diff = (otm_strike_ce - rounded_ltp)
-        .abs()
-        .max((otm_strike_pe - rounded_ltp).abs());
-
-    // create a json object
-    let result = serde_json::json!({
-        "ce_code": ce_code,
-        "pe_code": pe_code,
-        "ce_symbol": ce_symbol,
-        "pe_symbol": pe_symbol,
-        "ce_ltp": ce_quote,
-        "pe_ltp": pe_quote,
-        "ce_code_sl": ce_code_sl,
-        "pe_code_sl": pe_code_sl,
-        "ce_symbol_sl": ce_symbol_sl,
-        "pe_symbol_sl": pe_symbol_sl,
-        "ce_ltp_sl": ce_quote_sl,
-        "pe_ltp_sl": pe_quote_sl,
-        "max_diff": max_diff,
-    });
-    result
+        get_strike_info(scrip_data, symbol_name, expiry_date, otm_strike_pe, "PE")
+            .map_err(|e| e.to_string())?;
+
+    let (ce_quote_sl, pe_quote_sl) = leg_quotes(prices, exchange, &ce_code_sl, &pe_code_sl)?;
+
+    Ok(StrikeSelection {
+        index_quote,
+        atm_strike: rounded_ltp,
+        otm_strike_ce,
+        otm_strike_pe,
+        ce_code,
+        ce_symbol,
+        pe_code,
+        pe_symbol,
+        ce_quote,
+        pe_quote,
+        ce_code_sl,
+        ce_symbol_sl,
+        pe_code_sl,
+        pe_symbol_sl,
+        ce_quote_sl,
+        pe_quote_sl,
+    })
+}
+
+// The call and put whose premiums are nearest `premium`, each at least
+// `min_distance` points from the ATM strike so no leg sits where gamma is highest
+#[allow(clippy::too_many_arguments)]
+fn select_strangle<P: PriceSource>(
+    prices: &P,
+    exchange: &Exchange,
+    scrip_data: &[Scrip],
+    expiry_date: chrono::NaiveDate,
+    symbol_name: &str,
+    atm: f64,
+    premium: f64,
+    min_distance: f64,
+) -> Result<StrangleLegs, String> {
+    let pick = |option_type: &str| -> Result<Leg, String> {
+        let candidates: Vec<(f64, &str, &str)> = scrip_data
+            .iter()
+            .filter(|scrip| {
+                scrip.symbol == symbol_name
+                    && scrip.expiry == Some(expiry_date)
+                    && scrip.option_type == option_type
+            })
+            .map(|scrip| {
+                (
+                    scrip.strike,
+                    scrip.token.as_str(),
+                    scrip.trading_symbol.as_str(),
+                )
+            })
+            .filter(|(strike, _, _)| match option_type {
+                "CE" => *strike >= atm + min_distance,
+                _ => *strike <= atm - min_distance,
+            })
+            .collect();
+        let tokens: Vec<&str> = candidates.iter().map(|(_, token, _)| *token).collect();
+        let quotes = prices.ltps(exchange, &tokens);
+        candidates
+            .iter()
+            .filter_map(|(strike, token, tsym)| {
+                let ltp = checked_quote(token, quotes.get(*token).copied()).ok()?;
+                Some(Leg {
+                    token: token.to_string(),
+                    tsym: tsym.to_string(),
+                    strike: *strike,
+                    ltp,
+                })
+            })
+            .min_by(|a, b| (a.ltp - premium).abs().total_cmp(&(b.ltp - premium).abs()))
+            .ok_or_else(|| {
+                format!(
+                    "No {} quoted {} points or more from {}",
+                    option_type, min_distance, atm
+                )
+            })
+    };
+    Ok(StrangleLegs {
+        ce: pick("CE")?,
+        pe: pick("PE")?,
+    })
+}
+
+/// An option leg of the strategy
+#[derive(Debug, Clone, PartialEq)]
+struct Leg {
+    token: String,
+    tsym: String,
+    strike: f64,
+    ltp: f64,
+}
+
+impl Leg {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "token": self.token,
+            "tsym": self.tsym,
+            "strike": self.strike,
+            "ltp": self.ltp,
+        })
+    }
+}
+
+/// The short legs of a strangle
+#[derive(Debug, Clone, PartialEq)]
+struct StrangleLegs {
+    ce: Leg,
+    pe: Leg,
+}
+
+/// The iron fly picked by get_straddle_strikes: the short ATM straddle and its wings
+#[derive(Debug, Clone, PartialEq)]
+struct StrategyLegs {
+    exchange: Exchange,
+    symbol: String,
+    expiry: String,
+    ce: Leg,
+    pe: Leg,
+    ce_hedge: Leg,
+    pe_hedge: Leg,
+    strangle: Option<StrangleLegs>,
+    lot_size: u32,
+    breakevens: (f64, f64),
+    // decimals the exchange takes for the legs' prices
+    price_precision: u32,
+}
+
+impl StrategyLegs {
+    /// The short call and put, then their wings
+    fn legs(&self) -> [&Leg; 4] {
+        [&self.ce, &self.pe, &self.ce_hedge, &self.pe_hedge]
+    }
+
+    /// Premium sold less the premium paid for the wings
+    fn net_credit(&self) -> f64 {
+        self.ce.ltp + self.pe.ltp - self.ce_hedge.ltp - self.pe_hedge.ltp
+    }
+
+    /// Distance of the farther wing from the ATM strike
+    fn wing_width(&self) -> f64 {
+        (self.ce_hedge.strike - self.ce.strike)
+            .abs()
+            .max((self.pe_hedge.strike - self.pe.strike).abs())
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut result = serde_json::json!({
+            "exchange": get_exchange_str(&self.exchange),
+            "symbol": self.symbol,
+            "expiry": self.expiry,
+            "ce": self.ce.to_json(),
+            "pe": self.pe.to_json(),
+            "ce_hedge": self.ce_hedge.to_json(),
+            "pe_hedge": self.pe_hedge.to_json(),
+            "max_diff": self.wing_width(),
+            "lot_size": self.lot_size,
+            "breakeven_lower": self.breakevens.0,
+            "breakeven_upper": self.breakevens.1,
+        });
+        if let Some(strangle) = &self.strangle {
+            result["strangle"] = serde_json::json!({
+                "ce": strangle.ce.to_json(),
+                "pe": strangle.pe.to_json(),
+            });
+        }
+        result
+    }
+}
+
+// Exchange of the index's options and of the index itself
+fn index_exchanges(index: &str) -> Option<(Exchange, Exchange)> {
+    match index {
+        "NIFTY" | "BANKNIFTY" | "FINNIFTY" | "MIDCPNIFTY" => Some((Exchange::NFO, Exchange::NSE)),
+        "SENSEX" | "BANKEX" => Some((Exchange::BFO, Exchange::BSE)),
+        "CRUDEOIL" | "GOLD" | "SILVER" => Some((Exchange::MCX, Exchange::MCX)),
+        _ => None,
+    }
+}
+
+// Whether the options of `index` trade at `now`, an unknown index never does
+fn index_market_open(
+    index: &str,
+    now: chrono::NaiveDateTime,
+    holidays: &[chrono::NaiveDate],
+) -> bool {
+    index_exchanges(index).is_some_and(|(exchange, _)| market_is_open(&exchange, now, holidays))
+}
+
+/// Outcome of each scrips-check step, in the order they ran
+#[derive(Debug)]
+struct ScripReadiness {
+    index: String,
+    checks: Vec<(&'static str, Result<String, String>)>,
+}
+
+impl ScripReadiness {
+    fn ready(&self) -> bool {
+        self.checks.iter().all(|(_, check)| check.is_ok())
+    }
+
+    fn report(&self) {
+        for (name, check) in &self.checks {
+            match check {
+                Ok(detail) => info!("[OK] {}: {}", name, detail),
+                Err(detail) => error!("[FAIL] {}: {}", name, detail),
+            }
+        }
+        if self.ready() {
+            info!("{} is ready to trade", self.index);
+        } else {
+            error!("{} is not ready to trade", self.index);
+        }
+    }
+}
+
+// Checks the scrip file parses and the index resolves to its nearest expiry and
+// ATM strike, stopping at the first failed step
+fn check_scrips<P: PriceSource>(
+    prices: &P,
+    config: &serde_json::Value,
+    index: &str,
+    contents: Result<String, String>,
+) -> ScripReadiness {
+    let mut readiness = ScripReadiness {
+        index: index.to_string(),
+        checks: Vec::new(),
+    };
+    let mut check = |name: &'static str, result: Result<String, String>| {
+        let ok = result.is_ok();
+        readiness.checks.push((name, result));
+        ok
+    };
+
+    let scrip_data = contents.map(|contents| parse_scrip_csv(&contents));
+    let parsed = match &scrip_data {
+        Ok(rows) if rows.is_empty() => Err("no rows".to_string()),
+        Ok(rows) => Ok(format!("{} rows", rows.len())),
+        Err(e) => Err(e.clone()),
+    };
+    if !check("scrip file", parsed) {
+        return readiness;
+    }
+    let scrip_data = scrip_data.unwrap_or_default();
+
+    let symbol = config["SCRIP_SYMBOL_NAME"][index].as_str();
+    let found = match symbol {
+        None => Err(format!("no SCRIP_SYMBOL_NAME for {} in the config", index)),
+        Some(symbol) => {
+            let rows = scrip_data
+                .iter()
+                .filter(|scrip| scrip.symbol == symbol)
+                .count();
+            if rows == 0 {
+                Err(format!("{} is not in the scrip file", symbol))
+            } else {
+                Ok(format!("{} with {} rows", symbol, rows))
+            }
+        }
+    };
+    if !check("symbol", found) {
+        return readiness;
+    }
+    let symbol = symbol.unwrap_or_default();
+
+    let expiry = get_expiry_date(&scrip_data, symbol);
+    let resolved = match expiry {
+        Some(expiry) => Ok(format_expiry(expiry)),
+        None => Err(format!("no expiry of {} from today", symbol)),
+    };
+    if !check("expiry", resolved) {
+        return readiness;
+    }
+    let expiry = expiry.unwrap_or_default();
+
+    let atm = match (
+        index_exchanges(index),
+        config["INDICES_TOKEN"][index].as_str(),
+    ) {
+        (Some((_, index_exchange)), Some(index_token)) => {
+            checked_quote(index_token, Some(prices.ltp(&index_exchange, index_token)))
+                .and_then(|spot| {
+                    atm_strike(&scrip_data, symbol, expiry, spot)
+                        .ok_or(format!("no strikes listed for {}", format_expiry(expiry)))
+                })
+                .and_then(|strike| {
+                    let leg = |opt| get_strike_info(&scrip_data, symbol, expiry, strike, opt);
+                    match (leg("CE"), leg("PE")) {
+                        (Ok((ce, _)), Ok((pe, _))) => {
+                            Ok(format!("{} (CE {}, PE {})", strike, ce, pe))
+                        }
+                        (Err(e), _) | (_, Err(e)) => Err(e.to_string()),
+                    }
+                })
+        }
+        _ => Err(format!("no exchange or INDICES_TOKEN for {}", index)),
+    };
+    check("ATM strike", atm);
+    readiness
+}
+
+fn get_straddle_strikes(
+    auth: &Auth,
+    index: &str,
+    strangle_premium: Option<f64>,
+    min_distance: f64,
+) -> Result<StrategyLegs, String> {
+    // get the config file
+    let config_file = String::from("./common/config.json");
+    let config = load_config(&config_file);
+    let index_token: &str = config["INDICES_TOKEN"][index].as_str().unwrap();
+    let (exchange, index_exchange) =
+        index_exchanges(index).ok_or(format!("Unknown index {}", index))?;
+    let file_name = scrip_file_path(&exchange);
+    let (scrip_data, expiry_date) =
+        read_txt_file_as_csv(&file_name.to_string_lossy(), &config_file, index);
+
+    let rounding = config["INDICES_ROUNDING"][index].as_f64().unwrap();
+    let symbol_name = config["SCRIP_SYMBOL_NAME"][index].as_str().unwrap();
+    let expiry_date = expiry_date.ok_or(format!("No expiry of {} from today", symbol_name))?;
+    info!("Expiry date: {}", format_expiry(expiry_date));
+    let selection = select_strikes(
+        auth,
+        &exchange,
+        &index_exchange,
+        index_token,
+        &scrip_data,
+        expiry_date,
+        symbol_name,
+        rounding,
+    )?;
+    let StrikeSelection {
+        index_quote,
+        atm_strike: rounded_ltp,
+        otm_strike_ce,
+        otm_strike_pe,
+        ce_code,
+        ce_symbol,
+        pe_code,
+        pe_symbol,
+        ce_quote,
+        pe_quote,
+        ce_code_sl,
+        ce_symbol_sl,
+        pe_code_sl,
+        pe_symbol_sl,
+        ce_quote_sl,
+        pe_quote_sl,
+    } = selection;
+    let straddle_preimum = ce_quote + pe_quote;
+
+    // sold the ATM straddle, bought the wings
+    let legs = [
+        OptionLeg {
+            strike: rounded_ltp,
+            option_type: "CE".to_string(),
+            qty: -1,
+        },
+        OptionLeg {
+            strike: rounded_ltp,
+            option_type: "PE".to_string(),
+            qty: -1,
+        },
+        OptionLeg {
+            strike: otm_strike_ce,
+            option_type: "CE".to_string(),
+            qty: 1,
+        },
+        OptionLeg {
+            strike: otm_strike_pe,
+            option_type: "PE".to_string(),
+            qty: 1,
+        },
+    ];
+    let net_credit = straddle_preimum - (ce_quote_sl + pe_quote_sl);
+    let (breakeven_lower, breakeven_upper) = breakevens(&legs, net_credit);
+    info!(
+        "Breakevens: {} - {} (spot {})",
+        format_strike(&exchange, breakeven_lower),
+        format_strike(&exchange, breakeven_upper),
+        index_quote
+    );
+
+    let (lot_size, warning) = get_lot_size(&scrip_data, symbol_name, &config, index);
+    if let Some(warning) = warning {
+        warn!("{}", warning);
+    }
+
+    let strangle = strangle_premium
+        .map(|premium| {
+            select_strangle(
+                auth,
+                &exchange,
+                &scrip_data,
+                expiry_date,
+                symbol_name,
+                rounded_ltp,
+                premium,
+                min_distance,
+            )
+        })
+        .transpose()?;
+
+    let precision = config["PRICE_PRECISION"][index].as_u64().unwrap_or(2) as u32;
+    let ce_quote = round_price(ce_quote, precision);
+    let pe_quote = round_price(pe_quote, precision);
+    let ce_quote_sl = round_price(ce_quote_sl, precision);
+    let pe_quote_sl = round_price(pe_quote_sl, precision);
+
+    let leg = |token: String, tsym: String, strike: f64, ltp: f64| Leg {
+        token,
+        tsym,
+        strike,
+        ltp,
+    };
+    Ok(StrategyLegs {
+        exchange,
+        symbol: symbol_name.to_string(),
+        expiry: format_expiry(expiry_date),
+        ce: leg(ce_code, ce_symbol, rounded_ltp, ce_quote),
+        pe: leg(pe_code, pe_symbol, rounded_ltp, pe_quote),
+        ce_hedge: leg(ce_code_sl, ce_symbol_sl, otm_strike_ce, ce_quote_sl),
+        pe_hedge: leg(pe_code_sl, pe_symbol_sl, otm_strike_pe, pe_quote_sl),
+        strangle,
+        lot_size,
+        breakevens: (breakeven_lower, breakeven_upper),
+        price_precision: precision,
+    })
 }
 
 /// Shoonya Trading Bot
@@ -144,6 +572,10 @@ struct Cli {
     #[clap(short, long, default_value = "1")]
     qty: u32,
 
+    /// Size the position by the most to lose in rupees, instead of --qty
+    #[clap(long)]
+    risk_amount: Option<f64>,
+
     /// Stop loss factor
     #[clap(long, default_value = "30")]
     sl_factor: u32,
@@ -175,6 +607,139 @@ struct Cli {
     /// Credentials file
     #[clap(short, long, default_value = "../cred.yml")]
     credentials_file: String,
+
+    /// Credentials profile to use when the file holds several accounts
+    #[clap(long)]
+    profile: Option<String>,
+
+    /// Print JSON with serde_json::to_string_pretty instead of the compact form
+    #[clap(long)]
+    serde_json: bool,
+
+    /// Place the least liquid leg first and wait for its fill before the next
+    #[clap(long)]
+    sequential_entry: bool,
+
+    /// Send the entry as after market orders, only outside market hours
+    #[clap(long)]
+    amo: bool,
+
+    /// At the day's exit, convert in the money intraday legs to NRML and carry them
+    #[clap(long)]
+    carry_if_itm: bool,
+
+    /// Also pick a strangle, the call and put with the premium nearest this price
+    #[clap(long)]
+    strangle_premium: Option<f64>,
+
+    /// Points from the ATM strike within which no strangle strike is picked
+    #[clap(long, default_value = "0")]
+    min_distance: f64,
+
+    /// Maximum total quantity across all legs
+    #[clap(long)]
+    max_qty: Option<u32>,
+
+    /// Maximum total notional across all legs
+    #[clap(long)]
+    max_notional: Option<f64>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Fetch and print the full quote of a token, then exit
+    Quote {
+        /// Exchange of the token, e.g. NFO
+        exchange: String,
+
+        /// Token to quote
+        token: String,
+    },
+    /// Download and verify the scrip file, report whether the index can be traded, then exit
+    ScripsCheck {
+        /// Index to check
+        #[clap(long, default_value = "BANKNIFTY")]
+        index: String,
+    },
+}
+
+// The iron fly entry: sell the ATM straddle, buy the wings
+fn entry_orders(
+    strategy: &StrategyLegs,
+    qty: u32,
+    amo: bool,
+    market_open: bool,
+) -> Result<Vec<OrderBuilder>, Box<dyn std::error::Error>> {
+    let legs = [
+        ("S", &strategy.ce),
+        ("S", &strategy.pe),
+        ("B", &strategy.ce_hedge),
+        ("B", &strategy.pe_hedge),
+    ];
+    legs.iter()
+        .map(|(buy_or_sell, leg)| {
+            OrderBuilder::new(buy_or_sell, strategy.exchange, &leg.tsym, qty)
+                .price_precision(strategy.price_precision)
+                .after_market(amo, market_open)
+        })
+        .collect()
+}
+
+// SpanCalc legs of the iron fly entry, `qty` per leg
+fn entry_span_legs(strategy: &StrategyLegs, qty: u32) -> Vec<SpanLeg> {
+    let instname = if strategy.exchange == Exchange::MCX {
+        "OPTFUT"
+    } else {
+        "OPTIDX"
+    };
+    let qty = qty as i64;
+    let legs = [
+        ("CE", &strategy.ce, -qty),
+        ("PE", &strategy.pe, -qty),
+        ("CE", &strategy.ce_hedge, qty),
+        ("PE", &strategy.pe_hedge, qty),
+    ];
+    legs.iter()
+        .map(|(option_type, leg, net_qty)| SpanLeg {
+            exchange: strategy.exchange,
+            product: "M".to_string(),
+            instname: instname.to_string(),
+            symname: strategy.symbol.clone(),
+            expiry: strategy.expiry.clone(),
+            option_type: option_type.to_string(),
+            strike: leg.strike,
+            net_qty: *net_qty,
+        })
+        .collect()
+}
+
+// Checks the account can carry the entry before any leg is sent
+fn check_entry_margin(
+    auth: &Auth,
+    strategy: &StrategyLegs,
+    qty: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let margin = calculate_span(auth, &entry_span_legs(strategy, qty))?;
+    info!(
+        "Entry margin: span {:.2} + exposure {:.2}",
+        margin.span, margin.expo
+    );
+    get_limits(auth)?.check(margin.total())
+}
+
+fn run_quote<F>(
+    exchange: &str,
+    token: &str,
+    fetch: F,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>>
+where
+    F: Fn(&Exchange, &str) -> Result<serde_json::Value, Box<dyn std::error::Error>>,
+{
+    let exchange = get_exchange(exchange).ok_or(format!("Unknown exchange {}", exchange))?;
+    fetch(&exchange, token)
 }
 
 fn main() {
@@ -191,31 +756,373 @@ fn main() {
 
     logger::init_logger("shoonya_rust", log_level);
 
+    let json_style = if args.serde_json {
+        JsonStyle::Serde
+    } else {
+        JsonStyle::Custom(3)
+    };
+
+    let config = load_config("./common/config.json");
     let mut auth = Auth::new();
+    auth.set_profile(args.profile.as_deref());
+    auth.set_redis_url(&resolve_redis_url(config["REDIS_URL"].as_str()));
+
+    if let Some(Command::Quote { exchange, token }) = &args.command {
+        auth.login(args.credentials_file.as_str(), args.force);
+        match run_quote(exchange, token, |exchange, token| {
+            get_quote_full(&auth, exchange, token).map(|quote| quote.raw)
+        }) {
+            Ok(quote) => info!("Quote: {}", format_json(&quote, json_style)),
+            Err(e) => error!("Error: {}", e),
+        }
+        return;
+    }
 
-    auth.login(args.credentials_file.as_str(), args.force);
+    if let Some(Command::ScripsCheck { index }) = &args.command {
+        auth.login(args.credentials_file.as_str(), args.force);
+        let contents = match index_exchanges(index) {
+            Some((exchange, _)) => download_scrip(&exchange)
+                .map_err(|e| format!("Scrip download: {}", e))
+                .and_then(|path| {
+                    std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))
+                }),
+            None => Err(format!("Unknown index {}", index)),
+        };
+        let readiness = check_scrips(&auth, &config, index, contents);
+        readiness.report();
+        if !readiness.ready() {
+            std::process::exit(-1);
+        }
+        return;
+    }
+
+    let holidays = load_holidays(&config);
+    let now = chrono::Local::now().naive_local();
+    let market_open = index_market_open(&args.index, now, &holidays);
+    let past_exit = run::past_exit_time(&args.index, now.time());
+    let run_config = run::RunConfig::from_args(&args, market_open, past_exit);
+    let mut steps = run::LiveSteps::new(&args, config, auth, json_style);
+    if let Err(e) = run::run_strategy(&mut steps, &run_config) {
+        error!("{}, exiting!", e);
+        std::process::exit(-1);
+    }
+}
 
-    let order_book = get_order_book(&auth);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    match order_book {
-        Ok(order_book) => {
-            info!("Order book: {}", order_book);
+    #[test]
+    fn test_quote_subcommand() {
+        let args = Cli::try_parse_from(["shoonya_rust", "quote", "NFO", "12345"]).unwrap();
+        let (exchange, token) = match args.command {
+            Some(Command::Quote { exchange, token }) => (exchange, token),
+            _ => panic!("expected the quote subcommand"),
+        };
+        let quote = run_quote(&exchange, &token, |exchange, token| {
+            assert_eq!(*exchange, Exchange::NFO);
+            assert_eq!(token, "12345");
+            Ok(serde_json::json!({"stat": "Ok", "tk": token, "lp": "101.50"}))
+        })
+        .unwrap();
+        assert_eq!(quote["lp"], "101.50");
+
+        assert!(run_quote("XYZ", "12345", |_, _| Ok(serde_json::json!({}))).is_err());
+    }
+
+    fn expiry() -> chrono::NaiveDate {
+        chrono::NaiveDate::from_ymd_opt(2024, 1, 4).unwrap()
+    }
+
+    fn option_row(token: &str, strike: f64, option_type: &str) -> Scrip {
+        Scrip {
+            exchange: "NFO".to_string(),
+            token: token.to_string(),
+            lot_size: 50,
+            symbol: "NIFTY".to_string(),
+            trading_symbol: format!("NIFTY04JAN24{}{}", &option_type[..1], strike),
+            expiry: Some(expiry()),
+            instrument: "OPTIDX".to_string(),
+            option_type: option_type.to_string(),
+            strike,
+            tick_size: 0.05,
         }
-        Err(e) => {
-            info!("Error: {}", e);
+    }
+
+    #[test]
+    fn test_select_strikes_from_history() {
+        use shoonya::markets::{Candle, HistoricalPrices};
+
+        let at =
+            chrono::NaiveDateTime::parse_from_str("04-01-2024 09:20", "%d-%m-%Y %H:%M").unwrap();
+        let candle = |intc: f64| Candle {
+            time: at,
+            into: intc,
+            inth: intc,
+            intl: intc,
+            intc,
+            intv: 0,
+            intvwap: None,
+        };
+        let mut prices = HistoricalPrices::new(at);
+        prices.add_series(&Exchange::NSE, "26000", vec![candle(21640.0)]);
+        for (token, ltp) in [("1", 110.0), ("2", 90.0), ("3", 20.0), ("4", 15.0)] {
+            prices.add_series(&Exchange::NFO, token, vec![candle(ltp)]);
         }
+        let scrip_data = vec![
+            option_row("1", 21650.0, "CE"),
+            option_row("2", 21650.0, "PE"),
+            option_row("3", 21850.0, "CE"),
+            option_row("4", 21450.0, "PE"),
+        ];
+
+        let selection = select_strikes(
+            &prices,
+            &Exchange::NFO,
+            &Exchange::NSE,
+            "26000",
+            &scrip_data,
+            expiry(),
+            "NIFTY",
+            50.0,
+        )
+        .unwrap();
+        assert_eq!(selection.atm_strike, 21650.0);
+        assert_eq!(selection.otm_strike_ce, 21850.0);
+        assert_eq!(selection.otm_strike_pe, 21450.0);
+        assert_eq!(selection.ce_code_sl, "3");
+        assert_eq!(selection.pe_quote_sl, 15.0);
+
+        // no candle yet for the replay time
+        prices.set_time(at - chrono::Duration::minutes(5));
+        assert!(select_strikes(
+            &prices,
+            &Exchange::NFO,
+            &Exchange::NSE,
+            "26000",
+            &scrip_data,
+            expiry(),
+            "NIFTY",
+            50.0,
+        )
+        .is_err());
+
+        // the call wing is not listed in the chain
+        prices.set_time(at);
+        let error = select_strikes(
+            &prices,
+            &Exchange::NFO,
+            &Exchange::NSE,
+            "26000",
+            &scrip_data[..2],
+            expiry(),
+            "NIFTY",
+            50.0,
+        )
+        .unwrap_err();
+        assert_eq!(error, "No NIFTY 21850 CE in the 04-JAN-2024 chain");
     }
 
-    let indices_map = build_indices_map(&auth);
+    #[test]
+    fn test_strangle_skips_strikes_near_atm() {
+        struct Ltps(std::collections::HashMap<String, f64>);
+        impl PriceSource for Ltps {
+            fn ltp(&self, _exchange: &Exchange, token: &str) -> f64 {
+                self.0.get(token).copied().unwrap_or(NO_QUOTE)
+            }
+        }
+
+        let mut scrip_data = Vec::new();
+        let mut ltps = std::collections::HashMap::new();
+        for (token, strike, option_type, ltp) in [
+            ("1", 21650.0, "CE", 110.0),
+            ("2", 21750.0, "CE", 70.0),
+            ("3", 21850.0, "CE", 40.0),
+            ("4", 21950.0, "CE", 20.0),
+            ("5", 21650.0, "PE", 90.0),
+            ("6", 21550.0, "PE", 55.0),
+            ("7", 21450.0, "PE", 25.0),
+            ("8", 21350.0, "PE", 10.0),
+        ] {
+            scrip_data.push(option_row(token, strike, option_type));
+            ltps.insert(token.to_string(), ltp);
+        }
+        let prices = Ltps(ltps);
+        let select = |min_distance: f64| {
+            select_strangle(
+                &prices,
+                &Exchange::NFO,
+                &scrip_data,
+                expiry(),
+                "NIFTY",
+                21650.0,
+                60.0,
+                min_distance,
+            )
+        };
+
+        let strangle = select(0.0).unwrap();
+        assert_eq!(strangle.ce.strike, 21750.0);
+        assert_eq!(strangle.pe.strike, 21550.0);
+
+        // the strikes nearest the premium are within 150 points of the ATM strike
+        let strangle = select(150.0).unwrap();
+        assert_eq!(strangle.ce.strike, 21850.0);
+        assert_eq!(strangle.ce.tsym, "NIFTY04JAN24C21850");
+        assert_eq!(strangle.pe.strike, 21450.0);
+        assert_eq!(strangle.pe.ltp, 25.0);
 
-    // log the indices_map
-    for (idxname, token) in indices_map.iter() {
-        info!("{}: {}", idxname, token);
+        assert!(select(400.0).is_err());
     }
 
-    let straddle_strikes = get_straddle_strikes(&auth, args.index.as_str());
-    info!(
-        "Straddle strikes: {}",
-        pretty_print_json(&straddle_strikes, 3)
-    );
+    #[test]
+    fn test_sentinel_quote_aborts_selection() {
+        struct NoCallQuote;
+        impl PriceSource for NoCallQuote {
+            fn ltp(&self, _exchange: &Exchange, token: &str) -> f64 {
+                match token {
+                    "26000" => 21640.0,
+                    "1" => NO_QUOTE,
+                    _ => 90.0,
+                }
+            }
+        }
+        let scrip_data = vec![
+            option_row("1", 21650.0, "CE"),
+            option_row("2", 21650.0, "PE"),
+        ];
+        let error = select_strikes(
+            &NoCallQuote,
+            &Exchange::NFO,
+            &Exchange::NSE,
+            "26000",
+            &scrip_data,
+            expiry(),
+            "NIFTY",
+            50.0,
+        )
+        .unwrap_err();
+        assert_eq!(error, "No quote for 1, cannot price the fly");
+    }
+
+    fn iron_fly() -> StrategyLegs {
+        let leg = |token: &str, tsym: &str, strike: f64, ltp: f64| Leg {
+            token: token.to_string(),
+            tsym: tsym.to_string(),
+            strike,
+            ltp,
+        };
+        StrategyLegs {
+            exchange: Exchange::NFO,
+            symbol: "NIFTY".to_string(),
+            expiry: "04-JAN-2024".to_string(),
+            ce: leg("1", "NIFTY04JAN24C21650", 21650.0, 110.0),
+            pe: leg("2", "NIFTY04JAN24P21650", 21650.0, 90.0),
+            ce_hedge: leg("3", "NIFTY04JAN24C21850", 21850.0, 20.0),
+            pe_hedge: leg("4", "NIFTY04JAN24P21450", 21450.0, 15.0),
+            strangle: None,
+            lot_size: 50,
+            breakevens: (21485.0, 21815.0),
+            price_precision: 2,
+        }
+    }
+
+    #[test]
+    fn test_strategy_legs() {
+        let strategy = iron_fly();
+        assert_eq!(strategy.ce.tsym, "NIFTY04JAN24C21650");
+        assert_eq!(strategy.pe_hedge.token, "4");
+        assert_eq!(strategy.net_credit(), 165.0);
+        assert_eq!(strategy.wing_width(), 200.0);
+        assert!(strategy.strangle.is_none());
+
+        let json = strategy.to_json();
+        assert_eq!(json["ce_hedge"]["ltp"], 20.0);
+        assert_eq!(json["max_diff"], 200.0);
+        assert!(json.get("strangle").is_none());
+    }
+
+    #[test]
+    fn test_amo_entry_orders() {
+        let strategy = iron_fly();
+        let args = Cli::try_parse_from(["shoonya_rust", "--amo"]).unwrap();
+        let orders = entry_orders(&strategy, 50, args.amo, false).unwrap();
+        assert_eq!(orders.len(), 4);
+        assert!(entry_orders(&strategy, 50, args.amo, true).is_err());
+        assert!(entry_orders(&strategy, 50, false, true).is_ok());
+    }
+
+    #[test]
+    fn test_index_market_open() {
+        // a Thursday evening, after the equity close
+        let evening = chrono::NaiveDate::from_ymd_opt(2024, 1, 4)
+            .unwrap()
+            .and_hms_opt(16, 0, 0)
+            .unwrap();
+        assert!(!index_market_open("NIFTY", evening, &[]));
+        assert!(index_market_open("CRUDEOIL", evening, &[]));
+        assert!(!index_market_open("XYZ", evening, &[]));
+    }
+
+    #[test]
+    fn test_entry_span_legs() {
+        let legs = entry_span_legs(&iron_fly(), 50);
+        assert_eq!(legs.len(), 4);
+        assert_eq!(legs[0].net_qty, -50);
+        assert_eq!(legs[0].instname, "OPTIDX");
+        assert_eq!(legs[3].option_type, "PE");
+        assert_eq!(legs[3].strike, 21450.0);
+        assert_eq!(legs[3].net_qty, 50);
+    }
+
+    #[test]
+    fn test_scrips_check() {
+        struct Spot;
+        impl PriceSource for Spot {
+            fn ltp(&self, _exchange: &Exchange, _token: &str) -> f64 {
+                21640.0
+            }
+        }
+        let args =
+            Cli::try_parse_from(["shoonya_rust", "scrips-check", "--index", "NIFTY"]).unwrap();
+        let index = match args.command {
+            Some(Command::ScripsCheck { index }) => index,
+            _ => panic!("expected the scrips-check subcommand"),
+        };
+
+        let expiry = (chrono::Local::now() + chrono::Duration::days(7))
+            .format("%d-%b-%Y")
+            .to_string()
+            .to_uppercase();
+        let mut contents =
+            String::from("Exchange,Token,Symbol,TradingSymbol,Expiry,OptionType,StrikePrice\n");
+        for (token, strike, option_type) in [("1", "21650.00", "CE"), ("2", "21650.00", "PE")] {
+            contents.push_str(&format!(
+                "NFO,{},NIFTY,NIFTY{},{},{},{}\n",
+                token, option_type, expiry, option_type, strike
+            ));
+        }
+        let config = serde_json::json!({
+            "INDICES_TOKEN": {"NIFTY": "26000"},
+            "SCRIP_SYMBOL_NAME": {"NIFTY": "NIFTY"},
+        });
+
+        let readiness = check_scrips(&Spot, &config, &index, Ok(contents.clone()));
+        assert!(readiness.ready());
+        assert_eq!(readiness.checks.len(), 4);
+        assert_eq!(readiness.checks[2].1, Ok(expiry));
+        assert_eq!(readiness.checks[3].1, Ok("21650 (CE 1, PE 2)".to_string()));
+
+        // the symbol was renamed in the scrip file
+        let renamed = serde_json::json!({
+            "INDICES_TOKEN": {"NIFTY": "26000"},
+            "SCRIP_SYMBOL_NAME": {"NIFTY": "NIFTY50"},
+        });
+        let readiness = check_scrips(&Spot, &renamed, &index, Ok(contents));
+        assert!(!readiness.ready());
+        assert_eq!(readiness.checks.len(), 2);
+
+        let missing = check_scrips(&Spot, &config, &index, Err("no file".to_string()));
+        assert!(!missing.ready());
+    }
 }
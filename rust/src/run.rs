@@ -0,0 +1,770 @@
+use crate::*;
+use shoonya::order_manager::OrderManager;
+use shoonya::orders::ExposureCap;
+use shoonya::runner::{EntryMode, ExitRules, PositionAudit, StrategyRunner, WarmUp};
+use shoonya::transaction::MarkPrice;
+use shoonya::websocket::{ConnState, WebSocketConfig};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+// Minutes before the close at which the legs left open are squared off
+const EXIT_BEFORE_CLOSE: i64 = 10;
+// How long a sequential entry waits on the fill of a leg before giving up
+const ENTRY_FILL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What the run is asked to do, fixed before the first step
+#[derive(Debug, Clone)]
+pub(crate) struct RunConfig {
+    pub(crate) index: String,
+    pub(crate) amo: bool,
+    pub(crate) market_open: bool,
+    /// Cap on the quantity and notional held open across the legs
+    pub(crate) max_qty: Option<u32>,
+    pub(crate) max_notional: Option<f64>,
+    /// Send the least liquid leg first and wait for each fill, see `EntryMode`
+    pub(crate) sequential_entry: bool,
+    /// Keep the legs in the money at the day's exit overnight
+    pub(crate) carry_if_itm: bool,
+    /// Started at or after the day's exit, see `past_exit_time`
+    pub(crate) past_exit: bool,
+}
+
+impl RunConfig {
+    pub(crate) fn from_args(args: &Cli, market_open: bool, past_exit: bool) -> Self {
+        RunConfig {
+            index: args.index.clone(),
+            amo: args.amo,
+            market_open,
+            past_exit,
+            max_qty: args.max_qty,
+            max_notional: args.max_notional,
+            sequential_entry: args.sequential_entry,
+            carry_if_itm: args.carry_if_itm,
+        }
+    }
+}
+
+// Time of day at which the legs still open on `exchange` are squared off
+fn exit_time(exchange: &Exchange) -> chrono::NaiveTime {
+    let (_, close) = market_hours(exchange);
+    close - chrono::Duration::minutes(EXIT_BEFORE_CLOSE)
+}
+
+/// Whether `now` is at or after the day's exit for `index`, an entry would be
+/// squared off as soon as it is placed. An unknown index never is.
+pub(crate) fn past_exit_time(index: &str, now: chrono::NaiveTime) -> bool {
+    index_exchanges(index).is_some_and(|(exchange, _)| now >= exit_time(&exchange))
+}
+
+/// The steps of a trading day, taken in this order by `run_strategy`. Each
+/// returns why it failed, and no later step runs after a failure.
+pub(crate) trait StrategySteps {
+    fn validate_config(&mut self, index: &str) -> Result<(), String>;
+    fn login(&mut self) -> Result<(), String>;
+    /// The scrip file of the index is downloaded and resolves an ATM strike
+    fn ensure_scrips(&mut self, index: &str) -> Result<(), String>;
+    fn resolve_strikes(&mut self, index: &str) -> Result<StrategyLegs, String>;
+    /// Quantity per leg, once the account is known to carry it
+    fn size_position(&mut self, strategy: &StrategyLegs) -> Result<u32, String>;
+    /// Connects the websocket and subscribes to the order updates. Every order
+    /// sent from then on is held to `cap`.
+    fn connect(&mut self, cap: ExposureCap) -> Result<(), String>;
+    /// Subscribes to the ticks of the legs
+    fn subscribe_ticks(&mut self, strategy: &StrategyLegs) -> Result<(), String>;
+    /// Returns the norenordno of each entry order
+    fn place_entries(
+        &mut self,
+        orders: Vec<OrderBuilder>,
+        mode: EntryMode,
+    ) -> Result<Vec<String>, String>;
+    /// Blocks until the MTM exit or the day's exit time
+    fn monitor(&mut self, strategy: &StrategyLegs, qty: u32) -> Result<(), String>;
+    /// Keeps the legs in the money open past `shutdown`, returning their tradingsymbols
+    fn carry_itm_legs(&mut self, strategy: &StrategyLegs) -> Result<Vec<String>, String>;
+    /// Cancels the resting orders and squares off the legs not carried
+    fn shutdown(&mut self) -> Result<(), String>;
+}
+
+// Prefixes the error of a step with its name, so the log shows where the run stopped
+fn step<T>(name: &str, result: Result<T, String>) -> Result<T, String> {
+    match result {
+        Ok(value) => {
+            debug!("Step {} done", name);
+            Ok(value)
+        }
+        Err(e) => Err(format!("{} failed: {}", name, e)),
+    }
+}
+
+// The instruments to stream, the four legs of the fly
+fn leg_symbols(strategy: &StrategyLegs) -> Vec<String> {
+    let exchange = get_exchange_str(&strategy.exchange);
+    strategy
+        .legs()
+        .iter()
+        .map(|leg| format!("{}|{}", exchange, leg.token))
+        .collect()
+}
+
+// The whole entry must fit in the cap, a fly cut short halfway is naked. The
+// market legs are valued at the premium the strikes were picked at.
+fn check_entry_exposure(
+    cap: &ExposureCap,
+    strategy: &StrategyLegs,
+    qty: u32,
+) -> Result<(), String> {
+    let mut cap = cap.clone();
+    for leg in strategy.legs() {
+        cap.check(qty, leg.ltp).map_err(|e| e.to_string())?;
+        cap.record(qty, leg.ltp);
+    }
+    Ok(())
+}
+
+/// Runs the day from the config check to the exit, stopping at the first failed step.
+/// An AMO run ends once its entry is queued.
+pub(crate) fn run_strategy<S: StrategySteps>(
+    steps: &mut S,
+    config: &RunConfig,
+) -> Result<(), String> {
+    step("validate config", steps.validate_config(&config.index))?;
+    // the monitoring would square off a fresh entry at once, an AMO waits for the open
+    if config.past_exit && !config.amo {
+        return Err(format!(
+            "entry refused: past the {} exit time",
+            config.index
+        ));
+    }
+    step("login", steps.login())?;
+    step("ensure scrips", steps.ensure_scrips(&config.index))?;
+    let strategy = step("resolve strikes", steps.resolve_strikes(&config.index))?;
+    let qty = step("size position", steps.size_position(&strategy))?;
+    let orders = entry_orders(&strategy, qty, config.amo, config.market_open)
+        .map_err(|e| format!("entry orders failed: {}", e))?;
+    let cap = ExposureCap::new(config.max_qty, config.max_notional);
+    check_entry_exposure(&cap, &strategy, qty).map_err(|e| format!("entry refused: {}", e))?;
+    step("connect", steps.connect(cap))?;
+    step("subscribe ticks", steps.subscribe_ticks(&strategy))?;
+    let mode = if config.sequential_entry {
+        EntryMode::Sequential {
+            fill_timeout: ENTRY_FILL_TIMEOUT,
+        }
+    } else {
+        EntryMode::Simultaneous
+    };
+    let placed = step("place entries", steps.place_entries(orders, mode))?;
+    info!("Entry placed: {}", placed.join(", "));
+    // the AMO legs wait for the next open, a shutdown now would cancel them
+    if config.amo {
+        info!("AMO entry queued for the next session");
+        return Ok(());
+    }
+    // the legs are flattened however the monitoring ended
+    let monitored = step("monitor", steps.monitor(&strategy, qty));
+    if config.carry_if_itm {
+        match steps.carry_itm_legs(&strategy) {
+            Ok(carried) => info!("Carried overnight: {:?}", carried),
+            Err(e) => error!("Carrying the legs failed, squaring off every leg: {}", e),
+        }
+    }
+    step("shutdown", steps.shutdown())?;
+    monitored
+}
+
+/// The config holds everything the index needs to be traded
+pub(crate) fn validate_config(config: &serde_json::Value, index: &str) -> Result<(), String> {
+    if index_exchanges(index).is_none() {
+        return Err(format!("Unknown index {}", index));
+    }
+    if config["INDICES_TOKEN"][index].as_str().is_none() {
+        return Err(format!("no INDICES_TOKEN for {}", index));
+    }
+    if config["INDICES_ROUNDING"][index].as_f64().is_none() {
+        return Err(format!("no INDICES_ROUNDING for {}", index));
+    }
+    if config["SCRIP_SYMBOL_NAME"][index].as_str().is_none() {
+        return Err(format!("no SCRIP_SYMBOL_NAME for {}", index));
+    }
+    Ok(())
+}
+
+/// The steps against the broker, as set by the command line
+pub(crate) struct LiveSteps<'a> {
+    args: &'a Cli,
+    config: serde_json::Value,
+    json_style: JsonStyle,
+    auth: Arc<RwLock<Auth>>,
+    om: Option<OrderManager>,
+    // checks the order table against the position book while connected
+    audit: Option<PositionAudit>,
+    // the exits the monitor ran with, the day end decides the carry with them
+    exit_rules: Option<ExitRules>,
+}
+
+impl<'a> LiveSteps<'a> {
+    pub(crate) fn new(
+        args: &'a Cli,
+        config: serde_json::Value,
+        auth: Auth,
+        json_style: JsonStyle,
+    ) -> Self {
+        LiveSteps {
+            args,
+            config,
+            json_style,
+            auth: Arc::new(RwLock::new(auth)),
+            om: None,
+            audit: None,
+            exit_rules: None,
+        }
+    }
+
+    fn connected(&mut self) -> Result<&mut OrderManager, String> {
+        self.om.as_mut().ok_or("not connected".to_string())
+    }
+}
+
+impl StrategySteps for LiveSteps<'_> {
+    fn validate_config(&mut self, index: &str) -> Result<(), String> {
+        validate_config(&self.config, index)?;
+        let today = chrono::Local::now().date_naive();
+        if !is_trading_day(today, &load_holidays(&self.config)) {
+            return Err(format!("{} is not a trading day", today));
+        }
+        Ok(())
+    }
+
+    fn login(&mut self) -> Result<(), String> {
+        let mut auth = self.auth.write().unwrap();
+        auth.login(self.args.credentials_file.as_str(), self.args.force);
+        match get_order_book(&auth) {
+            Ok(order_book) => info!("Order book: {}", order_book),
+            Err(e) => info!("Error: {}", e),
+        }
+        for (idxname, token) in build_indices_map(&auth).iter() {
+            info!("{}: {}", idxname, token);
+        }
+        Ok(())
+    }
+
+    fn ensure_scrips(&mut self, index: &str) -> Result<(), String> {
+        let auth = self.auth.read().unwrap();
+        let (exchange, _) = index_exchanges(index).ok_or(format!("Unknown index {}", index))?;
+        auth.check_exchange(&exchange).map_err(|e| e.to_string())?;
+        let path = download_scrip(&exchange).map_err(|e| format!("Scrip download: {}", e))?;
+        // yesterday's dumps are never read again
+        let keep_days = self.config["SCRIP_KEEP_DAYS"].as_u64().unwrap_or(7) as u32;
+        if let Err(e) = cleanup_old_scrips(keep_days) {
+            warn!("Could not clean up old scrip files: {}", e);
+        }
+        let contents =
+            std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e));
+        let readiness = check_scrips(&*auth, &self.config, index, contents);
+        readiness.report();
+        if !readiness.ready() {
+            return Err(format!("{} is not ready to trade", index));
+        }
+        Ok(())
+    }
+
+    fn resolve_strikes(&mut self, index: &str) -> Result<StrategyLegs, String> {
+        let auth = self.auth.read().unwrap();
+        let mut strategy = get_straddle_strikes(
+            &auth,
+            index,
+            self.args.strangle_premium,
+            self.args.min_distance,
+        )?;
+        info!(
+            "Straddle strikes: {}",
+            format_json(&strategy.to_json(), self.json_style)
+        );
+        // the exchange's lot size and price precision win over the scrip file and config
+        match get_security_info(&auth, &strategy.exchange, &strategy.ce.token) {
+            Ok(security_info) => {
+                if security_info.ls != strategy.lot_size {
+                    warn!(
+                        "Lot size is {} live, not {}",
+                        security_info.ls, strategy.lot_size
+                    );
+                    strategy.lot_size = security_info.ls;
+                }
+                strategy.price_precision = security_info.pp;
+            }
+            Err(e) => warn!(
+                "Security info unavailable, keeping lot size {}: {}",
+                strategy.lot_size, e
+            ),
+        }
+        Ok(strategy)
+    }
+
+    fn size_position(&mut self, strategy: &StrategyLegs) -> Result<u32, String> {
+        let lots = match self.args.risk_amount {
+            Some(risk_amount) => {
+                let max_loss_per_lot = iron_fly_max_loss(
+                    strategy.wing_width(),
+                    strategy.net_credit(),
+                    strategy.lot_size,
+                );
+                match lots_for_risk(max_loss_per_lot, risk_amount)? {
+                    0 => {
+                        return Err(format!(
+                            "Risk of {} does not cover one lot losing {}",
+                            risk_amount, max_loss_per_lot
+                        ))
+                    }
+                    lots => lots,
+                }
+            }
+            None => self.args.qty,
+        };
+        let qty = lots * strategy.lot_size;
+        info!("Quantity per leg: {}", qty);
+        check_entry_margin(&self.auth.read().unwrap(), strategy, qty).map_err(|e| e.to_string())?;
+        Ok(qty)
+    }
+
+    fn connect(&mut self, cap: ExposureCap) -> Result<(), String> {
+        let mut ws_config = WebSocketConfig::default();
+        if let Some(endpoint) = self.config["WEBSOCKET_ENDPOINT"].as_str() {
+            ws_config.endpoint = endpoint.to_string();
+        }
+        let mut om = OrderManager::new(self.auth.clone(), ws_config);
+        om.limit_exposure(cap);
+        om.start().map_err(|e| e.to_string())?;
+        let interval = self.config["POSITION_AUDIT_SECS"].as_u64().unwrap_or(60);
+        self.audit = Some(PositionAudit::start(
+            self.auth.clone(),
+            om.transactions(),
+            Duration::from_secs(interval.max(1)),
+        ));
+        self.om = Some(om);
+        Ok(())
+    }
+
+    fn subscribe_ticks(&mut self, strategy: &StrategyLegs) -> Result<(), String> {
+        let om = self.connected()?;
+        let symbols = leg_symbols(strategy);
+        for (leg, symbol) in strategy.legs().iter().zip(&symbols) {
+            om.track(symbol, &leg.tsym, leg.ltp);
+        }
+        let symbols: Vec<&str> = symbols.iter().map(|symbol| symbol.as_str()).collect();
+        om.subscribe(&symbols).map_err(|e| e.to_string())
+    }
+
+    fn place_entries(
+        &mut self,
+        orders: Vec<OrderBuilder>,
+        mode: EntryMode,
+    ) -> Result<Vec<String>, String> {
+        let om = self.connected()?;
+        if om.feed_state() != ConnState::Connected {
+            return Err(format!("feed is {:?}, no order updates", om.feed_state()));
+        }
+        let placed = om.place_entry(orders, mode);
+        placed.map_err(|e| {
+            // a partial fly is naked, take back the legs already sent
+            let _ = self.shutdown();
+            e.to_string()
+        })
+    }
+
+    fn monitor(&mut self, strategy: &StrategyLegs, qty: u32) -> Result<(), String> {
+        let stop_mtm = self.args.risk_amount.unwrap_or(iron_fly_max_loss(
+            strategy.wing_width(),
+            strategy.net_credit(),
+            qty,
+        ));
+        let target_mtm = self.args.target_mtm as f64;
+        let interval = Duration::from_secs(self.args.pnl_display_interval.max(1) as u64);
+        let exit_at = exit_time(&strategy.exchange);
+        let rules = ExitRules {
+            target_mtm,
+            stop_mtm,
+            mark: MarkPrice::ExitCost,
+            warmup: WarmUp::UntilFilled,
+        };
+        self.exit_rules = Some(rules.clone());
+        let om = self.connected()?;
+        om.exit_on_mtm(StrategyRunner::new(rules));
+        info!(
+            "Exit at MTM {:.2} or -{:.2}, else at {}",
+            target_mtm, stop_mtm, exit_at
+        );
+        while !om.day_over() && chrono::Local::now().time() < exit_at {
+            std::thread::sleep(interval);
+            let (pnl, pnl_str) = om.transactions().lock().unwrap().get_pnl(MarkPrice::Ltp);
+            info!("PnL: {:.2}\n{}", pnl, pnl_str);
+            if let Err(e) = om.check_feed(true) {
+                error!("Feed check failed: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn carry_itm_legs(&mut self, strategy: &StrategyLegs) -> Result<Vec<String>, String> {
+        let rules = self.exit_rules.clone().ok_or("not monitored")?;
+        let index = self.args.index.as_str();
+        let token = self.config["INDICES_TOKEN"][index]
+            .as_str()
+            .ok_or(format!("no INDICES_TOKEN for {}", index))?
+            .to_string();
+        let (_, index_exchange) =
+            index_exchanges(index).ok_or(format!("Unknown index {}", index))?;
+        let spot = self.auth.read().unwrap().ltp(&index_exchange, &token);
+        let om = self.connected()?;
+        // the MTM exit already squared off every leg
+        if om.day_over() {
+            return Ok(Vec::new());
+        }
+        if spot == NO_QUOTE {
+            return Err(format!("no quote for {}", index));
+        }
+        info!("{} at {:.2} at the day end", strategy.symbol, spot);
+        om.carry_overnight(&StrategyRunner::new(rules).carry_if_itm(true), spot)
+            .map_err(|e| e.to_string())
+    }
+
+    fn shutdown(&mut self) -> Result<(), String> {
+        // the square off would show as drift to an audit still running
+        if let Some(mut audit) = self.audit.take() {
+            audit.stop();
+        }
+        self.connected()?.shutdown();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leg(token: &str, strike: f64, ltp: f64) -> Leg {
+        Leg {
+            token: token.to_string(),
+            tsym: format!("NIFTY04JAN24{}", strike),
+            strike,
+            ltp,
+        }
+    }
+
+    fn strategy() -> StrategyLegs {
+        StrategyLegs {
+            exchange: Exchange::NFO,
+            symbol: "NIFTY".to_string(),
+            expiry: "04-JAN-2024".to_string(),
+            ce: leg("1", 21650.0, 110.0),
+            pe: leg("2", 21650.0, 90.0),
+            ce_hedge: leg("3", 21850.0, 20.0),
+            pe_hedge: leg("4", 21450.0, 15.0),
+            strangle: None,
+            lot_size: 50,
+            breakevens: (21485.0, 21815.0),
+            price_precision: 2,
+        }
+    }
+
+    #[derive(Default)]
+    struct MockSteps {
+        calls: Vec<&'static str>,
+        fail_at: Option<&'static str>,
+        symbols: Vec<String>,
+        entries: Vec<serde_json::Value>,
+        cap: Option<ExposureCap>,
+        mode: Option<EntryMode>,
+    }
+
+    impl MockSteps {
+        fn call(&mut self, name: &'static str) -> Result<(), String> {
+            self.calls.push(name);
+            match self.fail_at {
+                Some(fail_at) if fail_at == name => Err("mock failure".to_string()),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl StrategySteps for MockSteps {
+        fn validate_config(&mut self, _: &str) -> Result<(), String> {
+            self.call("validate_config")
+        }
+        fn login(&mut self) -> Result<(), String> {
+            self.call("login")
+        }
+        fn ensure_scrips(&mut self, _: &str) -> Result<(), String> {
+            self.call("ensure_scrips")
+        }
+        fn resolve_strikes(&mut self, _: &str) -> Result<StrategyLegs, String> {
+            self.call("resolve_strikes").map(|_| strategy())
+        }
+        fn size_position(&mut self, strategy: &StrategyLegs) -> Result<u32, String> {
+            self.call("size_position").map(|_| 2 * strategy.lot_size)
+        }
+        fn connect(&mut self, cap: ExposureCap) -> Result<(), String> {
+            self.cap = Some(cap);
+            self.call("connect")
+        }
+        fn subscribe_ticks(&mut self, strategy: &StrategyLegs) -> Result<(), String> {
+            self.symbols = leg_symbols(strategy);
+            self.call("subscribe_ticks")
+        }
+        fn place_entries(
+            &mut self,
+            orders: Vec<OrderBuilder>,
+            mode: EntryMode,
+        ) -> Result<Vec<String>, String> {
+            self.mode = Some(mode);
+            let auth = Auth::new();
+            self.entries = orders
+                .iter()
+                .map(|order| order.build_payload(&auth))
+                .collect();
+            self.call("place_entries")?;
+            Ok(orders
+                .iter()
+                .enumerate()
+                .map(|(i, _)| i.to_string())
+                .collect())
+        }
+        fn monitor(&mut self, _: &StrategyLegs, _: u32) -> Result<(), String> {
+            self.call("monitor")
+        }
+        fn carry_itm_legs(&mut self, strategy: &StrategyLegs) -> Result<Vec<String>, String> {
+            self.call("carry_itm_legs")
+                .map(|_| vec![strategy.ce.tsym.clone()])
+        }
+        fn shutdown(&mut self) -> Result<(), String> {
+            self.call("shutdown")
+        }
+    }
+
+    #[test]
+    fn test_run_strategy_through_entry() {
+        let config = RunConfig {
+            index: "NIFTY".to_string(),
+            amo: false,
+            market_open: true,
+            max_qty: None,
+            max_notional: None,
+            sequential_entry: false,
+            carry_if_itm: false,
+            past_exit: false,
+        };
+        let mut steps = MockSteps::default();
+        run_strategy(&mut steps, &config).unwrap();
+        assert_eq!(
+            steps.calls,
+            [
+                "validate_config",
+                "login",
+                "ensure_scrips",
+                "resolve_strikes",
+                "size_position",
+                "connect",
+                "subscribe_ticks",
+                "place_entries",
+                "monitor",
+                "shutdown",
+            ]
+        );
+        assert_eq!(steps.symbols, ["NFO|1", "NFO|2", "NFO|3", "NFO|4"]);
+        let sides: Vec<&str> = steps
+            .entries
+            .iter()
+            .map(|order| order["trantype"].as_str().unwrap())
+            .collect();
+        assert_eq!(sides, ["S", "S", "B", "B"]);
+        assert!(steps.entries.iter().all(|order| order["qty"] == "100"));
+        assert_eq!(steps.mode, Some(EntryMode::Simultaneous));
+
+        // --sequential-entry hands the legs over to be sent one fill at a time
+        let sequential = RunConfig {
+            sequential_entry: true,
+            ..config.clone()
+        };
+        let mut steps = MockSteps::default();
+        run_strategy(&mut steps, &sequential).unwrap();
+        assert_eq!(
+            steps.mode,
+            Some(EntryMode::Sequential {
+                fill_timeout: ENTRY_FILL_TIMEOUT
+            })
+        );
+        assert_eq!(steps.entries.len(), 4);
+
+        // --carry-if-itm keeps the legs in the money before the rest are squared off
+        let carry = RunConfig {
+            carry_if_itm: true,
+            ..config.clone()
+        };
+        let mut steps = MockSteps::default();
+        run_strategy(&mut steps, &carry).unwrap();
+        assert_eq!(steps.calls[8..], ["monitor", "carry_itm_legs", "shutdown"]);
+        // a failed carry or monitor still leaves the account flat
+        for fail_at in ["carry_itm_legs", "monitor"] {
+            let mut steps = MockSteps {
+                fail_at: Some(fail_at),
+                ..Default::default()
+            };
+            let result = run_strategy(&mut steps, &carry);
+            assert_eq!(result.is_err(), fail_at == "monitor");
+            assert_eq!(steps.calls.last(), Some(&"shutdown"));
+        }
+
+        // a failed step stops the run before anything is sent
+        let mut steps = MockSteps {
+            fail_at: Some("ensure_scrips"),
+            ..Default::default()
+        };
+        let e = run_strategy(&mut steps, &config).unwrap_err();
+        assert_eq!(e, "ensure scrips failed: mock failure");
+        assert_eq!(steps.calls, ["validate_config", "login", "ensure_scrips"]);
+        assert!(steps.entries.is_empty());
+
+        // an AMO inside market hours is refused before connecting
+        let amo = RunConfig {
+            amo: true,
+            ..config
+        };
+        let mut steps = MockSteps::default();
+        assert!(run_strategy(&mut steps, &amo).is_err());
+        assert!(!steps.calls.contains(&"connect"));
+    }
+
+    #[test]
+    fn test_amo_run_stops_after_entry() {
+        let config = RunConfig {
+            index: "NIFTY".to_string(),
+            amo: true,
+            market_open: false,
+            max_qty: None,
+            max_notional: None,
+            sequential_entry: false,
+            carry_if_itm: true,
+            past_exit: false,
+        };
+        let mut steps = MockSteps::default();
+        run_strategy(&mut steps, &config).unwrap();
+        assert_eq!(steps.calls.last(), Some(&"place_entries"));
+        assert!(!steps.calls.contains(&"monitor"));
+        assert!(!steps.calls.contains(&"shutdown"));
+        assert!(steps.entries.iter().all(|order| order["amo"] == "YES"));
+    }
+
+    #[test]
+    fn test_late_start_refused() {
+        let config = RunConfig {
+            index: "NIFTY".to_string(),
+            amo: false,
+            market_open: true,
+            max_qty: None,
+            max_notional: None,
+            sequential_entry: false,
+            carry_if_itm: false,
+            past_exit: true,
+        };
+        let mut steps = MockSteps::default();
+        let error = run_strategy(&mut steps, &config).unwrap_err();
+        assert_eq!(error, "entry refused: past the NIFTY exit time");
+        assert_eq!(steps.calls, ["validate_config"]);
+        assert!(steps.entries.is_empty());
+
+        // an AMO is placed for the next open
+        let config = RunConfig {
+            amo: true,
+            market_open: false,
+            ..config
+        };
+        run_strategy(&mut MockSteps::default(), &config).unwrap();
+    }
+
+    #[test]
+    fn test_past_exit_time() {
+        let time = |h, m| chrono::NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        assert!(!past_exit_time("NIFTY", time(15, 19)));
+        assert!(past_exit_time("NIFTY", time(15, 20)));
+        assert!(past_exit_time("NIFTY", time(15, 25)));
+        assert!(!past_exit_time("CRUDEOIL", time(15, 25)));
+        assert!(!past_exit_time("XYZ", time(15, 25)));
+    }
+
+    #[test]
+    fn test_run_strategy_exposure_cap() {
+        let config = RunConfig {
+            index: "NIFTY".to_string(),
+            amo: false,
+            market_open: true,
+            max_qty: Some(400),
+            max_notional: Some(30000.0),
+            sequential_entry: false,
+            carry_if_itm: false,
+            past_exit: false,
+        };
+        // 4 legs of 100, (110 + 90 + 20 + 15) * 100 = 23500
+        let mut steps = MockSteps::default();
+        run_strategy(&mut steps, &config).unwrap();
+        // the order manager gets the cap untouched, it counts the fills itself
+        let cap = steps.cap.unwrap();
+        assert!(cap.check(400, 75.0).is_ok());
+        assert!(cap.check(401, 0.0).is_err());
+        assert!(cap.check(300, 101.0).is_err());
+
+        // the whole fly must fit, nothing is sent otherwise
+        for config in [
+            RunConfig {
+                max_qty: Some(399),
+                ..config.clone()
+            },
+            RunConfig {
+                max_notional: Some(23000.0),
+                ..config.clone()
+            },
+        ] {
+            let mut steps = MockSteps::default();
+            let e = run_strategy(&mut steps, &config).unwrap_err();
+            assert!(e.starts_with("entry refused"), "{}", e);
+            assert!(!steps.calls.contains(&"connect"));
+            assert!(steps.entries.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_run_config_from_args() {
+        let args = Cli::try_parse_from(["shoonya_rust", "--index", "NIFTY"]).unwrap();
+        let config = RunConfig::from_args(&args, true, false);
+        assert_eq!(config.index, "NIFTY");
+        assert!(config.market_open && !config.past_exit);
+        assert!(!config.amo && !config.sequential_entry && !config.carry_if_itm);
+        assert_eq!((config.max_qty, config.max_notional), (None, None));
+
+        let args = Cli::try_parse_from([
+            "shoonya_rust",
+            "--amo",
+            "--sequential-entry",
+            "--carry-if-itm",
+            "--max-qty",
+            "400",
+            "--max-notional",
+            "30000",
+        ])
+        .unwrap();
+        let config = RunConfig::from_args(&args, false, true);
+        assert!(config.past_exit);
+        assert!(config.amo && config.sequential_entry && config.carry_if_itm);
+        assert_eq!(config.max_qty, Some(400));
+        assert_eq!(config.max_notional, Some(30000.0));
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let config = serde_json::json!({
+            "INDICES_TOKEN": {"NIFTY": "26000"},
+            "INDICES_ROUNDING": {"NIFTY": 50.0},
+            "SCRIP_SYMBOL_NAME": {"NIFTY": "NIFTY"},
+        });
+        assert!(validate_config(&config, "NIFTY").is_ok());
+        assert!(validate_config(&config, "BANKNIFTY").is_err());
+        assert!(validate_config(&config, "XYZ").is_err());
+    }
+}